@@ -0,0 +1,108 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::Criterion;
+use memo_core::{time, Buffer};
+use uuid::Uuid;
+
+fn coalesce_fragments(c: &mut Criterion) {
+    c.bench_function("iter_after_typing", |b| {
+        let buffer = create_typed_buffer(100);
+        b.iter(|| buffer.iter().count())
+    });
+    c.bench_function("iter_after_typing_coalesced", |b| {
+        let mut buffer = create_typed_buffer(100);
+        buffer.coalesce_fragments();
+        b.iter(|| buffer.iter().count())
+    });
+    c.bench_function("coalesce_fragments", |b| {
+        b.iter_with_setup(
+            || create_typed_buffer(100),
+            |mut buffer| buffer.coalesce_fragments(),
+        )
+    });
+}
+
+// Simulates typing and immediately correcting characters throughout the text, which leaves
+// the base text split into one fragment per correction.
+fn create_typed_buffer(lines: usize) -> Buffer {
+    let mut base_text = String::new();
+    for i in 0..lines {
+        base_text.push_str(&format!("Lorem ipsum dolor sit amet {}\n", i));
+    }
+
+    let mut buffer = Buffer::new(base_text.as_str());
+    let mut local_clock = time::Local::new(Uuid::from_u128(1));
+    let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+    let line_len = buffer.len() / lines;
+    for i in 0..lines {
+        for column in &[5, 11, 17] {
+            let offset = i * line_len + column;
+            buffer.edit(
+                Some(offset..offset),
+                "x",
+                &mut local_clock,
+                &mut lamport_clock,
+            );
+            buffer.edit(
+                Some(offset..offset + 1),
+                "",
+                &mut local_clock,
+                &mut lamport_clock,
+            );
+        }
+    }
+    let barrier = buffer.version.clone();
+    buffer.collect_garbage(&barrier);
+    buffer
+}
+
+// Criterion only measures time, so the memory held by tombstones is printed once alongside the
+// cost of collecting them.
+fn heavy_deletes(c: &mut Criterion) {
+    let buffer = create_deleted_buffer(1000);
+    let mut collected = buffer.clone();
+    let barrier = collected.version.clone();
+    collected.collect_garbage(&barrier);
+    println!(
+        "heavy_deletes: {:?} tombstoned, {:?} collected",
+        buffer.memory_usage(),
+        collected.memory_usage()
+    );
+
+    c.bench_function("heavy_deletes_collect_garbage", move |b| {
+        b.iter_with_setup(
+            || buffer.clone(),
+            |mut buffer| {
+                let barrier = buffer.version.clone();
+                buffer.collect_garbage(&barrier)
+            },
+        )
+    });
+}
+
+// Deletes every other run of six characters, one run at a time.
+fn create_deleted_buffer(lines: usize) -> Buffer {
+    let mut base_text = String::new();
+    for i in 0..lines {
+        base_text.push_str(&format!("Lorem ipsum dolor sit amet {}\n", i));
+    }
+
+    let mut buffer = Buffer::new(base_text.as_str());
+    let mut local_clock = time::Local::new(Uuid::from_u128(1));
+    let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+    let mut offset = 0;
+    while offset + 6 < buffer.len() {
+        buffer.edit(
+            Some(offset..offset + 6),
+            "",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        offset += 6;
+    }
+    buffer
+}
+
+criterion_group!(benches, coalesce_fragments, heavy_deletes);
+criterion_main!(benches);
@@ -1008,6 +1008,17 @@ impl<F: Fn(&T::Summary) -> bool, T: Item> FilterCursor<F, T> {
     pub fn next(&mut self) {
         self.cursor.next_internal(&self.filter_node);
     }
+
+    // Skips ahead to `pos`, which may land on an item the filter would have skipped. Positions
+    // behind the cursor are ignored.
+    pub fn seek_forward<D>(&mut self, pos: &D, bias: SeekBias)
+    where
+        D: Dimension<T::Summary>,
+    {
+        if !self.cursor.at_end && self.cursor.start::<D>() < *pos {
+            self.cursor.seek_forward(pos, bias);
+        }
+    }
 }
 
 impl<F: Fn(&T::Summary) -> bool, T: Item> Iterator for FilterCursor<F, T> {
@@ -6,31 +6,64 @@ use crate::{Error, ReplicaId};
 use flatbuffers::{FlatBufferBuilder, WIPOffset};
 use lazy_static::lazy_static;
 use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use smallvec::SmallVec;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::cmp::{self, Ordering};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
 use std::iter;
 use std::mem;
 use std::ops::{Add, AddAssign, Range, Sub};
+use std::sync::atomic::{self, AtomicBool};
 use std::sync::Arc;
 use std::vec;
+use unicode_normalization::char::{canonical_combining_class, compose};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
 
 pub type SelectionSetId = time::Lamport;
 pub type SelectionsVersion = usize;
 
+const BYTE_ORDER_MARK: u16 = 0xFEFF;
+const SEARCH_CANCEL_CHECK_INTERVAL: usize = 4096;
+const RELOCATE_WINDOW: usize = 2048;
+const WRITE_CHUNK_SIZE: usize = 8192;
+const ROLLING_HASH_BASE: u64 = 0x100000001b3;
+const COMMON_CONTENT_MAX_WINDOWS: usize = 1 << 16;
+const COMMON_CONTENT_MAX_EXTENSIONS: usize = 1 << 12;
+const COMMON_CONTENT_MAX_EXTENSION_LEN: usize = 1 << 12;
+const RFIND_BLOCK_LEN: usize = 1024;
+const CHAR_COUNT_STRIDE: usize = 64;
+// The longest run of non-starters that the stream-safe text format of UAX #15 allows.
+const MAX_NON_STARTERS: usize = 30;
+
 #[derive(Clone)]
 pub struct Buffer {
     fragments: btree::Tree<Fragment>,
     insertion_splits: HashMap<time::Local, btree::Tree<InsertionSplit>>,
     anchor_cache: RefCell<HashMap<Anchor, (usize, Point)>>,
     offset_cache: RefCell<HashMap<Point, usize>>,
+    resolved_anchors: RefCell<Option<ResolvedAnchors>>,
+    cache_resolved_anchors: bool,
     pub version: time::Global,
     last_edit: time::Local,
     selections: HashMap<SelectionSetId, Vec<Selection>>,
+    hidden_selection_sets: HashSet<SelectionSetId>,
     pub selections_last_update: SelectionsVersion,
     deferred_ops: OperationQueue<Operation>,
     deferred_replicas: HashSet<ReplicaId>,
+    collected_garbage: bool,
+    anchor_fallbacks: Cell<usize>,
+    has_bom: bool,
+    marks: HashMap<char, Anchor>,
+}
+
+#[derive(Clone)]
+struct ResolvedAnchors {
+    version: time::Global,
+    anchors: Vec<Anchor>,
+    points: Arc<[Point]>,
 }
 
 #[derive(Clone, Copy, Deserialize, Eq, PartialEq, Debug, Hash, Serialize)]
@@ -50,12 +83,27 @@ pub enum Anchor {
     },
 }
 
-#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Hash, Serialize)]
 pub enum AnchorBias {
     Left,
     Right,
 }
 
+// An anchor in a form that can be written to disk. Insertions are identified by the replica and
+// sequence number that created them, so the anchor resolves against any buffer rebuilt from the
+// same operations, such as one reloaded from the operation log in a later session.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum PersistentAnchor {
+    Start,
+    End,
+    Middle {
+        replica_id: ReplicaId,
+        insertion: u64,
+        offset: usize,
+        bias: AnchorBias,
+    },
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Selection {
     pub start: Anchor,
@@ -74,6 +122,34 @@ struct ChangesIter<F: Fn(&FragmentSummary) -> bool> {
     since: time::Global,
 }
 
+// Decodes the characters preceding a point, nearest first.
+struct RevChars {
+    code_units: iter::Peekable<Iter>,
+}
+
+// A contiguous run of code units borrowed from a single insertion.
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    text: Arc<Text>,
+    range: Range<usize>,
+}
+
+struct ReverseChunks {
+    fragment_cursor: btree::Cursor<Fragment>,
+    range: Range<usize>,
+}
+
+// Yields the start offset and hash of every window of `window_len` code units, updating the hash
+// in constant time as the window slides forward.
+struct RollingHashes {
+    code_units: Iter,
+    window: VecDeque<u16>,
+    window_len: usize,
+    hash: u64,
+    leading_factor: u64,
+    offset: usize,
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct Change {
     pub range: Range<Point>,
@@ -81,6 +157,136 @@ pub struct Change {
     new_extent: Point,
 }
 
+pub trait WordClassifier {
+    fn is_word_char(&self, c: char) -> bool;
+}
+
+// Treats letters, digits and underscores as word characters, as is common in identifiers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CodeWordClassifier;
+
+// A measure of text that the fragment tree keeps a running total of, so that positions can be
+// located by it in logarithmic time. `usize` measures UTF-16 code units.
+pub trait SeekDimension: btree::Dimension<FragmentSummary> {
+    // The extent of a single character, or of an unpaired surrogate when `c` is `None`.
+    fn measure(c: Option<char>) -> Self;
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Utf8Offset(pub usize);
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct CodePointOffset(pub usize);
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Row(pub u32);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CharClass {
+    Whitespace,
+    Word,
+    Punctuation,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SearchOptions {
+    pub ignore_case: bool,
+    // Makes `replace_all` adapt the replacement to the casing of each match.
+    pub preserve_case: bool,
+    // Compares the NFC forms of the query and the text, so that composed and decomposed
+    // characters match each other.
+    pub normalize: bool,
+}
+
+// Yields the NFC form of a sequence of characters. Each character of the output is tagged with
+// the range of the input that the character sequence it came from occupied, which starts at a
+// character with a combining class of zero and extends over the combining marks following it, as
+// well as over any starters that compose with it, such as the jamo of a decomposed Hangul
+// syllable. Like the stream-safe format, a sequence ends after `MAX_NON_STARTERS` marks in a row.
+struct NfcChars<I: Iterator<Item = (usize, char)>> {
+    chars: iter::Peekable<I>,
+    pending: VecDeque<(Range<usize>, char)>,
+}
+
+impl SearchOptions {
+    fn chars_match(&self, a: char, b: char) -> bool {
+        a == b || (self.ignore_case && a.to_lowercase().eq(b.to_lowercase()))
+    }
+
+    fn query_chars(&self, query: &str) -> Vec<char> {
+        if self.normalize {
+            NfcChars {
+                chars: query.chars().enumerate().peekable(),
+                pending: VecDeque::new(),
+            }
+            .map(|(_, c)| c)
+            .collect()
+        } else {
+            query.chars().collect()
+        }
+    }
+
+    fn text_chars<'a, I>(
+        &self,
+        code_units: I,
+        start: usize,
+    ) -> Box<'a + Iterator<Item = (Range<usize>, char)>>
+    where
+        I: 'a + Iterator<Item = u16>,
+    {
+        let mut offset = start;
+        let chars = std::char::decode_utf16(code_units).map(move |c| {
+            let c = c.unwrap_or(std::char::REPLACEMENT_CHARACTER);
+            let char_offset = offset;
+            offset += c.len_utf16();
+            (char_offset, c)
+        });
+        if self.normalize {
+            Box::new(NfcChars {
+                chars: chars.peekable(),
+                pending: VecDeque::new(),
+            })
+        } else {
+            Box::new(chars.map(|(offset, c)| (offset..offset + c.len_utf16(), c)))
+        }
+    }
+}
+
+impl<I: Iterator<Item = (usize, char)>> Iterator for NfcChars<I> {
+    type Item = (Range<usize>, char);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(c) = self.pending.pop_front() {
+            return Some(c);
+        }
+
+        let (start, c) = self.chars.next()?;
+        let mut end = start + c.len_utf16();
+        let mut sequence = vec![c];
+        let mut starter = c;
+        let mut non_starters = 0;
+        while let Some((_, c)) = self.chars.peek().cloned() {
+            if canonical_combining_class(c) == 0 {
+                match compose(starter, c) {
+                    Some(composed) if non_starters == 0 => starter = composed,
+                    _ => break,
+                }
+            } else if non_starters == MAX_NON_STARTERS {
+                break;
+            } else {
+                non_starters += 1;
+            }
+            self.chars.next();
+            end += c.len_utf16();
+            sequence.push(c);
+        }
+        for c in sequence.into_iter().nfc() {
+            self.pending.push_back((start..end, c));
+        }
+        self.pending.pop_front()
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct Insertion {
     id: time::Local,
@@ -94,6 +300,15 @@ pub struct Insertion {
 pub struct Text {
     code_units: Vec<u16>,
     nodes: Vec<LineNode>,
+    char_counts: Vec<CharCount>,
+}
+
+// Cumulative UTF-8 byte and code point counts of the text preceding every
+// `CHAR_COUNT_STRIDE`-th code unit.
+#[derive(Clone, Copy, Default, Eq, PartialEq, Debug)]
+struct CharCount {
+    utf8_len: usize,
+    code_points: usize,
 }
 
 #[derive(Clone, Eq, PartialEq, Debug)]
@@ -131,6 +346,8 @@ struct Fragment {
 pub struct FragmentSummary {
     extent: usize,
     extent_2d: Point,
+    utf8_len: usize,
+    code_points: usize,
     max_fragment_id: FragmentId,
     first_row_len: u32,
     longest_row: u32,
@@ -138,6 +355,21 @@ pub struct FragmentSummary {
     max_version: time::Global,
 }
 
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct OpStats {
+    pub insertions: usize,
+    pub deletions: usize,
+    pub bytes: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MemoryReport {
+    pub fragment_bytes: usize,
+    pub tombstone_bytes: usize,
+    pub operation_log_bytes: usize,
+    pub version_vector_len: usize,
+}
+
 #[derive(Eq, PartialEq, Clone, Debug)]
 struct InsertionSplit {
     extent: usize,
@@ -165,6 +397,7 @@ pub enum Operation {
         set_id: SelectionSetId,
         selections: Option<Vec<Selection>>,
         lamport_timestamp: time::Lamport,
+        hidden: bool,
     },
 }
 
@@ -176,11 +409,19 @@ impl Buffer {
         let mut insertion_splits = HashMap::new();
         let mut fragments = btree::Tree::new();
 
+        // The byte order mark is not part of the logical text, so that it can't be moved or
+        // duplicated by edits. We re-emit it when exporting the buffer.
+        let mut base_text = base_text.into();
+        let has_bom = base_text.code_units.first() == Some(&BYTE_ORDER_MARK);
+        if has_bom {
+            base_text = Text::new(base_text.code_units[1..].to_vec());
+        }
+
         let base_insertion = Insertion {
             id: time::Local::default(),
             parent_id: time::Local::default(),
             offset_in_parent: 0,
-            text: Arc::new(base_text.into()),
+            text: Arc::new(base_text),
             lamport_timestamp: time::Lamport::default(),
         };
 
@@ -224,15 +465,33 @@ impl Buffer {
             insertion_splits,
             anchor_cache: RefCell::new(HashMap::default()),
             offset_cache: RefCell::new(HashMap::default()),
+            resolved_anchors: RefCell::new(None),
+            cache_resolved_anchors: true,
             version: time::Global::new(),
             last_edit: time::Local::default(),
             selections: HashMap::default(),
+            hidden_selection_sets: HashSet::new(),
             selections_last_update: 0,
             deferred_ops: OperationQueue::new(),
             deferred_replicas: HashSet::new(),
+            collected_garbage: false,
+            anchor_fallbacks: Cell::new(0),
+            has_bom,
+            marks: HashMap::new(),
         }
     }
 
+    // The number of anchors and remote edits that referred to a collected tombstone since the last
+    // call, which were resolved to the position the tombstone would have occupied. Collecting
+    // tombstones that a replica still refers to is a sign of a barrier that is too aggressive.
+    pub fn take_anchor_fallbacks(&self) -> usize {
+        self.anchor_fallbacks.replace(0)
+    }
+
+    pub fn has_bom(&self) -> bool {
+        self.has_bom
+    }
+
     pub fn is_modified(&self) -> bool {
         self.version != time::Global::new()
     }
@@ -241,6 +500,100 @@ impl Buffer {
         self.fragments.extent::<usize>()
     }
 
+    // The length of the text once encoded as UTF-8.
+    pub fn len_bytes(&self) -> usize {
+        self.fragments.extent::<Utf8Offset>().0
+    }
+
+    // Returns the first position at which at least `target` of the given dimension precedes it.
+    pub fn seek_by_dimension<D: SeekDimension>(&self, target: D) -> Result<Point, Error> {
+        let mut cursor = self.fragments.cursor();
+        cursor.seek(&target, SeekBias::Left);
+        let fragment = cursor.item().ok_or(Error::OffsetOutOfRange)?;
+
+        let mut overshoot = 0;
+        if fragment.is_visible() {
+            let mut measured = cursor.start::<D>();
+            let code_units =
+                &fragment.insertion.text.code_units[fragment.start_offset..fragment.end_offset];
+            for c in std::char::decode_utf16(code_units.iter().cloned()) {
+                if measured >= target {
+                    break;
+                }
+                let c = c.ok();
+                measured += &D::measure(c);
+                overshoot += c.map_or(1, |c| c.len_utf16());
+            }
+        }
+        self.point_for_offset(cursor.start::<usize>() + overshoot)
+    }
+
+    // Converts a UTF-8 byte offset into a character offset. Like `seek_by_dimension`, offsets inside
+    // of a multi-byte character round up to the end of the character.
+    pub fn byte_to_char(&self, byte_offset: usize) -> Result<usize, Error> {
+        self.convert_offset::<Utf8Offset, CodePointOffset>(Utf8Offset(byte_offset))
+            .map(|offset| offset.0)
+    }
+
+    pub fn char_to_byte(&self, char_offset: usize) -> Result<usize, Error> {
+        self.convert_offset::<CodePointOffset, Utf8Offset>(CodePointOffset(char_offset))
+            .map(|offset| offset.0)
+    }
+
+    // The UTF-8 byte offset at which each row starts. Only `\n` ends a row, so for `\r\n` line
+    // endings the `\r` is counted at the end of the preceding row.
+    pub fn line_offsets(&self) -> Vec<usize> {
+        self.line_offsets_in_range(0..self.max_point().row + 1)
+            .unwrap()
+    }
+
+    pub fn line_offsets_in_range(&self, rows: Range<u32>) -> Result<Vec<usize>, Error> {
+        if rows.end > self.max_point().row + 1 {
+            return Err(Error::OffsetOutOfRange);
+        }
+
+        rows.map(|row| {
+            let offset = self.offset_for_point(Point::new(row, 0))?;
+            self.convert_offset::<usize, Utf8Offset>(offset)
+                .map(|offset| offset.0)
+        })
+        .collect()
+    }
+
+    pub fn char_count(&self, range: Range<Point>) -> Result<usize, Error> {
+        let start = self.offset_for_point(range.start)?;
+        let end = self.offset_for_point(range.end)?;
+        let start = self.convert_offset::<usize, CodePointOffset>(start)?;
+        let end = self.convert_offset::<usize, CodePointOffset>(end)?;
+        Ok(end.0.saturating_sub(start.0))
+    }
+
+    fn convert_offset<S: SeekDimension, T: SeekDimension>(&self, source: S) -> Result<T, Error> {
+        if source == self.fragments.extent::<S>() {
+            return Ok(self.fragments.extent::<T>());
+        }
+
+        let mut cursor = self.fragments.cursor();
+        cursor.seek(&source, SeekBias::Left);
+        let fragment = cursor.item().ok_or(Error::OffsetOutOfRange)?;
+
+        let mut measured = cursor.start::<S>();
+        let mut converted = cursor.start::<T>();
+        if fragment.is_visible() {
+            let code_units =
+                &fragment.insertion.text.code_units[fragment.start_offset..fragment.end_offset];
+            for c in std::char::decode_utf16(code_units.iter().cloned()) {
+                if measured >= source {
+                    break;
+                }
+                let c = c.ok();
+                measured += &S::measure(c);
+                converted += &T::measure(c);
+            }
+        }
+        Ok(converted)
+    }
+
     pub fn len_for_row(&self, row: u32) -> Result<u32, Error> {
         let row_start_offset = self.offset_for_point(Point::new(row, 0))?;
         let row_end_offset = if row >= self.max_point().row {
@@ -269,2705 +622,7039 @@ impl Buffer {
         }
     }
 
-    pub fn to_u16_chars(&self) -> Vec<u16> {
-        self.iter().collect::<Vec<u16>>()
+    pub fn line_start(&self, point: Point) -> Point {
+        Point::new(cmp::min(point.row, self.max_point().row), 0)
     }
 
-    pub fn to_string(&self) -> String {
-        String::from_utf16_lossy(&self.to_u16_chars())
+    // The end of a line is before its line terminator, whether it is "\n" or "\r\n".
+    pub fn line_end(&self, point: Point) -> Point {
+        let row = cmp::min(point.row, self.max_point().row);
+        let mut column = self.len_for_row(row).unwrap();
+        if column > 0 && row < self.max_point().row {
+            let last_char = self.iter_at_point(Point::new(row, column - 1)).next();
+            if last_char == Some(u16::from(b'\r')) {
+                column -= 1;
+            }
+        }
+        Point::new(row, column)
     }
 
-    pub fn iter(&self) -> Iter {
-        Iter::new(self)
+    // Both anchors are biased to the right, so lines inserted before the start of the line shift
+    // the range down and text appended to the line extends it.
+    pub fn anchor_range_for_line(&self, row: u32) -> Result<Range<Anchor>, Error> {
+        if row > self.max_point().row {
+            return Err(Error::OffsetOutOfRange);
+        }
+        let start = self.anchor_after_point(Point::new(row, 0))?;
+        let end = self.anchor_after_point(self.line_end(Point::new(row, 0)))?;
+        Ok(start..end)
+    }
+
+    pub fn first_non_whitespace(&self, row: u32) -> Point {
+        let line_end = self.line_end(Point::new(row, 0));
+        let line = self
+            .iter_at_point(self.line_start(line_end))
+            .take(line_end.column as usize);
+        let mut column = 0;
+        for c in std::char::decode_utf16(line) {
+            match c {
+                Ok(c) if c.is_whitespace() => column += c.len_utf16() as u32,
+                _ => break,
+            }
+        }
+        Point::new(line_end.row, column)
     }
 
-    pub fn iter_at_point(&self, point: Point) -> Iter {
-        Iter::at_point(self, point)
+    // The runs of whitespace at the end of each line. Line terminators, including the carriage
+    // return of a CRLF, aren't part of the runs.
+    pub fn trailing_whitespace_ranges(&self) -> Vec<Range<Point>> {
+        let mut ranges = Vec::new();
+        for row in 0..=self.max_point().row {
+            let line = match self.line(row) {
+                Ok(line) => line,
+                Err(_) => continue,
+            };
+            let mut end = line.len();
+            if line.last() == Some(&u16::from(b'\r')) {
+                end -= 1;
+            }
+            let start = line[..end]
+                .iter()
+                .rposition(|c| {
+                    !std::char::from_u32(u32::from(*c)).map_or(false, char::is_whitespace)
+                })
+                .map_or(0, |index| index + 1);
+            if start < end {
+                ranges.push(Point::new(row, start as u32)..Point::new(row, end as u32));
+            }
+        }
+        ranges
     }
 
-    pub fn selections_changed_since(&self, since: SelectionsVersion) -> bool {
-        self.selections_last_update != since
+    // The width of the row's leading spaces and tabs, with tabs advancing to the next multiple of
+    // `tab_size`.
+    pub fn indent_for_row(&self, row: u32, tab_size: u32) -> Result<u32, Error> {
+        let (indentation, _) = self.row_indentation(row)?;
+        Ok(indentation_width(&indentation, cmp::max(tab_size, 1)))
     }
 
-    pub fn changes_since(&self, since: &time::Global) -> impl Iterator<Item = Change> {
-        let since_2 = since.clone();
-        let cursor = self
-            .fragments
-            .filter(move |summary| summary.max_version.changed_since(&since_2));
-        ChangesIter {
-            cursor,
-            since: since.clone(),
+    // The indentation of the closest row above `row` that has any non-whitespace characters, which
+    // is where a line inserted at `row` would start. Blank and whitespace-only rows are skipped.
+    pub fn suggested_indent(&self, row: u32, tab_size: u32) -> u32 {
+        let mut row = cmp::min(row, self.max_point().row + 1);
+        while row > 0 {
+            row -= 1;
+            if let Ok((indentation, false)) = self.row_indentation(row) {
+                return indentation_width(&indentation, cmp::max(tab_size, 1));
+            }
         }
+        0
     }
 
-    pub fn deferred_ops_len(&self) -> usize {
-        self.deferred_ops.len()
-    }
-
-    pub fn edit<I, T>(
-        &mut self,
-        old_ranges: I,
-        new_text: T,
-        local_clock: &mut time::Local,
-        lamport_clock: &mut time::Lamport,
-    ) -> Vec<Operation>
-    where
-        I: IntoIterator<Item = Range<usize>>,
-        T: Into<Text>,
-    {
-        let new_text = new_text.into();
-        let new_text = if new_text.len() > 0 {
-            Some(Arc::new(new_text))
-        } else {
-            None
-        };
+    // Returns the leading whitespace of the row, along with whether the row is blank.
+    fn row_indentation(&self, row: u32) -> Result<(String, bool), Error> {
+        if row > self.max_point().row {
+            return Err(Error::OffsetOutOfRange);
+        }
 
-        self.anchor_cache.borrow_mut().clear();
-        self.offset_cache.borrow_mut().clear();
-        let ops = self.splice_fragments(
-            old_ranges
-                .into_iter()
-                .filter(|old_range| new_text.is_some() || old_range.end > old_range.start),
-            new_text.clone(),
-            local_clock,
-            lamport_clock,
-        );
-        if let Some(op) = ops.last() {
-            if let Operation::Edit {
-                local_timestamp, ..
-            } = op
-            {
-                self.last_edit = *local_timestamp;
-                self.version.observe(*local_timestamp);
+        let mut indentation = String::new();
+        let mut chars = std::char::decode_utf16(
+            self.iter_at_point(Point::new(row, 0))
+                .take_while(|c| *c != u16::from(b'\n')),
+        )
+        .map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER));
+        while let Some(c) = chars.next() {
+            if c == ' ' || c == '\t' {
+                indentation.push(c);
             } else {
-                unreachable!()
+                let is_blank = c.is_whitespace() && chars.all(char::is_whitespace);
+                return Ok((indentation, is_blank));
             }
         }
-        ops
+        Ok((indentation, true))
     }
 
-    pub fn edit_2d<I, T>(
-        &mut self,
-        old_2d_ranges: I,
-        new_text: T,
-        local_clock: &mut time::Local,
-        lamport_clock: &mut time::Lamport,
-    ) -> Vec<Operation>
-    where
-        I: IntoIterator<Item = Range<Point>>,
-        T: Into<Text>,
-    {
-        let mut old_1d_ranges = SmallVec::<[_; 1]>::new();
-        for old_2d_range in old_2d_ranges {
-            let start = self.offset_for_point(old_2d_range.start);
-            let end = self.offset_for_point(old_2d_range.end);
-            if start.is_ok() && end.is_ok() {
-                old_1d_ranges.push(start.unwrap()..end.unwrap());
+    // Skips any whitespace after `point`, followed by a run of characters of the same class.
+    pub fn next_word_boundary<C: WordClassifier>(
+        &self,
+        point: Point,
+        classifier: &C,
+    ) -> Result<Point, Error> {
+        let mut offset = self.offset_for_point(point)?;
+        let mut run_class = None;
+        for c in std::char::decode_utf16(self.iter_at_point(point)) {
+            let c = c.unwrap_or(std::char::REPLACEMENT_CHARACTER);
+            let class = CharClass::new(c, classifier);
+            match run_class {
+                None if class != CharClass::Whitespace => run_class = Some(class),
+                Some(run_class) if class != run_class => break,
+                _ => {}
             }
+            offset += c.len_utf16();
         }
-        self.edit(old_1d_ranges, new_text, local_clock, lamport_clock)
+        self.point_for_offset(offset)
     }
 
-    pub fn add_selection_set<I>(
-        &mut self,
-        ranges: I,
-        lamport_clock: &mut time::Lamport,
-    ) -> Result<(SelectionSetId, Operation), Error>
-    where
-        I: IntoIterator<Item = Range<Point>>,
-    {
-        let selections = self.selections_from_ranges(ranges)?;
-        let lamport_timestamp = lamport_clock.tick();
-        self.selections
-            .insert(lamport_timestamp, selections.clone());
-        self.selections_last_update += 1;
-
-        Ok((
-            lamport_timestamp,
-            Operation::UpdateSelections {
-                set_id: lamport_timestamp,
-                selections: Some(selections),
-                lamport_timestamp,
-            },
-        ))
+    pub fn prev_word_boundary<C: WordClassifier>(
+        &self,
+        point: Point,
+        classifier: &C,
+    ) -> Result<Point, Error> {
+        let mut offset = self.offset_for_point(point)?;
+        let mut run_class = None;
+        for c in self.rev_chars_at_point(point) {
+            let class = CharClass::new(c, classifier);
+            match run_class {
+                None if class != CharClass::Whitespace => run_class = Some(class),
+                Some(run_class) if class != run_class => break,
+                _ => {}
+            }
+            offset -= c.len_utf16();
+        }
+        self.point_for_offset(offset)
     }
 
-    pub fn replace_selection_set<I>(
-        &mut self,
-        set_id: SelectionSetId,
-        ranges: I,
-        lamport_clock: &mut time::Lamport,
-    ) -> Result<Operation, Error>
-    where
-        I: IntoIterator<Item = Range<Point>>,
-    {
-        self.selections
-            .remove(&set_id)
-            .ok_or(Error::InvalidSelectionSet(set_id))?;
+    // The word containing `point` or ending at it, along with its text. When `point` is on
+    // whitespace, this returns `None` unless `adjacent` is true, in which case the first word after
+    // the whitespace on the same line is returned instead. The row is split into extended grapheme
+    // clusters, and a cluster belongs to a word when its first character is a word character, so
+    // combining marks and other extending characters stay with the character they modify.
+    pub fn word_at<C: WordClassifier>(
+        &self,
+        point: Point,
+        classifier: &C,
+        adjacent: bool,
+    ) -> Result<Option<(Range<Point>, String)>, Error> {
+        self.offset_for_point(point)?;
+        let row_len = self.len_for_row(point.row)?;
+        let row_code_units = self
+            .iter_at_point(Point::new(point.row, 0))
+            .take(row_len as usize)
+            .collect::<Vec<_>>();
+        // Unpaired surrogates are replaced by a character of the same UTF-16 length, so columns
+        // computed from the lossy text match the buffer's.
+        let row = String::from_utf16_lossy(&row_code_units);
+
+        struct Grapheme {
+            columns: Range<u32>,
+            bytes: Range<usize>,
+            is_word: bool,
+            is_whitespace: bool,
+        }
 
-        let mut selections = self.selections_from_ranges(ranges)?;
-        self.merge_selections(&mut selections);
-        self.selections.insert(set_id, selections.clone());
+        let mut graphemes = Vec::new();
+        let mut column = 0;
+        for (byte_offset, grapheme) in row.grapheme_indices(true) {
+            let len = grapheme.encode_utf16().count() as u32;
+            let first_char = grapheme.chars().next().unwrap();
+            graphemes.push(Grapheme {
+                columns: column..column + len,
+                bytes: byte_offset..byte_offset + grapheme.len(),
+                is_word: classifier.is_word_char(first_char),
+                is_whitespace: grapheme.chars().all(char::is_whitespace),
+            });
+            column += len;
+        }
 
-        let lamport_timestamp = lamport_clock.tick();
-        self.selections_last_update += 1;
+        let index = graphemes
+            .iter()
+            .position(|grapheme| grapheme.columns.end > point.column)
+            .unwrap_or(graphemes.len());
+        let mut start = index;
+        while start > 0 && graphemes[start - 1].is_word {
+            start -= 1;
+        }
 
-        Ok(Operation::UpdateSelections {
-            set_id,
-            selections: Some(selections),
-            lamport_timestamp,
-        })
-    }
+        if start == index && adjacent {
+            while start < graphemes.len() && graphemes[start].is_whitespace {
+                start += 1;
+            }
+        }
 
-    pub fn remove_selection_set(
-        &mut self,
-        set_id: SelectionSetId,
-        lamport_clock: &mut time::Lamport,
-    ) -> Result<Operation, Error> {
-        self.selections
-            .remove(&set_id)
-            .ok_or(Error::InvalidSelectionSet(set_id))?;
-        let lamport_timestamp = lamport_clock.tick();
-        self.selections_last_update += 1;
-        Ok(Operation::UpdateSelections {
-            set_id,
-            selections: None,
-            lamport_timestamp,
-        })
+        let mut end = start;
+        while end < graphemes.len() && graphemes[end].is_word {
+            end += 1;
+        }
+
+        if end == start {
+            Ok(None)
+        } else {
+            let first = &graphemes[start];
+            let last = &graphemes[end - 1];
+            Ok(Some((
+                Point::new(point.row, first.columns.start)..Point::new(point.row, last.columns.end),
+                row[first.bytes.start..last.bytes.end].to_string(),
+            )))
+        }
     }
 
-    pub fn selection_ranges<'a>(
-        &'a self,
-        set_id: SelectionSetId,
-    ) -> Result<impl Iterator<Item = Range<Point>> + 'a, Error> {
-        let selections = self
-            .selections
-            .get(&set_id)
-            .ok_or(Error::InvalidSelectionSet(set_id))?;
-        Ok(selections.iter().map(move |selection| {
-            let start = self.point_for_anchor(&selection.start).unwrap();
-            let end = self.point_for_anchor(&selection.end).unwrap();
-            if selection.reversed {
-                end..start
-            } else {
-                start..end
+    // If there's a bracket at `point`, scans outward from it for the bracket that closes (or opens)
+    // it, skipping over any nested pairs of the same kind.
+    pub fn matching_bracket(&self, point: Point) -> Option<Point> {
+        const PAIRS: [(u16, u16); 3] = [
+            (b'(' as u16, b')' as u16),
+            (b'[' as u16, b']' as u16),
+            (b'{' as u16, b'}' as u16),
+        ];
+
+        let offset = self.offset_for_point(point).ok()?;
+        let bracket = self.iter_at_point(point).next()?;
+        let (&(open, close), forward) = PAIRS
+            .iter()
+            .find(|(open, _)| *open == bracket)
+            .map(|pair| (pair, true))
+            .or_else(|| {
+                PAIRS
+                    .iter()
+                    .find(|(_, close)| *close == bracket)
+                    .map(|pair| (pair, false))
+            })?;
+
+        let mut depth = 0;
+        if forward {
+            for (i, c) in self.iter_at_point(point).enumerate() {
+                if c == open {
+                    depth += 1;
+                } else if c == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return self.point_for_offset(offset + i).ok();
+                    }
+                }
             }
-        }))
+        } else {
+            for (i, c) in self.iter_at_point(point).rev().enumerate() {
+                if c == open {
+                    if depth == 0 {
+                        return self.point_for_offset(offset - i - 1).ok();
+                    }
+                    depth -= 1;
+                } else if c == close {
+                    depth += 1;
+                }
+            }
+        }
+        None
     }
 
-    pub fn all_selections(&self) -> impl Iterator<Item = (&SelectionSetId, &Vec<Selection>)> {
-        self.selections.iter()
-    }
+    // Looks for `context` within `RELOCATE_WINDOW` code units of `stale_point` and returns the
+    // position of the closest occurrence. There is no confident match if the context can't be
+    // found or if two occurrences are equally close.
+    pub fn relocate(&self, stale_point: Point, context: &str) -> Option<Point> {
+        let context = context.encode_utf16().collect::<Vec<_>>();
+        if context.is_empty() {
+            return None;
+        }
 
-    pub fn all_selection_ranges<'a>(
-        &'a self,
-    ) -> impl 'a + Iterator<Item = (SelectionSetId, Vec<Range<Point>>)> {
-        self.selections
-            .keys()
-            .map(move |set_id| (*set_id, self.selection_ranges(*set_id).unwrap().collect()))
+        let max_point = self.max_point();
+        let row = cmp::min(stale_point.row, max_point.row);
+        let column = cmp::min(stale_point.column, self.len_for_row(row).ok()?);
+        let stale_offset = self.offset_for_point(Point::new(row, column)).ok()?;
+        let window_start = stale_offset.saturating_sub(RELOCATE_WINDOW);
+        let window = self
+            .iter_at_point(self.point_for_offset(window_start).ok()?)
+            .take(stale_offset - window_start + RELOCATE_WINDOW + context.len())
+            .collect::<Vec<_>>();
+
+        let mut best_match: Option<(usize, usize)> = None;
+        let mut is_ambiguous = false;
+        for (index, candidate) in window.windows(context.len()).enumerate() {
+            if candidate == context.as_slice() {
+                let offset = window_start + index;
+                let distance = cmp::max(offset, stale_offset) - cmp::min(offset, stale_offset);
+                match best_match {
+                    Some((_, best_distance)) if distance > best_distance => {}
+                    Some((_, best_distance)) if distance == best_distance => is_ambiguous = true,
+                    _ => {
+                        best_match = Some((offset, distance));
+                        is_ambiguous = false;
+                    }
+                }
+            }
+        }
+
+        if is_ambiguous {
+            None
+        } else {
+            best_match.and_then(|(offset, _)| self.point_for_offset(offset).ok())
+        }
     }
 
-    fn merge_selections(&mut self, selections: &mut Vec<Selection>) {
-        let mut new_selections = Vec::with_capacity(selections.len());
-        {
-            let mut old_selections = selections.drain(..);
-            if let Some(mut prev_selection) = old_selections.next() {
-                for selection in old_selections {
-                    if self
-                        .cmp_anchors(&prev_selection.end, &selection.start)
-                        .unwrap()
-                        >= Ordering::Equal
-                    {
-                        if self
-                            .cmp_anchors(&selection.end, &prev_selection.end)
-                            .unwrap()
-                            > Ordering::Equal
-                        {
-                            prev_selection.end = selection.end;
+    // Columns that fall inside a tab snap to whichever edge of the tab is nearest.
+    pub fn offset_for_display_column(
+        &self,
+        row: u32,
+        display_column: u32,
+        tab_size: u32,
+    ) -> Result<usize, Error> {
+        if row > self.max_point().row {
+            return Err(Error::OffsetOutOfRange);
+        }
+
+        let mut offset = self.offset_for_point(Point::new(row, 0))?;
+        let line = self
+            .iter_at_point(Point::new(row, 0))
+            .take_while(|c| *c != u16::from(b'\n'));
+        let mut column = 0;
+        for c in std::char::decode_utf16(line) {
+            let c = c.unwrap_or(std::char::REPLACEMENT_CHARACTER);
+            let width = if c == '\t' {
+                let tab_size = cmp::max(tab_size, 1);
+                tab_size - column % tab_size
+            } else {
+                1
+            };
+            if display_column < column + width {
+                if (display_column - column) * 2 >= width {
+                    offset += c.len_utf16();
+                }
+                break;
+            }
+            column += width;
+            offset += c.len_utf16();
+        }
+        Ok(offset)
+    }
+
+    pub fn surrounding_text(&self, point: Point, before: usize, after: usize) -> String {
+        let row = cmp::min(point.row, self.max_point().row);
+        let column = cmp::min(point.column, self.len_for_row(row).unwrap());
+        let point = Point::new(row, column);
+
+        let mut prefix = Vec::new();
+        let mut reversed_code_units = self.iter_at_point(point).rev().peekable();
+        for _ in 0..before {
+            if let Some(code_unit) = reversed_code_units.next() {
+                prefix.push(code_unit);
+                let is_low_surrogate = code_unit >= 0xDC00 && code_unit <= 0xDFFF;
+                if is_low_surrogate {
+                    if let Some(&next_code_unit) = reversed_code_units.peek() {
+                        if next_code_unit >= 0xD800 && next_code_unit <= 0xDBFF {
+                            prefix.push(next_code_unit);
+                            reversed_code_units.next();
                         }
-                    } else {
-                        new_selections.push(mem::replace(&mut prev_selection, selection));
                     }
                 }
-                new_selections.push(prev_selection);
+            } else {
+                break;
             }
         }
-        *selections = new_selections;
+        prefix.reverse();
+
+        let mut text = String::from_utf16_lossy(&prefix);
+        text.extend(
+            std::char::decode_utf16(self.iter_at_point(point))
+                .take(after)
+                .map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER)),
+        );
+        text
     }
 
-    fn selections_from_ranges<I>(&self, ranges: I) -> Result<Vec<Selection>, Error>
-    where
-        I: IntoIterator<Item = Range<Point>>,
-    {
-        let mut ranges = ranges.into_iter().collect::<Vec<_>>();
-        ranges.sort_unstable_by_key(|range| range.start);
+    pub fn to_u16_chars(&self) -> Vec<u16> {
+        self.iter().collect::<Vec<u16>>()
+    }
 
-        let mut selections = Vec::with_capacity(ranges.len());
-        for range in ranges {
-            if range.start > range.end {
-                selections.push(Selection {
-                    start: self.anchor_before_point(range.end)?,
-                    end: self.anchor_before_point(range.start)?,
-                    reversed: true,
-                });
-            } else {
-                selections.push(Selection {
-                    start: self.anchor_before_point(range.start)?,
-                    end: self.anchor_before_point(range.end)?,
-                    reversed: false,
-                });
-            }
+    pub fn to_string(&self) -> String {
+        String::from_utf16_lossy(&self.to_u16_chars())
+    }
+
+    pub fn export(&self) -> String {
+        let mut code_units = Vec::with_capacity(self.len() + 1);
+        if self.has_bom {
+            code_units.push(BYTE_ORDER_MARK);
         }
-        Ok(selections)
+        code_units.extend(self.iter());
+        String::from_utf16_lossy(&code_units)
     }
 
-    pub fn apply_ops<I: IntoIterator<Item = Operation>>(
-        &mut self,
-        ops: I,
-        local_clock: &mut time::Local,
-        lamport_clock: &mut time::Lamport,
-    ) -> Result<(), Error> {
-        let mut deferred_ops = Vec::new();
-        for op in ops {
-            if self.can_apply_op(&op) {
-                self.apply_op(op, local_clock, lamport_clock)?;
-            } else {
-                self.deferred_replicas.insert(op.replica_id());
-                deferred_ops.push(op);
+    pub fn ends_with_newline(&self) -> bool {
+        if self.len() == 0 {
+            false
+        } else {
+            let last_point = self.point_for_offset(self.len() - 1).unwrap();
+            self.iter_at_point(last_point).next() == Some(u16::from(b'\n'))
+        }
+    }
+
+    // Like `export`, but the exported text ends with exactly one line terminator. Any trailing
+    // terminators are collapsed into one, keeping "\r\n" if that's what the text used. Empty
+    // buffers are exported as is.
+    pub fn export_with_trailing_newline(&self) -> String {
+        let mut text = self.export();
+        if self.len() == 0 {
+            return text;
+        }
+
+        let content_len = text.trim_end_matches(|c| c == '\n' || c == '\r').len();
+        let terminator = if text[content_len..].starts_with("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        text.truncate(content_len);
+        text.push_str(terminator);
+        text
+    }
+
+    // Streams the same bytes as `export` without materializing the whole text.
+    pub fn write_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut chunk = Vec::with_capacity(WRITE_CHUNK_SIZE + 4);
+        let bom = if self.has_bom {
+            Some(BYTE_ORDER_MARK)
+        } else {
+            None
+        };
+        for c in std::char::decode_utf16(bom.into_iter().chain(self.iter())) {
+            let c = c.unwrap_or(std::char::REPLACEMENT_CHARACTER);
+            let mut bytes = [0; 4];
+            chunk.extend_from_slice(c.encode_utf8(&mut bytes).as_bytes());
+            if chunk.len() >= WRITE_CHUNK_SIZE {
+                writer.write_all(&chunk)?;
+                chunk.clear();
             }
         }
-        self.deferred_ops.insert(deferred_ops);
-        self.flush_deferred_ops(local_clock, lamport_clock)?;
-        Ok(())
+        writer.write_all(&chunk)
     }
 
-    fn apply_op(
-        &mut self,
-        op: Operation,
-        local_clock: &mut time::Local,
-        lamport_clock: &mut time::Lamport,
-    ) -> Result<(), Error> {
-        match op {
-            Operation::Edit {
-                start_id,
-                start_offset,
-                end_id,
-                end_offset,
-                new_text,
-                version_in_range,
-                local_timestamp,
-                lamport_timestamp,
-            } => {
-                if !self.version.observed(local_timestamp) {
-                    self.apply_edit(
-                        start_id,
-                        start_offset,
-                        end_id,
-                        end_offset,
-                        new_text.as_ref().cloned(),
-                        &version_in_range,
-                        local_timestamp,
-                        lamport_timestamp,
-                        local_clock,
-                        lamport_clock,
-                    )?;
-                    self.anchor_cache.borrow_mut().clear();
-                    self.offset_cache.borrow_mut().clear();
-                    self.version.observe(local_timestamp);
-                }
+    pub fn iter(&self) -> Iter {
+        Iter::new(self)
+    }
+
+    // The hash only depends on the code units in `range`, so replicas whose contents match there
+    // produce the same hash regardless of their fragment layout. Comparing the hashes of smaller
+    // and smaller ranges narrows down where two replicas diverged.
+    pub fn hash_range(&self, range: Range<usize>) -> Result<[u8; 32], Error> {
+        if range.start > range.end || range.end > self.len() {
+            return Err(Error::OffsetOutOfRange);
+        }
+
+        let mut hasher = Sha256::new();
+        let mut chunk = Vec::with_capacity(WRITE_CHUNK_SIZE + 2);
+        let start = self.point_for_offset(range.start)?;
+        for code_unit in self.iter_at_point(start).take(range.end - range.start) {
+            chunk.extend_from_slice(&code_unit.to_le_bytes());
+            if chunk.len() >= WRITE_CHUNK_SIZE {
+                hasher.input(&chunk);
+                chunk.clear();
             }
-            Operation::UpdateSelections {
-                set_id,
-                selections,
-                lamport_timestamp,
-            } => {
-                if let Some(selections) = selections {
-                    self.selections.insert(set_id, selections);
-                } else {
-                    self.selections.remove(&set_id);
+        }
+        hasher.input(&chunk);
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&hasher.result());
+        Ok(hash)
+    }
+
+    // The longest run of at least `min_len` code units that occurs in both buffers, located in this
+    // buffer and then in `other`. Windows of `other` are indexed by a rolling hash and candidates are
+    // extended by walking both trees. For very large buffers only a sample of those windows is
+    // indexed and the number of extensions is capped, so the result may not be the longest match.
+    // Each extension walks at most `COMMON_CONTENT_MAX_EXTENSION_LEN` code units in either
+    // direction, and a longer match is picked up by the next window along the same diagonal.
+    pub fn longest_common_substring(
+        &self,
+        other: &Buffer,
+        min_len: usize,
+    ) -> Option<(Range<Point>, Range<Point>)> {
+        let window_len = cmp::max(min_len, 1);
+        if self.len() < window_len || other.len() < window_len {
+            return None;
+        }
+
+        let window_count = other.len() - window_len + 1;
+        let stride = (window_count + COMMON_CONTENT_MAX_WINDOWS - 1) / COMMON_CONTENT_MAX_WINDOWS;
+        let mut windows: HashMap<u64, SmallVec<[usize; 2]>> = HashMap::new();
+        for (offset, hash) in RollingHashes::new(other.iter(), window_len) {
+            if offset % stride == 0 {
+                windows
+                    .entry(hash)
+                    .or_insert_with(SmallVec::new)
+                    .push(offset);
+            }
+        }
+
+        // For each diagonal, the offsets in this buffer where the last match along it started and
+        // ended. Windows that fall inside of a match we already extended are skipped, and a window
+        // starting where a match ended continues that match instead of walking back over it.
+        let mut extended: HashMap<isize, (usize, usize)> = HashMap::new();
+        let mut extension_count = 0;
+        let max_len = cmp::min(self.len(), other.len());
+        let mut longest: Option<(usize, usize, usize)> = None;
+        'windows: for (offset, hash) in RollingHashes::new(self.iter(), window_len) {
+            for other_offset in windows.get(&hash).into_iter().flatten() {
+                let diagonal = *other_offset as isize - offset as isize;
+                let prev_match = extended.get(&diagonal).cloned();
+                if prev_match.map_or(false, |(_, end)| end > offset) {
+                    continue;
+                }
+                if extension_count == COMMON_CONTENT_MAX_EXTENSIONS {
+                    break 'windows;
+                }
+                extension_count += 1;
+
+                let point = self.point_for_offset(offset).unwrap();
+                let other_point = other.point_for_offset(*other_offset).unwrap();
+                let forward = self
+                    .iter_at_point(point)
+                    .zip(other.iter_at_point(other_point))
+                    .take(COMMON_CONTENT_MAX_EXTENSION_LEN)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                let backward = match prev_match {
+                    Some((start, end)) if end == offset => offset - start,
+                    _ => self
+                        .iter_at_point(point)
+                        .rev()
+                        .zip(other.iter_at_point(other_point).rev())
+                        .take(COMMON_CONTENT_MAX_EXTENSION_LEN)
+                        .take_while(|(a, b)| a == b)
+                        .count(),
+                };
+                extended.insert(diagonal, (offset - backward, offset + forward));
+
+                let len = backward + forward;
+                if len >= window_len
+                    && longest.map_or(true, |(_, _, longest_len)| len > longest_len)
+                {
+                    longest = Some((offset - backward, *other_offset - backward, len));
+                    if len == max_len {
+                        break 'windows;
+                    }
                 }
-                lamport_clock.observe(lamport_timestamp);
-                self.selections_last_update += 1;
             }
         }
-        Ok(())
+
+        longest.map(|(start, other_start, len)| {
+            (
+                self.point_for_offset(start).unwrap()..self.point_for_offset(start + len).unwrap(),
+                other.point_for_offset(other_start).unwrap()
+                    ..other.point_for_offset(other_start + len).unwrap(),
+            )
+        })
     }
 
-    fn apply_edit(
-        &mut self,
-        start_id: time::Local,
-        start_offset: usize,
-        end_id: time::Local,
-        end_offset: usize,
-        new_text: Option<Arc<Text>>,
-        version_in_range: &time::Global,
-        local_timestamp: time::Local,
-        lamport_timestamp: time::Lamport,
-        local_clock: &mut time::Local,
-        lamport_clock: &mut time::Lamport,
-    ) -> Result<(), Error> {
-        let mut new_text = new_text.as_ref().cloned();
-        let start_fragment_id = self.resolve_fragment_id(start_id, start_offset)?;
-        let end_fragment_id = self.resolve_fragment_id(end_id, end_offset)?;
+    pub fn search(&self, query: &str, options: SearchOptions) -> Vec<Range<Anchor>> {
+        self.search_with_cancel(query, options, &AtomicBool::new(false))
+    }
 
-        let old_fragments = self.fragments.clone();
-        let mut cursor = old_fragments.cursor();
-        let mut new_fragments = cursor.slice(&start_fragment_id, SeekBias::Left);
+    // The cancellation flag is checked every `SEARCH_CANCEL_CHECK_INTERVAL` characters. Once it
+    // is set, the matches found so far are returned.
+    pub fn search_with_cancel(
+        &self,
+        query: &str,
+        options: SearchOptions,
+        cancel: &AtomicBool,
+    ) -> Vec<Range<Anchor>> {
+        let query = options.query_chars(query);
+        let mut matches = Vec::new();
+        if query.is_empty() {
+            return matches;
+        }
 
-        if start_offset == cursor.item().unwrap().end_offset {
-            new_fragments.push(cursor.item().unwrap());
-            cursor.next();
+        let mut chars = Vec::new();
+        for (i, c) in options.text_chars(self.iter(), 0).enumerate() {
+            if i % SEARCH_CANCEL_CHECK_INTERVAL == 0 && cancel.load(atomic::Ordering::Relaxed) {
+                return matches;
+            }
+            chars.push(c);
         }
 
-        while let Some(mut fragment) = cursor.item() {
-            if new_text.is_none() && fragment.id > end_fragment_id {
-                break;
+        let mut start = 0;
+        let mut next_cancel_check = 0;
+        while start + query.len() <= chars.len() {
+            if start >= next_cancel_check {
+                if cancel.load(atomic::Ordering::Relaxed) {
+                    break;
+                }
+                next_cancel_check = start + SEARCH_CANCEL_CHECK_INTERVAL;
             }
 
-            if fragment.id == start_fragment_id || fragment.id == end_fragment_id {
-                let split_start = if start_fragment_id == fragment.id {
-                    start_offset
-                } else {
-                    fragment.start_offset
-                };
-                let split_end = if end_fragment_id == fragment.id {
-                    end_offset
-                } else {
-                    fragment.end_offset
-                };
-                let (before_range, within_range, after_range) = self.split_fragment(
-                    cursor.prev_item().as_ref().unwrap(),
-                    &fragment,
-                    split_start..split_end,
+            let candidate = &chars[start..start + query.len()];
+            let is_match = candidate
+                .iter()
+                .zip(&query)
+                .all(|((_, a), b)| options.chars_match(*a, *b));
+            if is_match {
+                let start_offset = candidate[0].0.start;
+                let end_offset = candidate[query.len() - 1].0.end;
+                matches.push(
+                    self.anchor_after_offset(start_offset).unwrap()
+                        ..self.anchor_before_offset(end_offset).unwrap(),
                 );
-                let insertion = if let Some(new_text) = new_text.take() {
-                    Some(
-                        self.build_fragment_to_insert(
-                            before_range
-                                .as_ref()
-                                .or(cursor.prev_item().as_ref())
-                                .unwrap(),
-                            within_range.as_ref().or(after_range.as_ref()),
-                            new_text,
-                            local_timestamp,
-                            lamport_timestamp,
-                        ),
-                    )
-                } else {
-                    None
-                };
-                if let Some(fragment) = before_range {
-                    new_fragments.push(fragment);
-                }
-                if let Some(fragment) = insertion {
-                    new_fragments.push(fragment);
+                start += query.len();
+            } else {
+                start += 1;
+            }
+        }
+        matches
+    }
+
+    // Unlike `search`, this stops at the first match, so only the text between `start` and the
+    // match is decoded.
+    pub fn find_from(
+        &self,
+        start: Point,
+        query: &str,
+        options: SearchOptions,
+    ) -> Option<Range<Point>> {
+        let query = options.query_chars(query);
+        if query.is_empty() {
+            return None;
+        }
+
+        let start_offset = self.offset_for_point(start).ok()?;
+        let mut window = VecDeque::with_capacity(query.len() + 1);
+        for c in options.text_chars(self.iter_at_point(start), start_offset) {
+            window.push_back(c);
+            if window.len() > query.len() {
+                window.pop_front();
+            }
+            if window.len() == query.len()
+                && window
+                    .iter()
+                    .zip(&query)
+                    .all(|((_, a), b)| options.chars_match(*a, *b))
+            {
+                let match_start = self.point_for_offset(window[0].0.start).unwrap();
+                let match_end = window[query.len() - 1].0.end;
+                return Some(match_start..self.point_for_offset(match_end).unwrap());
+            }
+        }
+        None
+    }
+
+    // Returns the last match that ends at or before `end`. The text is read backwards from `end`
+    // in blocks, each of which is searched forwards for matches starting inside it, so only the
+    // text between the match and `end` is decoded.
+    pub fn rfind_from(
+        &self,
+        end: Point,
+        query: &str,
+        options: SearchOptions,
+    ) -> Option<Range<Point>> {
+        let query = options.query_chars(query);
+        if query.is_empty() {
+            return None;
+        }
+
+        let end_offset = self.offset_for_point(end).ok()?;
+        let mut chunks = self.reverse_chunks_for_range(0..end_offset).ok()?;
+        let mut block_end = end_offset;
+        while block_end > 0 {
+            let mut block_chunks = Vec::new();
+            let mut block_start = block_end;
+            while block_start > 0
+                && (block_end - block_start < RFIND_BLOCK_LEN
+                    || !self.is_search_boundary(block_start, options.normalize))
+            {
+                let chunk = chunks.next()?;
+                block_start -= chunk.len();
+                block_chunks.push(chunk);
+            }
+
+            // Matches starting in the block may end after it, so the search continues into the
+            // text that follows until the window has moved past the block.
+            let block = block_chunks
+                .iter()
+                .rev()
+                .flat_map(|chunk| chunk.code_units().iter().cloned());
+            let following = self
+                .iter_at_point(self.point_for_offset(block_end).unwrap())
+                .take(end_offset - block_end);
+            let mut last_match = None;
+            let mut window = VecDeque::with_capacity(query.len() + 1);
+            for c in options.text_chars(block.chain(following), block_start) {
+                window.push_back(c);
+                if window.len() > query.len() {
+                    window.pop_front();
                 }
-                if let Some(mut fragment) = within_range {
-                    if version_in_range.observed(fragment.insertion.id) {
-                        fragment.deletions.insert(local_timestamp);
+                if window.len() == query.len() {
+                    if window[0].0.start >= block_end {
+                        break;
+                    }
+                    if window
+                        .iter()
+                        .zip(&query)
+                        .all(|((_, a), b)| options.chars_match(*a, *b))
+                    {
+                        last_match = Some(window[0].0.start..window[query.len() - 1].0.end);
                     }
-                    new_fragments.push(fragment);
-                }
-                if let Some(fragment) = after_range {
-                    new_fragments.push(fragment);
-                }
-            } else {
-                if new_text.is_some() && lamport_timestamp > fragment.insertion.lamport_timestamp {
-                    new_fragments.push(self.build_fragment_to_insert(
-                        cursor.prev_item().as_ref().unwrap(),
-                        Some(&fragment),
-                        new_text.take().unwrap(),
-                        local_timestamp,
-                        lamport_timestamp,
-                    ));
                 }
+            }
+            if let Some(range) = last_match {
+                return Some(
+                    self.point_for_offset(range.start).unwrap()
+                        ..self.point_for_offset(range.end).unwrap(),
+                );
+            }
+            block_end = block_start;
+        }
+        None
+    }
 
-                if fragment.id < end_fragment_id && version_in_range.observed(fragment.insertion.id)
-                {
-                    fragment.deletions.insert(local_timestamp);
-                }
-                new_fragments.push(fragment);
+    // Whether splitting the text at `offset` leaves the decoding of the text after it unchanged,
+    // along with its normalization when `normalize` is set.
+    fn is_search_boundary(&self, offset: usize, normalize: bool) -> bool {
+        let point = self.point_for_offset(offset).unwrap();
+        let next = match self.iter_at_point(point).next() {
+            Some(next) => next,
+            None => return true,
+        };
+        let prev = match self.iter_at_point(point).rev().next() {
+            Some(prev) => prev,
+            None => return true,
+        };
+        let is_low_surrogate = next >= 0xDC00 && next < 0xE000;
+        let is_high_surrogate = prev >= 0xD800 && prev < 0xDC00;
+        if is_low_surrogate && is_high_surrogate {
+            return false;
+        }
+
+        if normalize {
+            let next = std::char::decode_utf16(self.iter_at_point(point))
+                .next()
+                .and_then(|c| c.ok());
+            let mut prev_chars = self.rev_chars_at_point(point);
+            let prev = prev_chars.next();
+            if let (Some(prev), Some(next)) = (prev, next) {
+                // The previous character may itself compose with the one preceding it, as the
+                // vowel of a decomposed Hangul syllable does, before composing with `next`.
+                let composed_prev = prev_chars
+                    .next()
+                    .and_then(|prev_prev| compose(prev_prev, prev))
+                    .unwrap_or(prev);
+                return canonical_combining_class(next) == 0
+                    && compose(prev, next).is_none()
+                    && compose(composed_prev, next).is_none();
             }
+        }
+        true
+    }
 
-            cursor.next();
+    pub fn iter_at_point(&self, point: Point) -> Iter {
+        Iter::at_point(self, point)
+    }
+
+    // Yields the visible text in `range` as chunks ordered from the end of the range to its start.
+    // The code units within each chunk remain in forward order.
+    pub fn reverse_chunks_for_range(
+        &self,
+        range: Range<usize>,
+    ) -> Result<impl Iterator<Item = Chunk>, Error> {
+        if range.start > range.end || range.end > self.len() {
+            return Err(Error::OffsetOutOfRange);
         }
 
-        if let Some(new_text) = new_text {
-            new_fragments.push(self.build_fragment_to_insert(
-                cursor.prev_item().as_ref().unwrap(),
-                None,
-                new_text,
-                local_timestamp,
-                lamport_timestamp,
-            ));
+        let mut fragment_cursor = self.fragments.cursor();
+        fragment_cursor.seek(&range.end, SeekBias::Left);
+        Ok(ReverseChunks {
+            fragment_cursor,
+            range,
+        })
+    }
+
+    fn rev_chars_at_point(&self, point: Point) -> RevChars {
+        RevChars {
+            code_units: self.iter_at_point(point).rev().peekable(),
         }
+    }
 
-        new_fragments.push_tree(cursor.slice(&old_fragments.extent::<usize>(), SeekBias::Right));
-        self.fragments = new_fragments;
-        local_clock.observe(local_timestamp);
-        lamport_clock.observe(lamport_timestamp);
-        Ok(())
+    pub fn selections_changed_since(&self, since: SelectionsVersion) -> bool {
+        self.selections_last_update != since
     }
 
-    fn flush_deferred_ops(
-        &mut self,
-        local_clock: &mut time::Local,
-        lamport_clock: &mut time::Lamport,
-    ) -> Result<(), Error> {
-        self.deferred_replicas.clear();
-        let mut deferred_ops = Vec::new();
-        for op in self.deferred_ops.drain() {
-            if self.can_apply_op(&op) {
-                self.apply_op(op, local_clock, lamport_clock)?;
-            } else {
-                self.deferred_replicas.insert(op.replica_id());
-                deferred_ops.push(op);
-            }
+    pub fn changes_since(&self, since: &time::Global) -> impl Iterator<Item = Change> {
+        let since_2 = since.clone();
+        let cursor = self
+            .fragments
+            .filter(move |summary| summary.max_version.changed_since(&since_2));
+        ChangesIter {
+            cursor,
+            since: since.clone(),
         }
-        self.deferred_ops.insert(deferred_ops);
-        Ok(())
     }
 
-    fn can_apply_op(&self, op: &Operation) -> bool {
-        if self.deferred_replicas.contains(&op.replica_id()) {
-            false
-        } else {
-            match op {
-                Operation::Edit {
-                    start_id,
-                    end_id,
-                    version_in_range,
-                    ..
-                } => {
-                    self.version.observed(*start_id)
-                        && self.version.observed(*end_id)
-                        && *version_in_range <= self.version
-                }
-                Operation::UpdateSelections { selections, .. } => {
-                    if let Some(selections) = selections {
-                        selections.iter().all(|selection| {
-                            let contains_start = match selection.start {
-                                Anchor::Middle { insertion_id, .. } => {
-                                    self.version.observed(insertion_id)
-                                }
-                                _ => true,
-                            };
-                            let contains_end = match selection.end {
-                                Anchor::Middle { insertion_id, .. } => {
-                                    self.version.observed(insertion_id)
-                                }
-                                _ => true,
-                            };
-                            contains_start && contains_end
-                        })
-                    } else {
-                        true
+    // The edits that revert `edit_id`: the text it inserted is removed and the text only it deleted
+    // is inserted again. Re-inserted text is new, so anchors that were attached to the deleted text
+    // aren't restored. The edits are sorted and expressed in current offsets.
+    pub fn inverse_of_edit(&self, edit_id: time::Local) -> Vec<(Range<usize>, Vec<u16>)> {
+        let mut edits: Vec<(Range<usize>, Vec<u16>)> = Vec::new();
+        let mut cursor = self.fragments.cursor();
+        while let Some(fragment) = cursor.item() {
+            let offset = cursor.start::<usize>();
+            let is_insertion = fragment.is_visible() && fragment.insertion.id == edit_id;
+            let is_deletion = fragment.deletions.len() == 1
+                && fragment.deletions.contains(&edit_id)
+                && fragment.extent() > 0;
+            if is_insertion || is_deletion {
+                let edit = match edits.last_mut() {
+                    Some(edit) if edit.0.end == offset => edit,
+                    _ => {
+                        edits.push((offset..offset, Vec::new()));
+                        edits.last_mut().unwrap()
                     }
+                };
+                if is_insertion {
+                    edit.0.end += fragment.extent();
+                } else {
+                    edit.1.extend_from_slice(fragment.code_units());
                 }
             }
+            cursor.next();
         }
+        edits
     }
 
-    fn resolve_fragment_id(
-        &self,
-        edit_id: time::Local,
-        offset: usize,
-    ) -> Result<FragmentId, Error> {
-        let split_tree = self
-            .insertion_splits
-            .get(&edit_id)
-            .ok_or(Error::InvalidOperation)?;
-        let mut cursor = split_tree.cursor();
-        cursor.seek(&offset, SeekBias::Left);
-        Ok(cursor
-            .item()
-            .ok_or(Error::InvalidOperation)?
-            .fragment_id
-            .clone())
+    // The regions that differ from the base text the buffer was created with, in current
+    // coordinates. Deletions show up as empty ranges where the deleted text used to be.
+    pub fn hunks(&self) -> impl Iterator<Item = Range<Point>> {
+        self.changes_since(&time::Global::new())
+            .map(|change| change.range.start..change.range.start + &change.new_extent)
     }
 
-    fn splice_fragments<I>(
-        &mut self,
-        mut old_ranges: I,
-        new_text: Option<Arc<Text>>,
-        local_clock: &mut time::Local,
-        lamport_clock: &mut time::Lamport,
-    ) -> Vec<Operation>
-    where
-        I: Iterator<Item = Range<usize>>,
-    {
-        let mut cur_range = old_ranges.next();
-        if cur_range.is_none() {
-            return Vec::new();
+    // Like `hunks`, but skips the fragments preceding the one containing `from` instead of
+    // scanning them. The first hunk returned may be the tail of one that started before `from`.
+    pub fn hunks_from(&self, from: Point) -> impl Iterator<Item = Range<Point>> {
+        let since = time::Global::new();
+        let since_2 = since.clone();
+        let mut cursor = self
+            .fragments
+            .filter(move |summary| summary.max_version.changed_since(&since_2));
+        cursor.seek_forward(&from, SeekBias::Left);
+        ChangesIter { cursor, since }
+            .map(|change| change.range.start..change.range.start + &change.new_extent)
+    }
+
+    // The regions that differ from `base`, in current coordinates, for bases other than the one
+    // the buffer was created with.
+    pub fn hunks_against(&self, base: &str) -> Vec<Range<Point>> {
+        let base = base.encode_utf16().collect::<Vec<_>>();
+        diff(&base, &self.to_u16_chars())
+            .into_iter()
+            .map(|change| change.range.start..change.range.start + &change.new_extent)
+            .collect()
+    }
+
+    // The rows touched by changes since `since`, in current coordinates, with overlapping and
+    // adjacent ranges merged. A deletion marks the row it was joined into as changed.
+    pub fn changed_lines(&self, since: &time::Global) -> Vec<Range<u32>> {
+        let mut rows: Vec<Range<u32>> = Vec::new();
+        for change in self.changes_since(since) {
+            let start = change.range.start.row;
+            let end = (change.range.start + &change.new_extent).row + 1;
+            if let Some(last_rows) = rows.last_mut() {
+                if start <= last_rows.end {
+                    last_rows.end = cmp::max(last_rows.end, end);
+                    continue;
+                }
+            }
+            rows.push(start..end);
         }
+        rows
+    }
 
-        let mut ops = Vec::with_capacity(old_ranges.size_hint().0);
-
-        let old_fragments = self.fragments.clone();
-        let mut cursor = old_fragments.cursor();
-        let mut new_fragments = btree::Tree::new();
-        new_fragments.push_tree(cursor.slice(&cur_range.as_ref().unwrap().start, SeekBias::Right));
+    pub fn deferred_ops_len(&self) -> usize {
+        self.deferred_ops.len()
+    }
 
-        let mut start_id = None;
-        let mut start_offset = None;
-        let mut end_id = None;
-        let mut end_offset = None;
-        let mut version_in_range = time::Global::new();
+    // Reconstructs the text as of `version` by hiding the fragments inserted after it and
+    // restoring the ones deleted after it. The fragment structure is preserved, so anchors created
+    // against this buffer resolve against the snapshot as well. Deletions whose tombstones were
+    // collected can't be restored. Snapshots are read-only, so they carry no selections, marks or
+    // deferred operations.
+    pub fn snapshot_at(&self, version: &time::Global) -> Buffer {
+        let mut fragments = btree::Tree::new();
+        for fragment in self.fragments.cursor() {
+            let mut fragment = fragment.clone();
+            if version.observed(fragment.insertion.id) {
+                fragment
+                    .deletions
+                    .retain(|deletion| version.observed(*deletion));
+            } else {
+                fragment.deletions = iter::once(fragment.insertion.id).collect();
+            }
+            fragments.push(fragment);
+        }
 
-        let mut local_timestamp = local_clock.tick();
-        let mut lamport_timestamp = lamport_clock.tick();
+        Buffer {
+            fragments,
+            insertion_splits: self.insertion_splits.clone(),
+            anchor_cache: RefCell::new(HashMap::new()),
+            offset_cache: RefCell::new(HashMap::new()),
+            resolved_anchors: RefCell::new(None),
+            cache_resolved_anchors: false,
+            version: self.version.meet(version),
+            last_edit: time::Local::default(),
+            selections: HashMap::new(),
+            hidden_selection_sets: HashSet::new(),
+            selections_last_update: 0,
+            deferred_ops: OperationQueue::new(),
+            deferred_replicas: HashSet::new(),
+            collected_garbage: self.collected_garbage,
+            anchor_fallbacks: Cell::new(0),
+            has_bom: self.has_bom,
+            marks: HashMap::new(),
+        }
+    }
 
-        while cur_range.is_some() && cursor.item().is_some() {
-            let mut fragment = cursor.item().unwrap();
-            let mut fragment_start = cursor.start::<usize>();
-            let mut fragment_end = fragment_start + fragment.len();
+    // Verifies the consistency of the fragment tree and the insertion splits, describing the first
+    // violation found. This walks every fragment, so it's meant for diagnosing suspected bugs
+    // rather than for routine use. Collecting tombstones leaves gaps in insertions, so those
+    // checks are relaxed for such buffers.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        let may_have_gaps = self.collected_garbage;
+        let mut prev_fragment_id: Option<FragmentId> = None;
+        let mut insertion_ends = HashMap::new();
+        let mut fragment_insertions = HashMap::new();
+        let mut visible_len = 0;
+        for fragment in self.fragments.cursor() {
+            if let Some(prev_fragment_id) = prev_fragment_id.as_ref() {
+                if *prev_fragment_id >= fragment.id {
+                    return Err(format!(
+                        "fragment {:?} is not ordered after {:?}",
+                        fragment.id, prev_fragment_id
+                    ));
+                }
+            }
 
-            let old_split_tree = self
-                .insertion_splits
-                .remove(&fragment.insertion.id)
-                .unwrap();
-            let mut splits_cursor = old_split_tree.cursor();
-            let mut new_split_tree = splits_cursor.slice(&fragment.start_offset, SeekBias::Right);
-
-            // Find all splices that start or end within the current fragment. Then, split the
-            // fragment and reassemble it in both trees accounting for the deleted and the newly
-            // inserted text.
-            while cur_range.as_ref().map_or(false, |r| r.start < fragment_end) {
-                let range = cur_range.clone().unwrap();
-                if range.start > fragment_start {
-                    let mut prefix = fragment.clone();
-                    prefix.end_offset = prefix.start_offset + (range.start - fragment_start);
-                    prefix.id =
-                        FragmentId::between(&new_fragments.last().unwrap().id, &fragment.id);
-                    fragment.start_offset = prefix.end_offset;
-                    new_fragments.push(prefix.clone());
-                    new_split_tree.push(InsertionSplit {
-                        extent: prefix.end_offset - prefix.start_offset,
-                        fragment_id: prefix.id,
-                    });
-                    fragment_start = range.start;
-                }
+            let insertion = &fragment.insertion;
+            if fragment.start_offset > fragment.end_offset
+                || fragment.end_offset > insertion.text.len()
+            {
+                return Err(format!(
+                    "fragment {:?} has range {}..{} in insertion {:?} of length {}",
+                    fragment.id,
+                    fragment.start_offset,
+                    fragment.end_offset,
+                    insertion.id,
+                    insertion.text.len()
+                ));
+            }
+            let expected_start = insertion_ends.get(&insertion.id).cloned().unwrap_or(0);
+            if fragment.start_offset < expected_start
+                || (fragment.start_offset > expected_start && !may_have_gaps)
+            {
+                return Err(format!(
+                    "fragment {:?} starts at {} in insertion {:?}, but the previous fragment ended at {}",
+                    fragment.id, fragment.start_offset, insertion.id, expected_start
+                ));
+            }
+            insertion_ends.insert(insertion.id, fragment.end_offset);
 
-                if range.end == fragment_start {
-                    end_id = Some(new_fragments.last().unwrap().insertion.id);
-                    end_offset = Some(new_fragments.last().unwrap().end_offset);
-                } else if range.end == fragment_end {
-                    end_id = Some(fragment.insertion.id);
-                    end_offset = Some(fragment.end_offset);
-                }
+            if insertion.id != time::Local::default() && !self.version.observed(insertion.id) {
+                return Err(format!(
+                    "insertion {:?} is not observed by the buffer's version",
+                    insertion.id
+                ));
+            }
+            if let Some(deletion) = fragment
+                .deletions
+                .iter()
+                .find(|deletion| !self.version.observed(**deletion))
+            {
+                return Err(format!(
+                    "deletion {:?} of fragment {:?} is not observed by the buffer's version",
+                    deletion, fragment.id
+                ));
+            }
 
-                if range.start == fragment_start {
-                    start_id = Some(new_fragments.last().unwrap().insertion.id);
-                    start_offset = Some(new_fragments.last().unwrap().end_offset);
+            visible_len += fragment.len();
+            fragment_insertions.insert(fragment.id.clone(), (insertion.id, insertion.text.len()));
+            prev_fragment_id = Some(fragment.id);
+        }
 
-                    if let Some(new_text) = new_text.clone() {
-                        let new_fragment = self.build_fragment_to_insert(
-                            &new_fragments.last().unwrap(),
-                            Some(&fragment),
-                            new_text,
-                            local_timestamp,
-                            lamport_timestamp,
-                        );
-                        new_fragments.push(new_fragment);
-                    }
-                }
+        if visible_len != self.len() {
+            return Err(format!(
+                "fragments contain {} visible code units, but the buffer's length is {}",
+                visible_len,
+                self.len()
+            ));
+        }
 
-                if range.end < fragment_end {
-                    if range.end > fragment_start {
-                        let mut prefix = fragment.clone();
-                        prefix.end_offset = prefix.start_offset + (range.end - fragment_start);
-                        prefix.id =
-                            FragmentId::between(&new_fragments.last().unwrap().id, &fragment.id);
-                        if fragment.is_visible() {
-                            prefix.deletions.insert(local_timestamp);
-                        }
-                        fragment.start_offset = prefix.end_offset;
-                        new_fragments.push(prefix.clone());
-                        new_split_tree.push(InsertionSplit {
-                            extent: prefix.end_offset - prefix.start_offset,
-                            fragment_id: prefix.id,
-                        });
-                        fragment_start = range.end;
-                        end_id = Some(fragment.insertion.id);
-                        end_offset = Some(fragment.start_offset);
-                        version_in_range.observe(fragment.insertion.id);
+        for (insertion_id, splits) in &self.insertion_splits {
+            let mut extent = 0;
+            let mut insertion_len = None;
+            for split in splits.cursor() {
+                extent += split.extent;
+                match fragment_insertions.get(&split.fragment_id) {
+                    Some((id, len)) if id == insertion_id => insertion_len = Some(*len),
+                    Some((id, _)) => {
+                        return Err(format!(
+                            "split of insertion {:?} refers to fragment {:?} of insertion {:?}",
+                            insertion_id, split.fragment_id, id
+                        ));
                     }
-                } else {
-                    version_in_range.observe(fragment.insertion.id);
-                    if fragment.is_visible() {
-                        fragment.deletions.insert(local_timestamp);
+                    None if may_have_gaps => {}
+                    None => {
+                        return Err(format!(
+                            "split of insertion {:?} refers to missing fragment {:?}",
+                            insertion_id, split.fragment_id
+                        ));
                     }
                 }
-
-                // If the splice ends inside this fragment, we can advance to the next splice and
-                // check if it also intersects the current fragment. Otherwise we break out of the
-                // loop and find the first fragment that the splice does not contain fully.
-                if range.end <= fragment_end {
-                    ops.push(Operation::Edit {
-                        start_id: start_id.unwrap(),
-                        start_offset: start_offset.unwrap(),
-                        end_id: end_id.unwrap(),
-                        end_offset: end_offset.unwrap(),
-                        version_in_range,
-                        new_text: new_text.clone(),
-                        local_timestamp,
-                        lamport_timestamp,
-                    });
-
-                    start_id = None;
-                    start_offset = None;
-                    end_id = None;
-                    end_offset = None;
-                    version_in_range = time::Global::new();
-                    cur_range = old_ranges.next();
-                    if cur_range.is_some() {
-                        local_timestamp = local_clock.tick();
-                        lamport_timestamp = lamport_clock.tick();
-                    }
-                } else {
-                    break;
+            }
+            if let Some(insertion_len) = insertion_len {
+                if extent != insertion_len && !may_have_gaps {
+                    return Err(format!(
+                        "splits of insertion {:?} cover {} code units, but it has {}",
+                        insertion_id, extent, insertion_len
+                    ));
                 }
             }
-            new_split_tree.push(InsertionSplit {
-                extent: fragment.end_offset - fragment.start_offset,
-                fragment_id: fragment.id.clone(),
-            });
-            splits_cursor.next();
-            new_split_tree
-                .push_tree(splits_cursor.slice(&old_split_tree.extent::<usize>(), SeekBias::Right));
-            self.insertion_splits
-                .insert(fragment.insertion.id, new_split_tree);
-            new_fragments.push(fragment);
-
-            // Scan forward until we find a fragment that is not fully contained by the current splice.
-            cursor.next();
-            if let Some(range) = cur_range.clone() {
-                while let Some(mut fragment) = cursor.item() {
-                    fragment_start = cursor.start::<usize>();
-                    fragment_end = fragment_start + fragment.len();
-                    if range.start < fragment_start && range.end >= fragment_end {
-                        if fragment.is_visible() {
-                            fragment.deletions.insert(local_timestamp);
-                        }
-                        version_in_range.observe(fragment.insertion.id);
-                        new_fragments.push(fragment.clone());
-                        cursor.next();
-
-                        if range.end == fragment_end {
-                            end_id = Some(fragment.insertion.id);
-                            end_offset = Some(fragment.end_offset);
-                            ops.push(Operation::Edit {
-                                start_id: start_id.unwrap(),
-                                start_offset: start_offset.unwrap(),
-                                end_id: end_id.unwrap(),
-                                end_offset: end_offset.unwrap(),
-                                version_in_range,
-                                new_text: new_text.clone(),
-                                local_timestamp,
-                                lamport_timestamp,
-                            });
-
-                            start_id = None;
-                            start_offset = None;
-                            end_id = None;
-                            end_offset = None;
-                            version_in_range = time::Global::new();
+        }
 
-                            cur_range = old_ranges.next();
-                            if cur_range.is_some() {
-                                local_timestamp = local_clock.tick();
-                                lamport_timestamp = lamport_clock.tick();
-                            }
-                            break;
-                        }
-                    } else {
-                        break;
-                    }
-                }
+        Ok(())
+    }
 
-                // If the splice we are currently evaluating starts after the end of the fragment
-                // that the cursor is parked at, we should seek to the next splice's start range
-                // and push all the fragments in between into the new tree.
-                if cur_range.as_ref().map_or(false, |r| r.start > fragment_end) {
-                    new_fragments.push_tree(
-                        cursor.slice(&cur_range.as_ref().unwrap().start, SeekBias::Right),
-                    );
+    // Fragments share their insertion's text, so each one is charged for the slice it covers
+    // rather than for the whole insertion.
+    pub fn memory_usage(&self) -> MemoryReport {
+        let mut report = MemoryReport::default();
+        for fragment in self.fragments.cursor() {
+            let bytes = mem::size_of::<Fragment>()
+                + (fragment.end_offset - fragment.start_offset) * mem::size_of::<u16>();
+            if fragment.is_visible() {
+                report.fragment_bytes += bytes;
+            } else {
+                report.tombstone_bytes += bytes;
+            }
+        }
+        report.operation_log_bytes = self.deferred_ops.iter().map(|op| op.estimated_size()).sum();
+        report.version_vector_len = self.version.replica_ids().count();
+        report
+    }
+
+    // Stats are derived from the insertion and deletion timestamps recorded on fragments, so
+    // this walks the fragment tree rather than the text itself.
+    pub fn operation_stats(&self) -> HashMap<ReplicaId, OpStats> {
+        let mut stats = HashMap::<ReplicaId, OpStats>::new();
+        let mut insertions = HashSet::new();
+        let mut deletions = HashSet::new();
+        for fragment in self.fragments.cursor() {
+            let insertion = &fragment.insertion;
+            if insertion.id != time::Local::default() && insertions.insert(insertion.id) {
+                let replica_stats = stats.entry(insertion.id.replica_id).or_default();
+                replica_stats.insertions += 1;
+                replica_stats.bytes += utf8_len(&insertion.text.code_units);
+            }
+            for deletion in &fragment.deletions {
+                if deletions.insert(*deletion) {
+                    stats.entry(deletion.replica_id).or_default().deletions += 1;
                 }
             }
         }
+        stats
+    }
 
-        // Handle range that is at the end of the buffer if it exists. There should never be
-        // multiple because ranges must be disjoint.
-        if cur_range.is_some() {
-            debug_assert_eq!(old_ranges.next(), None);
-            let last_fragment = new_fragments.last().unwrap();
-            ops.push(Operation::Edit {
-                start_id: last_fragment.insertion.id,
-                start_offset: last_fragment.end_offset,
-                end_id: last_fragment.insertion.id,
-                end_offset: last_fragment.end_offset,
-                version_in_range: time::Global::new(),
-                new_text: new_text.clone(),
-                local_timestamp,
-                lamport_timestamp,
-            });
+    pub fn rename_replica(&mut self, old: ReplicaId, new: ReplicaId) {
+        let mut fragments = btree::Tree::new();
+        fragments.extend(self.fragments.items().into_iter().map(|mut fragment| {
+            fragment.insertion.id.rename_replica(old, new);
+            fragment.insertion.parent_id.rename_replica(old, new);
+            fragment
+                .insertion
+                .lamport_timestamp
+                .rename_replica(old, new);
+            fragment.deletions = fragment
+                .deletions
+                .into_iter()
+                .map(|mut deletion| {
+                    deletion.rename_replica(old, new);
+                    deletion
+                })
+                .collect();
+            fragment
+        }));
+        self.fragments = fragments;
+
+        let insertion_splits = mem::replace(&mut self.insertion_splits, HashMap::new());
+        for (mut insertion_id, splits) in insertion_splits {
+            insertion_id.rename_replica(old, new);
+            self.insertion_splits.insert(insertion_id, splits);
+        }
 
-            if let Some(new_text) = new_text {
-                new_fragments.push(self.build_fragment_to_insert(
-                    &last_fragment,
-                    None,
-                    new_text,
-                    local_timestamp,
-                    lamport_timestamp,
-                ));
+        let selections = mem::replace(&mut self.selections, HashMap::new());
+        for (mut set_id, mut selections) in selections {
+            set_id.rename_replica(old, new);
+            for selection in &mut selections {
+                selection.rename_replica(old, new);
             }
-        } else {
-            new_fragments
-                .push_tree(cursor.slice(&old_fragments.extent::<usize>(), SeekBias::Right));
+            self.selections.insert(set_id, selections);
         }
 
-        self.fragments = new_fragments;
-        ops
+        let hidden_selection_sets = mem::replace(&mut self.hidden_selection_sets, HashSet::new());
+        for mut set_id in hidden_selection_sets {
+            set_id.rename_replica(old, new);
+            self.hidden_selection_sets.insert(set_id);
+        }
+
+        let deferred_ops = self
+            .deferred_ops
+            .drain()
+            .map(|mut op| {
+                op.rename_replica(old, new);
+                op
+            })
+            .collect();
+        self.deferred_ops.insert(deferred_ops);
+        if self.deferred_replicas.remove(&old) {
+            self.deferred_replicas.insert(new);
+        }
+
+        for anchor in self.marks.values_mut() {
+            anchor.rename_replica(old, new);
+        }
+
+        self.anchor_cache.borrow_mut().clear();
+        *self.resolved_anchors.borrow_mut() = None;
+        self.version.rename_replica(old, new);
+        self.last_edit.rename_replica(old, new);
     }
 
-    fn split_fragment(
+    pub fn edit<I, T>(
         &mut self,
-        prev_fragment: &Fragment,
-        fragment: &Fragment,
-        range: Range<usize>,
-    ) -> (Option<Fragment>, Option<Fragment>, Option<Fragment>) {
-        debug_assert!(range.start >= fragment.start_offset);
-        debug_assert!(range.start <= fragment.end_offset);
-        debug_assert!(range.end <= fragment.end_offset);
-        debug_assert!(range.end >= fragment.start_offset);
-
-        if range.end == fragment.start_offset {
-            (None, None, Some(fragment.clone()))
-        } else if range.start == fragment.end_offset {
-            (Some(fragment.clone()), None, None)
-        } else if range.start == fragment.start_offset && range.end == fragment.end_offset {
-            (None, Some(fragment.clone()), None)
+        old_ranges: I,
+        new_text: T,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Vec<Operation>
+    where
+        I: IntoIterator<Item = Range<usize>>,
+        T: Into<Text>,
+    {
+        let new_text = new_text.into();
+        let new_text = if new_text.len() > 0 {
+            Some(Arc::new(new_text))
         } else {
-            let mut prefix = fragment.clone();
+            None
+        };
 
-            let after_range = if range.end < fragment.end_offset {
-                let mut suffix = prefix.clone();
-                suffix.start_offset = range.end;
-                prefix.end_offset = range.end;
-                prefix.id = FragmentId::between(&prev_fragment.id, &suffix.id);
-                Some(suffix)
-            } else {
-                None
-            };
-
-            let within_range = if range.start != range.end {
-                let mut suffix = prefix.clone();
-                suffix.start_offset = range.start;
-                prefix.end_offset = range.start;
-                prefix.id = FragmentId::between(&prev_fragment.id, &suffix.id);
-                Some(suffix)
-            } else {
-                None
-            };
-
-            let before_range = if range.start > fragment.start_offset {
-                Some(prefix)
+        self.anchor_cache.borrow_mut().clear();
+        self.offset_cache.borrow_mut().clear();
+        let ops = self.splice_fragments(
+            old_ranges
+                .into_iter()
+                .filter(|old_range| new_text.is_some() || old_range.end > old_range.start),
+            new_text.clone(),
+            local_clock,
+            lamport_clock,
+        );
+        if let Some(op) = ops.last() {
+            if let Operation::Edit {
+                local_timestamp, ..
+            } = op
+            {
+                self.last_edit = *local_timestamp;
+                self.version.observe(*local_timestamp);
             } else {
-                None
-            };
-
-            let old_split_tree = self
-                .insertion_splits
-                .remove(&fragment.insertion.id)
-                .unwrap();
-            let mut cursor = old_split_tree.cursor();
-            let mut new_split_tree = cursor.slice(&fragment.start_offset, SeekBias::Right);
-
-            if let Some(ref fragment) = before_range {
-                new_split_tree.push(InsertionSplit {
-                    extent: range.start - fragment.start_offset,
-                    fragment_id: fragment.id.clone(),
-                });
-            }
-
-            if let Some(ref fragment) = within_range {
-                new_split_tree.push(InsertionSplit {
-                    extent: range.end - range.start,
-                    fragment_id: fragment.id.clone(),
-                });
+                unreachable!()
             }
+        }
+        ops
+    }
 
-            if let Some(ref fragment) = after_range {
-                new_split_tree.push(InsertionSplit {
-                    extent: fragment.end_offset - range.end,
-                    fragment_id: fragment.id.clone(),
-                });
+    pub fn edit_2d<I, T>(
+        &mut self,
+        old_2d_ranges: I,
+        new_text: T,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Vec<Operation>
+    where
+        I: IntoIterator<Item = Range<Point>>,
+        T: Into<Text>,
+    {
+        let mut old_1d_ranges = SmallVec::<[_; 1]>::new();
+        for old_2d_range in old_2d_ranges {
+            let start = self.offset_for_point(old_2d_range.start);
+            let end = self.offset_for_point(old_2d_range.end);
+            if start.is_ok() && end.is_ok() {
+                old_1d_ranges.push(start.unwrap()..end.unwrap());
             }
-
-            cursor.next();
-            new_split_tree
-                .push_tree(cursor.slice(&old_split_tree.extent::<usize>(), SeekBias::Right));
-
-            self.insertion_splits
-                .insert(fragment.insertion.id, new_split_tree);
-
-            (before_range, within_range, after_range)
         }
+        self.edit(old_1d_ranges, new_text, local_clock, lamport_clock)
     }
 
-    fn build_fragment_to_insert(
+    // Every line of `text` after the first is prefixed with the indentation of the line being
+    // inserted into, so that pasted snippets keep their structure relative to the insertion
+    // point. Indentation is measured in columns, and lines are re-indented with tabs when the
+    // insertion line's indentation contains any. Blank lines are left alone.
+    pub fn edit_indented(
         &mut self,
-        prev_fragment: &Fragment,
-        next_fragment: Option<&Fragment>,
-        text: Arc<Text>,
-        local_timestamp: time::Local,
-        lamport_timestamp: time::Lamport,
-    ) -> Fragment {
-        let new_fragment_id = FragmentId::between(
-            &prev_fragment.id,
-            next_fragment
-                .map(|f| &f.id)
-                .unwrap_or(&FragmentId::max_value()),
-        );
-
-        let mut split_tree = btree::Tree::new();
-        split_tree.push(InsertionSplit {
-            extent: text.len(),
-            fragment_id: new_fragment_id.clone(),
-        });
-        self.insertion_splits.insert(local_timestamp, split_tree);
+        range: Range<Point>,
+        text: &str,
+        tab_size: u32,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Vec<Operation>, Error> {
+        let start = self.offset_for_point(range.start)?;
+        let end = self.offset_for_point(range.end)?;
+        if start > end {
+            return Err(Error::OffsetOutOfRange);
+        }
 
-        Fragment::new(
-            new_fragment_id,
-            Insertion {
-                id: local_timestamp,
-                parent_id: prev_fragment.insertion.id,
-                offset_in_parent: prev_fragment.end_offset,
-                text,
-                lamport_timestamp,
-            },
+        let tab_size = cmp::max(tab_size, 1);
+        let indentation = std::char::decode_utf16(
+            self.iter_at_point(Point::new(range.start.row, 0))
+                .take(range.start.column as usize),
         )
-    }
-
-    pub fn anchor_before_offset(&self, offset: usize) -> Result<Anchor, Error> {
-        self.anchor_for_offset(offset, AnchorBias::Left)
-    }
+        .map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER))
+        .take_while(|c| *c == ' ' || *c == '\t')
+        .collect::<String>();
+        let base_width = indentation_width(&indentation, tab_size);
+        let use_tabs = indentation.contains('\t');
+
+        let mut new_text = String::with_capacity(text.len());
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                new_text.push('\n');
+                let content = line.trim_start_matches(|c| c == ' ' || c == '\t');
+                if !indentation.is_empty() && !content.trim_end_matches('\r').is_empty() {
+                    let line_indentation = &line[..line.len() - content.len()];
+                    let width = base_width + indentation_width(line_indentation, tab_size);
+                    if use_tabs {
+                        new_text.extend(iter::repeat('\t').take((width / tab_size) as usize));
+                        new_text.extend(iter::repeat(' ').take((width % tab_size) as usize));
+                    } else {
+                        new_text.extend(iter::repeat(' ').take(width as usize));
+                    }
+                    new_text.push_str(content);
+                    continue;
+                }
+            }
+            new_text.push_str(line);
+        }
 
-    pub fn anchor_after_offset(&self, offset: usize) -> Result<Anchor, Error> {
-        self.anchor_for_offset(offset, AnchorBias::Right)
+        Ok(self.edit(
+            Some(start..end),
+            new_text.as_str(),
+            local_clock,
+            lamport_clock,
+        ))
     }
 
-    fn anchor_for_offset(&self, offset: usize, bias: AnchorBias) -> Result<Anchor, Error> {
-        let max_offset = self.len();
-        if offset > max_offset {
+    // Replaces `range` with the UTF-8 contents of `reader`, decoding each chunk straight into the
+    // code units of the insertion so that the contents never exist as a single string. Sequences
+    // that are split across chunks are completed by the following chunk and invalid ones are
+    // replaced with U+FFFD.
+    pub fn edit_from_reader<R: io::BufRead>(
+        &mut self,
+        range: Range<Point>,
+        mut reader: R,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Vec<Operation>, Error> {
+        let start = self.offset_for_point(range.start)?;
+        let end = self.offset_for_point(range.end)?;
+        if start > end {
             return Err(Error::OffsetOutOfRange);
         }
 
-        let seek_bias;
-        match bias {
-            AnchorBias::Left => {
-                if offset == 0 {
-                    return Ok(Anchor::Start);
-                } else {
-                    seek_bias = SeekBias::Left;
+        let mut code_units = Vec::new();
+        let mut incomplete = Vec::new();
+        loop {
+            let chunk_len = {
+                let chunk = match reader.fill_buf() {
+                    Ok(chunk) => chunk,
+                    Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(error) => return Err(error.into()),
+                };
+                if chunk.is_empty() {
+                    break;
                 }
-            }
-            AnchorBias::Right => {
-                if offset == max_offset {
-                    return Ok(Anchor::End);
+
+                if incomplete.is_empty() {
+                    incomplete = decode_utf8_lossy_into(chunk, &mut code_units).to_vec();
                 } else {
-                    seek_bias = SeekBias::Right;
+                    incomplete.extend_from_slice(chunk);
+                    incomplete = decode_utf8_lossy_into(&incomplete, &mut code_units).to_vec();
                 }
-            }
-        };
+                chunk.len()
+            };
+            reader.consume(chunk_len);
+        }
+        if !incomplete.is_empty() {
+            code_units.push(std::char::REPLACEMENT_CHARACTER as u16);
+        }
 
-        let mut cursor = self.fragments.cursor();
-        cursor.seek(&offset, seek_bias);
-        let fragment = cursor.item().unwrap();
-        let offset_in_fragment = offset - cursor.start::<usize>();
-        let offset_in_insertion = fragment.start_offset + offset_in_fragment;
-        let point = cursor.start::<Point>() + &fragment.point_for_offset(offset_in_fragment)?;
-        let anchor = Anchor::Middle {
-            insertion_id: fragment.insertion.id,
-            offset: offset_in_insertion,
-            bias,
-        };
-        self.cache_position(Some(anchor.clone()), offset, point);
-        Ok(anchor)
+        Ok(self.edit(
+            Some(start..end),
+            Text::new(code_units),
+            local_clock,
+            lamport_clock,
+        ))
     }
 
-    pub fn anchor_before_point(&self, point: Point) -> Result<Anchor, Error> {
-        self.anchor_for_point(point, AnchorBias::Left)
+    pub fn set_text(
+        &mut self,
+        new_text: &str,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Vec<Operation> {
+        let old_text = self.to_u16_chars();
+        let new_text = new_text.encode_utf16().collect::<Vec<_>>();
+        let (prefix_len, suffix_len) = common_prefix_and_suffix(&old_text, &new_text);
+        let prefix_end = Text::extent(&old_text[..prefix_len]);
+
+        let mut ops = Vec::new();
+        for change in diff(
+            &old_text[prefix_len..old_text.len() - suffix_len],
+            &new_text[prefix_len..new_text.len() - suffix_len],
+        ) {
+            ops.extend(self.edit_2d(
+                Some(prefix_end + &change.range.start..prefix_end + &change.range.end),
+                change.code_units,
+                local_clock,
+                lamport_clock,
+            ));
+        }
+        ops
     }
 
-    pub fn anchor_after_point(&self, point: Point) -> Result<Anchor, Error> {
-        self.anchor_for_point(point, AnchorBias::Right)
+    // Shrinks an edit to the part of `range` that `new_text` actually changes. Applying the
+    // minimized edit produces the same text, but leaves the unchanged boundary text (and any anchors
+    // into it) alone. Invalid ranges are returned as is.
+    pub fn minimal_edit(&self, range: Range<Point>, new_text: &str) -> (Range<Point>, String) {
+        let (start, end) = match (
+            self.offset_for_point(range.start),
+            self.offset_for_point(range.end),
+        ) {
+            (Ok(start), Ok(end)) if start <= end => (start, end),
+            _ => return (range, new_text.to_string()),
+        };
+
+        let old_text = self
+            .iter_at_point(range.start)
+            .take(end - start)
+            .collect::<Vec<_>>();
+        let new_text = new_text.encode_utf16().collect::<Vec<_>>();
+        let (prefix_len, suffix_len) = common_prefix_and_suffix(&old_text, &new_text);
+        let old_middle = &old_text[prefix_len..old_text.len() - suffix_len];
+        let new_middle = &new_text[prefix_len..new_text.len() - suffix_len];
+
+        let new_start = range.start + &Text::extent(&old_text[..prefix_len]);
+        let new_end = new_start + &Text::extent(old_middle);
+        (new_start..new_end, String::from_utf16_lossy(new_middle))
     }
 
-    fn anchor_for_point(&self, point: Point, bias: AnchorBias) -> Result<Anchor, Error> {
-        let max_point = self.max_point();
-        if point > max_point {
-            return Err(Error::OffsetOutOfRange);
+    pub fn replace_all(
+        &mut self,
+        query: &str,
+        replacement: &str,
+        options: SearchOptions,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Vec<Operation> {
+        let ranges = self
+            .search(query, options)
+            .into_iter()
+            .map(|range| {
+                self.offset_for_anchor(&range.start).unwrap()
+                    ..self.offset_for_anchor(&range.end).unwrap()
+            })
+            .collect::<Vec<_>>();
+        if ranges.is_empty() {
+            return Vec::new();
+        } else if !options.preserve_case {
+            return self.edit(ranges, replacement, local_clock, lamport_clock);
         }
 
-        let seek_bias;
-        match bias {
-            AnchorBias::Left => {
-                if point.is_zero() {
-                    return Ok(Anchor::Start);
-                } else {
-                    seek_bias = SeekBias::Left;
-                }
-            }
-            AnchorBias::Right => {
-                if point == max_point {
-                    return Ok(Anchor::End);
-                } else {
-                    seek_bias = SeekBias::Right;
-                }
-            }
-        };
-
-        let mut cursor = self.fragments.cursor();
-        cursor.seek(&point, seek_bias);
-        let fragment = cursor.item().unwrap();
-        let offset_in_fragment = fragment.offset_for_point(point - &cursor.start::<Point>())?;
-        let offset_in_insertion = fragment.start_offset + offset_in_fragment;
-        let anchor = Anchor::Middle {
-            insertion_id: fragment.insertion.id,
-            offset: offset_in_insertion,
-            bias,
-        };
-        let offset = cursor.start::<usize>() + offset_in_fragment;
-        self.cache_position(Some(anchor.clone()), offset, point);
-        Ok(anchor)
+        // Every match may get a differently cased replacement, so they're replaced one by one,
+        // starting from the end so that the offsets of the remaining matches stay valid.
+        let mut ops = Vec::new();
+        for range in ranges.into_iter().rev() {
+            let matched = String::from_utf16_lossy(
+                &self
+                    .iter_at_point(self.point_for_offset(range.start).unwrap())
+                    .take(range.end - range.start)
+                    .collect::<Vec<_>>(),
+            );
+            let new_text = match_case(&matched, replacement);
+            ops.extend(self.edit(Some(range), new_text.as_str(), local_clock, lamport_clock));
+        }
+        ops
     }
 
-    pub fn offset_for_anchor(&self, anchor: &Anchor) -> Result<usize, Error> {
-        Ok(self.position_for_anchor(anchor)?.0)
+    // Computes the edits that would turn this buffer's text into `other`, without applying them.
+    // Ranges are expressed in the buffer's current coordinates and are sorted and disjoint, so
+    // applying them from last to first produces `other`.
+    pub fn diff_against(&self, other: &str) -> Vec<(Range<Point>, String)> {
+        let old_text = self.to_u16_chars();
+        let new_text = other.encode_utf16().collect::<Vec<_>>();
+        let (prefix_len, suffix_len) = common_prefix_and_suffix(&old_text, &new_text);
+        let prefix_end = Text::extent(&old_text[..prefix_len]);
+
+        diff_in_old_coordinates(
+            &old_text[prefix_len..old_text.len() - suffix_len],
+            &new_text[prefix_len..new_text.len() - suffix_len],
+        )
+        .into_iter()
+        .map(|(range, code_units)| {
+            (
+                prefix_end + &range.start..prefix_end + &range.end,
+                String::from_utf16_lossy(&code_units),
+            )
+        })
+        .collect()
     }
 
-    pub fn point_for_anchor(&self, anchor: &Anchor) -> Result<Point, Error> {
-        Ok(self.position_for_anchor(anchor)?.1)
+    // A copy of this buffer built from the same base text by applying `ops`, keeping its marks and
+    // settings. Anchors into insertions that `ops` contain resolve the same way in the copy.
+    pub fn rebuild<I: IntoIterator<Item = Operation>>(
+        &self,
+        ops: I,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Buffer, Error> {
+        let base_text = self.fragments.first().unwrap().insertion.text;
+        let mut buffer = Buffer::new(Text::clone(&base_text));
+        buffer.has_bom = self.has_bom;
+        buffer.cache_resolved_anchors = self.cache_resolved_anchors;
+        buffer.marks = self.marks.clone();
+        buffer.apply_ops(ops, local_clock, lamport_clock)?;
+        Ok(buffer)
     }
 
-    fn position_for_anchor(&self, anchor: &Anchor) -> Result<(usize, Point), Error> {
-        match anchor {
-            Anchor::Start => Ok((0, Point { row: 0, column: 0 })),
-            Anchor::End => Ok((self.len(), self.fragments.extent())),
-            Anchor::Middle {
-                ref insertion_id,
-                offset,
-                ref bias,
-            } => {
-                let cached_position = {
-                    let anchor_cache = self.anchor_cache.try_borrow().ok();
-                    anchor_cache
-                        .as_ref()
-                        .and_then(|cache| cache.get(anchor).cloned())
-                };
-
-                if let Some(cached_position) = cached_position {
-                    Ok(cached_position)
-                } else {
-                    let seek_bias = match bias {
-                        AnchorBias::Left => SeekBias::Left,
-                        AnchorBias::Right => SeekBias::Right,
-                    };
-
-                    let splits =
-                        self.insertion_splits
-                            .get(&insertion_id)
-                            .ok_or(Error::InvalidAnchor(
-                                "split does not exist for insertion id".into(),
-                            ))?;
-                    let mut splits_cursor = splits.cursor();
-                    splits_cursor.seek(offset, seek_bias);
-                    splits_cursor
-                        .item()
-                        .ok_or(Error::InvalidAnchor("split offset is out of range".into()))
-                        .and_then(|split| {
-                            let mut fragments_cursor = self.fragments.cursor();
-                            fragments_cursor.seek(&split.fragment_id, SeekBias::Left);
-                            fragments_cursor
-                                .item()
-                                .ok_or(Error::InvalidAnchor("fragment id does not exist".into()))
-                                .and_then(|fragment| {
-                                    let overshoot = if fragment.is_visible() {
-                                        offset - fragment.start_offset
-                                    } else {
-                                        0
-                                    };
-                                    let offset = fragments_cursor.start::<usize>() + overshoot;
-                                    let point = fragments_cursor.start::<Point>()
-                                        + &fragment.point_for_offset(overshoot)?;
-                                    self.cache_position(Some(anchor.clone()), offset, point);
-                                    Ok((offset, point))
-                                })
-                        })
-                }
-            }
+    pub fn edit_at_anchor<T>(
+        &mut self,
+        old_range: Range<Anchor>,
+        new_text: T,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Vec<Operation>, Error>
+    where
+        T: Into<Text>,
+    {
+        let start = self.offset_for_anchor(&old_range.start)?;
+        let end = self.offset_for_anchor(&old_range.end)?;
+        if start > end {
+            return Err(Error::InvalidAnchor("range start is after its end".into()));
         }
+        Ok(self.edit(Some(start..end), new_text, local_clock, lamport_clock))
     }
 
-    fn offset_for_point(&self, point: Point) -> Result<usize, Error> {
-        let cached_offset = {
-            let offset_cache = self.offset_cache.try_borrow().ok();
-            offset_cache
-                .as_ref()
-                .and_then(|cache| cache.get(&point).cloned())
-        };
-
-        if let Some(cached_offset) = cached_offset {
-            Ok(cached_offset)
-        } else {
-            let mut fragments_cursor = self.fragments.cursor();
-            fragments_cursor.seek(&point, SeekBias::Left);
-            fragments_cursor
-                .item()
-                .ok_or(Error::OffsetOutOfRange)
-                .map(|fragment| {
-                    let overshoot = fragment
-                        .offset_for_point(point - &fragments_cursor.start::<Point>())
-                        .unwrap();
-                    let offset = &fragments_cursor.start::<usize>() + &overshoot;
-                    self.cache_position(None, offset, point);
-                    offset
-                })
+    // The returned anchors cover exactly the inserted text, even after concurrent insertions at
+    // either of its ends.
+    pub fn splice(
+        &mut self,
+        old_range: Range<Point>,
+        new_text: &str,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<(Vec<Operation>, Range<Anchor>), Error> {
+        let start = self.offset_for_point(old_range.start)?;
+        let end = self.offset_for_point(old_range.end)?;
+        if start > end {
+            return Err(Error::OffsetOutOfRange);
         }
+        let new_text = Text::from(new_text);
+        let new_end = start + new_text.len();
+        let operations = self.edit(Some(start..end), new_text, local_clock, lamport_clock);
+        let new_range = self.anchor_after_offset(start)?..self.anchor_before_offset(new_end)?;
+        Ok((operations, new_range))
     }
 
-    pub fn cmp_anchors(&self, a: &Anchor, b: &Anchor) -> Result<Ordering, Error> {
-        let a_offset = self.offset_for_anchor(a)?;
-        let b_offset = self.offset_for_anchor(b)?;
-        Ok(a_offset.cmp(&b_offset))
+    pub fn add_selection_set<I>(
+        &mut self,
+        ranges: I,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<(SelectionSetId, Operation), Error>
+    where
+        I: IntoIterator<Item = Range<Point>>,
+    {
+        let selections = self.selections_from_ranges(ranges)?;
+        let lamport_timestamp = lamport_clock.tick();
+        self.selections
+            .insert(lamport_timestamp, selections.clone());
+        self.selections_last_update += 1;
+
+        Ok((
+            lamport_timestamp,
+            Operation::UpdateSelections {
+                set_id: lamport_timestamp,
+                selections: Some(selections),
+                lamport_timestamp,
+                hidden: false,
+            },
+        ))
     }
 
-    fn cache_position(&self, anchor: Option<Anchor>, offset: usize, point: Point) {
-        anchor.map(|anchor| {
-            if let Ok(mut anchor_cache) = self.anchor_cache.try_borrow_mut() {
-                anchor_cache.insert(anchor, (offset, point));
-            }
-        });
+    pub fn replace_selection_set<I>(
+        &mut self,
+        set_id: SelectionSetId,
+        ranges: I,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Operation, Error>
+    where
+        I: IntoIterator<Item = Range<Point>>,
+    {
+        self.selections
+            .remove(&set_id)
+            .ok_or(Error::InvalidSelectionSet(set_id))?;
 
-        if let Ok(mut offset_cache) = self.offset_cache.try_borrow_mut() {
-            offset_cache.insert(point, offset);
-        }
-    }
-}
+        let mut selections = self.selections_from_ranges(ranges)?;
+        self.merge_selections(&mut selections);
+        self.selections.insert(set_id, selections.clone());
 
-impl Point {
-    pub fn new(row: u32, column: u32) -> Self {
-        Point { row, column }
-    }
+        let lamport_timestamp = lamport_clock.tick();
+        self.selections_last_update += 1;
 
-    pub fn zero() -> Self {
-        Point::new(0, 0)
+        Ok(Operation::UpdateSelections {
+            set_id,
+            selections: Some(selections),
+            lamport_timestamp,
+            hidden: self.hidden_selection_sets.contains(&set_id),
+        })
     }
 
-    pub fn is_zero(&self) -> bool {
-        self.row == 0 && self.column == 0
+    // Hiding a set conceals it from `all_selection_ranges` on every replica while retaining its
+    // selections, which keep tracking edits and reappear when the set is shown again.
+    pub fn hide_selection_set(
+        &mut self,
+        set_id: SelectionSetId,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Operation, Error> {
+        self.set_selection_set_hidden(set_id, true, lamport_clock)
     }
-}
 
-impl btree::Dimension<FragmentSummary> for Point {
-    fn from_summary(summary: &FragmentSummary) -> Self {
-        summary.extent_2d
+    pub fn show_selection_set(
+        &mut self,
+        set_id: SelectionSetId,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Operation, Error> {
+        self.set_selection_set_hidden(set_id, false, lamport_clock)
     }
-}
 
-impl<'a> Add<&'a Self> for Point {
-    type Output = Point;
-
-    fn add(self, other: &'a Self) -> Self::Output {
-        if other.row == 0 {
-            Point::new(self.row, self.column + other.column)
+    pub fn is_selection_set_hidden(&self, set_id: SelectionSetId) -> Result<bool, Error> {
+        if self.selections.contains_key(&set_id) {
+            Ok(self.hidden_selection_sets.contains(&set_id))
         } else {
-            Point::new(self.row + other.row, other.column)
+            Err(Error::InvalidSelectionSet(set_id))
         }
     }
-}
 
-impl<'a> Sub<&'a Self> for Point {
-    type Output = Point;
-
-    fn sub(self, other: &'a Self) -> Self::Output {
-        debug_assert!(*other <= self);
-
-        if self.row == other.row {
-            Point::new(0, self.column - other.column)
+    fn set_selection_set_hidden(
+        &mut self,
+        set_id: SelectionSetId,
+        hidden: bool,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Operation, Error> {
+        let selections = self
+            .selections
+            .get(&set_id)
+            .ok_or(Error::InvalidSelectionSet(set_id))?
+            .clone();
+        if hidden {
+            self.hidden_selection_sets.insert(set_id);
         } else {
-            Point::new(self.row - other.row, self.column)
+            self.hidden_selection_sets.remove(&set_id);
         }
+
+        let lamport_timestamp = lamport_clock.tick();
+        self.selections_last_update += 1;
+        Ok(Operation::UpdateSelections {
+            set_id,
+            selections: Some(selections),
+            lamport_timestamp,
+            hidden,
+        })
     }
-}
 
-impl<'a> AddAssign<&'a Self> for Point {
-    fn add_assign(&mut self, other: &'a Self) {
-        if other.row == 0 {
-            self.column += other.column;
-        } else {
-            self.row += other.row;
-            self.column = other.column;
-        }
+    pub fn remove_selection_set(
+        &mut self,
+        set_id: SelectionSetId,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Operation, Error> {
+        self.selections
+            .remove(&set_id)
+            .ok_or(Error::InvalidSelectionSet(set_id))?;
+        self.hidden_selection_sets.remove(&set_id);
+        let lamport_timestamp = lamport_clock.tick();
+        self.selections_last_update += 1;
+        Ok(Operation::UpdateSelections {
+            set_id,
+            selections: None,
+            lamport_timestamp,
+            hidden: false,
+        })
     }
-}
 
-impl PartialOrd for Point {
-    fn partial_cmp(&self, other: &Point) -> Option<Ordering> {
-        Some(self.cmp(other))
+    pub fn selection_ranges<'a>(
+        &'a self,
+        set_id: SelectionSetId,
+    ) -> Result<impl Iterator<Item = Range<Point>> + 'a, Error> {
+        let selections = self
+            .selections
+            .get(&set_id)
+            .ok_or(Error::InvalidSelectionSet(set_id))?;
+        Ok(selections.iter().map(move |selection| {
+            let start = self.point_for_anchor(&selection.start).unwrap();
+            let end = self.point_for_anchor(&selection.end).unwrap();
+            if selection.reversed {
+                end..start
+            } else {
+                start..end
+            }
+        }))
     }
-}
 
-impl Ord for Point {
-    #[cfg(target_pointer_width = "64")]
-    fn cmp(&self, other: &Point) -> Ordering {
-        let a = (self.row as usize) << 32 | self.column as usize;
-        let b = (other.row as usize) << 32 | other.column as usize;
-        a.cmp(&b)
+    pub fn all_selections(&self) -> impl Iterator<Item = (&SelectionSetId, &Vec<Selection>)> {
+        self.selections.iter()
     }
 
-    #[cfg(target_pointer_width = "32")]
-    fn cmp(&self, other: &Point) -> Ordering {
-        match self.row.cmp(&other.row) {
-            Ordering::Equal => self.column.cmp(&other.column),
-            comparison @ _ => comparison,
-        }
+    // The ranges of every selection set that isn't hidden.
+    pub fn all_selection_ranges<'a>(
+        &'a self,
+    ) -> impl 'a + Iterator<Item = (SelectionSetId, Vec<Range<Point>>)> {
+        self.selections
+            .keys()
+            .filter(move |set_id| !self.hidden_selection_sets.contains(set_id))
+            .map(move |set_id| (*set_id, self.selection_ranges(*set_id).unwrap().collect()))
     }
-}
 
-impl Anchor {
-    fn to_flatbuf<'fbb>(
-        &self,
-        builder: &mut FlatBufferBuilder<'fbb>,
-    ) -> WIPOffset<serialization::buffer::Anchor<'fbb>> {
-        match self {
-            Anchor::Start => serialization::buffer::Anchor::create(
-                builder,
-                &serialization::buffer::AnchorArgs {
-                    variant: serialization::buffer::AnchorVariant::Start,
-                    ..serialization::buffer::AnchorArgs::default()
-                },
-            ),
-            Anchor::End => serialization::buffer::Anchor::create(
-                builder,
-                &serialization::buffer::AnchorArgs {
-                    variant: serialization::buffer::AnchorVariant::End,
-                    ..serialization::buffer::AnchorArgs::default()
-                },
-            ),
-            Anchor::Middle {
-                insertion_id,
-                offset,
-                bias,
-            } => serialization::buffer::Anchor::create(
-                builder,
-                &serialization::buffer::AnchorArgs {
-                    variant: serialization::buffer::AnchorVariant::Middle,
-                    insertion_id: Some(&insertion_id.to_flatbuf()),
-                    offset: *offset as u64,
-                    bias: bias.to_flatbuf(),
-                },
-            ),
-        }
-    }
+    // This is a non-semantic optimization: only fragments split from the same insertion are
+    // merged, so anchors and remote operations, which refer to insertion offsets, resolve to the
+    // same positions as before.
+    pub fn coalesce_fragments(&mut self) {
+        let mut merged_fragment_ids = HashMap::new();
+        let mut new_fragments = btree::Tree::new();
+        let mut prev_fragment: Option<Fragment> = None;
+        for fragment in self.fragments.cursor() {
+            if let Some(prev_fragment) = prev_fragment.as_mut() {
+                if prev_fragment.insertion.id == fragment.insertion.id
+                    && prev_fragment.end_offset == fragment.start_offset
+                    && prev_fragment.deletions == fragment.deletions
+                    && prev_fragment.extent() > 0
+                    && fragment.extent() > 0
+                {
+                    prev_fragment.end_offset = fragment.end_offset;
+                    merged_fragment_ids.insert(fragment.id, prev_fragment.insertion.id);
+                    continue;
+                }
+            }
 
-    fn from_flatbuf<'fbb>(
-        message: &serialization::buffer::Anchor<'fbb>,
-    ) -> Result<Self, crate::Error> {
-        match message.variant() {
-            serialization::buffer::AnchorVariant::Start => Ok(Anchor::Start),
-            serialization::buffer::AnchorVariant::End => Ok(Anchor::End),
-            serialization::buffer::AnchorVariant::Middle => Ok(Anchor::Middle {
-                insertion_id: time::Local::from_flatbuf(
-                    message
-                        .insertion_id()
-                        .ok_or(crate::Error::DeserializeError)?,
-                ),
-                offset: message.offset() as usize,
-                bias: AnchorBias::from_flatbuf(message.bias()),
-            }),
+            if let Some(prev_fragment) = mem::replace(&mut prev_fragment, Some(fragment)) {
+                new_fragments.push(prev_fragment);
+            }
         }
-    }
-}
+        if let Some(prev_fragment) = prev_fragment {
+            new_fragments.push(prev_fragment);
+        }
+        self.fragments = new_fragments;
 
-impl AnchorBias {
-    fn to_flatbuf(&self) -> serialization::buffer::AnchorBias {
-        match self {
-            AnchorBias::Left => serialization::buffer::AnchorBias::Left,
-            AnchorBias::Right => serialization::buffer::AnchorBias::Right,
+        let insertion_ids = merged_fragment_ids
+            .values()
+            .cloned()
+            .collect::<HashSet<_>>();
+        for insertion_id in insertion_ids {
+            let split_tree = self.insertion_splits.get_mut(&insertion_id).unwrap();
+            let mut new_splits: Vec<InsertionSplit> = Vec::new();
+            for split in split_tree.items() {
+                if merged_fragment_ids.contains_key(&split.fragment_id) {
+                    new_splits.last_mut().unwrap().extent += split.extent;
+                } else {
+                    new_splits.push(split);
+                }
+            }
+            *split_tree = btree::Tree::new();
+            split_tree.extend(new_splits);
         }
     }
 
-    fn from_flatbuf(message: serialization::buffer::AnchorBias) -> Self {
-        match message {
-            serialization::buffer::AnchorBias::Left => AnchorBias::Left,
-            serialization::buffer::AnchorBias::Right => AnchorBias::Right,
-        }
+    // Drops tombstones whose deletions are all observed by `barrier`. This is only safe if every
+    // replica has observed `barrier`, since operations concurrent with those deletions could
+    // otherwise refer to fragments that no longer exist.
+    pub fn collect_garbage(&mut self, barrier: &time::Global) {
+        self.collect_fragments(|fragment| Self::is_collectable(fragment, barrier));
     }
-}
 
-impl Iter {
-    fn new(buffer: &Buffer) -> Self {
-        let mut fragment_cursor = buffer.fragments.cursor();
-        fragment_cursor.seek(&0, SeekBias::Right);
-        Self {
-            fragment_cursor,
-            fragment_offset: 0,
-            reversed: false,
+    // Like `collect_garbage`, but keeps the tombstones of the `keep_last` most recent deletions.
+    // Tombstones of insertions that selections or marks are anchored to are always kept, so that
+    // those anchors keep resolving to the same position.
+    pub fn truncate_history(&mut self, keep_last: usize, barrier: &time::Global) {
+        let mut deletions = self
+            .fragments
+            .items()
+            .into_iter()
+            .filter(|fragment| Self::is_collectable(fragment, barrier))
+            .flat_map(|fragment| fragment.deletions)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        deletions.sort_by_key(|deletion| (deletion.value, deletion.replica_id));
+        let kept_deletions = deletions
+            .into_iter()
+            .rev()
+            .take(keep_last)
+            .collect::<HashSet<_>>();
+
+        let mut anchored_insertions = HashSet::new();
+        let anchors = self
+            .selections
+            .values()
+            .flatten()
+            .flat_map(|selection| vec![&selection.start, &selection.end])
+            .chain(self.marks.values());
+        for anchor in anchors {
+            if let Anchor::Middle { insertion_id, .. } = anchor {
+                anchored_insertions.insert(*insertion_id);
+            }
         }
+
+        self.collect_fragments(|fragment| {
+            Self::is_collectable(fragment, barrier)
+                && fragment.deletions.is_disjoint(&kept_deletions)
+                && !anchored_insertions.contains(&fragment.insertion.id)
+        });
     }
 
-    fn at_point(buffer: &Buffer, point: Point) -> Self {
-        let mut fragment_cursor = buffer.fragments.cursor();
-        fragment_cursor.seek(&point, SeekBias::Right);
-        let fragment_offset = if let Some(fragment) = fragment_cursor.item() {
-            let point_in_fragment = point - &fragment_cursor.start::<Point>();
-            fragment.offset_for_point(point_in_fragment).unwrap()
-        } else {
-            0
-        };
+    fn is_collectable(fragment: &Fragment, barrier: &time::Global) -> bool {
+        !fragment.deletions.is_empty()
+            && fragment
+                .deletions
+                .iter()
+                .all(|deletion| barrier.observed(*deletion))
+    }
 
-        Self {
-            fragment_cursor,
-            fragment_offset,
-            reversed: false,
+    fn collect_fragments<F: Fn(&Fragment) -> bool>(&mut self, collectable: F) {
+        if self.deferred_ops.len() > 0 {
+            return;
         }
-    }
 
-    pub fn rev(mut self) -> Iter {
-        self.reversed = true;
-        self
+        let mut collected = false;
+        let mut new_fragments = btree::Tree::new();
+        new_fragments.extend(self.fragments.items().into_iter().filter(|fragment| {
+            let collect = collectable(fragment);
+            collected |= collect;
+            !collect
+        }));
+        if collected {
+            self.fragments = new_fragments;
+            self.collected_garbage = true;
+            self.anchor_cache.borrow_mut().clear();
+            self.offset_cache.borrow_mut().clear();
+        }
     }
 
-    pub fn into_string(self) -> String {
-        String::from_utf16_lossy(&self.collect::<Vec<u16>>())
+    fn merge_selections(&mut self, selections: &mut Vec<Selection>) {
+        let mut new_selections = Vec::with_capacity(selections.len());
+        {
+            let mut old_selections = selections.drain(..);
+            if let Some(mut prev_selection) = old_selections.next() {
+                for selection in old_selections {
+                    if self
+                        .cmp_anchors(&prev_selection.end, &selection.start)
+                        .unwrap()
+                        >= Ordering::Equal
+                    {
+                        if self
+                            .cmp_anchors(&selection.end, &prev_selection.end)
+                            .unwrap()
+                            > Ordering::Equal
+                        {
+                            prev_selection.end = selection.end;
+                        }
+                    } else {
+                        new_selections.push(mem::replace(&mut prev_selection, selection));
+                    }
+                }
+                new_selections.push(prev_selection);
+            }
+        }
+        *selections = new_selections;
     }
-}
 
-impl Iterator for Iter {
-    type Item = u16;
+    fn selections_from_ranges<I>(&self, ranges: I) -> Result<Vec<Selection>, Error>
+    where
+        I: IntoIterator<Item = Range<Point>>,
+    {
+        let mut ranges = ranges.into_iter().collect::<Vec<_>>();
+        ranges.sort_unstable_by_key(|range| range.start);
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.reversed {
-            if let Some(fragment) = self.fragment_cursor.item() {
-                if self.fragment_offset > 0 {
-                    self.fragment_offset -= 1;
-                    if let Some(c) = fragment.code_unit(self.fragment_offset) {
-                        return Some(c);
-                    }
-                }
+        let mut selections = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            if range.start > range.end {
+                selections.push(Selection {
+                    start: self.anchor_before_point(range.end)?,
+                    end: self.anchor_before_point(range.start)?,
+                    reversed: true,
+                });
+            } else {
+                selections.push(Selection {
+                    start: self.anchor_before_point(range.start)?,
+                    end: self.anchor_before_point(range.end)?,
+                    reversed: false,
+                });
             }
+        }
+        Ok(selections)
+    }
 
-            loop {
-                self.fragment_cursor.prev();
-                if let Some(fragment) = self.fragment_cursor.item() {
-                    if fragment.len() > 0 {
-                        self.fragment_offset = fragment.len() - 1;
-                        return fragment.code_unit(self.fragment_offset);
-                    }
-                } else {
-                    break;
-                }
+    pub fn apply_ops<I: IntoIterator<Item = Operation>>(
+        &mut self,
+        ops: I,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<(), Error> {
+        let mut deferred_ops = Vec::new();
+        for op in ops {
+            if self.can_apply_op(&op) {
+                self.apply_op(op, local_clock, lamport_clock)?;
+            } else {
+                self.deferred_replicas.insert(op.replica_id());
+                deferred_ops.push(op);
             }
+        }
+        self.deferred_ops.insert(deferred_ops);
+        self.flush_deferred_ops(local_clock, lamport_clock)?;
+        Ok(())
+    }
 
-            None
-        } else {
-            if let Some(fragment) = self.fragment_cursor.item() {
-                if let Some(c) = fragment.code_unit(self.fragment_offset) {
-                    self.fragment_offset += 1;
-                    return Some(c);
+    fn apply_op(
+        &mut self,
+        op: Operation,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<(), Error> {
+        match op {
+            Operation::Edit {
+                start_id,
+                start_offset,
+                end_id,
+                end_offset,
+                new_text,
+                version_in_range,
+                local_timestamp,
+                lamport_timestamp,
+            } => {
+                if !self.version.observed(local_timestamp) {
+                    self.apply_edit(
+                        start_id,
+                        start_offset,
+                        end_id,
+                        end_offset,
+                        new_text.as_ref().cloned(),
+                        &version_in_range,
+                        local_timestamp,
+                        lamport_timestamp,
+                        local_clock,
+                        lamport_clock,
+                    )?;
+                    self.anchor_cache.borrow_mut().clear();
+                    self.offset_cache.borrow_mut().clear();
+                    self.version.observe(local_timestamp);
                 }
             }
-
-            loop {
-                self.fragment_cursor.next();
-                if let Some(fragment) = self.fragment_cursor.item() {
-                    if let Some(c) = fragment.code_unit(0) {
-                        self.fragment_offset = 1;
-                        return Some(c);
-                    }
+            Operation::UpdateSelections {
+                set_id,
+                selections,
+                lamport_timestamp,
+                hidden,
+            } => {
+                if let Some(selections) = selections {
+                    self.selections.insert(set_id, selections);
                 } else {
-                    break;
+                    self.selections.remove(&set_id);
+                }
+                if hidden {
+                    self.hidden_selection_sets.insert(set_id);
+                } else {
+                    self.hidden_selection_sets.remove(&set_id);
                 }
+                lamport_clock.observe(lamport_timestamp);
+                self.selections_last_update += 1;
             }
-
-            None
         }
+        Ok(())
     }
-}
 
-impl<F: Fn(&FragmentSummary) -> bool> Iterator for ChangesIter<F> {
-    type Item = Change;
+    fn apply_edit(
+        &mut self,
+        start_id: time::Local,
+        start_offset: usize,
+        end_id: time::Local,
+        end_offset: usize,
+        new_text: Option<Arc<Text>>,
+        version_in_range: &time::Global,
+        local_timestamp: time::Local,
+        lamport_timestamp: time::Lamport,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<(), Error> {
+        let mut new_text = new_text.as_ref().cloned();
+        let (mut start_offset, mut end_offset) = (start_offset, end_offset);
+        let mut fragment_ids = self
+            .resolve_fragment_id(start_id, start_offset)
+            .and_then(|start| Ok((start, self.resolve_fragment_id(end_id, end_offset)?)));
+        if self.collected_garbage {
+            // A collected tombstone had no visible text, so referring to it is equivalent to
+            // referring to the end of the fragment that preceded it.
+            if let Ok((start, end)) = fragment_ids.as_mut() {
+                self.resolve_collected_fragment(start, &mut start_offset);
+                self.resolve_collected_fragment(end, &mut end_offset);
+            }
+        }
+        let (start_fragment_id, end_fragment_id) = fragment_ids?;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut change: Option<Change> = None;
+        let old_fragments = self.fragments.clone();
+        let mut cursor = old_fragments.cursor();
+        let mut new_fragments = cursor.slice(&start_fragment_id, SeekBias::Left);
 
-        while let Some(fragment) = self.cursor.item() {
-            let position = self.cursor.start();
-            if !fragment.was_visible(&self.since) && fragment.is_visible() {
-                if let Some(ref mut change) = change {
-                    if change.range.start + &change.new_extent == position {
-                        change.code_units.extend(fragment.code_units());
-                        change.new_extent += &fragment.extent_2d();
-                    } else {
-                        break;
-                    }
+        if start_offset == cursor.item().unwrap().end_offset {
+            new_fragments.push(cursor.item().unwrap());
+            cursor.next();
+        }
+
+        while let Some(mut fragment) = cursor.item() {
+            if new_text.is_none() && fragment.id > end_fragment_id {
+                break;
+            }
+
+            if fragment.id == start_fragment_id || fragment.id == end_fragment_id {
+                let split_start = if start_fragment_id == fragment.id {
+                    start_offset
                 } else {
-                    change = Some(Change {
-                        range: position..position,
-                        code_units: Vec::from(fragment.code_units()),
-                        new_extent: fragment.extent_2d(),
-                    });
+                    fragment.start_offset
+                };
+                let split_end = if end_fragment_id == fragment.id {
+                    end_offset
+                } else {
+                    fragment.end_offset
+                };
+                let (before_range, within_range, after_range) = self.split_fragment(
+                    cursor.prev_item().as_ref().unwrap(),
+                    &fragment,
+                    split_start..split_end,
+                );
+                let insertion = if let Some(new_text) = new_text.take() {
+                    Some(
+                        self.build_fragment_to_insert(
+                            before_range
+                                .as_ref()
+                                .or(cursor.prev_item().as_ref())
+                                .unwrap(),
+                            within_range.as_ref().or(after_range.as_ref()),
+                            new_text,
+                            local_timestamp,
+                            lamport_timestamp,
+                        ),
+                    )
+                } else {
+                    None
+                };
+                if let Some(fragment) = before_range {
+                    new_fragments.push(fragment);
                 }
-            } else if fragment.was_visible(&self.since) && !fragment.is_visible() {
-                if let Some(ref mut change) = change {
-                    if change.range.start + &change.new_extent == position {
-                        change.range.end += &fragment.extent_2d();
-                    } else {
-                        break;
+                if let Some(fragment) = insertion {
+                    new_fragments.push(fragment);
+                }
+                if let Some(mut fragment) = within_range {
+                    if version_in_range.observed(fragment.insertion.id) {
+                        fragment.deletions.insert(local_timestamp);
                     }
-                } else {
-                    change = Some(Change {
-                        range: position..position + &fragment.extent_2d(),
-                        code_units: Vec::new(),
-                        new_extent: Point::zero(),
-                    });
+                    new_fragments.push(fragment);
+                }
+                if let Some(fragment) = after_range {
+                    new_fragments.push(fragment);
+                }
+            } else {
+                if new_text.is_some() && lamport_timestamp > fragment.insertion.lamport_timestamp {
+                    new_fragments.push(self.build_fragment_to_insert(
+                        cursor.prev_item().as_ref().unwrap(),
+                        Some(&fragment),
+                        new_text.take().unwrap(),
+                        local_timestamp,
+                        lamport_timestamp,
+                    ));
                 }
-            }
-
-            self.cursor.next();
-        }
-
-        change
-    }
-}
-
-pub fn diff(a: &[u16], b: &[u16]) -> Vec<Change> {
-    struct ChangeCollector<'a> {
-        a: &'a [u16],
-        b: &'a [u16],
-        position: Point,
-        changes: Vec<Change>,
-    }
 
-    impl<'a> diffs::Diff for ChangeCollector<'a> {
-        type Error = ();
+                if fragment.id < end_fragment_id && version_in_range.observed(fragment.insertion.id)
+                {
+                    fragment.deletions.insert(local_timestamp);
+                }
+                new_fragments.push(fragment);
+            }
 
-        fn equal(&mut self, old: usize, _: usize, len: usize) -> Result<(), ()> {
-            self.position += &Text::extent(&self.a[old..old + len]);
-            Ok(())
+            cursor.next();
         }
 
-        fn delete(&mut self, old: usize, len: usize) -> Result<(), ()> {
-            self.changes.push(Change {
-                range: self.position..self.position + &Text::extent(&self.a[old..old + len]),
-                code_units: Vec::new(),
-                new_extent: Point::zero(),
-            });
-            Ok(())
+        if let Some(new_text) = new_text {
+            new_fragments.push(self.build_fragment_to_insert(
+                cursor.prev_item().as_ref().unwrap(),
+                None,
+                new_text,
+                local_timestamp,
+                lamport_timestamp,
+            ));
         }
 
-        fn insert(&mut self, _: usize, new: usize, new_len: usize) -> Result<(), ()> {
-            let new_extent = Text::extent(&self.b[new..new + new_len]);
-            self.changes.push(Change {
-                range: self.position..self.position,
-                code_units: Vec::from(&self.b[new..new + new_len]),
-                new_extent,
-            });
-            self.position += &new_extent;
-            Ok(())
-        }
+        new_fragments.push_tree(cursor.slice(&old_fragments.extent::<usize>(), SeekBias::Right));
+        self.fragments = new_fragments;
+        local_clock.observe(local_timestamp);
+        lamport_clock.observe(lamport_timestamp);
+        Ok(())
+    }
 
-        fn replace(
-            &mut self,
-            old: usize,
-            old_len: usize,
-            new: usize,
-            new_len: usize,
-        ) -> Result<(), ()> {
-            let old_extent = Text::extent(&self.a[old..old + old_len]);
-            let new_extent = Text::extent(&self.b[new..new + new_len]);
-            self.changes.push(Change {
-                range: self.position..self.position + &old_extent,
-                code_units: Vec::from(&self.b[new..new + new_len]),
-                new_extent,
-            });
-            self.position += &new_extent;
-            Ok(())
+    fn flush_deferred_ops(
+        &mut self,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<(), Error> {
+        self.deferred_replicas.clear();
+        let mut deferred_ops = Vec::new();
+        for op in self.deferred_ops.drain() {
+            if self.can_apply_op(&op) {
+                self.apply_op(op, local_clock, lamport_clock)?;
+            } else {
+                self.deferred_replicas.insert(op.replica_id());
+                deferred_ops.push(op);
+            }
         }
+        self.deferred_ops.insert(deferred_ops);
+        Ok(())
     }
 
-    let mut collector = diffs::Replace::new(ChangeCollector {
-        a,
-        b,
-        position: Point::zero(),
-        changes: Vec::new(),
-    });
-    diffs::myers::diff(&mut collector, a, 0, a.len(), b, 0, b.len()).unwrap();
-    collector.into_inner().changes
-}
-
-impl Selection {
-    pub fn head(&self) -> &Anchor {
-        if self.reversed {
-            &self.start
+    fn can_apply_op(&self, op: &Operation) -> bool {
+        if self.deferred_replicas.contains(&op.replica_id()) {
+            false
         } else {
-            &self.end
+            match op {
+                Operation::Edit {
+                    start_id,
+                    end_id,
+                    version_in_range,
+                    ..
+                } => {
+                    self.version.observed(*start_id)
+                        && self.version.observed(*end_id)
+                        && *version_in_range <= self.version
+                }
+                Operation::UpdateSelections { selections, .. } => {
+                    if let Some(selections) = selections {
+                        selections.iter().all(|selection| {
+                            let contains_start = match selection.start {
+                                Anchor::Middle { insertion_id, .. } => {
+                                    self.version.observed(insertion_id)
+                                }
+                                _ => true,
+                            };
+                            let contains_end = match selection.end {
+                                Anchor::Middle { insertion_id, .. } => {
+                                    self.version.observed(insertion_id)
+                                }
+                                _ => true,
+                            };
+                            contains_start && contains_end
+                        })
+                    } else {
+                        true
+                    }
+                }
+            }
         }
     }
 
-    pub fn set_head<S>(&mut self, buffer: &Buffer, cursor: Anchor) {
-        if buffer.cmp_anchors(&cursor, self.tail()).unwrap() < Ordering::Equal {
-            if !self.reversed {
-                mem::swap(&mut self.start, &mut self.end);
-                self.reversed = true;
-            }
-            self.start = cursor;
-        } else {
-            if self.reversed {
-                mem::swap(&mut self.start, &mut self.end);
-                self.reversed = false;
-            }
-            self.end = cursor;
-        }
-    }
-
-    pub fn tail(&self) -> &Anchor {
-        if self.reversed {
-            &self.end
-        } else {
-            &self.start
+    fn resolve_collected_fragment(&self, fragment_id: &mut FragmentId, offset: &mut usize) {
+        let mut cursor = self.fragments.cursor();
+        cursor.seek(fragment_id, SeekBias::Left);
+        if cursor
+            .item()
+            .map_or(true, |fragment| fragment.id != *fragment_id)
+        {
+            let prev_fragment = cursor.prev_item().unwrap();
+            *fragment_id = prev_fragment.id;
+            *offset = prev_fragment.end_offset;
+            self.anchor_fallbacks.set(self.anchor_fallbacks.get() + 1);
         }
     }
 
-    pub fn is_empty(&self, buffer: &Buffer) -> bool {
-        buffer.cmp_anchors(&self.start, &self.end).unwrap() == Ordering::Equal
+    fn resolve_fragment_id(
+        &self,
+        edit_id: time::Local,
+        offset: usize,
+    ) -> Result<FragmentId, Error> {
+        let split_tree = self
+            .insertion_splits
+            .get(&edit_id)
+            .ok_or(Error::InvalidOperation)?;
+        let mut cursor = split_tree.cursor();
+        cursor.seek(&offset, SeekBias::Left);
+        Ok(cursor
+            .item()
+            .ok_or(Error::InvalidOperation)?
+            .fragment_id
+            .clone())
     }
 
-    pub fn anchor_range(&self) -> Range<Anchor> {
-        self.start.clone()..self.end.clone()
-    }
+    fn splice_fragments<I>(
+        &mut self,
+        mut old_ranges: I,
+        new_text: Option<Arc<Text>>,
+        local_clock: &mut time::Local,
+        lamport_clock: &mut time::Lamport,
+    ) -> Vec<Operation>
+    where
+        I: Iterator<Item = Range<usize>>,
+    {
+        let mut cur_range = old_ranges.next();
+        if cur_range.is_none() {
+            return Vec::new();
+        }
 
-    fn to_flatbuf<'fbb>(
-        &self,
-        builder: &mut FlatBufferBuilder<'fbb>,
-    ) -> WIPOffset<serialization::buffer::Selection<'fbb>> {
-        let start = Some(self.start.to_flatbuf(builder));
-        let end = Some(self.end.to_flatbuf(builder));
+        let mut ops = Vec::with_capacity(old_ranges.size_hint().0);
 
-        serialization::buffer::Selection::create(
-            builder,
-            &serialization::buffer::SelectionArgs {
-                start,
-                end,
-                reversed: self.reversed,
-            },
-        )
-    }
+        let old_fragments = self.fragments.clone();
+        let mut cursor = old_fragments.cursor();
+        let mut new_fragments = btree::Tree::new();
+        new_fragments.push_tree(cursor.slice(&cur_range.as_ref().unwrap().start, SeekBias::Right));
 
-    fn from_flatbuf<'fbb>(
-        message: serialization::buffer::Selection<'fbb>,
-    ) -> Result<Self, crate::Error> {
-        Ok(Self {
-            start: Anchor::from_flatbuf(&message.start().ok_or(crate::Error::DeserializeError)?)?,
-            end: Anchor::from_flatbuf(&message.end().ok_or(crate::Error::DeserializeError)?)?,
-            reversed: message.reversed(),
-        })
-    }
-}
+        let mut start_id = None;
+        let mut start_offset = None;
+        let mut end_id = None;
+        let mut end_offset = None;
+        let mut version_in_range = time::Global::new();
 
-impl Text {
-    pub fn new(code_units: Vec<u16>) -> Self {
-        fn build_tree(index: usize, line_lengths: &[u32], mut tree: &mut [LineNode]) {
-            if line_lengths.is_empty() {
-                return;
-            }
+        let mut local_timestamp = local_clock.tick();
+        let mut lamport_timestamp = lamport_clock.tick();
 
-            let mid = if line_lengths.len() == 1 {
-                0
-            } else {
-                let depth = log2_fast(line_lengths.len());
-                let max_elements = (1 << (depth)) - 1;
-                let right_subtree_elements = 1 << (depth - 1);
-                cmp::min(line_lengths.len() - right_subtree_elements, max_elements)
-            };
-            let len = line_lengths[mid];
-            let lower = &line_lengths[0..mid];
-            let upper = &line_lengths[mid + 1..];
+        while cur_range.is_some() && cursor.item().is_some() {
+            let mut fragment = cursor.item().unwrap();
+            let mut fragment_start = cursor.start::<usize>();
+            let mut fragment_end = fragment_start + fragment.len();
 
-            let left_child_index = index * 2 + 1;
-            let right_child_index = index * 2 + 2;
-            build_tree(left_child_index, lower, &mut tree);
-            build_tree(right_child_index, upper, &mut tree);
-            tree[index] = {
-                let mut left_child_longest_row = 0;
-                let mut left_child_longest_row_len = 0;
-                let mut left_child_offset = 0;
-                let mut left_child_rows = 0;
-                if let Some(left_child) = tree.get(left_child_index) {
-                    left_child_longest_row = left_child.longest_row;
-                    left_child_longest_row_len = left_child.longest_row_len;
-                    left_child_offset = left_child.offset;
-                    left_child_rows = left_child.rows;
-                }
-                let mut right_child_longest_row = 0;
-                let mut right_child_longest_row_len = 0;
-                let mut right_child_offset = 0;
-                let mut right_child_rows = 0;
-                if let Some(right_child) = tree.get(right_child_index) {
-                    right_child_longest_row = right_child.longest_row;
-                    right_child_longest_row_len = right_child.longest_row_len;
-                    right_child_offset = right_child.offset;
-                    right_child_rows = right_child.rows;
-                }
+            let old_split_tree = self
+                .insertion_splits
+                .remove(&fragment.insertion.id)
+                .unwrap();
+            let mut splits_cursor = old_split_tree.cursor();
+            let mut new_split_tree = splits_cursor.slice(&fragment.start_offset, SeekBias::Right);
 
-                let mut longest_row = 0;
-                let mut longest_row_len = 0;
-                if left_child_longest_row_len > longest_row_len {
-                    longest_row = left_child_longest_row;
-                    longest_row_len = left_child_longest_row_len;
-                }
-                if len > longest_row_len {
-                    longest_row = left_child_rows;
-                    longest_row_len = len;
-                }
-                if right_child_longest_row_len > longest_row_len {
-                    longest_row = left_child_rows + right_child_longest_row + 1;
-                    longest_row_len = right_child_longest_row_len;
+            // Find all splices that start or end within the current fragment. Then, split the
+            // fragment and reassemble it in both trees accounting for the deleted and the newly
+            // inserted text.
+            while cur_range.as_ref().map_or(false, |r| r.start < fragment_end) {
+                let range = cur_range.clone().unwrap();
+                if range.start > fragment_start {
+                    let mut prefix = fragment.clone();
+                    prefix.end_offset = prefix.start_offset + (range.start - fragment_start);
+                    prefix.id =
+                        FragmentId::between(&new_fragments.last().unwrap().id, &fragment.id);
+                    fragment.start_offset = prefix.end_offset;
+                    new_fragments.push(prefix.clone());
+                    new_split_tree.push(InsertionSplit {
+                        extent: prefix.end_offset - prefix.start_offset,
+                        fragment_id: prefix.id,
+                    });
+                    fragment_start = range.start;
                 }
 
-                LineNode {
-                    len,
-                    longest_row,
-                    longest_row_len,
-                    offset: left_child_offset + len as usize + right_child_offset + 1,
-                    rows: left_child_rows + right_child_rows + 1,
+                if range.end == fragment_start {
+                    end_id = Some(new_fragments.last().unwrap().insertion.id);
+                    end_offset = Some(new_fragments.last().unwrap().end_offset);
+                } else if range.end == fragment_end {
+                    end_id = Some(fragment.insertion.id);
+                    end_offset = Some(fragment.end_offset);
                 }
-            };
-        }
-
-        let mut line_lengths = Vec::new();
-        let mut prev_offset = 0;
-        for (offset, code_unit) in code_units.iter().enumerate() {
-            if code_unit == &u16::from(b'\n') {
-                line_lengths.push((offset - prev_offset) as u32);
-                prev_offset = offset + 1;
-            }
-        }
-        line_lengths.push((code_units.len() - prev_offset) as u32);
-
-        let mut nodes = Vec::new();
-        nodes.resize(
-            line_lengths.len(),
-            LineNode {
-                len: 0,
-                longest_row_len: 0,
-                longest_row: 0,
-                offset: 0,
-                rows: 0,
-            },
-        );
-        build_tree(0, &line_lengths, &mut nodes);
-
-        Self { code_units, nodes }
-    }
-
-    fn extent(code_units: &[u16]) -> Point {
-        let mut rows = 0;
-        let mut last_row_len = 0;
-        for ch in code_units {
-            if *ch == b'\n' as u16 {
-                rows += 1;
-                last_row_len = 0;
-            } else {
-                last_row_len += 1;
-            }
-        }
-        Point::new(rows, last_row_len)
-    }
-
-    fn len(&self) -> usize {
-        self.code_units.len()
-    }
 
-    fn longest_row_in_range(&self, target_range: Range<usize>) -> Result<(u32, u32), Error> {
-        let mut longest_row = 0;
-        let mut longest_row_len = 0;
+                if range.start == fragment_start {
+                    start_id = Some(new_fragments.last().unwrap().insertion.id);
+                    start_offset = Some(new_fragments.last().unwrap().end_offset);
 
-        self.search(|probe| {
-            if target_range.start <= probe.offset_range.end
-                && probe.right_ancestor_start_offset <= target_range.end
-            {
-                if let Some(right_child) = probe.right_child {
-                    if right_child.longest_row_len >= longest_row_len {
-                        longest_row = probe.row + 1 + right_child.longest_row;
-                        longest_row_len = right_child.longest_row_len;
+                    if let Some(new_text) = new_text.clone() {
+                        let new_fragment = self.build_fragment_to_insert(
+                            &new_fragments.last().unwrap(),
+                            Some(&fragment),
+                            new_text,
+                            local_timestamp,
+                            lamport_timestamp,
+                        );
+                        new_fragments.push(new_fragment);
                     }
                 }
-            }
 
-            if target_range.start < probe.offset_range.start {
-                if probe.offset_range.end < target_range.end && probe.node.len >= longest_row_len {
-                    longest_row = probe.row;
-                    longest_row_len = probe.node.len;
+                if range.end < fragment_end {
+                    if range.end > fragment_start {
+                        let mut prefix = fragment.clone();
+                        prefix.end_offset = prefix.start_offset + (range.end - fragment_start);
+                        prefix.id =
+                            FragmentId::between(&new_fragments.last().unwrap().id, &fragment.id);
+                        if fragment.is_visible() {
+                            prefix.deletions.insert(local_timestamp);
+                        }
+                        fragment.start_offset = prefix.end_offset;
+                        new_fragments.push(prefix.clone());
+                        new_split_tree.push(InsertionSplit {
+                            extent: prefix.end_offset - prefix.start_offset,
+                            fragment_id: prefix.id,
+                        });
+                        fragment_start = range.end;
+                        end_id = Some(fragment.insertion.id);
+                        end_offset = Some(fragment.start_offset);
+                        version_in_range.observe(fragment.insertion.id);
+                    }
+                } else {
+                    version_in_range.observe(fragment.insertion.id);
+                    if fragment.is_visible() {
+                        fragment.deletions.insert(local_timestamp);
+                    }
                 }
 
-                Ordering::Less
-            } else if target_range.start > probe.offset_range.end {
-                Ordering::Greater
-            } else {
-                let node_end = cmp::min(probe.offset_range.end, target_range.end);
-                let node_len = (node_end - target_range.start) as u32;
-                if node_len >= longest_row_len {
-                    longest_row = probe.row;
-                    longest_row_len = node_len;
-                }
-                Ordering::Equal
-            }
-        })
-        .ok_or(Error::OffsetOutOfRange)?;
+                // If the splice ends inside this fragment, we can advance to the next splice and
+                // check if it also intersects the current fragment. Otherwise we break out of the
+                // loop and find the first fragment that the splice does not contain fully.
+                if range.end <= fragment_end {
+                    ops.push(Operation::Edit {
+                        start_id: start_id.unwrap(),
+                        start_offset: start_offset.unwrap(),
+                        end_id: end_id.unwrap(),
+                        end_offset: end_offset.unwrap(),
+                        version_in_range,
+                        new_text: new_text.clone(),
+                        local_timestamp,
+                        lamport_timestamp,
+                    });
 
-        self.search(|probe| {
-            if target_range.end >= probe.offset_range.start
-                && probe.left_ancestor_end_offset >= target_range.start
-            {
-                if let Some(left_child) = probe.left_child {
-                    if left_child.longest_row_len > longest_row_len {
-                        let left_ancestor_row = probe.row - left_child.rows;
-                        longest_row = left_ancestor_row + left_child.longest_row;
-                        longest_row_len = left_child.longest_row_len;
+                    start_id = None;
+                    start_offset = None;
+                    end_id = None;
+                    end_offset = None;
+                    version_in_range = time::Global::new();
+                    cur_range = old_ranges.next();
+                    if cur_range.is_some() {
+                        local_timestamp = local_clock.tick();
+                        lamport_timestamp = lamport_clock.tick();
                     }
+                } else {
+                    break;
                 }
             }
+            new_split_tree.push(InsertionSplit {
+                extent: fragment.end_offset - fragment.start_offset,
+                fragment_id: fragment.id.clone(),
+            });
+            splits_cursor.next();
+            new_split_tree
+                .push_tree(splits_cursor.slice(&old_split_tree.extent::<usize>(), SeekBias::Right));
+            self.insertion_splits
+                .insert(fragment.insertion.id, new_split_tree);
+            new_fragments.push(fragment);
 
-            if target_range.end < probe.offset_range.start {
-                Ordering::Less
-            } else if target_range.end > probe.offset_range.end {
-                if target_range.start < probe.offset_range.start && probe.node.len > longest_row_len
-                {
-                    longest_row = probe.row;
-                    longest_row_len = probe.node.len;
+            // Scan forward until we find a fragment that is not fully contained by the current splice.
+            cursor.next();
+            if let Some(range) = cur_range.clone() {
+                while let Some(mut fragment) = cursor.item() {
+                    fragment_start = cursor.start::<usize>();
+                    fragment_end = fragment_start + fragment.len();
+                    if range.start < fragment_start && range.end >= fragment_end {
+                        if fragment.is_visible() {
+                            fragment.deletions.insert(local_timestamp);
+                        }
+                        version_in_range.observe(fragment.insertion.id);
+                        new_fragments.push(fragment.clone());
+                        cursor.next();
+
+                        if range.end == fragment_end {
+                            end_id = Some(fragment.insertion.id);
+                            end_offset = Some(fragment.end_offset);
+                            ops.push(Operation::Edit {
+                                start_id: start_id.unwrap(),
+                                start_offset: start_offset.unwrap(),
+                                end_id: end_id.unwrap(),
+                                end_offset: end_offset.unwrap(),
+                                version_in_range,
+                                new_text: new_text.clone(),
+                                local_timestamp,
+                                lamport_timestamp,
+                            });
+
+                            start_id = None;
+                            start_offset = None;
+                            end_id = None;
+                            end_offset = None;
+                            version_in_range = time::Global::new();
+
+                            cur_range = old_ranges.next();
+                            if cur_range.is_some() {
+                                local_timestamp = local_clock.tick();
+                                lamport_timestamp = lamport_clock.tick();
+                            }
+                            break;
+                        }
+                    } else {
+                        break;
+                    }
                 }
 
-                Ordering::Greater
-            } else {
-                let node_start = cmp::max(target_range.start, probe.offset_range.start);
-                let node_len = (target_range.end - node_start) as u32;
-                if node_len > longest_row_len {
-                    longest_row = probe.row;
-                    longest_row_len = node_len;
+                // If the splice we are currently evaluating starts after the end of the fragment
+                // that the cursor is parked at, we should seek to the next splice's start range
+                // and push all the fragments in between into the new tree.
+                if cur_range.as_ref().map_or(false, |r| r.start > fragment_end) {
+                    new_fragments.push_tree(
+                        cursor.slice(&cur_range.as_ref().unwrap().start, SeekBias::Right),
+                    );
                 }
-                Ordering::Equal
             }
-        })
-        .ok_or(Error::OffsetOutOfRange)?;
+        }
 
-        Ok((longest_row, longest_row_len))
-    }
+        // Handle range that is at the end of the buffer if it exists. There should never be
+        // multiple because ranges must be disjoint.
+        if cur_range.is_some() {
+            debug_assert_eq!(old_ranges.next(), None);
+            let last_fragment = new_fragments.last().unwrap();
+            ops.push(Operation::Edit {
+                start_id: last_fragment.insertion.id,
+                start_offset: last_fragment.end_offset,
+                end_id: last_fragment.insertion.id,
+                end_offset: last_fragment.end_offset,
+                version_in_range: time::Global::new(),
+                new_text: new_text.clone(),
+                local_timestamp,
+                lamport_timestamp,
+            });
 
-    fn point_for_offset(&self, offset: usize) -> Result<Point, Error> {
-        let search_result = self.search(|probe| {
-            if offset < probe.offset_range.start {
-                Ordering::Less
-            } else if offset > probe.offset_range.end {
-                Ordering::Greater
-            } else {
-                Ordering::Equal
+            if let Some(new_text) = new_text {
+                new_fragments.push(self.build_fragment_to_insert(
+                    &last_fragment,
+                    None,
+                    new_text,
+                    local_timestamp,
+                    lamport_timestamp,
+                ));
             }
-        });
-        if let Some((offset_range, row, _)) = search_result {
-            Ok(Point::new(row, (offset - offset_range.start) as u32))
         } else {
-            Err(Error::OffsetOutOfRange)
+            new_fragments
+                .push_tree(cursor.slice(&old_fragments.extent::<usize>(), SeekBias::Right));
         }
+
+        self.fragments = new_fragments;
+        ops
     }
 
-    fn offset_for_point(&self, point: Point) -> Result<usize, Error> {
-        if let Some((offset_range, _, node)) = self.search(|probe| point.row.cmp(&probe.row)) {
-            if point.column <= node.len {
-                Ok(offset_range.start + point.column as usize)
-            } else {
-                Err(Error::OffsetOutOfRange)
-            }
+    fn split_fragment(
+        &mut self,
+        prev_fragment: &Fragment,
+        fragment: &Fragment,
+        range: Range<usize>,
+    ) -> (Option<Fragment>, Option<Fragment>, Option<Fragment>) {
+        debug_assert!(range.start >= fragment.start_offset);
+        debug_assert!(range.start <= fragment.end_offset);
+        debug_assert!(range.end <= fragment.end_offset);
+        debug_assert!(range.end >= fragment.start_offset);
+
+        if range.end == fragment.start_offset {
+            (None, None, Some(fragment.clone()))
+        } else if range.start == fragment.end_offset {
+            (Some(fragment.clone()), None, None)
+        } else if range.start == fragment.start_offset && range.end == fragment.end_offset {
+            (None, Some(fragment.clone()), None)
         } else {
-            Err(Error::OffsetOutOfRange)
-        }
-    }
+            let mut prefix = fragment.clone();
 
-    fn search<F>(&self, mut f: F) -> Option<(Range<usize>, u32, &LineNode)>
-    where
-        F: FnMut(LineNodeProbe) -> Ordering,
-    {
-        let mut left_ancestor_end_offset = 0;
-        let mut left_ancestor_row = 0;
-        let mut right_ancestor_start_offset = self.nodes[0].offset;
-        let mut cur_node_index = 0;
-        while let Some(cur_node) = self.nodes.get(cur_node_index) {
-            let left_child = self.nodes.get(cur_node_index * 2 + 1);
-            let right_child = self.nodes.get(cur_node_index * 2 + 2);
-            let cur_offset_range = {
-                let start = left_ancestor_end_offset + left_child.map_or(0, |node| node.offset);
-                let end = start + cur_node.len as usize;
-                start..end
+            let after_range = if range.end < fragment.end_offset {
+                let mut suffix = prefix.clone();
+                suffix.start_offset = range.end;
+                prefix.end_offset = range.end;
+                prefix.id = FragmentId::between(&prev_fragment.id, &suffix.id);
+                Some(suffix)
+            } else {
+                None
             };
-            let cur_row = left_ancestor_row + left_child.map_or(0, |node| node.rows);
-            match f(LineNodeProbe {
-                offset_range: &cur_offset_range,
-                row: cur_row,
-                left_ancestor_end_offset,
-                right_ancestor_start_offset,
-                node: cur_node,
-                left_child,
-                right_child,
-            }) {
-                Ordering::Less => {
-                    cur_node_index = cur_node_index * 2 + 1;
-                    right_ancestor_start_offset = cur_offset_range.start;
-                }
-                Ordering::Equal => return Some((cur_offset_range, cur_row, cur_node)),
-                Ordering::Greater => {
-                    cur_node_index = cur_node_index * 2 + 2;
-                    left_ancestor_end_offset = cur_offset_range.end + 1;
-                    left_ancestor_row = cur_row + 1;
-                }
-            }
-        }
-        None
-    }
-}
-
-impl<'a> From<&'a str> for Text {
-    fn from(s: &'a str) -> Self {
-        Self::new(s.encode_utf16().collect())
-    }
-}
 
-impl From<String> for Text {
-    fn from(s: String) -> Self {
-        Self::from(s.as_str())
-    }
-}
+            let within_range = if range.start != range.end {
+                let mut suffix = prefix.clone();
+                suffix.start_offset = range.start;
+                prefix.end_offset = range.start;
+                prefix.id = FragmentId::between(&prev_fragment.id, &suffix.id);
+                Some(suffix)
+            } else {
+                None
+            };
 
-impl<'a> From<Vec<u16>> for Text {
-    fn from(s: Vec<u16>) -> Self {
-        Self::new(s)
-    }
-}
+            let before_range = if range.start > fragment.start_offset {
+                Some(prefix)
+            } else {
+                None
+            };
 
-#[inline(always)]
-fn log2_fast(x: usize) -> usize {
-    8 * mem::size_of::<usize>() - (x.leading_zeros() as usize) - 1
-}
+            let old_split_tree = self
+                .insertion_splits
+                .remove(&fragment.insertion.id)
+                .unwrap();
+            let mut cursor = old_split_tree.cursor();
+            let mut new_split_tree = cursor.slice(&fragment.start_offset, SeekBias::Right);
 
-lazy_static! {
-    static ref FRAGMENT_ID_MIN_VALUE: FragmentId = FragmentId(Arc::new(vec![0 as u16]));
-    static ref FRAGMENT_ID_MAX_VALUE: FragmentId = FragmentId(Arc::new(vec![u16::max_value()]));
-}
+            if let Some(ref fragment) = before_range {
+                new_split_tree.push(InsertionSplit {
+                    extent: range.start - fragment.start_offset,
+                    fragment_id: fragment.id.clone(),
+                });
+            }
 
-impl FragmentId {
-    fn min_value() -> Self {
-        FRAGMENT_ID_MIN_VALUE.clone()
-    }
+            if let Some(ref fragment) = within_range {
+                new_split_tree.push(InsertionSplit {
+                    extent: range.end - range.start,
+                    fragment_id: fragment.id.clone(),
+                });
+            }
 
-    fn max_value() -> Self {
-        FRAGMENT_ID_MAX_VALUE.clone()
-    }
+            if let Some(ref fragment) = after_range {
+                new_split_tree.push(InsertionSplit {
+                    extent: fragment.end_offset - range.end,
+                    fragment_id: fragment.id.clone(),
+                });
+            }
 
-    fn between(left: &Self, right: &Self) -> Self {
-        Self::between_with_max(left, right, u16::max_value())
-    }
+            cursor.next();
+            new_split_tree
+                .push_tree(cursor.slice(&old_split_tree.extent::<usize>(), SeekBias::Right));
 
-    fn between_with_max(left: &Self, right: &Self, max_value: u16) -> Self {
-        let mut new_entries = Vec::new();
+            self.insertion_splits
+                .insert(fragment.insertion.id, new_split_tree);
 
-        let left_entries = left.0.iter().cloned().chain(iter::repeat(0));
-        let right_entries = right.0.iter().cloned().chain(iter::repeat(max_value));
-        for (l, r) in left_entries.zip(right_entries) {
-            let interval = r - l;
-            if interval > 1 {
-                new_entries.push(l + interval / 2);
-                break;
-            } else {
-                new_entries.push(l);
-            }
+            (before_range, within_range, after_range)
         }
-
-        FragmentId(Arc::new(new_entries))
     }
-}
 
-impl btree::Dimension<FragmentSummary> for FragmentId {
-    fn from_summary(summary: &FragmentSummary) -> Self {
-        summary.max_fragment_id.clone()
-    }
-}
+    fn build_fragment_to_insert(
+        &mut self,
+        prev_fragment: &Fragment,
+        next_fragment: Option<&Fragment>,
+        text: Arc<Text>,
+        local_timestamp: time::Local,
+        lamport_timestamp: time::Lamport,
+    ) -> Fragment {
+        let new_fragment_id = FragmentId::between(
+            &prev_fragment.id,
+            next_fragment
+                .map(|f| &f.id)
+                .unwrap_or(&FragmentId::max_value()),
+        );
 
-impl<'a> Add<&'a Self> for FragmentId {
-    type Output = FragmentId;
+        let mut split_tree = btree::Tree::new();
+        split_tree.push(InsertionSplit {
+            extent: text.len(),
+            fragment_id: new_fragment_id.clone(),
+        });
+        self.insertion_splits.insert(local_timestamp, split_tree);
 
-    fn add(self, other: &'a Self) -> Self::Output {
-        debug_assert!(self <= *other);
-        other.clone()
+        Fragment::new(
+            new_fragment_id,
+            Insertion {
+                id: local_timestamp,
+                parent_id: prev_fragment.insertion.id,
+                offset_in_parent: prev_fragment.end_offset,
+                text,
+                lamport_timestamp,
+            },
+        )
     }
-}
 
-impl<'a> AddAssign<&'a Self> for FragmentId {
-    fn add_assign(&mut self, other: &'a Self) {
-        debug_assert!(*self <= *other);
-        *self = other.clone();
+    pub fn set_mark(&mut self, name: char, point: Point) -> Result<(), Error> {
+        let anchor = self.anchor_before_point(point)?;
+        self.marks.insert(name, anchor);
+        Ok(())
     }
-}
 
-impl Fragment {
-    fn new(id: FragmentId, insertion: Insertion) -> Self {
-        let end_offset = insertion.text.len();
-        Self {
-            id,
-            insertion,
-            start_offset: 0,
-            end_offset,
-            deletions: HashSet::new(),
-        }
+    pub fn mark(&self, name: char) -> Option<Point> {
+        self.marks
+            .get(&name)
+            .and_then(|anchor| self.point_for_anchor(anchor).ok())
     }
 
-    fn code_unit(&self, offset: usize) -> Option<u16> {
-        if offset < self.len() {
-            Some(self.insertion.text.code_units[self.start_offset + offset].clone())
-        } else {
-            None
-        }
+    pub fn anchor_before_offset(&self, offset: usize) -> Result<Anchor, Error> {
+        self.anchor_for_offset(offset, AnchorBias::Left)
     }
 
-    fn code_units(&self) -> &[u16] {
-        &self.insertion.text.code_units[self.start_offset..self.end_offset]
+    pub fn anchor_after_offset(&self, offset: usize) -> Result<Anchor, Error> {
+        self.anchor_for_offset(offset, AnchorBias::Right)
     }
 
-    fn len(&self) -> usize {
-        if self.is_visible() {
-            self.extent()
-        } else {
-            0
+    fn anchor_for_offset(&self, offset: usize, bias: AnchorBias) -> Result<Anchor, Error> {
+        let max_offset = self.len();
+        if offset > max_offset {
+            return Err(Error::OffsetOutOfRange);
         }
-    }
-
-    fn extent(&self) -> usize {
-        self.end_offset - self.start_offset
-    }
-
-    fn extent_2d(&self) -> Point {
-        self.point_for_offset(self.extent()).unwrap()
-    }
 
-    fn is_visible(&self) -> bool {
-        self.deletions.is_empty()
-    }
+        let seek_bias;
+        match bias {
+            AnchorBias::Left => {
+                if offset == 0 {
+                    return Ok(Anchor::Start);
+                } else {
+                    seek_bias = SeekBias::Left;
+                }
+            }
+            AnchorBias::Right => {
+                if offset == max_offset {
+                    return Ok(Anchor::End);
+                } else {
+                    seek_bias = SeekBias::Right;
+                }
+            }
+        };
 
-    fn was_visible(&self, version: &time::Global) -> bool {
-        version.observed(self.insertion.id) && self.deletions.iter().all(|d| !version.observed(*d))
+        let mut cursor = self.fragments.cursor();
+        cursor.seek(&offset, seek_bias);
+        let fragment = cursor.item().unwrap();
+        let offset_in_fragment = offset - cursor.start::<usize>();
+        let offset_in_insertion = fragment.start_offset + offset_in_fragment;
+        let point = cursor.start::<Point>() + &fragment.point_for_offset(offset_in_fragment)?;
+        let anchor = Anchor::Middle {
+            insertion_id: fragment.insertion.id,
+            offset: offset_in_insertion,
+            bias,
+        };
+        self.cache_position(Some(anchor.clone()), offset, point);
+        Ok(anchor)
     }
 
-    fn point_for_offset(&self, offset: usize) -> Result<Point, Error> {
-        let text = &self.insertion.text;
-        let offset_in_insertion = self.start_offset + offset;
-        Ok(text.point_for_offset(offset_in_insertion)?
-            - &text.point_for_offset(self.start_offset)?)
+    pub fn anchor_before_point(&self, point: Point) -> Result<Anchor, Error> {
+        self.anchor_for_point(point, AnchorBias::Left)
     }
 
-    fn offset_for_point(&self, point: Point) -> Result<usize, Error> {
-        let text = &self.insertion.text;
-        let point_in_insertion = text.point_for_offset(self.start_offset)? + &point;
-        Ok(text.offset_for_point(point_in_insertion)? - self.start_offset)
+    pub fn anchor_after_point(&self, point: Point) -> Result<Anchor, Error> {
+        self.anchor_for_point(point, AnchorBias::Right)
     }
-}
 
-impl btree::Item for Fragment {
-    type Summary = FragmentSummary;
+    fn anchor_for_point(&self, point: Point, bias: AnchorBias) -> Result<Anchor, Error> {
+        let max_point = self.max_point();
+        if point > max_point {
+            return Err(Error::OffsetOutOfRange);
+        }
 
-    fn summarize(&self) -> Self::Summary {
-        let mut max_version = time::Global::new();
-        max_version.observe(self.insertion.id);
-        for deletion in &self.deletions {
-            max_version.observe(*deletion);
-        }
+        let seek_bias;
+        match bias {
+            AnchorBias::Left => {
+                if point.is_zero() {
+                    return Ok(Anchor::Start);
+                } else {
+                    seek_bias = SeekBias::Left;
+                }
+            }
+            AnchorBias::Right => {
+                if point == max_point {
+                    return Ok(Anchor::End);
+                } else {
+                    seek_bias = SeekBias::Right;
+                }
+            }
+        };
 
-        if self.is_visible() {
-            let fragment_2d_start = self
-                .insertion
-                .text
-                .point_for_offset(self.start_offset)
-                .unwrap();
-            let fragment_2d_end = self
-                .insertion
-                .text
-                .point_for_offset(self.end_offset)
-                .unwrap();
+        let mut cursor = self.fragments.cursor();
+        cursor.seek(&point, seek_bias);
+        let fragment = cursor.item().unwrap();
+        let offset_in_fragment = fragment.offset_for_point(point - &cursor.start::<Point>())?;
+        let offset_in_insertion = fragment.start_offset + offset_in_fragment;
+        let anchor = Anchor::Middle {
+            insertion_id: fragment.insertion.id,
+            offset: offset_in_insertion,
+            bias,
+        };
+        let offset = cursor.start::<usize>() + offset_in_fragment;
+        self.cache_position(Some(anchor.clone()), offset, point);
+        Ok(anchor)
+    }
 
-            let first_row_len = if fragment_2d_start.row == fragment_2d_end.row {
-                self.extent() as u32
-            } else {
-                self.offset_for_point(Point::new(1, 0)).unwrap() as u32 - 1
-            };
-            let (longest_row, longest_row_len) = self
-                .insertion
-                .text
-                .longest_row_in_range(self.start_offset as usize..self.end_offset as usize)
-                .unwrap();
-            FragmentSummary {
-                extent: self.len(),
-                extent_2d: fragment_2d_end - &fragment_2d_start,
-                max_fragment_id: self.id.clone(),
-                first_row_len,
-                longest_row: longest_row - fragment_2d_start.row,
-                longest_row_len,
-                max_version,
-            }
-        } else {
-            FragmentSummary {
-                extent: 0,
-                extent_2d: Point { row: 0, column: 0 },
-                max_fragment_id: self.id.clone(),
-                first_row_len: 0,
-                longest_row: 0,
-                longest_row_len: 0,
-                max_version,
+    pub fn offset_for_anchor(&self, anchor: &Anchor) -> Result<usize, Error> {
+        Ok(self.position_for_anchor(anchor)?.0)
+    }
+
+    pub fn point_for_anchor(&self, anchor: &Anchor) -> Result<Point, Error> {
+        Ok(self.position_for_anchor(anchor)?.1)
+    }
+
+    // Resolving the same anchors again returns the previous points until the buffer's version
+    // changes. Only the most recently resolved set of anchors is remembered.
+    pub fn resolve_anchors_cached(&self, anchors: &[Anchor]) -> Result<Arc<[Point]>, Error> {
+        if let Some(resolved) = self.resolved_anchors.borrow().as_ref() {
+            if resolved.version == self.version && resolved.anchors.as_slice() == anchors {
+                return Ok(resolved.points.clone());
             }
         }
+
+        let points = anchors
+            .iter()
+            .map(|anchor| self.point_for_anchor(anchor))
+            .collect::<Result<Vec<_>, _>>()?;
+        let points = Arc::<[Point]>::from(points);
+        if self.cache_resolved_anchors {
+            *self.resolved_anchors.borrow_mut() = Some(ResolvedAnchors {
+                version: self.version.clone(),
+                anchors: anchors.to_vec(),
+                points: points.clone(),
+            });
+        }
+        Ok(points)
     }
-}
 
-impl<'a> AddAssign<&'a FragmentSummary> for FragmentSummary {
-    fn add_assign(&mut self, other: &Self) {
-        let last_row_len = self.extent_2d.column + other.first_row_len;
-        if last_row_len > self.longest_row_len {
-            self.longest_row = self.extent_2d.row;
-            self.longest_row_len = last_row_len;
+    pub fn set_cache_resolved_anchors(&mut self, cache_resolved_anchors: bool) {
+        self.cache_resolved_anchors = cache_resolved_anchors;
+        if !cache_resolved_anchors {
+            *self.resolved_anchors.borrow_mut() = None;
         }
-        if other.longest_row_len > self.longest_row_len {
-            self.longest_row = self.extent_2d.row + other.longest_row;
-            self.longest_row_len = other.longest_row_len;
+    }
+
+    // Anchors that outlive the state they were created against, such as bookmarks restored from
+    // disk, can refer to insertions this buffer doesn't know about. Anchors into text whose
+    // tombstones were collected remain valid and resolve to where the tombstones used to be.
+    pub fn is_anchor_valid(&self, anchor: &Anchor) -> bool {
+        self.position_for_anchor(anchor).is_ok()
+    }
+
+    // Anchors into deleted text resolve to where the text used to be. Anchors into insertions
+    // this buffer hasn't seen return an error.
+    pub fn resolve_persistent(&self, anchor: &PersistentAnchor) -> Result<Point, Error> {
+        self.point_for_anchor(&anchor.to_anchor())
+    }
+
+    // Only the insertion half of the edit can be recovered from the fragment tree, so the
+    // returned operation doesn't delete anything. Anchors into the base text have no
+    // originating operation.
+    pub fn operation_for_anchor(&self, anchor: &Anchor) -> Option<Operation> {
+        let (insertion_id, offset) = match anchor {
+            Anchor::Start | Anchor::End => return None,
+            Anchor::Middle {
+                insertion_id,
+                offset,
+                ..
+            } => (*insertion_id, *offset),
+        };
+        if insertion_id == time::Local::default() {
+            return None;
         }
-        if self.extent_2d.row == 0 {
-            self.first_row_len += other.first_row_len;
+
+        let fragment_id = self.resolve_fragment_id(insertion_id, offset).ok()?;
+        let mut cursor = self.fragments.cursor();
+        cursor.seek(&fragment_id, SeekBias::Left);
+        let insertion = cursor.item()?.insertion;
+        if insertion.id != insertion_id {
+            return None;
         }
 
-        self.extent += other.extent;
-        self.extent_2d += &other.extent_2d;
-        debug_assert!(self.max_fragment_id <= other.max_fragment_id);
-        self.max_fragment_id = other.max_fragment_id.clone();
-        self.max_version.observe_all(&other.max_version);
+        Some(Operation::Edit {
+            start_id: insertion.parent_id,
+            start_offset: insertion.offset_in_parent,
+            end_id: insertion.parent_id,
+            end_offset: insertion.offset_in_parent,
+            version_in_range: time::Global::new(),
+            new_text: Some(insertion.text),
+            local_timestamp: insertion.id,
+            lamport_timestamp: insertion.lamport_timestamp,
+        })
     }
-}
 
-impl Default for FragmentSummary {
-    fn default() -> Self {
-        FragmentSummary {
-            extent: 0,
-            extent_2d: Point { row: 0, column: 0 },
-            max_fragment_id: FragmentId::min_value(),
-            first_row_len: 0,
-            longest_row: 0,
-            longest_row_len: 0,
-            max_version: time::Global::new(),
+    // Returns the local timestamps of every edit that inserted or deleted text between the given
+    // anchors, including text that is no longer visible.
+    pub fn edit_ids_in_range(&self, range: Range<Anchor>) -> Result<HashSet<time::Local>, Error> {
+        let (start_id, start_offset) = self.fragment_position_for_anchor(&range.start)?;
+        let (end_id, end_offset) = self.fragment_position_for_anchor(&range.end)?;
+
+        let mut edit_ids = HashSet::new();
+        let mut cursor = self.fragments.cursor();
+        cursor.seek(&start_id, SeekBias::Left);
+        while let Some(fragment) = cursor.item() {
+            if fragment.id > end_id {
+                break;
+            }
+
+            let after_start = fragment.id != start_id || start_offset < fragment.end_offset;
+            let before_end = fragment.id != end_id || end_offset > fragment.start_offset;
+            if after_start && before_end {
+                if fragment.insertion.id != time::Local::default() {
+                    edit_ids.insert(fragment.insertion.id);
+                }
+                edit_ids.extend(fragment.deletions.iter().cloned());
+            }
+            cursor.next();
         }
+        Ok(edit_ids)
     }
-}
 
-impl btree::Dimension<FragmentSummary> for usize {
-    fn from_summary(summary: &FragmentSummary) -> Self {
-        summary.extent
+    fn fragment_position_for_anchor(&self, anchor: &Anchor) -> Result<(FragmentId, usize), Error> {
+        match anchor {
+            Anchor::Start => Ok((FragmentId::min_value(), 0)),
+            Anchor::End => Ok((FragmentId::max_value(), 0)),
+            Anchor::Middle {
+                insertion_id,
+                offset,
+                bias,
+            } => {
+                let seek_bias = match bias {
+                    AnchorBias::Left => SeekBias::Left,
+                    AnchorBias::Right => SeekBias::Right,
+                };
+                let splits =
+                    self.insertion_splits
+                        .get(insertion_id)
+                        .ok_or(Error::InvalidAnchor(
+                            "split does not exist for insertion id".into(),
+                        ))?;
+                let mut cursor = splits.cursor();
+                cursor.seek(offset, seek_bias);
+                let split = cursor
+                    .item()
+                    .ok_or(Error::InvalidAnchor("split offset is out of range".into()))?;
+                Ok((split.fragment_id, *offset))
+            }
+        }
     }
-}
 
-impl btree::Item for InsertionSplit {
-    type Summary = InsertionSplitSummary;
+    fn position_for_anchor(&self, anchor: &Anchor) -> Result<(usize, Point), Error> {
+        match anchor {
+            Anchor::Start => Ok((0, Point { row: 0, column: 0 })),
+            Anchor::End => Ok((self.len(), self.fragments.extent())),
+            Anchor::Middle {
+                ref insertion_id,
+                offset,
+                ref bias,
+            } => {
+                let cached_position = {
+                    let anchor_cache = self.anchor_cache.try_borrow().ok();
+                    anchor_cache
+                        .as_ref()
+                        .and_then(|cache| cache.get(anchor).cloned())
+                };
 
-    fn summarize(&self) -> Self::Summary {
-        InsertionSplitSummary {
-            extent: self.extent,
+                if let Some(cached_position) = cached_position {
+                    Ok(cached_position)
+                } else {
+                    let seek_bias = match bias {
+                        AnchorBias::Left => SeekBias::Left,
+                        AnchorBias::Right => SeekBias::Right,
+                    };
+
+                    let splits =
+                        self.insertion_splits
+                            .get(&insertion_id)
+                            .ok_or(Error::InvalidAnchor(
+                                "split does not exist for insertion id".into(),
+                            ))?;
+                    let mut splits_cursor = splits.cursor();
+                    splits_cursor.seek(offset, seek_bias);
+                    splits_cursor
+                        .item()
+                        .ok_or(Error::InvalidAnchor("split offset is out of range".into()))
+                        .and_then(|split| {
+                            let mut fragments_cursor = self.fragments.cursor();
+                            fragments_cursor.seek(&split.fragment_id, SeekBias::Left);
+                            let fragment = fragments_cursor.item();
+                            if !self.collected_garbage && fragment.is_none() {
+                                return Err(Error::InvalidAnchor(
+                                    "fragment id does not exist".into(),
+                                ));
+                            }
+
+                            // A collected fragment resolves to where its tombstone would be.
+                            let mut overshoot = 0;
+                            let mut point = fragments_cursor.start::<Point>();
+                            match fragment {
+                                Some(ref fragment) if fragment.id == split.fragment_id => {
+                                    if fragment.is_visible() {
+                                        overshoot = offset - fragment.start_offset;
+                                        point += &fragment.point_for_offset(overshoot)?;
+                                    }
+                                }
+                                _ => self.anchor_fallbacks.set(self.anchor_fallbacks.get() + 1),
+                            }
+                            let offset = fragments_cursor.start::<usize>() + overshoot;
+                            self.cache_position(Some(anchor.clone()), offset, point);
+                            Ok((offset, point))
+                        })
+                }
+            }
         }
     }
-}
 
-impl<'a> AddAssign<&'a InsertionSplitSummary> for InsertionSplitSummary {
-    fn add_assign(&mut self, other: &Self) {
-        self.extent += other.extent;
-    }
-}
+    fn offset_for_point(&self, point: Point) -> Result<usize, Error> {
+        let cached_offset = {
+            let offset_cache = self.offset_cache.try_borrow().ok();
+            offset_cache
+                .as_ref()
+                .and_then(|cache| cache.get(&point).cloned())
+        };
 
-impl Default for InsertionSplitSummary {
-    fn default() -> Self {
-        InsertionSplitSummary { extent: 0 }
+        if let Some(cached_offset) = cached_offset {
+            Ok(cached_offset)
+        } else {
+            let mut fragments_cursor = self.fragments.cursor();
+            fragments_cursor.seek(&point, SeekBias::Left);
+            fragments_cursor
+                .item()
+                .ok_or(Error::OffsetOutOfRange)
+                .map(|fragment| {
+                    let overshoot = fragment
+                        .offset_for_point(point - &fragments_cursor.start::<Point>())
+                        .unwrap();
+                    let offset = &fragments_cursor.start::<usize>() + &overshoot;
+                    self.cache_position(None, offset, point);
+                    offset
+                })
+        }
     }
-}
 
-impl btree::Dimension<InsertionSplitSummary> for usize {
-    fn from_summary(summary: &InsertionSplitSummary) -> Self {
-        summary.extent
+    fn point_for_offset(&self, offset: usize) -> Result<Point, Error> {
+        let mut fragments_cursor = self.fragments.cursor();
+        fragments_cursor.seek(&offset, SeekBias::Left);
+        fragments_cursor
+            .item()
+            .ok_or(Error::OffsetOutOfRange)
+            .map(|fragment| {
+                let overshoot = fragment
+                    .point_for_offset(offset - &fragments_cursor.start::<usize>())
+                    .unwrap();
+                fragments_cursor.start::<Point>() + &overshoot
+            })
     }
-}
 
-impl Operation {
-    fn replica_id(&self) -> ReplicaId {
-        self.lamport_timestamp().replica_id
+    pub fn cmp_anchors(&self, a: &Anchor, b: &Anchor) -> Result<Ordering, Error> {
+        let a_offset = self.offset_for_anchor(a)?;
+        let b_offset = self.offset_for_anchor(b)?;
+        Ok(a_offset.cmp(&b_offset))
     }
 
-    fn lamport_timestamp(&self) -> time::Lamport {
-        match self {
-            Operation::Edit {
-                lamport_timestamp, ..
-            } => *lamport_timestamp,
-            Operation::UpdateSelections {
-                lamport_timestamp, ..
-            } => *lamport_timestamp,
+    // Orders `anchors` as `cmp_anchors` would, resolving each one to an offset only once rather
+    // than on every comparison and never converting it to a point. Anchors at the same position
+    // keep their relative order.
+    pub fn sort_anchors(&self, anchors: &mut [Anchor]) -> Result<(), Error> {
+        let mut offsets = anchors
+            .iter()
+            .enumerate()
+            .map(|(index, anchor)| Ok((self.offset_for_anchor(anchor)?, index)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        offsets.sort_unstable();
+        let sorted = offsets
+            .into_iter()
+            .map(|(_, index)| anchors[index].clone())
+            .collect::<Vec<_>>();
+        for (anchor, sorted_anchor) in anchors.iter_mut().zip(sorted) {
+            *anchor = sorted_anchor;
         }
+        Ok(())
     }
 
-    pub fn is_edit(&self) -> bool {
-        match self {
-            Operation::Edit { .. } => true,
-            _ => false,
+    fn cache_position(&self, anchor: Option<Anchor>, offset: usize, point: Point) {
+        anchor.map(|anchor| {
+            if let Ok(mut anchor_cache) = self.anchor_cache.try_borrow_mut() {
+                anchor_cache.insert(anchor, (offset, point));
+            }
+        });
+
+        if let Ok(mut offset_cache) = self.offset_cache.try_borrow_mut() {
+            offset_cache.insert(point, offset);
         }
     }
+}
 
-    pub fn to_flatbuf<'fbb>(
+impl Point {
+    pub fn new(row: u32, column: u32) -> Self {
+        Point { row, column }
+    }
+
+    pub fn zero() -> Self {
+        Point::new(0, 0)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.row == 0 && self.column == 0
+    }
+}
+
+impl btree::Dimension<FragmentSummary> for Point {
+    fn from_summary(summary: &FragmentSummary) -> Self {
+        summary.extent_2d
+    }
+}
+
+impl<'a> Add<&'a Self> for Point {
+    type Output = Point;
+
+    fn add(self, other: &'a Self) -> Self::Output {
+        if other.row == 0 {
+            Point::new(self.row, self.column + other.column)
+        } else {
+            Point::new(self.row + other.row, other.column)
+        }
+    }
+}
+
+impl<'a> Sub<&'a Self> for Point {
+    type Output = Point;
+
+    fn sub(self, other: &'a Self) -> Self::Output {
+        debug_assert!(*other <= self);
+
+        if self.row == other.row {
+            Point::new(0, self.column - other.column)
+        } else {
+            Point::new(self.row - other.row, self.column)
+        }
+    }
+}
+
+impl<'a> AddAssign<&'a Self> for Point {
+    fn add_assign(&mut self, other: &'a Self) {
+        if other.row == 0 {
+            self.column += other.column;
+        } else {
+            self.row += other.row;
+            self.column = other.column;
+        }
+    }
+}
+
+impl PartialOrd for Point {
+    fn partial_cmp(&self, other: &Point) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Point {
+    #[cfg(target_pointer_width = "64")]
+    fn cmp(&self, other: &Point) -> Ordering {
+        let a = (self.row as usize) << 32 | self.column as usize;
+        let b = (other.row as usize) << 32 | other.column as usize;
+        a.cmp(&b)
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    fn cmp(&self, other: &Point) -> Ordering {
+        match self.row.cmp(&other.row) {
+            Ordering::Equal => self.column.cmp(&other.column),
+            comparison @ _ => comparison,
+        }
+    }
+}
+
+impl Anchor {
+    pub fn start() -> Self {
+        Anchor::Start
+    }
+
+    pub fn end() -> Self {
+        Anchor::End
+    }
+
+    pub fn to_persistent(&self) -> PersistentAnchor {
+        match self {
+            Anchor::Start => PersistentAnchor::Start,
+            Anchor::End => PersistentAnchor::End,
+            Anchor::Middle {
+                insertion_id,
+                offset,
+                bias,
+            } => PersistentAnchor::Middle {
+                replica_id: insertion_id.replica_id,
+                insertion: insertion_id.value,
+                offset: *offset,
+                bias: bias.clone(),
+            },
+        }
+    }
+
+    fn rename_replica(&mut self, old: ReplicaId, new: ReplicaId) {
+        if let Anchor::Middle { insertion_id, .. } = self {
+            insertion_id.rename_replica(old, new);
+        }
+    }
+
+    fn to_flatbuf<'fbb>(
         &self,
         builder: &mut FlatBufferBuilder<'fbb>,
-    ) -> WIPOffset<serialization::buffer::Operation<'fbb>> {
-        let variant_type;
-        let variant;
+    ) -> WIPOffset<serialization::buffer::Anchor<'fbb>> {
         match self {
-            Operation::Edit {
-                start_id,
-                start_offset,
-                end_id,
-                end_offset,
-                version_in_range,
-                new_text,
-                local_timestamp,
-                lamport_timestamp,
-            } => {
-                let new_text = new_text.as_ref().map(|new_text| {
-                    builder.create_string(String::from_utf16_lossy(&new_text.code_units).as_str())
-                });
-                let version_in_range = Some(version_in_range.to_flatbuf(builder));
-                variant_type = serialization::buffer::OperationVariant::Edit;
-                variant = serialization::buffer::Edit::create(
-                    builder,
-                    &serialization::buffer::EditArgs {
-                        start_id: Some(&start_id.to_flatbuf()),
-                        start_offset: *start_offset as u64,
-                        end_id: Some(&end_id.to_flatbuf()),
-                        end_offset: *end_offset as u64,
-                        version_in_range,
-                        new_text,
-                        local_timestamp: Some(&local_timestamp.to_flatbuf()),
-                        lamport_timestamp: Some(&lamport_timestamp.to_flatbuf()),
-                    },
-                )
-                .as_union_value();
-            }
-            Operation::UpdateSelections {
-                set_id,
-                selections,
-                lamport_timestamp,
-            } => {
-                variant_type = serialization::buffer::OperationVariant::UpdateSelections;
-                let selections = selections.as_ref().map(|selections| {
-                    let selection_flatbufs = &selections
-                        .iter()
-                        .map(|s| s.to_flatbuf(builder))
-                        .collect::<Vec<_>>();
-                    builder.create_vector(selection_flatbufs)
-                });
-                variant = serialization::buffer::UpdateSelections::create(
-                    builder,
-                    &serialization::buffer::UpdateSelectionsArgs {
-                        set_id: Some(&set_id.to_flatbuf()),
-                        selections,
-                        lamport_timestamp: Some(&lamport_timestamp.to_flatbuf()),
-                    },
-                )
-                .as_union_value();
-            }
+            Anchor::Start => serialization::buffer::Anchor::create(
+                builder,
+                &serialization::buffer::AnchorArgs {
+                    variant: serialization::buffer::AnchorVariant::Start,
+                    ..serialization::buffer::AnchorArgs::default()
+                },
+            ),
+            Anchor::End => serialization::buffer::Anchor::create(
+                builder,
+                &serialization::buffer::AnchorArgs {
+                    variant: serialization::buffer::AnchorVariant::End,
+                    ..serialization::buffer::AnchorArgs::default()
+                },
+            ),
+            Anchor::Middle {
+                insertion_id,
+                offset,
+                bias,
+            } => serialization::buffer::Anchor::create(
+                builder,
+                &serialization::buffer::AnchorArgs {
+                    variant: serialization::buffer::AnchorVariant::Middle,
+                    insertion_id: Some(&insertion_id.to_flatbuf()),
+                    offset: *offset as u64,
+                    bias: bias.to_flatbuf(),
+                },
+            ),
+        }
+    }
+
+    fn from_flatbuf<'fbb>(
+        message: &serialization::buffer::Anchor<'fbb>,
+    ) -> Result<Self, crate::Error> {
+        match message.variant() {
+            serialization::buffer::AnchorVariant::Start => Ok(Anchor::Start),
+            serialization::buffer::AnchorVariant::End => Ok(Anchor::End),
+            serialization::buffer::AnchorVariant::Middle => Ok(Anchor::Middle {
+                insertion_id: time::Local::from_flatbuf(
+                    message
+                        .insertion_id()
+                        .ok_or(crate::Error::DeserializeError("insertion_id"))?,
+                ),
+                offset: message.offset() as usize,
+                bias: AnchorBias::from_flatbuf(message.bias()),
+            }),
+        }
+    }
+}
+
+impl PersistentAnchor {
+    pub fn to_anchor(&self) -> Anchor {
+        match self {
+            PersistentAnchor::Start => Anchor::Start,
+            PersistentAnchor::End => Anchor::End,
+            PersistentAnchor::Middle {
+                replica_id,
+                insertion,
+                offset,
+                bias,
+            } => Anchor::Middle {
+                insertion_id: time::Local {
+                    replica_id: *replica_id,
+                    value: *insertion,
+                },
+                offset: *offset,
+                bias: bias.clone(),
+            },
+        }
+    }
+}
+
+impl AnchorBias {
+    fn to_flatbuf(&self) -> serialization::buffer::AnchorBias {
+        match self {
+            AnchorBias::Left => serialization::buffer::AnchorBias::Left,
+            AnchorBias::Right => serialization::buffer::AnchorBias::Right,
+        }
+    }
+
+    fn from_flatbuf(message: serialization::buffer::AnchorBias) -> Self {
+        match message {
+            serialization::buffer::AnchorBias::Left => AnchorBias::Left,
+            serialization::buffer::AnchorBias::Right => AnchorBias::Right,
+        }
+    }
+}
+
+impl Iter {
+    fn new(buffer: &Buffer) -> Self {
+        let mut fragment_cursor = buffer.fragments.cursor();
+        fragment_cursor.seek(&0, SeekBias::Right);
+        Self {
+            fragment_cursor,
+            fragment_offset: 0,
+            reversed: false,
         }
+    }
+
+    fn at_point(buffer: &Buffer, point: Point) -> Self {
+        let mut fragment_cursor = buffer.fragments.cursor();
+        fragment_cursor.seek(&point, SeekBias::Right);
+        let fragment_offset = if let Some(fragment) = fragment_cursor.item() {
+            let point_in_fragment = point - &fragment_cursor.start::<Point>();
+            fragment.offset_for_point(point_in_fragment).unwrap()
+        } else {
+            0
+        };
+
+        Self {
+            fragment_cursor,
+            fragment_offset,
+            reversed: false,
+        }
+    }
+
+    pub fn rev(mut self) -> Iter {
+        self.reversed = true;
+        self
+    }
+
+    pub fn into_string(self) -> String {
+        String::from_utf16_lossy(&self.collect::<Vec<u16>>())
+    }
+}
+
+impl Iterator for Iter {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.reversed {
+            if let Some(fragment) = self.fragment_cursor.item() {
+                if self.fragment_offset > 0 {
+                    self.fragment_offset -= 1;
+                    if let Some(c) = fragment.code_unit(self.fragment_offset) {
+                        return Some(c);
+                    }
+                }
+            }
+
+            loop {
+                self.fragment_cursor.prev();
+                if let Some(fragment) = self.fragment_cursor.item() {
+                    if fragment.len() > 0 {
+                        self.fragment_offset = fragment.len() - 1;
+                        return fragment.code_unit(self.fragment_offset);
+                    }
+                } else {
+                    break;
+                }
+            }
+
+            None
+        } else {
+            if let Some(fragment) = self.fragment_cursor.item() {
+                if let Some(c) = fragment.code_unit(self.fragment_offset) {
+                    self.fragment_offset += 1;
+                    return Some(c);
+                }
+            }
+
+            loop {
+                self.fragment_cursor.next();
+                if let Some(fragment) = self.fragment_cursor.item() {
+                    if let Some(c) = fragment.code_unit(0) {
+                        self.fragment_offset = 1;
+                        return Some(c);
+                    }
+                } else {
+                    break;
+                }
+            }
+
+            None
+        }
+    }
+}
+
+impl Iterator for RevChars {
+    type Item = char;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let code_unit = self.code_units.next()?;
+        let is_low_surrogate = code_unit >= 0xDC00 && code_unit < 0xE000;
+        let c = if is_low_surrogate
+            && self
+                .code_units
+                .peek()
+                .map_or(false, |high| *high >= 0xD800 && *high < 0xDC00)
+        {
+            let high = self.code_units.next().unwrap();
+            std::char::decode_utf16([high, code_unit].iter().cloned()).next()
+        } else {
+            std::char::decode_utf16(Some(code_unit)).next()
+        };
+        Some(c.unwrap().unwrap_or(std::char::REPLACEMENT_CHARACTER))
+    }
+}
+
+impl Chunk {
+    pub fn code_units(&self) -> &[u16] {
+        &self.text.code_units[self.range.clone()]
+    }
+
+    pub fn len(&self) -> usize {
+        self.range.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range.start == self.range.end
+    }
+}
+
+impl Iterator for ReverseChunks {
+    type Item = Chunk;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.range.start < self.range.end {
+            let fragment = self.fragment_cursor.item()?;
+            let fragment_start = self.fragment_cursor.start::<usize>();
+            let start = cmp::max(fragment_start, self.range.start);
+            let end = self.range.end;
+            self.range.end = start;
+            self.fragment_cursor.prev();
+
+            if start < end {
+                return Some(Chunk {
+                    text: fragment.insertion.text.clone(),
+                    range: fragment.start_offset + start - fragment_start
+                        ..fragment.start_offset + end - fragment_start,
+                });
+            }
+        }
+        None
+    }
+}
+
+impl RollingHashes {
+    fn new(code_units: Iter, window_len: usize) -> Self {
+        let mut leading_factor = 1_u64;
+        for _ in 1..window_len {
+            leading_factor = leading_factor.wrapping_mul(ROLLING_HASH_BASE);
+        }
+        Self {
+            code_units,
+            window: VecDeque::with_capacity(window_len),
+            window_len,
+            hash: 0,
+            leading_factor,
+            offset: 0,
+        }
+    }
+}
+
+impl Iterator for RollingHashes {
+    type Item = (usize, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.window.len() == self.window_len {
+            let code_unit = self.window.pop_front().unwrap();
+            self.hash = self
+                .hash
+                .wrapping_sub((code_unit as u64).wrapping_mul(self.leading_factor));
+            self.offset += 1;
+        }
+        while self.window.len() < self.window_len {
+            let code_unit = self.code_units.next()?;
+            self.hash = self
+                .hash
+                .wrapping_mul(ROLLING_HASH_BASE)
+                .wrapping_add(code_unit as u64);
+            self.window.push_back(code_unit);
+        }
+        Some((self.offset, self.hash))
+    }
+}
+
+impl<F: Fn(&FragmentSummary) -> bool> Iterator for ChangesIter<F> {
+    type Item = Change;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut change: Option<Change> = None;
+
+        while let Some(fragment) = self.cursor.item() {
+            let position = self.cursor.start();
+            if !fragment.was_visible(&self.since) && fragment.is_visible() {
+                if let Some(ref mut change) = change {
+                    if change.range.start + &change.new_extent == position {
+                        change.code_units.extend(fragment.code_units());
+                        change.new_extent += &fragment.extent_2d();
+                    } else {
+                        break;
+                    }
+                } else {
+                    change = Some(Change {
+                        range: position..position,
+                        code_units: Vec::from(fragment.code_units()),
+                        new_extent: fragment.extent_2d(),
+                    });
+                }
+            } else if fragment.was_visible(&self.since) && !fragment.is_visible() {
+                if let Some(ref mut change) = change {
+                    if change.range.start + &change.new_extent == position {
+                        change.range.end += &fragment.extent_2d();
+                    } else {
+                        break;
+                    }
+                } else {
+                    change = Some(Change {
+                        range: position..position + &fragment.extent_2d(),
+                        code_units: Vec::new(),
+                        new_extent: Point::zero(),
+                    });
+                }
+            }
+
+            self.cursor.next();
+        }
+
+        change
+    }
+}
+
+// Adapts the casing of `replacement` to that of `matched` when it's all upper case, all lower case
+// or capitalized. Any other casing, or the lack of letters, leaves the replacement untouched.
+fn match_case(matched: &str, replacement: &str) -> String {
+    let mut letters = matched.chars().filter(|c| c.is_alphabetic());
+    let first_is_upper = match letters.next() {
+        Some(c) => c.is_uppercase(),
+        None => return replacement.to_string(),
+    };
+    let (mut rest_upper, mut rest_lower) = (false, false);
+    for c in letters {
+        rest_upper |= c.is_uppercase();
+        rest_lower |= c.is_lowercase();
+    }
+
+    if first_is_upper && !rest_lower {
+        replacement.to_uppercase()
+    } else if !first_is_upper && !rest_upper {
+        replacement.to_lowercase()
+    } else if first_is_upper && !rest_upper {
+        let mut chars = replacement.chars();
+        chars.next().map_or(String::new(), |first| {
+            first
+                .to_uppercase()
+                .chain(chars.flat_map(char::to_lowercase))
+                .collect()
+        })
+    } else {
+        replacement.to_string()
+    }
+}
+
+// Appends the UTF-16 encoding of `bytes` to `code_units`, replacing invalid sequences with U+FFFD.
+// A sequence that is cut short by the end of `bytes` isn't decoded but returned, so that the caller
+// can prepend it to the bytes that follow.
+fn decode_utf8_lossy_into<'a>(mut bytes: &'a [u8], code_units: &mut Vec<u16>) -> &'a [u8] {
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => {
+                code_units.extend(text.encode_utf16());
+                return &[];
+            }
+            Err(error) => {
+                let (valid, rest) = bytes.split_at(error.valid_up_to());
+                code_units.extend(std::str::from_utf8(valid).unwrap().encode_utf16());
+                if let Some(invalid_len) = error.error_len() {
+                    code_units.push(std::char::REPLACEMENT_CHARACTER as u16);
+                    bytes = &rest[invalid_len..];
+                } else {
+                    return rest;
+                }
+            }
+        }
+    }
+}
+
+// The length of `code_units` once encoded as UTF-8, counting unpaired surrogates as the
+// replacement character they're decoded to.
+fn utf8_len(code_units: &[u16]) -> usize {
+    let mut len = 0;
+    let mut code_units = code_units.iter().peekable();
+    while let Some(&code_unit) = code_units.next() {
+        len += if code_unit < 0x80 {
+            1
+        } else if code_unit < 0x800 {
+            2
+        } else if code_unit >= 0xD800
+            && code_unit < 0xDC00
+            && code_units
+                .peek()
+                .map_or(false, |low| **low >= 0xDC00 && **low < 0xE000)
+        {
+            code_units.next();
+            4
+        } else {
+            3
+        };
+    }
+    len
+}
+
+fn indentation_width(indentation: &str, tab_size: u32) -> u32 {
+    indentation.chars().fold(0, |width, c| {
+        if c == '\t' {
+            width + tab_size - width % tab_size
+        } else {
+            width + 1
+        }
+    })
+}
+
+// Returns the lengths of the prefix and suffix that `a` and `b` have in common. They never overlap
+// and never split a surrogate pair.
+fn common_prefix_and_suffix(a: &[u16], b: &[u16]) -> (usize, usize) {
+    let mut prefix_len = a.iter().zip(b).take_while(|(a, b)| a == b).count();
+    if prefix_len > 0 && a[prefix_len - 1] >= 0xD800 && a[prefix_len - 1] <= 0xDBFF {
+        prefix_len -= 1;
+    }
+
+    let mut suffix_len = a[prefix_len..]
+        .iter()
+        .rev()
+        .zip(b[prefix_len..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if suffix_len > 0 && a[a.len() - suffix_len] >= 0xDC00 && a[a.len() - suffix_len] <= 0xDFFF {
+        suffix_len -= 1;
+    }
+
+    (prefix_len, suffix_len)
+}
+
+pub fn diff(a: &[u16], b: &[u16]) -> Vec<Change> {
+    struct ChangeCollector<'a> {
+        a: &'a [u16],
+        b: &'a [u16],
+        position: Point,
+        changes: Vec<Change>,
+    }
+
+    impl<'a> diffs::Diff for ChangeCollector<'a> {
+        type Error = ();
+
+        fn equal(&mut self, old: usize, _: usize, len: usize) -> Result<(), ()> {
+            self.position += &Text::extent(&self.a[old..old + len]);
+            Ok(())
+        }
+
+        fn delete(&mut self, old: usize, len: usize) -> Result<(), ()> {
+            self.changes.push(Change {
+                range: self.position..self.position + &Text::extent(&self.a[old..old + len]),
+                code_units: Vec::new(),
+                new_extent: Point::zero(),
+            });
+            Ok(())
+        }
+
+        fn insert(&mut self, _: usize, new: usize, new_len: usize) -> Result<(), ()> {
+            let new_extent = Text::extent(&self.b[new..new + new_len]);
+            self.changes.push(Change {
+                range: self.position..self.position,
+                code_units: Vec::from(&self.b[new..new + new_len]),
+                new_extent,
+            });
+            self.position += &new_extent;
+            Ok(())
+        }
+
+        fn replace(
+            &mut self,
+            old: usize,
+            old_len: usize,
+            new: usize,
+            new_len: usize,
+        ) -> Result<(), ()> {
+            let old_extent = Text::extent(&self.a[old..old + old_len]);
+            let new_extent = Text::extent(&self.b[new..new + new_len]);
+            self.changes.push(Change {
+                range: self.position..self.position + &old_extent,
+                code_units: Vec::from(&self.b[new..new + new_len]),
+                new_extent,
+            });
+            self.position += &new_extent;
+            Ok(())
+        }
+    }
+
+    let mut collector = diffs::Replace::new(ChangeCollector {
+        a,
+        b,
+        position: Point::zero(),
+        changes: Vec::new(),
+    });
+    diffs::myers::diff(&mut collector, a, 0, a.len(), b, 0, b.len()).unwrap();
+    collector.into_inner().changes
+}
+
+// Unlike `diff`, whose ranges account for the preceding changes having been applied, every range
+// returned here refers to a position in `a`.
+fn diff_in_old_coordinates(a: &[u16], b: &[u16]) -> Vec<(Range<Point>, Vec<u16>)> {
+    struct EditCollector<'a> {
+        a: &'a [u16],
+        b: &'a [u16],
+        position: Point,
+        edits: Vec<(Range<Point>, Vec<u16>)>,
+    }
+
+    impl<'a> EditCollector<'a> {
+        fn push(&mut self, old: Range<usize>, new: Range<usize>) {
+            let start = self.position;
+            self.position += &Text::extent(&self.a[old]);
+            self.edits
+                .push((start..self.position, Vec::from(&self.b[new])));
+        }
+    }
+
+    impl<'a> diffs::Diff for EditCollector<'a> {
+        type Error = ();
+
+        fn equal(&mut self, old: usize, _: usize, len: usize) -> Result<(), ()> {
+            self.position += &Text::extent(&self.a[old..old + len]);
+            Ok(())
+        }
+
+        fn delete(&mut self, old: usize, len: usize) -> Result<(), ()> {
+            self.push(old..old + len, 0..0);
+            Ok(())
+        }
+
+        fn insert(&mut self, old: usize, new: usize, new_len: usize) -> Result<(), ()> {
+            self.push(old..old, new..new + new_len);
+            Ok(())
+        }
+
+        fn replace(
+            &mut self,
+            old: usize,
+            old_len: usize,
+            new: usize,
+            new_len: usize,
+        ) -> Result<(), ()> {
+            self.push(old..old + old_len, new..new + new_len);
+            Ok(())
+        }
+    }
+
+    let mut collector = diffs::Replace::new(EditCollector {
+        a,
+        b,
+        position: Point::zero(),
+        edits: Vec::new(),
+    });
+    diffs::myers::diff(&mut collector, a, 0, a.len(), b, 0, b.len()).unwrap();
+    collector.into_inner().edits
+}
+
+impl Selection {
+    pub fn head(&self) -> &Anchor {
+        if self.reversed {
+            &self.start
+        } else {
+            &self.end
+        }
+    }
+
+    pub fn set_head<S>(&mut self, buffer: &Buffer, cursor: Anchor) {
+        if buffer.cmp_anchors(&cursor, self.tail()).unwrap() < Ordering::Equal {
+            if !self.reversed {
+                mem::swap(&mut self.start, &mut self.end);
+                self.reversed = true;
+            }
+            self.start = cursor;
+        } else {
+            if self.reversed {
+                mem::swap(&mut self.start, &mut self.end);
+                self.reversed = false;
+            }
+            self.end = cursor;
+        }
+    }
+
+    pub fn tail(&self) -> &Anchor {
+        if self.reversed {
+            &self.end
+        } else {
+            &self.start
+        }
+    }
+
+    pub fn is_empty(&self, buffer: &Buffer) -> bool {
+        buffer.cmp_anchors(&self.start, &self.end).unwrap() == Ordering::Equal
+    }
+
+    fn rename_replica(&mut self, old: ReplicaId, new: ReplicaId) {
+        self.start.rename_replica(old, new);
+        self.end.rename_replica(old, new);
+    }
+
+    pub fn anchor_range(&self) -> Range<Anchor> {
+        self.start.clone()..self.end.clone()
+    }
+
+    fn to_flatbuf<'fbb>(
+        &self,
+        builder: &mut FlatBufferBuilder<'fbb>,
+    ) -> WIPOffset<serialization::buffer::Selection<'fbb>> {
+        let start = Some(self.start.to_flatbuf(builder));
+        let end = Some(self.end.to_flatbuf(builder));
+
+        serialization::buffer::Selection::create(
+            builder,
+            &serialization::buffer::SelectionArgs {
+                start,
+                end,
+                reversed: self.reversed,
+            },
+        )
+    }
+
+    fn from_flatbuf<'fbb>(
+        message: serialization::buffer::Selection<'fbb>,
+    ) -> Result<Self, crate::Error> {
+        Ok(Self {
+            start: Anchor::from_flatbuf(
+                &message
+                    .start()
+                    .ok_or(crate::Error::DeserializeError("start"))?,
+            )?,
+            end: Anchor::from_flatbuf(
+                &message.end().ok_or(crate::Error::DeserializeError("end"))?,
+            )?,
+            reversed: message.reversed(),
+        })
+    }
+}
+
+impl Text {
+    pub fn new(code_units: Vec<u16>) -> Self {
+        fn build_tree(index: usize, line_lengths: &[u32], mut tree: &mut [LineNode]) {
+            if line_lengths.is_empty() {
+                return;
+            }
+
+            let mid = if line_lengths.len() == 1 {
+                0
+            } else {
+                let depth = log2_fast(line_lengths.len());
+                let max_elements = (1 << (depth)) - 1;
+                let right_subtree_elements = 1 << (depth - 1);
+                cmp::min(line_lengths.len() - right_subtree_elements, max_elements)
+            };
+            let len = line_lengths[mid];
+            let lower = &line_lengths[0..mid];
+            let upper = &line_lengths[mid + 1..];
+
+            let left_child_index = index * 2 + 1;
+            let right_child_index = index * 2 + 2;
+            build_tree(left_child_index, lower, &mut tree);
+            build_tree(right_child_index, upper, &mut tree);
+            tree[index] = {
+                let mut left_child_longest_row = 0;
+                let mut left_child_longest_row_len = 0;
+                let mut left_child_offset = 0;
+                let mut left_child_rows = 0;
+                if let Some(left_child) = tree.get(left_child_index) {
+                    left_child_longest_row = left_child.longest_row;
+                    left_child_longest_row_len = left_child.longest_row_len;
+                    left_child_offset = left_child.offset;
+                    left_child_rows = left_child.rows;
+                }
+                let mut right_child_longest_row = 0;
+                let mut right_child_longest_row_len = 0;
+                let mut right_child_offset = 0;
+                let mut right_child_rows = 0;
+                if let Some(right_child) = tree.get(right_child_index) {
+                    right_child_longest_row = right_child.longest_row;
+                    right_child_longest_row_len = right_child.longest_row_len;
+                    right_child_offset = right_child.offset;
+                    right_child_rows = right_child.rows;
+                }
+
+                let mut longest_row = 0;
+                let mut longest_row_len = 0;
+                if left_child_longest_row_len > longest_row_len {
+                    longest_row = left_child_longest_row;
+                    longest_row_len = left_child_longest_row_len;
+                }
+                if len > longest_row_len {
+                    longest_row = left_child_rows;
+                    longest_row_len = len;
+                }
+                if right_child_longest_row_len > longest_row_len {
+                    longest_row = left_child_rows + right_child_longest_row + 1;
+                    longest_row_len = right_child_longest_row_len;
+                }
+
+                LineNode {
+                    len,
+                    longest_row,
+                    longest_row_len,
+                    offset: left_child_offset + len as usize + right_child_offset + 1,
+                    rows: left_child_rows + right_child_rows + 1,
+                }
+            };
+        }
+
+        let mut line_lengths = Vec::new();
+        let mut prev_offset = 0;
+        for (offset, code_unit) in code_units.iter().enumerate() {
+            if code_unit == &u16::from(b'\n') {
+                line_lengths.push((offset - prev_offset) as u32);
+                prev_offset = offset + 1;
+            }
+        }
+        line_lengths.push((code_units.len() - prev_offset) as u32);
+
+        let mut nodes = Vec::new();
+        nodes.resize(
+            line_lengths.len(),
+            LineNode {
+                len: 0,
+                longest_row_len: 0,
+                longest_row: 0,
+                offset: 0,
+                rows: 0,
+            },
+        );
+        build_tree(0, &line_lengths, &mut nodes);
+
+        let mut char_counts = Vec::with_capacity(code_units.len() / CHAR_COUNT_STRIDE + 1);
+        let mut char_count = CharCount::default();
+        for offset in 0..code_units.len() {
+            if offset % CHAR_COUNT_STRIDE == 0 {
+                char_counts.push(char_count);
+            }
+            char_count += code_unit_char_count(&code_units, offset);
+        }
+        if code_units.len() % CHAR_COUNT_STRIDE == 0 {
+            char_counts.push(char_count);
+        }
+
+        Self {
+            code_units,
+            nodes,
+            char_counts,
+        }
+    }
+
+    // Returns the UTF-8 length and code point count of the given range. A
+    // surrogate pair is attributed entirely to its high surrogate, so counts of
+    // adjacent ranges always add up to the count of the text they cover.
+    fn char_count(&self, range: Range<usize>) -> CharCount {
+        let end = self.char_count_before(range.end);
+        let start = self.char_count_before(range.start);
+        CharCount {
+            utf8_len: end.utf8_len - start.utf8_len,
+            code_points: end.code_points - start.code_points,
+        }
+    }
+
+    fn char_count_before(&self, offset: usize) -> CharCount {
+        let stride_start = offset - offset % CHAR_COUNT_STRIDE;
+        let mut char_count = self.char_counts[offset / CHAR_COUNT_STRIDE];
+        for offset in stride_start..offset {
+            char_count += code_unit_char_count(&self.code_units, offset);
+        }
+        char_count
+    }
+
+    fn extent(code_units: &[u16]) -> Point {
+        let mut rows = 0;
+        let mut last_row_len = 0;
+        for ch in code_units {
+            if *ch == b'\n' as u16 {
+                rows += 1;
+                last_row_len = 0;
+            } else {
+                last_row_len += 1;
+            }
+        }
+        Point::new(rows, last_row_len)
+    }
+
+    pub fn len(&self) -> usize {
+        self.code_units.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code_units.is_empty()
+    }
+
+    pub fn contains_newline(&self) -> bool {
+        self.code_units.contains(&u16::from(b'\n'))
+    }
+
+    fn longest_row_in_range(&self, target_range: Range<usize>) -> Result<(u32, u32), Error> {
+        let mut longest_row = 0;
+        let mut longest_row_len = 0;
+
+        self.search(|probe| {
+            if target_range.start <= probe.offset_range.end
+                && probe.right_ancestor_start_offset <= target_range.end
+            {
+                if let Some(right_child) = probe.right_child {
+                    if right_child.longest_row_len >= longest_row_len {
+                        longest_row = probe.row + 1 + right_child.longest_row;
+                        longest_row_len = right_child.longest_row_len;
+                    }
+                }
+            }
+
+            if target_range.start < probe.offset_range.start {
+                if probe.offset_range.end < target_range.end && probe.node.len >= longest_row_len {
+                    longest_row = probe.row;
+                    longest_row_len = probe.node.len;
+                }
+
+                Ordering::Less
+            } else if target_range.start > probe.offset_range.end {
+                Ordering::Greater
+            } else {
+                let node_end = cmp::min(probe.offset_range.end, target_range.end);
+                let node_len = (node_end - target_range.start) as u32;
+                if node_len >= longest_row_len {
+                    longest_row = probe.row;
+                    longest_row_len = node_len;
+                }
+                Ordering::Equal
+            }
+        })
+        .ok_or(Error::OffsetOutOfRange)?;
+
+        self.search(|probe| {
+            if target_range.end >= probe.offset_range.start
+                && probe.left_ancestor_end_offset >= target_range.start
+            {
+                if let Some(left_child) = probe.left_child {
+                    if left_child.longest_row_len > longest_row_len {
+                        let left_ancestor_row = probe.row - left_child.rows;
+                        longest_row = left_ancestor_row + left_child.longest_row;
+                        longest_row_len = left_child.longest_row_len;
+                    }
+                }
+            }
+
+            if target_range.end < probe.offset_range.start {
+                Ordering::Less
+            } else if target_range.end > probe.offset_range.end {
+                if target_range.start < probe.offset_range.start && probe.node.len > longest_row_len
+                {
+                    longest_row = probe.row;
+                    longest_row_len = probe.node.len;
+                }
+
+                Ordering::Greater
+            } else {
+                let node_start = cmp::max(target_range.start, probe.offset_range.start);
+                let node_len = (target_range.end - node_start) as u32;
+                if node_len > longest_row_len {
+                    longest_row = probe.row;
+                    longest_row_len = node_len;
+                }
+                Ordering::Equal
+            }
+        })
+        .ok_or(Error::OffsetOutOfRange)?;
+
+        Ok((longest_row, longest_row_len))
+    }
+
+    fn point_for_offset(&self, offset: usize) -> Result<Point, Error> {
+        let search_result = self.search(|probe| {
+            if offset < probe.offset_range.start {
+                Ordering::Less
+            } else if offset > probe.offset_range.end {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        });
+        if let Some((offset_range, row, _)) = search_result {
+            Ok(Point::new(row, (offset - offset_range.start) as u32))
+        } else {
+            Err(Error::OffsetOutOfRange)
+        }
+    }
+
+    fn offset_for_point(&self, point: Point) -> Result<usize, Error> {
+        if let Some((offset_range, _, node)) = self.search(|probe| point.row.cmp(&probe.row)) {
+            if point.column <= node.len {
+                Ok(offset_range.start + point.column as usize)
+            } else {
+                Err(Error::OffsetOutOfRange)
+            }
+        } else {
+            Err(Error::OffsetOutOfRange)
+        }
+    }
+
+    fn search<F>(&self, mut f: F) -> Option<(Range<usize>, u32, &LineNode)>
+    where
+        F: FnMut(LineNodeProbe) -> Ordering,
+    {
+        let mut left_ancestor_end_offset = 0;
+        let mut left_ancestor_row = 0;
+        let mut right_ancestor_start_offset = self.nodes[0].offset;
+        let mut cur_node_index = 0;
+        while let Some(cur_node) = self.nodes.get(cur_node_index) {
+            let left_child = self.nodes.get(cur_node_index * 2 + 1);
+            let right_child = self.nodes.get(cur_node_index * 2 + 2);
+            let cur_offset_range = {
+                let start = left_ancestor_end_offset + left_child.map_or(0, |node| node.offset);
+                let end = start + cur_node.len as usize;
+                start..end
+            };
+            let cur_row = left_ancestor_row + left_child.map_or(0, |node| node.rows);
+            match f(LineNodeProbe {
+                offset_range: &cur_offset_range,
+                row: cur_row,
+                left_ancestor_end_offset,
+                right_ancestor_start_offset,
+                node: cur_node,
+                left_child,
+                right_child,
+            }) {
+                Ordering::Less => {
+                    cur_node_index = cur_node_index * 2 + 1;
+                    right_ancestor_start_offset = cur_offset_range.start;
+                }
+                Ordering::Equal => return Some((cur_offset_range, cur_row, cur_node)),
+                Ordering::Greater => {
+                    cur_node_index = cur_node_index * 2 + 2;
+                    left_ancestor_end_offset = cur_offset_range.end + 1;
+                    left_ancestor_row = cur_row + 1;
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Text {
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf16_lossy(&self.code_units)
+    }
+}
+
+impl<'a> From<&'a str> for Text {
+    fn from(s: &'a str) -> Self {
+        Self::new(s.encode_utf16().collect())
+    }
+}
+
+impl From<String> for Text {
+    fn from(s: String) -> Self {
+        Self::from(s.as_str())
+    }
+}
+
+impl<'a> From<Vec<u16>> for Text {
+    fn from(s: Vec<u16>) -> Self {
+        Self::new(s)
+    }
+}
+
+impl AddAssign for CharCount {
+    fn add_assign(&mut self, other: Self) {
+        self.utf8_len += other.utf8_len;
+        self.code_points += other.code_points;
+    }
+}
+
+fn code_unit_char_count(code_units: &[u16], offset: usize) -> CharCount {
+    let is_high_surrogate = |code_unit: u16| code_unit >= 0xD800 && code_unit < 0xDC00;
+    let is_low_surrogate = |code_unit: u16| code_unit >= 0xDC00 && code_unit < 0xE000;
+
+    let code_unit = code_units[offset];
+    let (utf8_len, code_points) = if code_unit < 0x80 {
+        (1, 1)
+    } else if code_unit < 0x800 {
+        (2, 1)
+    } else if is_high_surrogate(code_unit)
+        && code_units
+            .get(offset + 1)
+            .map_or(false, |next| is_low_surrogate(*next))
+    {
+        (4, 1)
+    } else if is_low_surrogate(code_unit) && offset > 0 && is_high_surrogate(code_units[offset - 1])
+    {
+        (0, 0)
+    } else {
+        (3, 1)
+    };
+    CharCount {
+        utf8_len,
+        code_points,
+    }
+}
+
+#[inline(always)]
+fn log2_fast(x: usize) -> usize {
+    8 * mem::size_of::<usize>() - (x.leading_zeros() as usize) - 1
+}
+
+lazy_static! {
+    static ref FRAGMENT_ID_MIN_VALUE: FragmentId = FragmentId(Arc::new(vec![0 as u16]));
+    static ref FRAGMENT_ID_MAX_VALUE: FragmentId = FragmentId(Arc::new(vec![u16::max_value()]));
+}
+
+impl FragmentId {
+    fn min_value() -> Self {
+        FRAGMENT_ID_MIN_VALUE.clone()
+    }
+
+    fn max_value() -> Self {
+        FRAGMENT_ID_MAX_VALUE.clone()
+    }
+
+    fn between(left: &Self, right: &Self) -> Self {
+        Self::between_with_max(left, right, u16::max_value())
+    }
+
+    fn between_with_max(left: &Self, right: &Self, max_value: u16) -> Self {
+        let mut new_entries = Vec::new();
+
+        let left_entries = left.0.iter().cloned().chain(iter::repeat(0));
+        let right_entries = right.0.iter().cloned().chain(iter::repeat(max_value));
+        for (l, r) in left_entries.zip(right_entries) {
+            let interval = r - l;
+            if interval > 1 {
+                new_entries.push(l + interval / 2);
+                break;
+            } else {
+                new_entries.push(l);
+            }
+        }
+
+        FragmentId(Arc::new(new_entries))
+    }
+}
+
+impl btree::Dimension<FragmentSummary> for FragmentId {
+    fn from_summary(summary: &FragmentSummary) -> Self {
+        summary.max_fragment_id.clone()
+    }
+}
+
+impl<'a> Add<&'a Self> for FragmentId {
+    type Output = FragmentId;
+
+    fn add(self, other: &'a Self) -> Self::Output {
+        debug_assert!(self <= *other);
+        other.clone()
+    }
+}
+
+impl<'a> AddAssign<&'a Self> for FragmentId {
+    fn add_assign(&mut self, other: &'a Self) {
+        debug_assert!(*self <= *other);
+        *self = other.clone();
+    }
+}
+
+impl Fragment {
+    fn new(id: FragmentId, insertion: Insertion) -> Self {
+        let end_offset = insertion.text.len();
+        Self {
+            id,
+            insertion,
+            start_offset: 0,
+            end_offset,
+            deletions: HashSet::new(),
+        }
+    }
+
+    fn code_unit(&self, offset: usize) -> Option<u16> {
+        if offset < self.len() {
+            Some(self.insertion.text.code_units[self.start_offset + offset].clone())
+        } else {
+            None
+        }
+    }
+
+    fn code_units(&self) -> &[u16] {
+        &self.insertion.text.code_units[self.start_offset..self.end_offset]
+    }
+
+    fn len(&self) -> usize {
+        if self.is_visible() {
+            self.extent()
+        } else {
+            0
+        }
+    }
+
+    fn extent(&self) -> usize {
+        self.end_offset - self.start_offset
+    }
+
+    fn extent_2d(&self) -> Point {
+        self.point_for_offset(self.extent()).unwrap()
+    }
+
+    fn is_visible(&self) -> bool {
+        self.deletions.is_empty()
+    }
+
+    fn was_visible(&self, version: &time::Global) -> bool {
+        version.observed(self.insertion.id) && self.deletions.iter().all(|d| !version.observed(*d))
+    }
+
+    fn point_for_offset(&self, offset: usize) -> Result<Point, Error> {
+        let text = &self.insertion.text;
+        let offset_in_insertion = self.start_offset + offset;
+        Ok(text.point_for_offset(offset_in_insertion)?
+            - &text.point_for_offset(self.start_offset)?)
+    }
+
+    fn offset_for_point(&self, point: Point) -> Result<usize, Error> {
+        let text = &self.insertion.text;
+        let point_in_insertion = text.point_for_offset(self.start_offset)? + &point;
+        Ok(text.offset_for_point(point_in_insertion)? - self.start_offset)
+    }
+}
+
+impl btree::Item for Fragment {
+    type Summary = FragmentSummary;
+
+    fn summarize(&self) -> Self::Summary {
+        let mut max_version = time::Global::new();
+        max_version.observe(self.insertion.id);
+        for deletion in &self.deletions {
+            max_version.observe(*deletion);
+        }
+
+        if self.is_visible() {
+            let fragment_2d_start = self
+                .insertion
+                .text
+                .point_for_offset(self.start_offset)
+                .unwrap();
+            let fragment_2d_end = self
+                .insertion
+                .text
+                .point_for_offset(self.end_offset)
+                .unwrap();
+
+            let first_row_len = if fragment_2d_start.row == fragment_2d_end.row {
+                self.extent() as u32
+            } else {
+                self.offset_for_point(Point::new(1, 0)).unwrap() as u32 - 1
+            };
+            let (longest_row, longest_row_len) = self
+                .insertion
+                .text
+                .longest_row_in_range(self.start_offset as usize..self.end_offset as usize)
+                .unwrap();
+            let char_count = self
+                .insertion
+                .text
+                .char_count(self.start_offset..self.end_offset);
+
+            FragmentSummary {
+                extent: self.len(),
+                extent_2d: fragment_2d_end - &fragment_2d_start,
+                utf8_len: char_count.utf8_len,
+                code_points: char_count.code_points,
+                max_fragment_id: self.id.clone(),
+                first_row_len,
+                longest_row: longest_row - fragment_2d_start.row,
+                longest_row_len,
+                max_version,
+            }
+        } else {
+            FragmentSummary {
+                extent: 0,
+                extent_2d: Point { row: 0, column: 0 },
+                utf8_len: 0,
+                code_points: 0,
+                max_fragment_id: self.id.clone(),
+                first_row_len: 0,
+                longest_row: 0,
+                longest_row_len: 0,
+                max_version,
+            }
+        }
+    }
+}
+
+impl<'a> AddAssign<&'a FragmentSummary> for FragmentSummary {
+    fn add_assign(&mut self, other: &Self) {
+        let last_row_len = self.extent_2d.column + other.first_row_len;
+        if last_row_len > self.longest_row_len {
+            self.longest_row = self.extent_2d.row;
+            self.longest_row_len = last_row_len;
+        }
+        if other.longest_row_len > self.longest_row_len {
+            self.longest_row = self.extent_2d.row + other.longest_row;
+            self.longest_row_len = other.longest_row_len;
+        }
+        if self.extent_2d.row == 0 {
+            self.first_row_len += other.first_row_len;
+        }
+
+        self.extent += other.extent;
+        self.extent_2d += &other.extent_2d;
+        self.utf8_len += other.utf8_len;
+        self.code_points += other.code_points;
+        debug_assert!(self.max_fragment_id <= other.max_fragment_id);
+        self.max_fragment_id = other.max_fragment_id.clone();
+        self.max_version.observe_all(&other.max_version);
+    }
+}
+
+impl Default for FragmentSummary {
+    fn default() -> Self {
+        FragmentSummary {
+            extent: 0,
+            extent_2d: Point { row: 0, column: 0 },
+            utf8_len: 0,
+            code_points: 0,
+            max_fragment_id: FragmentId::min_value(),
+            first_row_len: 0,
+            longest_row: 0,
+            longest_row_len: 0,
+            max_version: time::Global::new(),
+        }
+    }
+}
+
+impl btree::Dimension<FragmentSummary> for usize {
+    fn from_summary(summary: &FragmentSummary) -> Self {
+        summary.extent
+    }
+}
+
+impl SeekDimension for usize {
+    fn measure(c: Option<char>) -> Self {
+        c.map_or(1, |c| c.len_utf16())
+    }
+}
+
+impl btree::Dimension<FragmentSummary> for Utf8Offset {
+    fn from_summary(summary: &FragmentSummary) -> Self {
+        Utf8Offset(summary.utf8_len)
+    }
+}
+
+impl SeekDimension for Utf8Offset {
+    fn measure(c: Option<char>) -> Self {
+        Utf8Offset(c.map_or(3, |c| c.len_utf8()))
+    }
+}
+
+impl<'a> Add<&'a Self> for Utf8Offset {
+    type Output = Utf8Offset;
+
+    fn add(self, other: &'a Self) -> Self::Output {
+        Utf8Offset(self.0 + other.0)
+    }
+}
+
+impl<'a> AddAssign<&'a Self> for Utf8Offset {
+    fn add_assign(&mut self, other: &'a Self) {
+        self.0 += other.0;
+    }
+}
+
+impl btree::Dimension<FragmentSummary> for CodePointOffset {
+    fn from_summary(summary: &FragmentSummary) -> Self {
+        CodePointOffset(summary.code_points)
+    }
+}
+
+impl SeekDimension for CodePointOffset {
+    fn measure(_: Option<char>) -> Self {
+        CodePointOffset(1)
+    }
+}
+
+impl<'a> Add<&'a Self> for CodePointOffset {
+    type Output = CodePointOffset;
+
+    fn add(self, other: &'a Self) -> Self::Output {
+        CodePointOffset(self.0 + other.0)
+    }
+}
+
+impl<'a> AddAssign<&'a Self> for CodePointOffset {
+    fn add_assign(&mut self, other: &'a Self) {
+        self.0 += other.0;
+    }
+}
+
+impl btree::Dimension<FragmentSummary> for Row {
+    fn from_summary(summary: &FragmentSummary) -> Self {
+        Row(summary.extent_2d.row)
+    }
+}
+
+impl SeekDimension for Row {
+    fn measure(c: Option<char>) -> Self {
+        if c == Some('\n') {
+            Row(1)
+        } else {
+            Row(0)
+        }
+    }
+}
+
+impl<'a> Add<&'a Self> for Row {
+    type Output = Row;
+
+    fn add(self, other: &'a Self) -> Self::Output {
+        Row(self.0 + other.0)
+    }
+}
+
+impl<'a> AddAssign<&'a Self> for Row {
+    fn add_assign(&mut self, other: &'a Self) {
+        self.0 += other.0;
+    }
+}
+
+impl WordClassifier for CodeWordClassifier {
+    fn is_word_char(&self, c: char) -> bool {
+        c.is_alphanumeric() || c == '_'
+    }
+}
+
+impl CharClass {
+    fn new<C: WordClassifier>(c: char, classifier: &C) -> Self {
+        if c.is_whitespace() {
+            CharClass::Whitespace
+        } else if classifier.is_word_char(c) {
+            CharClass::Word
+        } else {
+            CharClass::Punctuation
+        }
+    }
+}
+
+impl<'a> AddAssign<&'a Self> for MemoryReport {
+    fn add_assign(&mut self, other: &Self) {
+        self.fragment_bytes += other.fragment_bytes;
+        self.tombstone_bytes += other.tombstone_bytes;
+        self.operation_log_bytes += other.operation_log_bytes;
+        // Every buffer's version vector has an entry per replica that edited it, so summing them
+        // would count replicas more than once.
+        self.version_vector_len = cmp::max(self.version_vector_len, other.version_vector_len);
+    }
+}
+
+impl<'a> AddAssign<&'a Self> for OpStats {
+    fn add_assign(&mut self, other: &Self) {
+        self.insertions += other.insertions;
+        self.deletions += other.deletions;
+        self.bytes += other.bytes;
+    }
+}
+
+impl btree::Item for InsertionSplit {
+    type Summary = InsertionSplitSummary;
+
+    fn summarize(&self) -> Self::Summary {
+        InsertionSplitSummary {
+            extent: self.extent,
+        }
+    }
+}
+
+impl<'a> AddAssign<&'a InsertionSplitSummary> for InsertionSplitSummary {
+    fn add_assign(&mut self, other: &Self) {
+        self.extent += other.extent;
+    }
+}
+
+impl Default for InsertionSplitSummary {
+    fn default() -> Self {
+        InsertionSplitSummary { extent: 0 }
+    }
+}
+
+impl btree::Dimension<InsertionSplitSummary> for usize {
+    fn from_summary(summary: &InsertionSplitSummary) -> Self {
+        summary.extent
+    }
+}
+
+impl Operation {
+    fn replica_id(&self) -> ReplicaId {
+        self.lamport_timestamp().replica_id
+    }
+
+    pub fn estimated_size(&self) -> usize {
+        let heap_size = match self {
+            Operation::Edit {
+                version_in_range,
+                new_text,
+                ..
+            } => {
+                version_in_range.replica_ids().count() * mem::size_of::<(ReplicaId, u64)>()
+                    + new_text
+                        .as_ref()
+                        .map_or(0, |text| text.len() * mem::size_of::<u16>())
+            }
+            Operation::UpdateSelections { selections, .. } => {
+                selections.as_ref().map_or(0, |selections| {
+                    selections.len() * mem::size_of::<Selection>()
+                })
+            }
+        };
+        mem::size_of::<Self>() + heap_size
+    }
+
+    fn lamport_timestamp(&self) -> time::Lamport {
+        match self {
+            Operation::Edit {
+                lamport_timestamp, ..
+            } => *lamport_timestamp,
+            Operation::UpdateSelections {
+                lamport_timestamp, ..
+            } => *lamport_timestamp,
+        }
+    }
+
+    pub fn rename_replica(&mut self, old: ReplicaId, new: ReplicaId) {
+        match self {
+            Operation::Edit {
+                start_id,
+                end_id,
+                version_in_range,
+                local_timestamp,
+                lamport_timestamp,
+                ..
+            } => {
+                start_id.rename_replica(old, new);
+                end_id.rename_replica(old, new);
+                version_in_range.rename_replica(old, new);
+                local_timestamp.rename_replica(old, new);
+                lamport_timestamp.rename_replica(old, new);
+            }
+            Operation::UpdateSelections {
+                set_id,
+                selections,
+                lamport_timestamp,
+                ..
+            } => {
+                set_id.rename_replica(old, new);
+                if let Some(selections) = selections {
+                    for selection in selections {
+                        selection.rename_replica(old, new);
+                    }
+                }
+                lamport_timestamp.rename_replica(old, new);
+            }
+        }
+    }
+
+    pub fn is_edit(&self) -> bool {
+        match self {
+            Operation::Edit { .. } => true,
+            _ => false,
+        }
+    }
+
+    // Fails if the context includes the edit itself, or doesn't include the insertions its range is
+    // anchored to.
+    pub fn restrict_to_context(&mut self, context: &time::Global) -> Result<(), Error> {
+        if let Operation::Edit {
+            start_id,
+            end_id,
+            version_in_range,
+            local_timestamp,
+            ..
+        } = self
+        {
+            if context.observed(*local_timestamp)
+                || !context.observed(*start_id)
+                || !context.observed(*end_id)
+            {
+                return Err(Error::InvalidOperation);
+            }
+            *version_in_range = version_in_range.meet(context);
+        }
+        Ok(())
+    }
+
+    pub fn to_flatbuf<'fbb>(
+        &self,
+        builder: &mut FlatBufferBuilder<'fbb>,
+    ) -> WIPOffset<serialization::buffer::Operation<'fbb>> {
+        let variant_type;
+        let variant;
+        match self {
+            Operation::Edit {
+                start_id,
+                start_offset,
+                end_id,
+                end_offset,
+                version_in_range,
+                new_text,
+                local_timestamp,
+                lamport_timestamp,
+            } => {
+                let new_text = new_text.as_ref().map(|new_text| {
+                    builder.create_string(String::from_utf16_lossy(&new_text.code_units).as_str())
+                });
+                let version_in_range = Some(version_in_range.to_flatbuf(builder));
+                variant_type = serialization::buffer::OperationVariant::Edit;
+                variant = serialization::buffer::Edit::create(
+                    builder,
+                    &serialization::buffer::EditArgs {
+                        start_id: Some(&start_id.to_flatbuf()),
+                        start_offset: *start_offset as u64,
+                        end_id: Some(&end_id.to_flatbuf()),
+                        end_offset: *end_offset as u64,
+                        version_in_range,
+                        new_text,
+                        local_timestamp: Some(&local_timestamp.to_flatbuf()),
+                        lamport_timestamp: Some(&lamport_timestamp.to_flatbuf()),
+                    },
+                )
+                .as_union_value();
+            }
+            Operation::UpdateSelections {
+                set_id,
+                selections,
+                lamport_timestamp,
+                hidden,
+            } => {
+                variant_type = serialization::buffer::OperationVariant::UpdateSelections;
+                let selections = selections.as_ref().map(|selections| {
+                    let selection_flatbufs = &selections
+                        .iter()
+                        .map(|s| s.to_flatbuf(builder))
+                        .collect::<Vec<_>>();
+                    builder.create_vector(selection_flatbufs)
+                });
+                variant = serialization::buffer::UpdateSelections::create(
+                    builder,
+                    &serialization::buffer::UpdateSelectionsArgs {
+                        set_id: Some(&set_id.to_flatbuf()),
+                        selections,
+                        lamport_timestamp: Some(&lamport_timestamp.to_flatbuf()),
+                        hidden: *hidden,
+                    },
+                )
+                .as_union_value();
+            }
+        }
+
+        serialization::buffer::Operation::create(
+            builder,
+            &serialization::buffer::OperationArgs {
+                variant_type,
+                variant: Some(variant),
+            },
+        )
+    }
+
+    pub fn from_flatbuf<'fbb>(
+        message: &serialization::buffer::Operation<'fbb>,
+    ) -> Result<Option<Self>, crate::Error> {
+        match message.variant_type() {
+            serialization::buffer::OperationVariant::Edit => {
+                let message = serialization::buffer::Edit::init_from_table(
+                    message
+                        .variant()
+                        .ok_or(crate::Error::DeserializeError("variant"))?,
+                );
+                Ok(Some(Operation::Edit {
+                    start_id: time::Local::from_flatbuf(
+                        message
+                            .start_id()
+                            .ok_or(crate::Error::DeserializeError("start_id"))?,
+                    ),
+                    start_offset: message.start_offset() as usize,
+                    end_id: time::Local::from_flatbuf(
+                        message
+                            .end_id()
+                            .ok_or(crate::Error::DeserializeError("end_id"))?,
+                    ),
+                    end_offset: message.end_offset() as usize,
+                    version_in_range: time::Global::from_flatbuf(
+                        message
+                            .version_in_range()
+                            .ok_or(crate::Error::DeserializeError("version_in_range"))?,
+                    )?,
+                    new_text: message.new_text().map(|new_text| Arc::new(new_text.into())),
+                    local_timestamp: time::Local::from_flatbuf(
+                        message
+                            .local_timestamp()
+                            .ok_or(crate::Error::DeserializeError("local_timestamp"))?,
+                    ),
+                    lamport_timestamp: time::Lamport::from_flatbuf(
+                        message
+                            .lamport_timestamp()
+                            .ok_or(crate::Error::DeserializeError("lamport_timestamp"))?,
+                    ),
+                }))
+            }
+            serialization::buffer::OperationVariant::UpdateSelections => {
+                let message = serialization::buffer::UpdateSelections::init_from_table(
+                    message
+                        .variant()
+                        .ok_or(crate::Error::DeserializeError("variant"))?,
+                );
+
+                let selections = if let Some(flatbufs) = message.selections() {
+                    let mut selections = Vec::with_capacity(flatbufs.len());
+                    for i in 0..flatbufs.len() {
+                        selections.push(Selection::from_flatbuf(flatbufs.get(i))?);
+                    }
+                    Some(selections)
+                } else {
+                    None
+                };
+
+                Ok(Some(Operation::UpdateSelections {
+                    set_id: time::Lamport::from_flatbuf(
+                        message
+                            .set_id()
+                            .ok_or(crate::Error::DeserializeError("set_id"))?,
+                    ),
+                    selections,
+                    lamport_timestamp: time::Lamport::from_flatbuf(
+                        message
+                            .lamport_timestamp()
+                            .ok_or(crate::Error::DeserializeError("lamport_timestamp"))?,
+                    ),
+                    hidden: message.hidden(),
+                }))
+            }
+            serialization::buffer::OperationVariant::NONE => Ok(None),
+        }
+    }
+}
+
+impl operation_queue::Operation for Operation {
+    fn timestamp(&self) -> time::Lamport {
+        self.lamport_timestamp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng, StdRng};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_edit() {
+        let replica_id = Uuid::from_u128(1);
+        let mut local_clock = time::Local::new(replica_id);
+        let mut lamport_clock = time::Lamport::new(replica_id);
+        let mut buffer = Buffer::new("abc");
+        assert_eq!(buffer.to_string(), "abc");
+        buffer.edit(vec![3..3], "def", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "abcdef");
+        buffer.edit(vec![0..0], "ghi", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "ghiabcdef");
+        buffer.edit(vec![5..5], "jkl", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "ghiabjklcdef");
+        buffer.edit(vec![6..7], "", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "ghiabjlcdef");
+        buffer.edit(vec![4..9], "mno", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "ghiamnoef");
+    }
+
+    #[test]
+    fn test_random_edits() {
+        for seed in 0..100 {
+            println!("{:?}", seed);
+            let mut rng = StdRng::from_seed(&[seed]);
+
+            let mut reference_string = RandomCharIter(rng)
+                .take(rng.gen_range(0, 10))
+                .collect::<String>();
+            let mut buffer = Buffer::new(reference_string.as_str());
+            let mut buffer_versions = Vec::new();
+            let replica_id = Uuid::from_u128(1);
+            let mut local_clock = time::Local::new(replica_id);
+            let mut lamport_clock = time::Lamport::new(replica_id);
+
+            for _i in 0..10 {
+                let (old_ranges, new_text, _) =
+                    buffer.randomly_mutate(&mut rng, &mut local_clock, &mut lamport_clock);
+                for old_range in old_ranges.iter().rev() {
+                    reference_string = [
+                        &reference_string[0..old_range.start],
+                        new_text.as_str(),
+                        &reference_string[old_range.end..],
+                    ]
+                    .concat();
+                }
+                assert_eq!(buffer.to_string(), reference_string);
+                buffer.check_invariants().unwrap();
+
+                if rng.gen_weighted_bool(3) {
+                    buffer_versions.push(buffer.clone());
+                }
+            }
+
+            for mut old_buffer in buffer_versions {
+                for change in buffer.changes_since(&old_buffer.version) {
+                    old_buffer.edit_2d(
+                        Some(change.range),
+                        Text::new(change.code_units),
+                        &mut local_clock,
+                        &mut lamport_clock,
+                    );
+                }
+                assert_eq!(old_buffer.to_string(), buffer.to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn test_len_for_row() {
+        let mut buffer = Buffer::new("");
+        let replica_id = Uuid::from_u128(1);
+        let mut local_clock = time::Local::new(replica_id);
+        let mut lamport_clock = time::Lamport::new(replica_id);
+        buffer.edit(
+            vec![0..0],
+            "abcd\nefg\nhij",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        buffer.edit(
+            vec![12..12],
+            "kl\nmno",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        buffer.edit(
+            vec![18..18],
+            "\npqrs\n",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        buffer.edit(vec![18..21], "\nPQ", &mut local_clock, &mut lamport_clock);
+
+        assert_eq!(buffer.len_for_row(0), Ok(4));
+        assert_eq!(buffer.len_for_row(1), Ok(3));
+        assert_eq!(buffer.len_for_row(2), Ok(5));
+        assert_eq!(buffer.len_for_row(3), Ok(3));
+        assert_eq!(buffer.len_for_row(4), Ok(4));
+        assert_eq!(buffer.len_for_row(5), Ok(0));
+        assert_eq!(buffer.len_for_row(6), Err(Error::OffsetOutOfRange));
+    }
+
+    #[test]
+    fn test_longest_row() {
+        let mut buffer = Buffer::new("");
+        let replica_id = Uuid::from_u128(1);
+        let mut local_clock = time::Local::new(replica_id);
+        let mut lamport_clock = time::Lamport::new(replica_id);
+        assert_eq!(buffer.longest_row(), 0);
+        buffer.edit(
+            vec![0..0],
+            "abcd\nefg\nhij",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        assert_eq!(buffer.longest_row(), 0);
+        buffer.edit(
+            vec![12..12],
+            "kl\nmno",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        assert_eq!(buffer.longest_row(), 2);
+        buffer.edit(vec![18..18], "\npqrs", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.longest_row(), 2);
+        buffer.edit(vec![10..12], "", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.longest_row(), 0);
+        buffer.edit(vec![24..24], "tuv", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.longest_row(), 4);
+    }
+
+    #[test]
+    fn test_reverse_chunks_for_range() {
+        let mut buffer = Buffer::new("");
+        let replica_id = Uuid::from_u128(1);
+        let mut local_clock = time::Local::new(replica_id);
+        let mut lamport_clock = time::Lamport::new(replica_id);
+        buffer.edit(vec![0..0], "abcdefgh", &mut local_clock, &mut lamport_clock);
+        buffer.edit(vec![4..4], "1234", &mut local_clock, &mut lamport_clock);
+        buffer.edit(vec![2..3], "", &mut local_clock, &mut lamport_clock);
+        buffer.edit(
+            vec![10..10],
+            "\u{1F600}",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        let text = buffer.iter().collect::<Vec<u16>>();
+
+        for start in 0..=text.len() {
+            for end in start..=text.len() {
+                let chunks = buffer
+                    .reverse_chunks_for_range(start..end)
+                    .unwrap()
+                    .collect::<Vec<_>>();
+                assert!(chunks.iter().all(|chunk| !chunk.is_empty()));
+                let mut reconstructed = Vec::new();
+                for chunk in chunks.iter().rev() {
+                    reconstructed.extend_from_slice(chunk.code_units());
+                }
+                assert_eq!(reconstructed, &text[start..end]);
+            }
+        }
+
+        let chunks = buffer
+            .reverse_chunks_for_range(0..text.len())
+            .unwrap()
+            .map(|chunk| String::from_utf16_lossy(chunk.code_units()))
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, vec!["h", "\u{1F600}", "efg", "1234", "d", "ab"]);
+        assert!(buffer.reverse_chunks_for_range(3..2).is_err());
+        assert!(buffer.reverse_chunks_for_range(0..text.len() + 1).is_err());
+    }
+
+    #[test]
+    fn test_iter_starting_at_point() {
+        let mut buffer = Buffer::new("");
+        let replica_id = Uuid::from_u128(1);
+        let mut local_clock = time::Local::new(replica_id);
+        let mut lamport_clock = time::Lamport::new(replica_id);
+        buffer.edit(
+            vec![0..0],
+            "abcd\nefgh\nij",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        buffer.edit(
+            vec![12..12],
+            "kl\nmno",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        buffer.edit(vec![18..18], "\npqrs", &mut local_clock, &mut lamport_clock);
+        buffer.edit(vec![18..21], "\nPQ", &mut local_clock, &mut lamport_clock);
+
+        let cursor = buffer.iter_at_point(Point::new(0, 0));
+        assert_eq!(cursor.into_string(), "abcd\nefgh\nijkl\nmno\nPQrs");
+
+        let cursor = buffer.iter_at_point(Point::new(1, 0));
+        assert_eq!(cursor.into_string(), "efgh\nijkl\nmno\nPQrs");
+
+        let cursor = buffer.iter_at_point(Point::new(2, 0));
+        assert_eq!(cursor.into_string(), "ijkl\nmno\nPQrs");
+
+        let cursor = buffer.iter_at_point(Point::new(3, 0));
+        assert_eq!(cursor.into_string(), "mno\nPQrs");
+
+        let cursor = buffer.iter_at_point(Point::new(4, 0));
+        assert_eq!(cursor.into_string(), "PQrs");
+
+        let cursor = buffer.iter_at_point(Point::new(5, 0));
+        assert_eq!(cursor.into_string(), "");
+
+        let cursor = buffer.iter_at_point(Point::new(0, 0)).rev();
+        assert_eq!(cursor.into_string(), "");
+
+        let cursor = buffer.iter_at_point(Point::new(0, 3)).rev();
+        assert_eq!(cursor.into_string(), "cba");
+
+        let cursor = buffer.iter_at_point(Point::new(1, 4)).rev();
+        assert_eq!(cursor.into_string(), "hgfe\ndcba");
+
+        let cursor = buffer.iter_at_point(Point::new(3, 2)).rev();
+        assert_eq!(cursor.into_string(), "nm\nlkji\nhgfe\ndcba");
+
+        let cursor = buffer.iter_at_point(Point::new(4, 4)).rev();
+        assert_eq!(cursor.into_string(), "srQP\nonm\nlkji\nhgfe\ndcba");
+
+        let cursor = buffer.iter_at_point(Point::new(5, 0)).rev();
+        assert_eq!(cursor.into_string(), "srQP\nonm\nlkji\nhgfe\ndcba");
+
+        // Regression test:
+        let mut buffer = Buffer::new("");
+        let replica_id = Uuid::from_u128(1);
+        let mut local_clock = time::Local::new(replica_id);
+        let mut lamport_clock = time::Lamport::new(replica_id);
+        buffer.edit(vec![0..0], "[workspace]\nmembers = [\n    \"xray_core\",\n    \"xray_server\",\n    \"xray_cli\",\n    \"xray_wasm\",\n]\n", &mut local_clock, &mut lamport_clock);
+        buffer.edit(vec![60..60], "\n", &mut local_clock, &mut lamport_clock);
+
+        let cursor = buffer.iter_at_point(Point::new(6, 0));
+        assert_eq!(cursor.into_string(), "    \"xray_wasm\",\n]\n");
+    }
+
+    #[test]
+    fn test_surrounding_text() {
+        let mut buffer = Buffer::new("abc\ndef");
+        let replica_id = Uuid::from_u128(1);
+        let mut local_clock = time::Local::new(replica_id);
+        let mut lamport_clock = time::Lamport::new(replica_id);
+        buffer.edit(vec![5..5], "🎉x", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "abc\nd🎉xef");
+
+        assert_eq!(buffer.surrounding_text(Point::new(1, 0), 2, 2), "c\nd🎉");
+        assert_eq!(buffer.surrounding_text(Point::new(1, 3), 2, 1), "d🎉x");
+        assert_eq!(buffer.surrounding_text(Point::new(1, 3), 1, 0), "🎉");
+        assert_eq!(buffer.surrounding_text(Point::new(0, 1), 10, 0), "a");
+        assert_eq!(buffer.surrounding_text(Point::new(1, 5), 0, 10), "f");
+        assert_eq!(
+            buffer.surrounding_text(Point::new(0, 0), 10, 10),
+            "abc\nd🎉xef"
+        );
+        assert_eq!(buffer.surrounding_text(Point::new(7, 30), 2, 2), "ef");
+    }
+
+    #[test]
+    fn test_anchor_range_for_line() {
+        let mut buffer = Buffer::new("abc\ndef\r\nghi");
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+        let range = buffer.anchor_range_for_line(1).unwrap();
+        let resolve = |buffer: &Buffer| {
+            buffer.point_for_anchor(&range.start).unwrap()
+                ..buffer.point_for_anchor(&range.end).unwrap()
+        };
+        assert_eq!(resolve(&buffer), Point::new(1, 0)..Point::new(1, 3));
+
+        buffer.edit_2d(
+            Some(Point::new(0, 0)..Point::new(0, 0)),
+            "xyz\n",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        assert_eq!(resolve(&buffer), Point::new(2, 0)..Point::new(2, 3));
+
+        buffer.edit_2d(
+            Some(Point::new(2, 0)..Point::new(2, 0)),
+            "12",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        buffer.edit_2d(
+            Some(Point::new(2, 5)..Point::new(2, 5)),
+            "!",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        assert_eq!(resolve(&buffer), Point::new(2, 2)..Point::new(2, 6));
+
+        let range = buffer.anchor_range_for_line(3).unwrap();
+        assert_eq!(
+            buffer.point_for_anchor(&range.start).unwrap()
+                ..buffer.point_for_anchor(&range.end).unwrap(),
+            Point::new(3, 0)..Point::new(3, 3)
+        );
+        assert!(buffer.anchor_range_for_line(4).is_err());
+    }
+
+    #[test]
+    fn test_splice() {
+        let mut buffer = Buffer::new("hello world\n!");
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+        let resolve = |buffer: &Buffer, range: &Range<Anchor>| {
+            buffer.point_for_anchor(&range.start).unwrap()
+                ..buffer.point_for_anchor(&range.end).unwrap()
+        };
+
+        let (_, range) = buffer
+            .splice(
+                Point::new(0, 6)..Point::new(0, 11),
+                "rust",
+                &mut local_clock,
+                &mut lamport_clock,
+            )
+            .unwrap();
+        assert_eq!(buffer.to_string(), "hello rust\n!");
+        assert_eq!(
+            resolve(&buffer, &range),
+            Point::new(0, 6)..Point::new(0, 10)
+        );
+        buffer.edit(
+            vec![6..6, 10..10],
+            "_",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        assert_eq!(buffer.to_string(), "hello _rust_\n!");
+        assert_eq!(
+            resolve(&buffer, &range),
+            Point::new(0, 7)..Point::new(0, 11)
+        );
+
+        let (_, range) = buffer
+            .splice(
+                Point::new(0, 0)..Point::new(0, 5),
+                "goodbye\ncruel",
+                &mut local_clock,
+                &mut lamport_clock,
+            )
+            .unwrap();
+        assert_eq!(buffer.to_string(), "goodbye\ncruel _rust_\n!");
+        assert_eq!(resolve(&buffer, &range), Point::new(0, 0)..Point::new(1, 5));
+
+        let (_, range) = buffer
+            .splice(
+                Point::new(1, 5)..Point::new(2, 0),
+                "",
+                &mut local_clock,
+                &mut lamport_clock,
+            )
+            .unwrap();
+        assert_eq!(buffer.to_string(), "goodbye\ncruel!");
+        assert_eq!(resolve(&buffer, &range), Point::new(1, 5)..Point::new(1, 5));
+
+        assert!(buffer
+            .splice(
+                Point::new(1, 2)..Point::new(1, 1),
+                "",
+                &mut local_clock,
+                &mut lamport_clock
+            )
+            .is_err());
+        assert!(buffer
+            .splice(
+                Point::new(5, 0)..Point::new(5, 0),
+                "",
+                &mut local_clock,
+                &mut lamport_clock
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_write_to() {
+        let mut buffer = Buffer::new("\u{FEFF}abc\r\ndef\n");
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+        buffer.edit(
+            vec![2..2],
+            "\u{1F600}",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        buffer.edit(
+            vec![8..8],
+            "x".repeat(WRITE_CHUNK_SIZE).as_str(),
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        buffer.edit(vec![0..1], "", &mut local_clock, &mut lamport_clock);
+
+        let mut bytes = Vec::new();
+        buffer.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes, buffer.export().into_bytes());
+
+        let mut bytes = Vec::new();
+        Buffer::new("").write_to(&mut bytes).unwrap();
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_hash_range() {
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+        let buffer_1 = Buffer::new("abcdefghi");
+        let mut buffer_2 = Buffer::new("");
+        buffer_2.edit(Some(0..0), "ghi", &mut local_clock, &mut lamport_clock);
+        buffer_2.edit(Some(0..0), "aXc", &mut local_clock, &mut lamport_clock);
+        buffer_2.edit(Some(3..3), "def", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer_2.to_string(), "aXcdefghi");
+
+        assert_eq!(buffer_1.hash_range(2..9), buffer_2.hash_range(2..9));
+        assert_eq!(buffer_1.hash_range(3..6), buffer_2.hash_range(3..6));
+        assert_ne!(buffer_1.hash_range(0..9), buffer_2.hash_range(0..9));
+        assert_ne!(buffer_1.hash_range(1..2), buffer_2.hash_range(1..2));
+        assert_ne!(
+            buffer_1.hash_range(3..6).unwrap(),
+            buffer_1.hash_range(6..9).unwrap()
+        );
+        assert_eq!(
+            buffer_1.hash_range(4..4).unwrap(),
+            Buffer::new("").hash_range(0..0).unwrap()
+        );
+        assert_eq!(buffer_1.hash_range(4..10), Err(Error::OffsetOutOfRange));
+        assert_eq!(buffer_1.hash_range(4..3), Err(Error::OffsetOutOfRange));
+    }
+
+    #[test]
+    fn test_longest_common_substring() {
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+        let buffer_1 = Buffer::new("fn a() {\n    let x = compute(1, 2);\n}\n");
+        let mut buffer_2 = Buffer::new("");
+        buffer_2.edit(
+            Some(0..0),
+            "= compute(1, 2);\n",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        buffer_2.edit(
+            Some(0..0),
+            "fn b() {\n    let y ",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        buffer_2.edit(Some(36..36), "}", &mut local_clock, &mut lamport_clock);
+        assert_eq!(
+            buffer_2.to_string(),
+            "fn b() {\n    let y = compute(1, 2);\n}"
+        );
+
+        let (range_1, range_2) = buffer_1.longest_common_substring(&buffer_2, 4).unwrap();
+        assert_eq!(range_1, Point::new(1, 9)..Point::new(2, 1));
+        assert_eq!(range_2, Point::new(1, 9)..Point::new(2, 1));
+        assert_eq!(
+            buffer_1.longest_common_substring(&buffer_2, 19),
+            Some((range_1.clone(), range_2.clone()))
+        );
+        assert_eq!(buffer_1.longest_common_substring(&buffer_2, 20), None);
+        assert_eq!(
+            buffer_2.longest_common_substring(&buffer_1, 4),
+            Some((range_2, range_1))
+        );
+        assert_eq!(Buffer::new("").longest_common_substring(&buffer_1, 0), None);
+
+        // Matches longer than a single extension are continued along their diagonal.
+        let mut rng = StdRng::from_seed(&[0]);
+        let common = (0..3 * COMMON_CONTENT_MAX_EXTENSION_LEN)
+            .map(|_| rng.gen_range(b'a', b'z' + 1) as char)
+            .collect::<String>();
+        let buffer_1 = Buffer::new(format!("1{}2", common).as_str());
+        let buffer_2 = Buffer::new(format!("34{}5", common).as_str());
+        let len = common.len() as u32;
+        assert_eq!(
+            buffer_1.longest_common_substring(&buffer_2, 32),
+            Some((
+                Point::new(0, 1)..Point::new(0, 1 + len),
+                Point::new(0, 2)..Point::new(0, 2 + len)
+            ))
+        );
+
+        for seed in 0..100 {
+            let mut rng = StdRng::from_seed(&[seed]);
+            let string_1 = (0..rng.gen_range(0, 20))
+                .map(|_| *rng.choose(&['a', 'b', '\n']).unwrap())
+                .collect::<String>();
+            let string_2 = (0..rng.gen_range(0, 20))
+                .map(|_| *rng.choose(&['a', 'b', '\n']).unwrap())
+                .collect::<String>();
+            let buffer_1 = Buffer::new(string_1.as_str());
+            let buffer_2 = Buffer::new(string_2.as_str());
+            let min_len = rng.gen_range(0, 4);
+
+            let mut expected_len = 0;
+            for start_1 in 0..string_1.len() {
+                for start_2 in 0..string_2.len() {
+                    let len = string_1[start_1..]
+                        .chars()
+                        .zip(string_2[start_2..].chars())
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    expected_len = cmp::max(expected_len, len);
+                }
+            }
+
+            match buffer_1.longest_common_substring(&buffer_2, min_len) {
+                Some((range_1, range_2)) => {
+                    let start_1 = buffer_1.offset_for_point(range_1.start).unwrap();
+                    let end_1 = buffer_1.offset_for_point(range_1.end).unwrap();
+                    let start_2 = buffer_2.offset_for_point(range_2.start).unwrap();
+                    let end_2 = buffer_2.offset_for_point(range_2.end).unwrap();
+                    assert_eq!(end_1 - start_1, expected_len);
+                    assert_eq!(string_1[start_1..end_1], string_2[start_2..end_2]);
+                }
+                None => assert!(expected_len == 0 || expected_len < min_len),
+            }
+        }
+    }
+
+    #[test]
+    fn test_relocate() {
+        let buffer = Buffer::new("fn a() {}\nfn b() {}\n\nfn c() {}\nfn b() {}");
+        assert_eq!(
+            buffer.relocate(Point::new(0, 0), "fn b"),
+            Some(Point::new(1, 0))
+        );
+        assert_eq!(
+            buffer.relocate(Point::new(4, 2), "fn b"),
+            Some(Point::new(4, 0))
+        );
+        assert_eq!(
+            buffer.relocate(Point::new(10, 0), "fn c"),
+            Some(Point::new(3, 0))
+        );
+        assert_eq!(Buffer::new("ab..ab").relocate(Point::new(0, 2), "ab"), None);
+        assert_eq!(buffer.relocate(Point::new(0, 0), "fn d"), None);
+        assert_eq!(buffer.relocate(Point::new(0, 0), ""), None);
+
+        let mut text = "x".repeat(RELOCATE_WINDOW * 2);
+        text.push_str("needle");
+        let buffer = Buffer::new(text.as_str());
+        assert_eq!(buffer.relocate(Point::new(0, 0), "needle"), None);
+        assert_eq!(
+            buffer.relocate(Point::new(0, RELOCATE_WINDOW as u32), "needle"),
+            Some(Point::new(0, RELOCATE_WINDOW as u32 * 2))
+        );
+    }
+
+    #[test]
+    fn test_line_navigation() {
+        let buffer = Buffer::new("  abc\r\n\t\u{1F600}de\n\r\n  \r\nxy\r");
+        assert_eq!(buffer.line_start(Point::new(0, 3)), Point::new(0, 0));
+        assert_eq!(buffer.line_end(Point::new(0, 3)), Point::new(0, 5));
+        assert_eq!(buffer.first_non_whitespace(0), Point::new(0, 2));
+
+        assert_eq!(buffer.line_end(Point::new(1, 0)), Point::new(1, 5));
+        assert_eq!(buffer.first_non_whitespace(1), Point::new(1, 1));
+
+        assert_eq!(buffer.line_end(Point::new(2, 0)), Point::new(2, 0));
+        assert_eq!(buffer.first_non_whitespace(2), Point::new(2, 0));
+        assert_eq!(buffer.line_end(Point::new(3, 1)), Point::new(3, 2));
+        assert_eq!(buffer.first_non_whitespace(3), Point::new(3, 2));
+
+        // A trailing carriage return without a newline is part of the last line.
+        assert_eq!(buffer.line_end(Point::new(4, 0)), Point::new(4, 3));
+        assert_eq!(buffer.line_start(Point::new(10, 2)), Point::new(4, 0));
+        assert_eq!(buffer.line_end(Point::new(10, 2)), Point::new(4, 3));
+        assert_eq!(buffer.first_non_whitespace(10), Point::new(4, 0));
+    }
+
+    #[test]
+    fn test_word_boundaries() {
+        struct KebabCaseClassifier;
+
+        impl WordClassifier for KebabCaseClassifier {
+            fn is_word_char(&self, c: char) -> bool {
+                c.is_alphanumeric() || c == '_' || c == '-'
+            }
+        }
+
+        let buffer = Buffer::new("foo_bar-baz  (qux)\nnext a\u{1F600}b");
+        let code = CodeWordClassifier;
+        let next = |point, classifier| buffer.next_word_boundary(point, classifier).unwrap();
+        assert_eq!(next(Point::new(0, 0), &code), Point::new(0, 7));
+        assert_eq!(next(Point::new(0, 7), &code), Point::new(0, 8));
+        assert_eq!(next(Point::new(0, 8), &code), Point::new(0, 11));
+        assert_eq!(next(Point::new(0, 11), &code), Point::new(0, 14));
+        assert_eq!(next(Point::new(0, 18), &code), Point::new(1, 4));
+        assert_eq!(next(Point::new(1, 8), &code), Point::new(1, 9));
+        assert_eq!(next(Point::new(1, 9), &code), Point::new(1, 9));
+
+        let kebab = KebabCaseClassifier;
+        assert_eq!(
+            buffer.next_word_boundary(Point::new(0, 0), &kebab).unwrap(),
+            Point::new(0, 11)
+        );
+
+        let prev = |point, classifier| buffer.prev_word_boundary(point, classifier).unwrap();
+        assert_eq!(prev(Point::new(0, 11), &code), Point::new(0, 8));
+        assert_eq!(prev(Point::new(0, 8), &code), Point::new(0, 7));
+        assert_eq!(prev(Point::new(1, 0), &code), Point::new(0, 17));
+        assert_eq!(prev(Point::new(1, 9), &code), Point::new(1, 8));
+        assert_eq!(prev(Point::new(1, 8), &code), Point::new(1, 6));
+        assert_eq!(prev(Point::new(0, 0), &code), Point::new(0, 0));
+        assert_eq!(
+            buffer
+                .prev_word_boundary(Point::new(0, 11), &kebab)
+                .unwrap(),
+            Point::new(0, 0)
+        );
+        assert!(buffer.next_word_boundary(Point::new(5, 0), &code).is_err());
+    }
+
+    #[test]
+    fn test_word_at() {
+        let buffer = Buffer::new("let caf\u{E9} = cafe\u{301}  (\u{4E16}\u{754C})\n  x");
+        let code = CodeWordClassifier;
+        let word_at = |point, adjacent| {
+            buffer
+                .word_at(point, &code, adjacent)
+                .unwrap()
+                .map(|(range, text)| (range.start.column..range.end.column, text))
+        };
+        assert_eq!(word_at(Point::new(0, 0), false), Some((0..3, "let".into())));
+        assert_eq!(word_at(Point::new(0, 2), false), Some((0..3, "let".into())));
+        assert_eq!(word_at(Point::new(0, 3), false), Some((0..3, "let".into())));
+        assert_eq!(
+            word_at(Point::new(0, 6), false),
+            Some((4..8, "caf\u{E9}".into()))
+        );
+        assert_eq!(
+            word_at(Point::new(0, 13), false),
+            Some((11..16, "cafe\u{301}".into()))
+        );
+        assert_eq!(
+            word_at(Point::new(0, 20), false),
+            Some((19..21, "\u{4E16}\u{754C}".into()))
+        );
+
+        // Whitespace and punctuation.
+        assert_eq!(word_at(Point::new(0, 9), false), None);
+        assert_eq!(word_at(Point::new(0, 17), false), None);
+        assert_eq!(word_at(Point::new(0, 18), false), None);
+        assert_eq!(word_at(Point::new(1, 0), false), None);
+
+        // Adjacent words on the same line.
+        assert_eq!(word_at(Point::new(0, 17), true), None);
+        assert_eq!(word_at(Point::new(1, 0), true), Some((2..3, "x".into())));
+        assert_eq!(word_at(Point::new(1, 1), true), Some((2..3, "x".into())));
+
+        assert!(buffer.word_at(Point::new(2, 0), &code, false).is_err());
+
+        // Points inside of a grapheme cluster select the whole cluster, and extending characters
+        // outside of the common combining blocks stay with their base character.
+        assert_eq!(
+            word_at(Point::new(0, 15), false),
+            Some((11..16, "cafe\u{301}".into()))
+        );
+        let buffer = Buffer::new("a \u{43F}\u{483}\u{440} b");
+        let word_at = |point| {
+            buffer
+                .word_at(point, &code, false)
+                .unwrap()
+                .map(|(range, text)| (range.start.column..range.end.column, text))
+        };
+        assert_eq!(
+            word_at(Point::new(0, 3)),
+            Some((2..5, "\u{43F}\u{483}\u{440}".into()))
+        );
+        assert_eq!(word_at(Point::new(0, 6)), Some((6..7, "b".into())));
+    }
+
+    #[test]
+    fn test_offset_for_display_column() {
+        let buffer = Buffer::new("xyz\n\tab\tc\n\u{1F600}\t");
+        let offsets = (0..=10)
+            .map(|column| buffer.offset_for_display_column(1, column, 4).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, vec![4, 4, 5, 5, 5, 6, 7, 8, 8, 9, 9]);
+        assert_eq!(buffer.offset_for_display_column(0, 2, 4), Ok(2));
+        assert_eq!(buffer.offset_for_display_column(2, 1, 4), Ok(12));
+        assert_eq!(buffer.offset_for_display_column(2, 3, 4), Ok(13));
+        assert_eq!(buffer.offset_for_display_column(1, 1, 0), Ok(5));
+        assert_eq!(
+            buffer.offset_for_display_column(3, 0, 4),
+            Err(Error::OffsetOutOfRange)
+        );
+    }
+
+    #[test]
+    fn test_point_for_offset() {
+        let text = Text::from("abc\ndefgh\nijklm\nopq");
+        assert_eq!(text.point_for_offset(0), Ok(Point { row: 0, column: 0 }));
+        assert_eq!(text.point_for_offset(1), Ok(Point { row: 0, column: 1 }));
+        assert_eq!(text.point_for_offset(2), Ok(Point { row: 0, column: 2 }));
+        assert_eq!(text.point_for_offset(3), Ok(Point { row: 0, column: 3 }));
+        assert_eq!(text.point_for_offset(4), Ok(Point { row: 1, column: 0 }));
+        assert_eq!(text.point_for_offset(5), Ok(Point { row: 1, column: 1 }));
+        assert_eq!(text.point_for_offset(9), Ok(Point { row: 1, column: 5 }));
+        assert_eq!(text.point_for_offset(10), Ok(Point { row: 2, column: 0 }));
+        assert_eq!(text.point_for_offset(14), Ok(Point { row: 2, column: 4 }));
+        assert_eq!(text.point_for_offset(15), Ok(Point { row: 2, column: 5 }));
+        assert_eq!(text.point_for_offset(16), Ok(Point { row: 3, column: 0 }));
+        assert_eq!(text.point_for_offset(17), Ok(Point { row: 3, column: 1 }));
+        assert_eq!(text.point_for_offset(19), Ok(Point { row: 3, column: 3 }));
+        assert_eq!(text.point_for_offset(20), Err(Error::OffsetOutOfRange));
+
+        let text = Text::from("abc");
+        assert_eq!(text.point_for_offset(0), Ok(Point { row: 0, column: 0 }));
+        assert_eq!(text.point_for_offset(1), Ok(Point { row: 0, column: 1 }));
+        assert_eq!(text.point_for_offset(2), Ok(Point { row: 0, column: 2 }));
+        assert_eq!(text.point_for_offset(3), Ok(Point { row: 0, column: 3 }));
+        assert_eq!(text.point_for_offset(4), Err(Error::OffsetOutOfRange));
+    }
+
+    #[test]
+    fn test_offset_for_point() {
+        let text = Text::from("abc\ndefgh");
+        assert_eq!(text.offset_for_point(Point { row: 0, column: 0 }), Ok(0));
+        assert_eq!(text.offset_for_point(Point { row: 0, column: 1 }), Ok(1));
+        assert_eq!(text.offset_for_point(Point { row: 0, column: 2 }), Ok(2));
+        assert_eq!(text.offset_for_point(Point { row: 0, column: 3 }), Ok(3));
+        assert_eq!(
+            text.offset_for_point(Point { row: 0, column: 4 }),
+            Err(Error::OffsetOutOfRange)
+        );
+        assert_eq!(text.offset_for_point(Point { row: 1, column: 0 }), Ok(4));
+        assert_eq!(text.offset_for_point(Point { row: 1, column: 1 }), Ok(5));
+        assert_eq!(text.offset_for_point(Point { row: 1, column: 5 }), Ok(9));
+        assert_eq!(
+            text.offset_for_point(Point { row: 1, column: 6 }),
+            Err(Error::OffsetOutOfRange)
+        );
+
+        let text = Text::from("abc");
+        assert_eq!(text.offset_for_point(Point { row: 0, column: 0 }), Ok(0));
+        assert_eq!(text.offset_for_point(Point { row: 0, column: 1 }), Ok(1));
+        assert_eq!(text.offset_for_point(Point { row: 0, column: 2 }), Ok(2));
+        assert_eq!(text.offset_for_point(Point { row: 0, column: 3 }), Ok(3));
+        assert_eq!(
+            text.offset_for_point(Point { row: 0, column: 4 }),
+            Err(Error::OffsetOutOfRange)
+        );
+    }
+
+    #[test]
+    fn test_longest_row_in_range() {
+        for seed in 0..100 {
+            println!("{:?}", seed);
+            let mut rng = StdRng::from_seed(&[seed]);
+            let string = RandomCharIter(rng)
+                .take(rng.gen_range(1, 10))
+                .collect::<String>();
+            let text = Text::from(string.as_ref());
+
+            for _i in 0..10 {
+                let end = rng.gen_range(1, string.len() + 1);
+                let start = rng.gen_range(0, end);
+
+                let mut cur_row = string[0..start].chars().filter(|c| *c == '\n').count() as u32;
+                let mut cur_row_len = 0;
+                let mut expected_longest_row = cur_row;
+                let mut expected_longest_row_len = cur_row_len;
+                for ch in string[start..end].chars() {
+                    if ch == '\n' {
+                        if cur_row_len > expected_longest_row_len {
+                            expected_longest_row = cur_row;
+                            expected_longest_row_len = cur_row_len;
+                        }
+                        cur_row += 1;
+                        cur_row_len = 0;
+                    } else {
+                        cur_row_len += 1;
+                    }
+                }
+                if cur_row_len > expected_longest_row_len {
+                    expected_longest_row = cur_row;
+                    expected_longest_row_len = cur_row_len;
+                }
+
+                assert_eq!(
+                    text.longest_row_in_range(start..end),
+                    Ok((expected_longest_row, expected_longest_row_len))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_fragment_ids() {
+        for seed in 0..10 {
+            use rand::{Rng, SeedableRng, StdRng};
+            let mut rng = StdRng::from_seed(&[seed]);
+
+            let mut ids = vec![FragmentId(Arc::new(vec![0])), FragmentId(Arc::new(vec![4]))];
+            for _i in 0..100 {
+                let index = rng.gen_range::<usize>(1, ids.len());
+
+                let left = ids[index - 1].clone();
+                let right = ids[index].clone();
+                ids.insert(index, FragmentId::between_with_max(&left, &right, 4));
+
+                let mut sorted_ids = ids.clone();
+                sorted_ids.sort();
+                assert_eq!(ids, sorted_ids);
+            }
+        }
+    }
+
+    #[test]
+    fn test_anchors() {
+        let mut buffer = Buffer::new("");
+        let replica_id = Uuid::from_u128(1);
+        let mut local_clock = time::Local::new(replica_id);
+        let mut lamport_clock = time::Lamport::new(replica_id);
+        buffer.edit(vec![0..0], "abc", &mut local_clock, &mut lamport_clock);
+        let left_anchor = buffer.anchor_before_offset(2).unwrap();
+        let right_anchor = buffer.anchor_after_offset(2).unwrap();
+
+        buffer.edit(vec![1..1], "def\n", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "adef\nbc");
+        assert_eq!(buffer.offset_for_anchor(&left_anchor).unwrap(), 6);
+        assert_eq!(buffer.offset_for_anchor(&right_anchor).unwrap(), 6);
+        assert_eq!(
+            buffer.point_for_anchor(&left_anchor).unwrap(),
+            Point { row: 1, column: 1 }
+        );
+        assert_eq!(
+            buffer.point_for_anchor(&right_anchor).unwrap(),
+            Point { row: 1, column: 1 }
+        );
+
+        buffer.edit(vec![2..3], "", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "adf\nbc");
+        assert_eq!(buffer.offset_for_anchor(&left_anchor).unwrap(), 5);
+        assert_eq!(buffer.offset_for_anchor(&right_anchor).unwrap(), 5);
+        assert_eq!(
+            buffer.point_for_anchor(&left_anchor).unwrap(),
+            Point { row: 1, column: 1 }
+        );
+        assert_eq!(
+            buffer.point_for_anchor(&right_anchor).unwrap(),
+            Point { row: 1, column: 1 }
+        );
+
+        buffer.edit(vec![5..5], "ghi\n", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "adf\nbghi\nc");
+        assert_eq!(buffer.offset_for_anchor(&left_anchor).unwrap(), 5);
+        assert_eq!(buffer.offset_for_anchor(&right_anchor).unwrap(), 9);
+        assert_eq!(
+            buffer.point_for_anchor(&left_anchor).unwrap(),
+            Point { row: 1, column: 1 }
+        );
+        assert_eq!(
+            buffer.point_for_anchor(&right_anchor).unwrap(),
+            Point { row: 2, column: 0 }
+        );
+
+        buffer.edit(vec![7..9], "", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "adf\nbghc");
+        assert_eq!(buffer.offset_for_anchor(&left_anchor).unwrap(), 5);
+        assert_eq!(buffer.offset_for_anchor(&right_anchor).unwrap(), 7);
+        assert_eq!(
+            buffer.point_for_anchor(&left_anchor).unwrap(),
+            Point { row: 1, column: 1 }
+        );
+        assert_eq!(
+            buffer.point_for_anchor(&right_anchor).unwrap(),
+            Point { row: 1, column: 3 }
+        );
+
+        // Ensure anchoring to a point is equivalent to anchoring to an offset.
+        assert_eq!(
+            buffer.anchor_before_point(Point { row: 0, column: 0 }),
+            buffer.anchor_before_offset(0)
+        );
+        assert_eq!(
+            buffer.anchor_before_point(Point { row: 0, column: 1 }),
+            buffer.anchor_before_offset(1)
+        );
+        assert_eq!(
+            buffer.anchor_before_point(Point { row: 0, column: 2 }),
+            buffer.anchor_before_offset(2)
+        );
+        assert_eq!(
+            buffer.anchor_before_point(Point { row: 0, column: 3 }),
+            buffer.anchor_before_offset(3)
+        );
+        assert_eq!(
+            buffer.anchor_before_point(Point { row: 1, column: 0 }),
+            buffer.anchor_before_offset(4)
+        );
+        assert_eq!(
+            buffer.anchor_before_point(Point { row: 1, column: 1 }),
+            buffer.anchor_before_offset(5)
+        );
+        assert_eq!(
+            buffer.anchor_before_point(Point { row: 1, column: 2 }),
+            buffer.anchor_before_offset(6)
+        );
+        assert_eq!(
+            buffer.anchor_before_point(Point { row: 1, column: 3 }),
+            buffer.anchor_before_offset(7)
+        );
+        assert_eq!(
+            buffer.anchor_before_point(Point { row: 1, column: 4 }),
+            buffer.anchor_before_offset(8)
+        );
 
-        serialization::buffer::Operation::create(
-            builder,
-            &serialization::buffer::OperationArgs {
-                variant_type,
-                variant: Some(variant),
-            },
-        )
+        // Comparison between anchors.
+        let anchor_at_offset_0 = buffer.anchor_before_offset(0).unwrap();
+        let anchor_at_offset_1 = buffer.anchor_before_offset(1).unwrap();
+        let anchor_at_offset_2 = buffer.anchor_before_offset(2).unwrap();
+
+        assert_eq!(
+            buffer.cmp_anchors(&anchor_at_offset_0, &anchor_at_offset_0),
+            Ok(Ordering::Equal)
+        );
+        assert_eq!(
+            buffer.cmp_anchors(&anchor_at_offset_1, &anchor_at_offset_1),
+            Ok(Ordering::Equal)
+        );
+        assert_eq!(
+            buffer.cmp_anchors(&anchor_at_offset_2, &anchor_at_offset_2),
+            Ok(Ordering::Equal)
+        );
+
+        assert_eq!(
+            buffer.cmp_anchors(&anchor_at_offset_0, &anchor_at_offset_1),
+            Ok(Ordering::Less)
+        );
+        assert_eq!(
+            buffer.cmp_anchors(&anchor_at_offset_1, &anchor_at_offset_2),
+            Ok(Ordering::Less)
+        );
+        assert_eq!(
+            buffer.cmp_anchors(&anchor_at_offset_0, &anchor_at_offset_2),
+            Ok(Ordering::Less)
+        );
+
+        assert_eq!(
+            buffer.cmp_anchors(&anchor_at_offset_1, &anchor_at_offset_0),
+            Ok(Ordering::Greater)
+        );
+        assert_eq!(
+            buffer.cmp_anchors(&anchor_at_offset_2, &anchor_at_offset_1),
+            Ok(Ordering::Greater)
+        );
+        assert_eq!(
+            buffer.cmp_anchors(&anchor_at_offset_2, &anchor_at_offset_0),
+            Ok(Ordering::Greater)
+        );
     }
 
-    pub fn from_flatbuf<'fbb>(
-        message: &serialization::buffer::Operation<'fbb>,
-    ) -> Result<Option<Self>, crate::Error> {
-        match message.variant_type() {
-            serialization::buffer::OperationVariant::Edit => {
-                let message = serialization::buffer::Edit::init_from_table(
-                    message.variant().ok_or(crate::Error::DeserializeError)?,
+    #[test]
+    fn test_sort_anchors() {
+        for seed in 0..50 {
+            let mut rng = StdRng::from_seed(&[seed]);
+            let replica_id = Uuid::from_u128(1);
+            let mut local_clock = time::Local::new(replica_id);
+            let mut lamport_clock = time::Lamport::new(replica_id);
+            let base_text = RandomCharIter(rng).take(20).collect::<String>();
+            let mut buffer = Buffer::new(base_text.as_str());
+
+            let mut anchors = Vec::new();
+            for _ in 0..10 {
+                for _ in 0..3 {
+                    let offset = rng.gen_range(0, buffer.len() + 1);
+                    anchors.push(if rng.gen() {
+                        buffer.anchor_before_offset(offset).unwrap()
+                    } else {
+                        buffer.anchor_after_offset(offset).unwrap()
+                    });
+                }
+                let end = rng.gen_range(0, buffer.len() + 1);
+                let start = rng.gen_range(0, end + 1);
+                let new_text = RandomCharIter(rng)
+                    .take(rng.gen_range(0, 5))
+                    .collect::<String>();
+                buffer.edit(
+                    Some(start..end),
+                    new_text.as_str(),
+                    &mut local_clock,
+                    &mut lamport_clock,
                 );
-                Ok(Some(Operation::Edit {
-                    start_id: time::Local::from_flatbuf(
-                        message.start_id().ok_or(crate::Error::DeserializeError)?,
-                    ),
-                    start_offset: message.start_offset() as usize,
-                    end_id: time::Local::from_flatbuf(
-                        message.end_id().ok_or(crate::Error::DeserializeError)?,
-                    ),
-                    end_offset: message.end_offset() as usize,
-                    version_in_range: time::Global::from_flatbuf(
-                        message
-                            .version_in_range()
-                            .ok_or(crate::Error::DeserializeError)?,
-                    )?,
-                    new_text: message.new_text().map(|new_text| Arc::new(new_text.into())),
-                    local_timestamp: time::Local::from_flatbuf(
-                        message
-                            .local_timestamp()
-                            .ok_or(crate::Error::DeserializeError)?,
-                    ),
-                    lamport_timestamp: time::Lamport::from_flatbuf(
-                        message
-                            .lamport_timestamp()
-                            .ok_or(crate::Error::DeserializeError)?,
-                    ),
-                }))
             }
-            serialization::buffer::OperationVariant::UpdateSelections => {
-                let message = serialization::buffer::UpdateSelections::init_from_table(
-                    message.variant().ok_or(crate::Error::DeserializeError)?,
-                );
+            anchors.push(Anchor::End);
+            anchors.push(Anchor::Start);
+            rng.shuffle(&mut anchors);
 
-                let selections = if let Some(flatbufs) = message.selections() {
-                    let mut selections = Vec::with_capacity(flatbufs.len());
-                    for i in 0..flatbufs.len() {
-                        selections.push(Selection::from_flatbuf(flatbufs.get(i))?);
-                    }
-                    Some(selections)
-                } else {
-                    None
-                };
+            let mut expected_points = anchors
+                .iter()
+                .map(|anchor| buffer.point_for_anchor(anchor).unwrap())
+                .collect::<Vec<_>>();
+            expected_points.sort();
 
-                Ok(Some(Operation::UpdateSelections {
-                    set_id: time::Lamport::from_flatbuf(
-                        message.set_id().ok_or(crate::Error::DeserializeError)?,
-                    ),
-                    selections,
-                    lamport_timestamp: time::Lamport::from_flatbuf(
-                        message
-                            .lamport_timestamp()
-                            .ok_or(crate::Error::DeserializeError)?,
-                    ),
-                }))
+            buffer.sort_anchors(&mut anchors).unwrap();
+            let points = anchors
+                .iter()
+                .map(|anchor| buffer.point_for_anchor(anchor).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(points, expected_points);
+            for pair in anchors.windows(2) {
+                assert_ne!(
+                    buffer.cmp_anchors(&pair[0], &pair[1]),
+                    Ok(Ordering::Greater)
+                );
             }
-            serialization::buffer::OperationVariant::NONE => Ok(None),
         }
     }
-}
-
-impl operation_queue::Operation for Operation {
-    fn timestamp(&self) -> time::Lamport {
-        self.lamport_timestamp()
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rand::{Rng, SeedableRng, StdRng};
-    use uuid::Uuid;
 
     #[test]
-    fn test_edit() {
+    fn test_anchors_at_start_and_end() {
+        let mut buffer = Buffer::new("");
         let replica_id = Uuid::from_u128(1);
         let mut local_clock = time::Local::new(replica_id);
         let mut lamport_clock = time::Lamport::new(replica_id);
-        let mut buffer = Buffer::new("abc");
+        let before_start_anchor = buffer.anchor_before_offset(0).unwrap();
+        let after_end_anchor = buffer.anchor_after_offset(0).unwrap();
+
+        buffer.edit(vec![0..0], "abc", &mut local_clock, &mut lamport_clock);
         assert_eq!(buffer.to_string(), "abc");
+        assert_eq!(buffer.offset_for_anchor(&before_start_anchor).unwrap(), 0);
+        assert_eq!(buffer.offset_for_anchor(&after_end_anchor).unwrap(), 3);
+
+        let after_start_anchor = buffer.anchor_after_offset(0).unwrap();
+        let before_end_anchor = buffer.anchor_before_offset(3).unwrap();
+
         buffer.edit(vec![3..3], "def", &mut local_clock, &mut lamport_clock);
-        assert_eq!(buffer.to_string(), "abcdef");
         buffer.edit(vec![0..0], "ghi", &mut local_clock, &mut lamport_clock);
         assert_eq!(buffer.to_string(), "ghiabcdef");
-        buffer.edit(vec![5..5], "jkl", &mut local_clock, &mut lamport_clock);
-        assert_eq!(buffer.to_string(), "ghiabjklcdef");
-        buffer.edit(vec![6..7], "", &mut local_clock, &mut lamport_clock);
-        assert_eq!(buffer.to_string(), "ghiabjlcdef");
-        buffer.edit(vec![4..9], "mno", &mut local_clock, &mut lamport_clock);
-        assert_eq!(buffer.to_string(), "ghiamnoef");
+        assert_eq!(buffer.offset_for_anchor(&before_start_anchor).unwrap(), 0);
+        assert_eq!(buffer.offset_for_anchor(&after_start_anchor).unwrap(), 3);
+        assert_eq!(buffer.offset_for_anchor(&before_end_anchor).unwrap(), 6);
+        assert_eq!(buffer.offset_for_anchor(&after_end_anchor).unwrap(), 9);
     }
 
     #[test]
-    fn test_random_edits() {
-        for seed in 0..100 {
-            println!("{:?}", seed);
-            let mut rng = StdRng::from_seed(&[seed]);
+    fn test_start_and_end_sentinel_anchors() {
+        let mut buffer = Buffer::new("abc");
+        let replica_id = Uuid::from_u128(1);
+        let mut local_clock = time::Local::new(replica_id);
+        let mut lamport_clock = time::Lamport::new(replica_id);
+        let start = Anchor::start();
+        let end = Anchor::end();
+        let middle = buffer.anchor_before_offset(2).unwrap();
 
-            let mut reference_string = RandomCharIter(rng)
-                .take(rng.gen_range(0, 10))
-                .collect::<String>();
-            let mut buffer = Buffer::new(reference_string.as_str());
-            let mut buffer_versions = Vec::new();
-            let replica_id = Uuid::from_u128(1);
-            let mut local_clock = time::Local::new(replica_id);
-            let mut lamport_clock = time::Lamport::new(replica_id);
+        buffer.edit(vec![3..3], "def", &mut local_clock, &mut lamport_clock);
+        buffer.edit(vec![0..0], "ghi", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "ghiabcdef");
+        assert_eq!(buffer.offset_for_anchor(&start).unwrap(), 0);
+        assert_eq!(buffer.offset_for_anchor(&end).unwrap(), 9);
+        assert_eq!(buffer.point_for_anchor(&end).unwrap(), Point::new(0, 9));
+        assert_eq!(buffer.cmp_anchors(&start, &middle), Ok(Ordering::Less));
+        assert_eq!(buffer.cmp_anchors(&end, &middle), Ok(Ordering::Greater));
+        assert_eq!(buffer.cmp_anchors(&start, &end), Ok(Ordering::Less));
 
-            for _i in 0..10 {
-                let (old_ranges, new_text, _) =
-                    buffer.randomly_mutate(&mut rng, &mut local_clock, &mut lamport_clock);
-                for old_range in old_ranges.iter().rev() {
-                    reference_string = [
-                        &reference_string[0..old_range.start],
-                        new_text.as_str(),
-                        &reference_string[old_range.end..],
-                    ]
-                    .concat();
-                }
-                assert_eq!(buffer.to_string(), reference_string);
+        buffer.edit(vec![9..9], "\njkl", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.offset_for_anchor(&end).unwrap(), 13);
+        assert_eq!(buffer.point_for_anchor(&end).unwrap(), Point::new(1, 3));
+    }
 
-                if rng.gen_weighted_bool(3) {
-                    buffer_versions.push(buffer.clone());
-                }
-            }
+    #[test]
+    fn test_collect_garbage() {
+        let mut buffer_1 = Buffer::new("abcdef");
+        let mut buffer_2 = buffer_1.clone();
+        let mut local_clock_1 = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock_1 = time::Lamport::new(Uuid::from_u128(1));
+        let mut local_clock_2 = time::Local::new(Uuid::from_u128(2));
+        let mut lamport_clock_2 = time::Lamport::new(Uuid::from_u128(2));
+
+        let ops = buffer_1.edit(vec![1..3], "", &mut local_clock_1, &mut lamport_clock_1);
+        buffer_2
+            .apply_ops(ops, &mut local_clock_2, &mut lamport_clock_2)
+            .unwrap();
+        let ops = buffer_2.edit(vec![3..4], "", &mut local_clock_2, &mut lamport_clock_2);
+        buffer_1
+            .apply_ops(ops, &mut local_clock_1, &mut lamport_clock_1)
+            .unwrap();
+        assert_eq!(buffer_1.to_string(), "adf");
+        let fragment_count = buffer_1.fragments.items().len();
+
+        // Only deletions observed by the barrier are collected.
+        let mut barrier = time::Global::new();
+        barrier.observe(local_clock_1);
+        buffer_1.collect_garbage(&barrier);
+        assert_eq!(buffer_1.to_string(), "adf");
+        assert_eq!(buffer_1.fragments.items().len(), fragment_count - 1);
+
+        buffer_1.collect_garbage(&buffer_1.version.meet(&buffer_2.version));
+        assert_eq!(buffer_1.to_string(), "adf");
+        assert_eq!(buffer_1.fragments.items().len(), fragment_count - 2);
+
+        let ops = buffer_2.edit(vec![1..2], "xyz", &mut local_clock_2, &mut lamport_clock_2);
+        buffer_1
+            .apply_ops(ops, &mut local_clock_1, &mut lamport_clock_1)
+            .unwrap();
+        assert_eq!(buffer_1.to_string(), buffer_2.to_string());
+    }
 
-            for mut old_buffer in buffer_versions {
-                for change in buffer.changes_since(&old_buffer.version) {
-                    old_buffer.edit_2d(
-                        Some(change.range),
-                        Text::new(change.code_units),
-                        &mut local_clock,
-                        &mut lamport_clock,
-                    );
-                }
-                assert_eq!(old_buffer.to_string(), buffer.to_string());
-            }
-        }
+    #[test]
+    fn test_truncate_history() {
+        let mut buffer = Buffer::new("abcdef");
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+
+        buffer.edit(Some(6..6), "ghi", &mut local_clock, &mut lamport_clock);
+        buffer.edit(Some(0..1), "", &mut local_clock, &mut lamport_clock);
+        buffer.edit(Some(1..2), "", &mut local_clock, &mut lamport_clock);
+        buffer.set_mark('m', Point::new(0, 5)).unwrap();
+        buffer.edit(Some(4..6), "", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "bdefi");
+        let fragment_count = buffer.fragments.items().len();
+
+        // Nothing is dropped until the barrier observes the deletions.
+        buffer.truncate_history(0, &time::Global::new());
+        assert_eq!(buffer.fragments.items().len(), fragment_count);
+
+        // The oldest deletions are dropped first.
+        let barrier = buffer.version.clone();
+        buffer.truncate_history(1, &barrier);
+        assert_eq!(buffer.to_string(), "bdefi");
+        assert_eq!(buffer.fragments.items().len(), fragment_count - 2);
+
+        // The mark's insertion is retained even when no history is kept.
+        buffer.truncate_history(0, &barrier);
+        assert_eq!(buffer.fragments.items().len(), fragment_count - 2);
+        assert_eq!(buffer.mark('m'), Some(Point::new(0, 4)));
     }
 
     #[test]
-    fn test_len_for_row() {
-        let mut buffer = Buffer::new("");
-        let replica_id = Uuid::from_u128(1);
-        let mut local_clock = time::Local::new(replica_id);
-        let mut lamport_clock = time::Lamport::new(replica_id);
-        buffer.edit(
-            vec![0..0],
-            "abcd\nefg\nhij",
-            &mut local_clock,
-            &mut lamport_clock,
-        );
-        buffer.edit(
-            vec![12..12],
-            "kl\nmno",
-            &mut local_clock,
-            &mut lamport_clock,
-        );
-        buffer.edit(
-            vec![18..18],
-            "\npqrs\n",
-            &mut local_clock,
-            &mut lamport_clock,
+    fn test_is_anchor_valid() {
+        let mut buffer_1 = Buffer::new("abcdef");
+        let mut buffer_2 = buffer_1.clone();
+        let mut local_clock_1 = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock_1 = time::Lamport::new(Uuid::from_u128(1));
+        let mut local_clock_2 = time::Local::new(Uuid::from_u128(2));
+        let mut lamport_clock_2 = time::Lamport::new(Uuid::from_u128(2));
+
+        let deleted_anchor = buffer_1.anchor_before_offset(2).unwrap();
+        let ops = buffer_1.edit(vec![1..3], "", &mut local_clock_1, &mut lamport_clock_1);
+        buffer_1.collect_garbage(&buffer_1.version.clone());
+        assert!(buffer_1.is_anchor_valid(&deleted_anchor));
+        assert_eq!(buffer_1.offset_for_anchor(&deleted_anchor), Ok(1));
+        assert_eq!(buffer_1.take_anchor_fallbacks(), 1);
+        assert_eq!(buffer_1.take_anchor_fallbacks(), 0);
+        assert!(buffer_1.is_anchor_valid(&Anchor::Start));
+        assert!(buffer_1.is_anchor_valid(&Anchor::End));
+
+        buffer_2.edit(vec![6..6], "ghi", &mut local_clock_2, &mut lamport_clock_2);
+        let unknown_anchor = buffer_2.anchor_before_offset(8).unwrap();
+        assert!(buffer_2.is_anchor_valid(&unknown_anchor));
+        assert!(!buffer_1.is_anchor_valid(&unknown_anchor));
+        assert!(buffer_1.offset_for_anchor(&unknown_anchor).is_err());
+
+        buffer_2
+            .apply_ops(ops, &mut local_clock_2, &mut lamport_clock_2)
+            .unwrap();
+        assert!(buffer_2.is_anchor_valid(&deleted_anchor));
+        assert_eq!(buffer_2.offset_for_anchor(&deleted_anchor), Ok(1));
+        assert_eq!(buffer_2.take_anchor_fallbacks(), 0);
+    }
+
+    #[test]
+    fn test_operation_for_anchor() {
+        let mut buffer_1 = Buffer::new("abc");
+        let mut buffer_2 = buffer_1.clone();
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+
+        buffer_1.edit(vec![1..1], "123", &mut local_clock, &mut lamport_clock);
+        let ops = buffer_1.edit(vec![2..4], "xy", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer_1.to_string(), "a1xybc");
+
+        let anchor = buffer_1.anchor_before_offset(3).unwrap();
+        let operation = buffer_1.operation_for_anchor(&anchor).unwrap();
+        assert_eq!(
+            operation_local_timestamp(&operation),
+            operation_local_timestamp(&ops[0])
         );
-        buffer.edit(vec![18..21], "\nPQ", &mut local_clock, &mut lamport_clock);
 
-        assert_eq!(buffer.len_for_row(0), Ok(4));
-        assert_eq!(buffer.len_for_row(1), Ok(3));
-        assert_eq!(buffer.len_for_row(2), Ok(5));
-        assert_eq!(buffer.len_for_row(3), Ok(3));
-        assert_eq!(buffer.len_for_row(4), Ok(4));
-        assert_eq!(buffer.len_for_row(5), Ok(0));
-        assert_eq!(buffer.len_for_row(6), Err(Error::OffsetOutOfRange));
+        // Applying the reconstructed operation reproduces the insertion.
+        let mut local_clock_2 = time::Local::new(Uuid::from_u128(2));
+        let mut lamport_clock_2 = time::Lamport::new(Uuid::from_u128(2));
+        let first_insertion = buffer_1
+            .operation_for_anchor(&buffer_1.anchor_before_offset(2).unwrap())
+            .unwrap();
+        buffer_2
+            .apply_ops(
+                vec![first_insertion, operation],
+                &mut local_clock_2,
+                &mut lamport_clock_2,
+            )
+            .unwrap();
+        assert_eq!(buffer_2.to_string(), "a1xy23bc");
+
+        assert_eq!(buffer_1.operation_for_anchor(&Anchor::Start), None);
+        assert_eq!(buffer_1.operation_for_anchor(&Anchor::End), None);
+        let base_anchor = buffer_1.anchor_before_offset(1).unwrap();
+        assert_eq!(buffer_1.operation_for_anchor(&base_anchor), None);
+    }
+
+    fn operation_local_timestamp(operation: &Operation) -> time::Local {
+        match operation {
+            Operation::Edit {
+                local_timestamp, ..
+            } => *local_timestamp,
+            _ => panic!("expected an edit"),
+        }
     }
 
     #[test]
-    fn test_longest_row() {
-        let mut buffer = Buffer::new("");
+    fn test_bom() {
+        let mut buffer = Buffer::new("\u{FEFF}abc\ndef");
         let replica_id = Uuid::from_u128(1);
         let mut local_clock = time::Local::new(replica_id);
         let mut lamport_clock = time::Lamport::new(replica_id);
-        assert_eq!(buffer.longest_row(), 0);
-        buffer.edit(
-            vec![0..0],
-            "abcd\nefg\nhij",
+        assert!(buffer.has_bom());
+        assert_eq!(buffer.len(), 7);
+        assert_eq!(buffer.to_string(), "abc\ndef");
+        assert_eq!(
+            buffer.line(0).unwrap(),
+            "abc".encode_utf16().collect::<Vec<_>>()
+        );
+
+        buffer.edit_2d(
+            Some(Point::new(0, 0)..Point::new(0, 1)),
+            "xy",
             &mut local_clock,
             &mut lamport_clock,
         );
-        assert_eq!(buffer.longest_row(), 0);
+        buffer.edit(vec![0..0], "z", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "zxybc\ndef");
+        assert_eq!(buffer.export(), "\u{FEFF}zxybc\ndef");
+
         buffer.edit(
-            vec![12..12],
-            "kl\nmno",
+            vec![0..buffer.len()],
+            "",
             &mut local_clock,
             &mut lamport_clock,
         );
-        assert_eq!(buffer.longest_row(), 2);
-        buffer.edit(vec![18..18], "\npqrs", &mut local_clock, &mut lamport_clock);
-        assert_eq!(buffer.longest_row(), 2);
-        buffer.edit(vec![10..12], "", &mut local_clock, &mut lamport_clock);
-        assert_eq!(buffer.longest_row(), 0);
-        buffer.edit(vec![24..24], "tuv", &mut local_clock, &mut lamport_clock);
-        assert_eq!(buffer.longest_row(), 4);
+        assert_eq!(buffer.export(), "\u{FEFF}");
+
+        let buffer = Buffer::new("abc");
+        assert!(!buffer.has_bom());
+        assert_eq!(buffer.export(), "abc");
     }
 
     #[test]
-    fn test_iter_starting_at_point() {
-        let mut buffer = Buffer::new("");
+    fn test_trailing_newline() {
+        let buffer = Buffer::new("abc");
+        assert!(!buffer.ends_with_newline());
+        assert_eq!(buffer.export_with_trailing_newline(), "abc\n");
+
+        let buffer = Buffer::new("abc\n");
+        assert!(buffer.ends_with_newline());
+        assert_eq!(buffer.export_with_trailing_newline(), "abc\n");
+
+        let buffer = Buffer::new("abc\n\n\n");
+        assert!(buffer.ends_with_newline());
+        assert_eq!(buffer.export_with_trailing_newline(), "abc\n");
+
+        let buffer = Buffer::new("\u{FEFF}abc\r\ndef\r\n\r\n");
+        assert!(buffer.ends_with_newline());
+        assert_eq!(
+            buffer.export_with_trailing_newline(),
+            "\u{FEFF}abc\r\ndef\r\n"
+        );
+
+        let buffer = Buffer::new("");
+        assert!(!buffer.ends_with_newline());
+        assert_eq!(buffer.export_with_trailing_newline(), "");
+    }
+
+    #[test]
+    fn test_marks() {
+        let mut buffer = Buffer::new("abc\ndef\nghi");
         let replica_id = Uuid::from_u128(1);
         let mut local_clock = time::Local::new(replica_id);
         let mut lamport_clock = time::Lamport::new(replica_id);
-        buffer.edit(
-            vec![0..0],
-            "abcd\nefgh\nij",
-            &mut local_clock,
-            &mut lamport_clock,
+
+        buffer.set_mark('a', Point::new(1, 1)).unwrap();
+        buffer.set_mark('b', Point::new(2, 3)).unwrap();
+        assert_eq!(
+            buffer.set_mark('c', Point::new(5, 0)),
+            Err(Error::OffsetOutOfRange)
         );
-        buffer.edit(
-            vec![12..12],
-            "kl\nmno",
-            &mut local_clock,
-            &mut lamport_clock,
+        assert_eq!(buffer.mark('a'), Some(Point::new(1, 1)));
+        assert_eq!(buffer.mark('c'), None);
+
+        buffer.edit(vec![0..0], "xy\n", &mut local_clock, &mut lamport_clock);
+        buffer.edit(vec![8..8], "zz", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "xy\nabc\ndzzef\nghi");
+        assert_eq!(buffer.mark('a'), Some(Point::new(2, 1)));
+        assert_eq!(buffer.mark('b'), Some(Point::new(3, 3)));
+
+        buffer.set_mark('a', Point::new(0, 0)).unwrap();
+        assert_eq!(buffer.mark('a'), Some(Point::new(0, 0)));
+    }
+
+    #[test]
+    fn test_edit_at_anchor() {
+        let mut buffer_1 = Buffer::new("abcdef");
+        let mut buffer_2 = buffer_1.clone();
+        let mut local_clock_1 = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock_1 = time::Lamport::new(Uuid::from_u128(1));
+        let mut local_clock_2 = time::Local::new(Uuid::from_u128(2));
+        let mut lamport_clock_2 = time::Lamport::new(Uuid::from_u128(2));
+
+        let range =
+            buffer_1.anchor_after_offset(2).unwrap()..buffer_1.anchor_before_offset(4).unwrap();
+        let ops = buffer_2.edit(
+            vec![0..1, 5..5],
+            "XYZ",
+            &mut local_clock_2,
+            &mut lamport_clock_2,
         );
-        buffer.edit(vec![18..18], "\npqrs", &mut local_clock, &mut lamport_clock);
-        buffer.edit(vec![18..21], "\nPQ", &mut local_clock, &mut lamport_clock);
+        buffer_1
+            .apply_ops(ops, &mut local_clock_1, &mut lamport_clock_1)
+            .unwrap();
+        assert_eq!(buffer_1.to_string(), "XYZbcdeXYZf");
+
+        let ops = buffer_1
+            .edit_at_anchor(
+                range.clone(),
+                "123",
+                &mut local_clock_1,
+                &mut lamport_clock_1,
+            )
+            .unwrap();
+        assert_eq!(buffer_1.to_string(), "XYZb123eXYZf");
+        buffer_2
+            .apply_ops(ops, &mut local_clock_2, &mut lamport_clock_2)
+            .unwrap();
+        assert_eq!(buffer_2.to_string(), buffer_1.to_string());
 
-        let cursor = buffer.iter_at_point(Point::new(0, 0));
-        assert_eq!(cursor.into_string(), "abcd\nefgh\nijkl\nmno\nPQrs");
+        assert_eq!(
+            buffer_1.edit_at_anchor(
+                Anchor::end()..Anchor::start(),
+                "",
+                &mut local_clock_1,
+                &mut lamport_clock_1
+            ),
+            Err(Error::InvalidAnchor("range start is after its end".into()))
+        );
+    }
 
-        let cursor = buffer.iter_at_point(Point::new(1, 0));
-        assert_eq!(cursor.into_string(), "efgh\nijkl\nmno\nPQrs");
+    #[test]
+    fn test_set_text() {
+        let mut buffer_1 = Buffer::new("abc\ndef\nghi");
+        let mut buffer_2 = buffer_1.clone();
+        let mut local_clock_1 = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock_1 = time::Lamport::new(Uuid::from_u128(1));
+        let mut local_clock_2 = time::Local::new(Uuid::from_u128(2));
+        let mut lamport_clock_2 = time::Lamport::new(Uuid::from_u128(2));
+
+        let anchor_1 = buffer_1.anchor_before_point(Point::new(1, 1)).unwrap();
+        let anchor_2 = buffer_1.anchor_after_point(Point::new(2, 2)).unwrap();
+        let ops = buffer_1.set_text(
+            "aXc\ndef\nghYi\njkl",
+            &mut local_clock_1,
+            &mut lamport_clock_1,
+        );
+        assert_eq!(buffer_1.to_string(), "aXc\ndef\nghYi\njkl");
+        assert_eq!(
+            buffer_1.point_for_anchor(&anchor_1).unwrap(),
+            Point::new(1, 1)
+        );
+        assert_eq!(
+            buffer_1.point_for_anchor(&anchor_2).unwrap(),
+            Point::new(2, 3)
+        );
 
-        let cursor = buffer.iter_at_point(Point::new(2, 0));
-        assert_eq!(cursor.into_string(), "ijkl\nmno\nPQrs");
+        buffer_2
+            .apply_ops(ops, &mut local_clock_2, &mut lamport_clock_2)
+            .unwrap();
+        assert_eq!(buffer_2.to_string(), buffer_1.to_string());
 
-        let cursor = buffer.iter_at_point(Point::new(3, 0));
-        assert_eq!(cursor.into_string(), "mno\nPQrs");
+        let ops = buffer_1.set_text(
+            "aXc\ndef\nghYi\njkl",
+            &mut local_clock_1,
+            &mut lamport_clock_1,
+        );
+        assert!(ops.is_empty());
+        buffer_1.set_text("", &mut local_clock_1, &mut lamport_clock_1);
+        assert_eq!(buffer_1.to_string(), "");
+    }
 
-        let cursor = buffer.iter_at_point(Point::new(4, 0));
-        assert_eq!(cursor.into_string(), "PQrs");
+    #[test]
+    fn test_snapshot_at() {
+        let mut buffer = Buffer::new("abc");
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+
+        buffer.edit(Some(1..2), "XY", &mut local_clock, &mut lamport_clock);
+        let version = buffer.version.clone();
+        let anchor = buffer.anchor_before_offset(2).unwrap();
+        buffer.edit(Some(0..2), "", &mut local_clock, &mut lamport_clock);
+        buffer.edit(Some(1..1), "Z", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "YZc");
+
+        let snapshot = buffer.snapshot_at(&version);
+        assert_eq!(snapshot.to_string(), "aXYc");
+        assert_eq!(
+            snapshot.point_for_anchor(&anchor).unwrap(),
+            Point::new(0, 2)
+        );
+        assert_eq!(buffer.point_for_anchor(&anchor).unwrap(), Point::new(0, 0));
+        assert_eq!(buffer.snapshot_at(&time::Global::new()).to_string(), "abc");
+    }
 
-        let cursor = buffer.iter_at_point(Point::new(5, 0));
-        assert_eq!(cursor.into_string(), "");
+    #[test]
+    fn test_diff_against() {
+        let mut buffer = Buffer::new("abc\ndef\nghi");
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
 
-        let cursor = buffer.iter_at_point(Point::new(0, 0)).rev();
-        assert_eq!(cursor.into_string(), "");
+        assert_eq!(buffer.diff_against("abc\ndef\nghi"), vec![]);
+        assert_eq!(
+            buffer.diff_against("abc\nde\nf\nghi"),
+            vec![(Point::new(1, 2)..Point::new(1, 2), "\n".to_string())]
+        );
 
-        let cursor = buffer.iter_at_point(Point::new(0, 3)).rev();
-        assert_eq!(cursor.into_string(), "cba");
+        // Computing the diff leaves the buffer untouched.
+        let new_text = "aXc\ndef\ngi\njkl";
+        let edits = buffer.diff_against(new_text);
+        assert_eq!(buffer.to_string(), "abc\ndef\nghi");
+        assert!(!buffer.is_modified());
 
-        let cursor = buffer.iter_at_point(Point::new(1, 4)).rev();
-        assert_eq!(cursor.into_string(), "hgfe\ndcba");
+        // Ranges refer to the current text, so they can be applied from last to first.
+        assert_eq!(edits.first().unwrap().0, Point::new(0, 1)..Point::new(0, 2));
+        for (range, text) in edits.into_iter().rev() {
+            buffer.edit_2d(
+                Some(range),
+                text.as_str(),
+                &mut local_clock,
+                &mut lamport_clock,
+            );
+        }
+        assert_eq!(buffer.to_string(), new_text);
+    }
 
-        let cursor = buffer.iter_at_point(Point::new(3, 2)).rev();
-        assert_eq!(cursor.into_string(), "nm\nlkji\nhgfe\ndcba");
+    #[test]
+    fn test_minimal_edit() {
+        let mut buffer = Buffer::new("let one = 1;\nlet two = 2;");
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
 
-        let cursor = buffer.iter_at_point(Point::new(4, 4)).rev();
-        assert_eq!(cursor.into_string(), "srQP\nonm\nlkji\nhgfe\ndcba");
+        assert_eq!(
+            buffer.minimal_edit(Point::new(1, 0)..Point::new(1, 12), "let six = 2;"),
+            (Point::new(1, 4)..Point::new(1, 7), "six".to_string())
+        );
+        assert_eq!(
+            buffer.minimal_edit(Point::new(0, 4)..Point::new(0, 7), "one"),
+            (Point::new(0, 7)..Point::new(0, 7), "".to_string())
+        );
+        assert_eq!(
+            buffer.minimal_edit(Point::new(0, 0)..Point::new(1, 0), "let one = 10;\n"),
+            (Point::new(0, 11)..Point::new(0, 11), "0".to_string())
+        );
+        assert_eq!(
+            buffer.minimal_edit(Point::new(0, 9)..Point::new(0, 1), "x"),
+            (Point::new(0, 9)..Point::new(0, 1), "x".to_string())
+        );
 
-        let cursor = buffer.iter_at_point(Point::new(5, 0)).rev();
-        assert_eq!(cursor.into_string(), "srQP\nonm\nlkji\nhgfe\ndcba");
+        let anchor_1 = buffer.anchor_before_point(Point::new(1, 4)).unwrap();
+        let anchor_2 = buffer.anchor_after_point(Point::new(1, 7)).unwrap();
+        let ops = buffer.set_text(
+            "let one = 1;\nlet six = 2;",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        assert_eq!(ops.len(), 1);
+        assert_eq!(buffer.to_string(), "let one = 1;\nlet six = 2;");
+        assert_eq!(
+            buffer.point_for_anchor(&anchor_1).unwrap(),
+            Point::new(1, 4)
+        );
+        assert_eq!(
+            buffer.point_for_anchor(&anchor_2).unwrap(),
+            Point::new(1, 7)
+        );
 
-        // Regression test:
-        let mut buffer = Buffer::new("");
-        let replica_id = Uuid::from_u128(1);
-        let mut local_clock = time::Local::new(replica_id);
-        let mut lamport_clock = time::Lamport::new(replica_id);
-        buffer.edit(vec![0..0], "[workspace]\nmembers = [\n    \"xray_core\",\n    \"xray_server\",\n    \"xray_cli\",\n    \"xray_wasm\",\n]\n", &mut local_clock, &mut lamport_clock);
-        buffer.edit(vec![60..60], "\n", &mut local_clock, &mut lamport_clock);
+        // Surrogate pairs are never split.
+        let buffer = Buffer::new("a\u{1F600}b");
+        assert_eq!(
+            buffer.minimal_edit(Point::new(0, 0)..Point::new(0, 4), "a\u{1F601}b"),
+            (Point::new(0, 1)..Point::new(0, 3), "\u{1F601}".to_string())
+        );
+    }
 
-        let cursor = buffer.iter_at_point(Point::new(6, 0));
-        assert_eq!(cursor.into_string(), "    \"xray_wasm\",\n]\n");
+    #[test]
+    fn test_coalesce_fragments() {
+        let mut buffer_1 = Buffer::new("abcdef");
+        let mut buffer_2 = buffer_1.clone();
+        let mut local_clock_1 = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock_1 = time::Lamport::new(Uuid::from_u128(1));
+        let mut local_clock_2 = time::Local::new(Uuid::from_u128(2));
+        let mut lamport_clock_2 = time::Lamport::new(Uuid::from_u128(2));
+
+        let mut ops = Vec::new();
+        ops.extend(buffer_1.edit(Some(3..3), "X", &mut local_clock_1, &mut lamport_clock_1));
+        ops.extend(buffer_1.edit(Some(3..4), "", &mut local_clock_1, &mut lamport_clock_1));
+        ops.extend(buffer_1.edit(Some(5..5), "Y", &mut local_clock_1, &mut lamport_clock_1));
+        ops.extend(buffer_1.edit(Some(5..6), "", &mut local_clock_1, &mut lamport_clock_1));
+        let barrier = buffer_1.version.clone();
+        buffer_1.collect_garbage(&barrier);
+        assert_eq!(buffer_1.to_string(), "abcdef");
+        assert_eq!(buffer_1.fragments.items().len(), 4);
+
+        let anchors = (0..=6)
+            .flat_map(|offset| {
+                vec![
+                    buffer_1.anchor_before_offset(offset).unwrap(),
+                    buffer_1.anchor_after_offset(offset).unwrap(),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let offsets = anchors
+            .iter()
+            .map(|anchor| buffer_1.offset_for_anchor(anchor).unwrap())
+            .collect::<Vec<_>>();
+
+        buffer_1.coalesce_fragments();
+        assert_eq!(buffer_1.to_string(), "abcdef");
+        assert_eq!(buffer_1.fragments.items().len(), 2);
+        buffer_1.anchor_cache.borrow_mut().clear();
+        assert_eq!(
+            anchors
+                .iter()
+                .map(|anchor| buffer_1.offset_for_anchor(anchor).unwrap())
+                .collect::<Vec<_>>(),
+            offsets
+        );
+
+        ops.extend(buffer_1.edit(Some(2..4), "Z", &mut local_clock_1, &mut lamport_clock_1));
+        assert_eq!(buffer_1.to_string(), "abZef");
+        buffer_2
+            .apply_ops(ops, &mut local_clock_2, &mut lamport_clock_2)
+            .unwrap();
+        assert_eq!(buffer_2.to_string(), buffer_1.to_string());
     }
 
     #[test]
-    fn test_point_for_offset() {
-        let text = Text::from("abc\ndefgh\nijklm\nopq");
-        assert_eq!(text.point_for_offset(0), Ok(Point { row: 0, column: 0 }));
-        assert_eq!(text.point_for_offset(1), Ok(Point { row: 0, column: 1 }));
-        assert_eq!(text.point_for_offset(2), Ok(Point { row: 0, column: 2 }));
-        assert_eq!(text.point_for_offset(3), Ok(Point { row: 0, column: 3 }));
-        assert_eq!(text.point_for_offset(4), Ok(Point { row: 1, column: 0 }));
-        assert_eq!(text.point_for_offset(5), Ok(Point { row: 1, column: 1 }));
-        assert_eq!(text.point_for_offset(9), Ok(Point { row: 1, column: 5 }));
-        assert_eq!(text.point_for_offset(10), Ok(Point { row: 2, column: 0 }));
-        assert_eq!(text.point_for_offset(14), Ok(Point { row: 2, column: 4 }));
-        assert_eq!(text.point_for_offset(15), Ok(Point { row: 2, column: 5 }));
-        assert_eq!(text.point_for_offset(16), Ok(Point { row: 3, column: 0 }));
-        assert_eq!(text.point_for_offset(17), Ok(Point { row: 3, column: 1 }));
-        assert_eq!(text.point_for_offset(19), Ok(Point { row: 3, column: 3 }));
-        assert_eq!(text.point_for_offset(20), Err(Error::OffsetOutOfRange));
+    fn test_search() {
+        let buffer = Buffer::new("Hello hello HELLO héllo a\u{1F600}aaa\u{1F600}");
+        let search = |query: &str, ignore_case: bool| {
+            buffer
+                .search(
+                    query,
+                    SearchOptions {
+                        ignore_case,
+                        ..SearchOptions::default()
+                    },
+                )
+                .into_iter()
+                .map(|range| {
+                    buffer.offset_for_anchor(&range.start).unwrap()
+                        ..buffer.offset_for_anchor(&range.end).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
 
-        let text = Text::from("abc");
-        assert_eq!(text.point_for_offset(0), Ok(Point { row: 0, column: 0 }));
-        assert_eq!(text.point_for_offset(1), Ok(Point { row: 0, column: 1 }));
-        assert_eq!(text.point_for_offset(2), Ok(Point { row: 0, column: 2 }));
-        assert_eq!(text.point_for_offset(3), Ok(Point { row: 0, column: 3 }));
-        assert_eq!(text.point_for_offset(4), Err(Error::OffsetOutOfRange));
+        assert_eq!(search("hello", false), vec![6..11]);
+        assert_eq!(search("hello", true), vec![0..5, 6..11, 12..17]);
+        assert_eq!(search("HÉLLO", true), vec![18..23]);
+        assert_eq!(search("aa", false), vec![27..29]);
+        assert_eq!(search("\u{1F600}", false), vec![25..27, 30..32]);
+        assert_eq!(search("a\u{1F600}", false), vec![24..27, 29..32]);
+        assert!(search("", false).is_empty());
+        assert!(search("goodbye", true).is_empty());
+
+        let cancel = AtomicBool::new(true);
+        assert!(buffer
+            .search_with_cancel("hello", SearchOptions::default(), &cancel)
+            .is_empty());
     }
 
     #[test]
-    fn test_offset_for_point() {
-        let text = Text::from("abc\ndefgh");
-        assert_eq!(text.offset_for_point(Point { row: 0, column: 0 }), Ok(0));
-        assert_eq!(text.offset_for_point(Point { row: 0, column: 1 }), Ok(1));
-        assert_eq!(text.offset_for_point(Point { row: 0, column: 2 }), Ok(2));
-        assert_eq!(text.offset_for_point(Point { row: 0, column: 3 }), Ok(3));
+    fn test_search_normalized() {
+        // The first word is composed, the second one is decomposed.
+        let buffer = Buffer::new("caf\u{e9} cafe\u{301} cafe");
+        let normalize = SearchOptions {
+            normalize: true,
+            ..SearchOptions::default()
+        };
+        let search = |query, options| {
+            buffer
+                .search(query, options)
+                .into_iter()
+                .map(|range| {
+                    buffer.offset_for_anchor(&range.start).unwrap()
+                        ..buffer.offset_for_anchor(&range.end).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(search("caf\u{e9}", SearchOptions::default()), vec![0..4]);
+        assert_eq!(search("cafe\u{301}", SearchOptions::default()), vec![5..10]);
+        assert_eq!(search("caf\u{e9}", normalize), vec![0..4, 5..10]);
+        assert_eq!(search("cafe\u{301}", normalize), vec![0..4, 5..10]);
+        // A base character doesn't match when it is followed by a combining mark.
+        assert_eq!(search("cafe", normalize), vec![11..15]);
         assert_eq!(
-            text.offset_for_point(Point { row: 0, column: 4 }),
-            Err(Error::OffsetOutOfRange)
+            search(
+                "CAF\u{c9}",
+                SearchOptions {
+                    ignore_case: true,
+                    normalize: true,
+                }
+            ),
+            vec![0..4, 5..10]
         );
-        assert_eq!(text.offset_for_point(Point { row: 1, column: 0 }), Ok(4));
-        assert_eq!(text.offset_for_point(Point { row: 1, column: 1 }), Ok(5));
-        assert_eq!(text.offset_for_point(Point { row: 1, column: 5 }), Ok(9));
+
         assert_eq!(
-            text.offset_for_point(Point { row: 1, column: 6 }),
-            Err(Error::OffsetOutOfRange)
+            buffer.find_from(Point::new(0, 1), "caf\u{e9}", normalize),
+            Some(Point::new(0, 5)..Point::new(0, 10))
+        );
+        assert_eq!(
+            buffer.rfind_from(Point::new(0, 15), "cafe\u{301}", normalize),
+            Some(Point::new(0, 5)..Point::new(0, 10))
         );
 
-        let text = Text::from("abc");
-        assert_eq!(text.offset_for_point(Point { row: 0, column: 0 }), Ok(0));
-        assert_eq!(text.offset_for_point(Point { row: 0, column: 1 }), Ok(1));
-        assert_eq!(text.offset_for_point(Point { row: 0, column: 2 }), Ok(2));
-        assert_eq!(text.offset_for_point(Point { row: 0, column: 3 }), Ok(3));
+        // Decomposed Hangul syllables are made of starters that compose with each other.
+        let buffer = Buffer::new("\u{d55c} \u{1112}\u{1161}\u{11ab} \u{1112}\u{1161}");
+        let search = |query, options| {
+            buffer
+                .search(query, options)
+                .into_iter()
+                .map(|range| {
+                    buffer.offset_for_anchor(&range.start).unwrap()
+                        ..buffer.offset_for_anchor(&range.end).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(search("\u{d55c}", normalize), vec![0..1, 2..5]);
         assert_eq!(
-            text.offset_for_point(Point { row: 0, column: 4 }),
-            Err(Error::OffsetOutOfRange)
+            search("\u{1112}\u{1161}\u{11ab}", normalize),
+            vec![0..1, 2..5]
+        );
+        assert_eq!(search("\u{1112}\u{1161}", normalize), vec![6..8]);
+        assert_eq!(
+            buffer.find_from(Point::new(0, 1), "\u{d55c}", normalize),
+            Some(Point::new(0, 2)..Point::new(0, 5))
+        );
+        assert_eq!(
+            buffer.rfind_from(Point::new(0, 8), "\u{d55c}", normalize),
+            Some(Point::new(0, 2)..Point::new(0, 5))
         );
     }
 
     #[test]
-    fn test_longest_row_in_range() {
-        for seed in 0..100 {
-            println!("{:?}", seed);
-            let mut rng = StdRng::from_seed(&[seed]);
-            let string = RandomCharIter(rng)
-                .take(rng.gen_range(1, 10))
-                .collect::<String>();
-            let text = Text::from(string.as_ref());
-
-            for _i in 0..10 {
-                let end = rng.gen_range(1, string.len() + 1);
-                let start = rng.gen_range(0, end);
+    fn test_replace_all() {
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+        let text = "foo Foo FOO fOo f\u{f6}\u{f6} F\u{f6}\u{f6} F\u{d6}\u{d6} 1";
+        let ignore_case = SearchOptions {
+            ignore_case: true,
+            ..SearchOptions::default()
+        };
+        let preserve_case = SearchOptions {
+            preserve_case: true,
+            ..ignore_case
+        };
 
-                let mut cur_row = string[0..start].chars().filter(|c| *c == '\n').count() as u32;
-                let mut cur_row_len = 0;
-                let mut expected_longest_row = cur_row;
-                let mut expected_longest_row_len = cur_row_len;
-                for ch in string[start..end].chars() {
-                    if ch == '\n' {
-                        if cur_row_len > expected_longest_row_len {
-                            expected_longest_row = cur_row;
-                            expected_longest_row_len = cur_row_len;
-                        }
-                        cur_row += 1;
-                        cur_row_len = 0;
-                    } else {
-                        cur_row_len += 1;
-                    }
-                }
-                if cur_row_len > expected_longest_row_len {
-                    expected_longest_row = cur_row;
-                    expected_longest_row_len = cur_row_len;
-                }
+        let mut buffer = Buffer::new(text);
+        let ops = buffer.replace_all(
+            "foo",
+            "bar",
+            ignore_case,
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        assert_eq!(ops.len(), 1);
+        assert_eq!(
+            buffer.to_string(),
+            "bar bar bar bar f\u{f6}\u{f6} F\u{f6}\u{f6} F\u{d6}\u{d6} 1"
+        );
 
-                assert_eq!(
-                    text.longest_row_in_range(start..end),
-                    Ok((expected_longest_row, expected_longest_row_len))
-                );
-            }
-        }
+        // The replacement follows lower case, capitalized and all caps matches, and is left alone
+        // for mixed case ones.
+        let mut buffer = Buffer::new(text);
+        buffer.replace_all(
+            "foo",
+            "baR",
+            preserve_case,
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        assert_eq!(
+            buffer.to_string(),
+            "bar Bar BAR baR f\u{f6}\u{f6} F\u{f6}\u{f6} F\u{d6}\u{d6} 1"
+        );
+
+        // Casing is analyzed per character, so it works beyond ASCII.
+        let mut buffer = Buffer::new(text);
+        buffer.replace_all(
+            "f\u{f6}\u{f6}",
+            "\u{e9}t\u{e9}",
+            preserve_case,
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        assert_eq!(
+            buffer.to_string(),
+            "foo Foo FOO fOo \u{e9}t\u{e9} \u{c9}t\u{e9} \u{c9}T\u{c9} 1"
+        );
+        assert!(buffer
+            .replace_all(
+                "2",
+                "x",
+                preserve_case,
+                &mut local_clock,
+                &mut lamport_clock
+            )
+            .is_empty());
     }
 
     #[test]
-    fn test_fragment_ids() {
-        for seed in 0..10 {
-            use rand::{Rng, SeedableRng, StdRng};
-            let mut rng = StdRng::from_seed(&[seed]);
-
-            let mut ids = vec![FragmentId(Arc::new(vec![0])), FragmentId(Arc::new(vec![4]))];
-            for _i in 0..100 {
-                let index = rng.gen_range::<usize>(1, ids.len());
-
-                let left = ids[index - 1].clone();
-                let right = ids[index].clone();
-                ids.insert(index, FragmentId::between_with_max(&left, &right, 4));
+    fn test_matching_bracket() {
+        let buffer = Buffer::new("fn f(a: [u8; 2]) {\n    g((a), {b})\n}\n(}");
+        assert_eq!(
+            buffer.matching_bracket(Point::new(0, 4)),
+            Some(Point::new(0, 15))
+        );
+        assert_eq!(
+            buffer.matching_bracket(Point::new(0, 15)),
+            Some(Point::new(0, 4))
+        );
+        assert_eq!(
+            buffer.matching_bracket(Point::new(0, 8)),
+            Some(Point::new(0, 14))
+        );
+        assert_eq!(
+            buffer.matching_bracket(Point::new(0, 17)),
+            Some(Point::new(2, 0))
+        );
+        assert_eq!(
+            buffer.matching_bracket(Point::new(2, 0)),
+            Some(Point::new(0, 17))
+        );
+        assert_eq!(
+            buffer.matching_bracket(Point::new(1, 5)),
+            Some(Point::new(1, 14))
+        );
+        assert_eq!(
+            buffer.matching_bracket(Point::new(1, 14)),
+            Some(Point::new(1, 5))
+        );
+        assert_eq!(
+            buffer.matching_bracket(Point::new(1, 6)),
+            Some(Point::new(1, 8))
+        );
+        assert_eq!(
+            buffer.matching_bracket(Point::new(1, 13)),
+            Some(Point::new(1, 11))
+        );
 
-                let mut sorted_ids = ids.clone();
-                sorted_ids.sort();
-                assert_eq!(ids, sorted_ids);
-            }
-        }
+        // Not on a bracket, unbalanced brackets and out of range points.
+        assert_eq!(buffer.matching_bracket(Point::new(0, 0)), None);
+        assert_eq!(buffer.matching_bracket(Point::new(3, 0)), None);
+        assert_eq!(buffer.matching_bracket(Point::new(3, 1)), None);
+        assert_eq!(buffer.matching_bracket(Point::new(3, 2)), None);
+        assert_eq!(buffer.matching_bracket(Point::new(9, 0)), None);
     }
 
     #[test]
-    fn test_anchors() {
-        let mut buffer = Buffer::new("");
-        let replica_id = Uuid::from_u128(1);
-        let mut local_clock = time::Local::new(replica_id);
-        let mut lamport_clock = time::Lamport::new(replica_id);
-        buffer.edit(vec![0..0], "abc", &mut local_clock, &mut lamport_clock);
-        let left_anchor = buffer.anchor_before_offset(2).unwrap();
-        let right_anchor = buffer.anchor_after_offset(2).unwrap();
+    fn test_find_from() {
+        let buffer = Buffer::new("abc\nHello hello\nhello a\u{1F600}b");
+        let options = SearchOptions::default();
+        let ignore_case = SearchOptions {
+            ignore_case: true,
+            ..SearchOptions::default()
+        };
 
-        buffer.edit(vec![1..1], "def\n", &mut local_clock, &mut lamport_clock);
-        assert_eq!(buffer.to_string(), "adef\nbc");
-        assert_eq!(buffer.offset_for_anchor(&left_anchor).unwrap(), 6);
-        assert_eq!(buffer.offset_for_anchor(&right_anchor).unwrap(), 6);
         assert_eq!(
-            buffer.point_for_anchor(&left_anchor).unwrap(),
-            Point { row: 1, column: 1 }
+            buffer.find_from(Point::new(0, 0), "hello", options),
+            Some(Point::new(1, 6)..Point::new(1, 11))
         );
         assert_eq!(
-            buffer.point_for_anchor(&right_anchor).unwrap(),
-            Point { row: 1, column: 1 }
+            buffer.find_from(Point::new(0, 0), "hello", ignore_case),
+            Some(Point::new(1, 0)..Point::new(1, 5))
         );
-
-        buffer.edit(vec![2..3], "", &mut local_clock, &mut lamport_clock);
-        assert_eq!(buffer.to_string(), "adf\nbc");
-        assert_eq!(buffer.offset_for_anchor(&left_anchor).unwrap(), 5);
-        assert_eq!(buffer.offset_for_anchor(&right_anchor).unwrap(), 5);
         assert_eq!(
-            buffer.point_for_anchor(&left_anchor).unwrap(),
-            Point { row: 1, column: 1 }
+            buffer.find_from(Point::new(1, 7), "hello", options),
+            Some(Point::new(2, 0)..Point::new(2, 5))
         );
         assert_eq!(
-            buffer.point_for_anchor(&right_anchor).unwrap(),
-            Point { row: 1, column: 1 }
+            buffer.find_from(Point::new(0, 0), "\u{1F600}b", options),
+            Some(Point::new(2, 7)..Point::new(2, 10))
         );
+        assert_eq!(buffer.find_from(Point::new(2, 1), "hello", options), None);
+        assert_eq!(buffer.find_from(Point::new(0, 0), "", options), None);
+        assert_eq!(buffer.find_from(Point::new(9, 0), "hello", options), None);
 
-        buffer.edit(vec![5..5], "ghi\n", &mut local_clock, &mut lamport_clock);
-        assert_eq!(buffer.to_string(), "adf\nbghi\nc");
-        assert_eq!(buffer.offset_for_anchor(&left_anchor).unwrap(), 5);
-        assert_eq!(buffer.offset_for_anchor(&right_anchor).unwrap(), 9);
         assert_eq!(
-            buffer.point_for_anchor(&left_anchor).unwrap(),
-            Point { row: 1, column: 1 }
+            buffer.rfind_from(buffer.max_point(), "hello", options),
+            Some(Point::new(2, 0)..Point::new(2, 5))
         );
         assert_eq!(
-            buffer.point_for_anchor(&right_anchor).unwrap(),
-            Point { row: 2, column: 0 }
+            buffer.rfind_from(Point::new(2, 4), "hello", options),
+            Some(Point::new(1, 6)..Point::new(1, 11))
+        );
+        assert_eq!(
+            buffer.rfind_from(Point::new(1, 10), "hello", ignore_case),
+            Some(Point::new(1, 0)..Point::new(1, 5))
         );
+        assert_eq!(buffer.rfind_from(Point::new(1, 4), "hello", options), None);
+        assert_eq!(buffer.rfind_from(buffer.max_point(), "", options), None);
+    }
 
-        buffer.edit(vec![7..9], "", &mut local_clock, &mut lamport_clock);
-        assert_eq!(buffer.to_string(), "adf\nbghc");
-        assert_eq!(buffer.offset_for_anchor(&left_anchor).unwrap(), 5);
-        assert_eq!(buffer.offset_for_anchor(&right_anchor).unwrap(), 7);
+    #[test]
+    fn test_random_rfind_from() {
+        for seed in 0..20 {
+            let mut rng = StdRng::from_seed(&[seed]);
+            let mut local_clock = time::Local::new(Uuid::from_u128(1));
+            let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+
+            // Matches straddle the blocks the text is read in, and fragment boundaries within them.
+            let mut buffer = Buffer::new("");
+            while buffer.len() < 3 * RFIND_BLOCK_LEN {
+                let offset = rng.gen_range(0, buffer.len() + 1);
+                let text = (0..rng.gen_range(1, 20))
+                    .map(|_| *rng.choose(&['a', 'b']).unwrap())
+                    .collect::<String>();
+                buffer.edit(
+                    Some(offset..offset),
+                    text,
+                    &mut local_clock,
+                    &mut lamport_clock,
+                );
+            }
+            let text = buffer.to_string();
+
+            for _ in 0..10 {
+                let end = rng.gen_range(0, text.len() + 1);
+                let query = (0..rng.gen_range(1, 8))
+                    .map(|_| *rng.choose(&['a', 'b']).unwrap())
+                    .collect::<String>();
+                let expected = text[..end].rfind(&query).map(|start| {
+                    buffer.point_for_offset(start).unwrap()
+                        ..buffer.point_for_offset(start + query.len()).unwrap()
+                });
+                let end = buffer.point_for_offset(end).unwrap();
+                assert_eq!(
+                    buffer.rfind_from(end, &query, SearchOptions::default()),
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_operation_stats() {
+        let replica_1 = Uuid::from_u128(1);
+        let replica_2 = Uuid::from_u128(2);
+        let mut buffer = Buffer::new("abc");
+        let mut local_clock_1 = time::Local::new(replica_1);
+        let mut lamport_clock_1 = time::Lamport::new(replica_1);
+        let mut local_clock_2 = time::Local::new(replica_2);
+        let mut lamport_clock_2 = time::Lamport::new(replica_2);
+        assert!(buffer.operation_stats().is_empty());
+
+        buffer.edit(Some(3..3), "dé", &mut local_clock_1, &mut lamport_clock_1);
+        buffer.edit(Some(0..0), "xy", &mut local_clock_1, &mut lamport_clock_1);
+        buffer.edit(Some(1..4), "", &mut local_clock_2, &mut lamport_clock_2);
+        buffer.edit(Some(0..1), "z", &mut local_clock_2, &mut lamport_clock_2);
+        assert_eq!(buffer.to_string(), "zcdé");
+
+        let stats = buffer.operation_stats();
+        assert_eq!(stats.len(), 2);
         assert_eq!(
-            buffer.point_for_anchor(&left_anchor).unwrap(),
-            Point { row: 1, column: 1 }
+            stats[&replica_1],
+            OpStats {
+                insertions: 2,
+                deletions: 0,
+                bytes: 5,
+            }
         );
         assert_eq!(
-            buffer.point_for_anchor(&right_anchor).unwrap(),
-            Point { row: 1, column: 3 }
+            stats[&replica_2],
+            OpStats {
+                insertions: 1,
+                deletions: 2,
+                bytes: 1,
+            }
         );
 
-        // Ensure anchoring to a point is equivalent to anchoring to an offset.
-        assert_eq!(
-            buffer.anchor_before_point(Point { row: 0, column: 0 }),
-            buffer.anchor_before_offset(0)
+        let text = Text::from("a\u{E9}\u{20AC}\u{1F600}");
+        assert_eq!(utf8_len(&text.code_units), 10);
+        assert_eq!(utf8_len(&[0xD83D, 0x61, 0xDE00]), 7);
+    }
+
+    #[test]
+    fn test_persistent_anchors() {
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+
+        let mut buffer = Buffer::new("abc");
+        let mut ops = buffer.edit(Some(1..1), "xyz\n", &mut local_clock, &mut lamport_clock);
+        let bookmark = buffer.anchor_before_offset(3).unwrap().to_persistent();
+        let start = buffer.anchor_before_offset(0).unwrap().to_persistent();
+        assert_eq!(buffer.resolve_persistent(&bookmark), Ok(Point::new(0, 3)));
+
+        // The bookmark resolves against a buffer rebuilt from the same operations.
+        let mut reloaded = Buffer::new("abc");
+        let mut reloaded_local_clock = time::Local::new(Uuid::from_u128(2));
+        let mut reloaded_lamport_clock = time::Lamport::new(Uuid::from_u128(2));
+        reloaded
+            .apply_ops(
+                ops.drain(..),
+                &mut reloaded_local_clock,
+                &mut reloaded_lamport_clock,
+            )
+            .unwrap();
+        assert_eq!(reloaded.resolve_persistent(&bookmark), Ok(Point::new(0, 3)));
+        assert_eq!(reloaded.resolve_persistent(&start), Ok(Point::new(0, 0)));
+
+        // Deleting the text around the bookmark moves it to where the text used to be.
+        reloaded.edit(
+            Some(2..4),
+            "",
+            &mut reloaded_local_clock,
+            &mut reloaded_lamport_clock,
         );
+        assert_eq!(reloaded.to_string(), "ax\nbc");
+        assert_eq!(reloaded.resolve_persistent(&bookmark), Ok(Point::new(0, 2)));
+
+        let unknown = PersistentAnchor::Middle {
+            replica_id: Uuid::from_u128(3),
+            insertion: 1,
+            offset: 0,
+            bias: AnchorBias::Left,
+        };
+        assert!(reloaded.resolve_persistent(&unknown).is_err());
         assert_eq!(
-            buffer.anchor_before_point(Point { row: 0, column: 1 }),
-            buffer.anchor_before_offset(1)
+            buffer.resolve_persistent(&PersistentAnchor::End),
+            Ok(Point::new(1, 2))
         );
+    }
+
+    #[test]
+    fn test_resolve_anchors_cached() {
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+
+        let mut buffer = Buffer::new("abc\ndef");
+        let anchors = vec![
+            buffer.anchor_before_offset(1).unwrap(),
+            buffer.anchor_after_offset(5).unwrap(),
+        ];
+        let points = buffer.resolve_anchors_cached(&anchors).unwrap();
+        assert_eq!(&*points, &[Point::new(0, 1), Point::new(1, 1)]);
+        assert!(Arc::ptr_eq(
+            &points,
+            &buffer.resolve_anchors_cached(&anchors).unwrap()
+        ));
+
+        // Resolving a different set of anchors doesn't return the cached points.
+        let other_points = buffer.resolve_anchors_cached(&anchors[0..1]).unwrap();
+        assert_eq!(&*other_points, &[Point::new(0, 1)]);
+
+        let points = buffer.resolve_anchors_cached(&anchors).unwrap();
+        buffer.edit(Some(0..0), "x\n", &mut local_clock, &mut lamport_clock);
+        let new_points = buffer.resolve_anchors_cached(&anchors).unwrap();
+        assert!(!Arc::ptr_eq(&points, &new_points));
+        assert_eq!(&*new_points, &[Point::new(1, 1), Point::new(2, 1)]);
+
+        buffer.set_cache_resolved_anchors(false);
+        let points = buffer.resolve_anchors_cached(&anchors).unwrap();
+        assert!(!Arc::ptr_eq(
+            &points,
+            &buffer.resolve_anchors_cached(&anchors).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_byte_and_char_offsets() {
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+
+        let mut buffer = Buffer::new("a\u{e9}\n\u{1F600}b");
+        buffer.edit(Some(1..1), "xy", &mut local_clock, &mut lamport_clock);
+        buffer.edit(Some(0..1), "", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "xy\u{e9}\n\u{1F600}b");
+
+        let byte_offsets = [0, 1, 2, 4, 5, 9, 10];
+        for (char_offset, byte_offset) in byte_offsets.iter().enumerate() {
+            assert_eq!(buffer.byte_to_char(*byte_offset), Ok(char_offset));
+            assert_eq!(buffer.char_to_byte(char_offset), Ok(*byte_offset));
+        }
+
+        // Byte offsets inside of a multi-byte character round up to the end of the character.
+        assert_eq!(buffer.byte_to_char(3), Ok(3));
+        assert_eq!(buffer.byte_to_char(7), Ok(5));
+        assert_eq!(buffer.byte_to_char(11), Err(Error::OffsetOutOfRange));
+        assert_eq!(buffer.char_to_byte(7), Err(Error::OffsetOutOfRange));
+
+        assert_eq!(buffer.char_count(Point::new(0, 1)..Point::new(1, 3)), Ok(5));
+        assert_eq!(buffer.char_count(Point::new(1, 0)..Point::new(1, 0)), Ok(0));
         assert_eq!(
-            buffer.anchor_before_point(Point { row: 0, column: 2 }),
-            buffer.anchor_before_offset(2)
+            buffer.char_count(Point::new(0, 0)..Point::new(2, 0)),
+            Err(Error::OffsetOutOfRange)
         );
-        assert_eq!(
-            buffer.anchor_before_point(Point { row: 0, column: 3 }),
-            buffer.anchor_before_offset(3)
+    }
+
+    #[test]
+    fn test_line_offsets() {
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+
+        let mut buffer = Buffer::new("a\r\nb\u{e9}\nc\r\n\nd");
+        assert_eq!(buffer.line_offsets(), vec![0, 3, 7, 10, 11]);
+
+        buffer.edit(
+            Some(0..0),
+            "\u{1F600}\r\n",
+            &mut local_clock,
+            &mut lamport_clock,
         );
+        buffer.edit(Some(7..8), "", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "\u{1F600}\r\na\r\n\u{e9}\nc\r\n\nd");
+        assert_eq!(buffer.line_offsets(), vec![0, 6, 9, 12, 15, 16]);
+        assert_eq!(buffer.line_offsets_in_range(1..3), Ok(vec![6, 9]));
+        assert_eq!(buffer.line_offsets_in_range(5..6), Ok(vec![16]));
+        assert_eq!(buffer.line_offsets_in_range(2..2), Ok(vec![]));
         assert_eq!(
-            buffer.anchor_before_point(Point { row: 1, column: 0 }),
-            buffer.anchor_before_offset(4)
+            buffer.line_offsets_in_range(4..7),
+            Err(Error::OffsetOutOfRange)
         );
+
+        assert_eq!(Buffer::new("").line_offsets(), vec![0]);
+        assert_eq!(Buffer::new("a\n").line_offsets(), vec![0, 2]);
+    }
+
+    #[test]
+    fn test_text_char_count() {
+        let text = Text::from("ab\u{e9}\u{1F600}\n".repeat(40));
+        for start in 0..=text.len() {
+            for end in start..=text.len() {
+                let expected_utf8_len = (start..end)
+                    .map(|offset| code_unit_char_count(&text.code_units, offset).utf8_len)
+                    .sum::<usize>();
+                assert_eq!(text.char_count(start..end).utf8_len, expected_utf8_len);
+            }
+        }
+        let whole = text.char_count(0..text.len());
+        assert_eq!(whole.utf8_len, utf8_len(&text.code_units));
+        assert_eq!(whole.code_points, text.to_string_lossy().chars().count());
+
+        // Splitting a surrogate pair across fragments doesn't inflate the counts.
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+        let mut buffer = Buffer::new("\u{1F600}");
+        buffer.edit(Some(1..1), "x", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.len_bytes(), 5);
+        assert_eq!(buffer.fragments.summary().code_points, 2);
+    }
+
+    #[test]
+    fn test_seek_by_dimension() {
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+
+        let mut buffer = Buffer::new("a\u{e9}\n\u{1F600}b");
+        buffer.edit(Some(1..1), "xy", &mut local_clock, &mut lamport_clock);
+        buffer.edit(Some(0..1), "", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "xy\u{e9}\n\u{1F600}b");
+        assert_eq!(buffer.len_bytes(), 10);
+
         assert_eq!(
-            buffer.anchor_before_point(Point { row: 1, column: 1 }),
-            buffer.anchor_before_offset(5)
+            buffer.seek_by_dimension(Utf8Offset(0)),
+            Ok(Point::new(0, 0))
         );
         assert_eq!(
-            buffer.anchor_before_point(Point { row: 1, column: 2 }),
-            buffer.anchor_before_offset(6)
+            buffer.seek_by_dimension(Utf8Offset(2)),
+            Ok(Point::new(0, 2))
         );
+        // Offsets inside of a multi-byte character round up to the end of the character.
         assert_eq!(
-            buffer.anchor_before_point(Point { row: 1, column: 3 }),
-            buffer.anchor_before_offset(7)
+            buffer.seek_by_dimension(Utf8Offset(3)),
+            Ok(Point::new(0, 3))
         );
         assert_eq!(
-            buffer.anchor_before_point(Point { row: 1, column: 4 }),
-            buffer.anchor_before_offset(8)
+            buffer.seek_by_dimension(Utf8Offset(5)),
+            Ok(Point::new(1, 0))
         );
-
-        // Comparison between anchors.
-        let anchor_at_offset_0 = buffer.anchor_before_offset(0).unwrap();
-        let anchor_at_offset_1 = buffer.anchor_before_offset(1).unwrap();
-        let anchor_at_offset_2 = buffer.anchor_before_offset(2).unwrap();
-
         assert_eq!(
-            buffer.cmp_anchors(&anchor_at_offset_0, &anchor_at_offset_0),
-            Ok(Ordering::Equal)
+            buffer.seek_by_dimension(Utf8Offset(6)),
+            Ok(Point::new(1, 2))
         );
         assert_eq!(
-            buffer.cmp_anchors(&anchor_at_offset_1, &anchor_at_offset_1),
-            Ok(Ordering::Equal)
+            buffer.seek_by_dimension(Utf8Offset(10)),
+            Ok(Point::new(1, 3))
         );
         assert_eq!(
-            buffer.cmp_anchors(&anchor_at_offset_2, &anchor_at_offset_2),
-            Ok(Ordering::Equal)
+            buffer.seek_by_dimension(Utf8Offset(11)),
+            Err(Error::OffsetOutOfRange)
         );
 
         assert_eq!(
-            buffer.cmp_anchors(&anchor_at_offset_0, &anchor_at_offset_1),
-            Ok(Ordering::Less)
+            buffer.seek_by_dimension(CodePointOffset(5)),
+            Ok(Point::new(1, 2))
         );
         assert_eq!(
-            buffer.cmp_anchors(&anchor_at_offset_1, &anchor_at_offset_2),
-            Ok(Ordering::Less)
+            buffer.seek_by_dimension(CodePointOffset(6)),
+            Ok(Point::new(1, 3))
         );
+        assert_eq!(buffer.seek_by_dimension(4_usize), Ok(Point::new(1, 0)));
+        assert_eq!(buffer.seek_by_dimension(Row(0)), Ok(Point::new(0, 0)));
+        assert_eq!(buffer.seek_by_dimension(Row(1)), Ok(Point::new(1, 0)));
         assert_eq!(
-            buffer.cmp_anchors(&anchor_at_offset_0, &anchor_at_offset_2),
-            Ok(Ordering::Less)
+            buffer.seek_by_dimension(Row(2)),
+            Err(Error::OffsetOutOfRange)
         );
+    }
 
+    #[test]
+    fn test_trailing_whitespace_ranges() {
+        let buffer = Buffer::new("a \t\nb\n  \n\tc\t \r\n\n d  ");
         assert_eq!(
-            buffer.cmp_anchors(&anchor_at_offset_1, &anchor_at_offset_0),
-            Ok(Ordering::Greater)
+            buffer.trailing_whitespace_ranges(),
+            vec![
+                Point::new(0, 1)..Point::new(0, 3),
+                Point::new(2, 0)..Point::new(2, 2),
+                Point::new(3, 2)..Point::new(3, 4),
+                Point::new(5, 2)..Point::new(5, 4),
+            ]
+        );
+        assert!(Buffer::new("a\nb\n")
+            .trailing_whitespace_ranges()
+            .is_empty());
+        assert!(Buffer::new("").trailing_whitespace_ranges().is_empty());
+    }
+
+    #[test]
+    fn test_indentation() {
+        let buffer = Buffer::new("fn a() {\n\tif b {\n  \t  c();\n\n    \n\t}\n}\n");
+        assert_eq!(buffer.indent_for_row(0, 4), Ok(0));
+        assert_eq!(buffer.indent_for_row(1, 4), Ok(4));
+        assert_eq!(buffer.indent_for_row(2, 4), Ok(6));
+        assert_eq!(buffer.indent_for_row(2, 8), Ok(10));
+        assert_eq!(buffer.indent_for_row(4, 4), Ok(4));
+        assert_eq!(buffer.indent_for_row(7, 4), Ok(0));
+        assert_eq!(buffer.indent_for_row(8, 4), Err(Error::OffsetOutOfRange));
+
+        // Blank and whitespace-only rows don't contribute their indentation.
+        assert_eq!(buffer.suggested_indent(0, 4), 0);
+        assert_eq!(buffer.suggested_indent(2, 4), 4);
+        assert_eq!(buffer.suggested_indent(3, 4), 6);
+        assert_eq!(buffer.suggested_indent(5, 4), 6);
+        assert_eq!(buffer.suggested_indent(6, 4), 4);
+        assert_eq!(buffer.suggested_indent(100, 4), 0);
+    }
+
+    #[test]
+    fn test_changed_lines() {
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+        let mut buffer = Buffer::new("a\nb\nc\nd\ne\nf");
+        buffer.edit(Some(0..1), "A", &mut local_clock, &mut lamport_clock);
+
+        let version = buffer.version.clone();
+        assert_eq!(buffer.changed_lines(&version), vec![]);
+
+        buffer.edit(Some(2..2), "X", &mut local_clock, &mut lamport_clock);
+        buffer.edit(Some(6..8), "", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.to_string(), "A\nXb\nc\ne\nf");
+        assert_eq!(buffer.changed_lines(&version), vec![1..3]);
+
+        buffer.edit(Some(10..10), "Y\nZ", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.changed_lines(&version), vec![1..3, 4..6]);
+        assert_eq!(buffer.changed_lines(&time::Global::new()), vec![0..3, 4..6]);
+        assert_eq!(buffer.changed_lines(&buffer.version), vec![]);
+    }
+
+    #[test]
+    fn test_edit_from_reader() {
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+        let mut bytes = "caf\u{e9} \u{20ac}5\n\u{1F600}!".as_bytes().to_vec();
+        bytes.extend_from_slice(&[0xFF, b'x', 0xE2, 0x82, b'y', 0xF0, 0x9F]);
+        let expected_insertion = String::from_utf8_lossy(&bytes);
+
+        for capacity in 1..8 {
+            let mut buffer = Buffer::new("ab\ncd");
+            let mut replica = buffer.clone();
+            let ops = buffer
+                .edit_from_reader(
+                    Point::new(0, 1)..Point::new(1, 1),
+                    io::BufReader::with_capacity(capacity, bytes.as_slice()),
+                    &mut local_clock,
+                    &mut lamport_clock,
+                )
+                .unwrap();
+            assert_eq!(
+                buffer.to_string(),
+                format!("a{}d", expected_insertion),
+                "capacity {}",
+                capacity
+            );
+
+            replica
+                .apply_ops(
+                    ops,
+                    &mut time::Local::new(Uuid::from_u128(2)),
+                    &mut time::Lamport::new(Uuid::from_u128(2)),
+                )
+                .unwrap();
+            assert_eq!(replica.to_string(), buffer.to_string());
+        }
+
+        let mut buffer = Buffer::new("abc");
+        assert_eq!(
+            buffer.edit_from_reader(
+                Point::new(0, 2)..Point::new(0, 1),
+                io::empty(),
+                &mut local_clock,
+                &mut lamport_clock,
+            ),
+            Err(Error::OffsetOutOfRange)
         );
+        buffer
+            .edit_from_reader(
+                Point::new(0, 1)..Point::new(0, 2),
+                io::empty(),
+                &mut local_clock,
+                &mut lamport_clock,
+            )
+            .unwrap();
+        assert_eq!(buffer.to_string(), "ac");
+    }
+
+    #[test]
+    fn test_edit_indented() {
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+
+        let mut buffer = Buffer::new("fn main() {\n    foo();\n}");
+        buffer
+            .edit_indented(
+                Point::new(1, 10)..Point::new(1, 10),
+                "\nif x {\n    bar();\n\n}",
+                4,
+                &mut local_clock,
+                &mut lamport_clock,
+            )
+            .unwrap();
         assert_eq!(
-            buffer.cmp_anchors(&anchor_at_offset_2, &anchor_at_offset_1),
-            Ok(Ordering::Greater)
+            buffer.to_string(),
+            "fn main() {\n    foo();\n    if x {\n        bar();\n\n    }\n}"
         );
+
+        let mut buffer = Buffer::new("\tfoo");
+        buffer
+            .edit_indented(
+                Point::new(0, 1)..Point::new(0, 4),
+                "a\r\n  b\r\n\tc",
+                4,
+                &mut local_clock,
+                &mut lamport_clock,
+            )
+            .unwrap();
+        assert_eq!(buffer.to_string(), "\ta\r\n\t  b\r\n\t\tc");
+
+        let mut buffer = Buffer::new("foo");
+        buffer
+            .edit_indented(
+                Point::new(0, 3)..Point::new(0, 3),
+                "\n\tbar",
+                4,
+                &mut local_clock,
+                &mut lamport_clock,
+            )
+            .unwrap();
+        assert_eq!(buffer.to_string(), "foo\n\tbar");
+
         assert_eq!(
-            buffer.cmp_anchors(&anchor_at_offset_2, &anchor_at_offset_0),
-            Ok(Ordering::Greater)
+            buffer.edit_indented(
+                Point::new(5, 0)..Point::new(5, 0),
+                "x",
+                4,
+                &mut local_clock,
+                &mut lamport_clock,
+            ),
+            Err(Error::OffsetOutOfRange)
         );
     }
 
     #[test]
-    fn test_anchors_at_start_and_end() {
-        let mut buffer = Buffer::new("");
-        let replica_id = Uuid::from_u128(1);
-        let mut local_clock = time::Local::new(replica_id);
-        let mut lamport_clock = time::Lamport::new(replica_id);
-        let before_start_anchor = buffer.anchor_before_offset(0).unwrap();
-        let after_end_anchor = buffer.anchor_after_offset(0).unwrap();
+    fn test_check_invariants() {
+        let mut buffer = Buffer::new("abcdef");
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+        assert_eq!(buffer.check_invariants(), Ok(()));
 
-        buffer.edit(vec![0..0], "abc", &mut local_clock, &mut lamport_clock);
-        assert_eq!(buffer.to_string(), "abc");
-        assert_eq!(buffer.offset_for_anchor(&before_start_anchor).unwrap(), 0);
-        assert_eq!(buffer.offset_for_anchor(&after_end_anchor).unwrap(), 3);
+        buffer.edit(
+            vec![1..3, 4..4],
+            "xyz",
+            &mut local_clock,
+            &mut lamport_clock,
+        );
+        buffer.edit(vec![0..2], "", &mut local_clock, &mut lamport_clock);
+        assert_eq!(buffer.check_invariants(), Ok(()));
+
+        let mut corrupted = buffer.clone();
+        corrupted.version = time::Global::new();
+        assert!(corrupted
+            .check_invariants()
+            .unwrap_err()
+            .contains("not observed"));
+
+        let mut corrupted = buffer.clone();
+        corrupted.insertion_splits.insert(
+            local_clock.tick(),
+            btree::Tree::from_item(InsertionSplit {
+                extent: 1,
+                fragment_id: FragmentId::max_value(),
+            }),
+        );
+        assert!(corrupted
+            .check_invariants()
+            .unwrap_err()
+            .contains("missing fragment"));
+    }
 
-        let after_start_anchor = buffer.anchor_after_offset(0).unwrap();
-        let before_end_anchor = buffer.anchor_before_offset(3).unwrap();
+    #[test]
+    fn test_memory_usage() {
+        let mut buffer = Buffer::new("abcdef");
+        let mut local_clock = time::Local::new(Uuid::from_u128(1));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(1));
+
+        let report = buffer.memory_usage();
+        assert_eq!(report.tombstone_bytes, 0);
+        assert_eq!(report.operation_log_bytes, 0);
+        assert_eq!(report.version_vector_len, 0);
+
+        buffer.edit(vec![1..3], "", &mut local_clock, &mut lamport_clock);
+        let report_after_delete = buffer.memory_usage();
+        assert_eq!(
+            report_after_delete.tombstone_bytes,
+            mem::size_of::<Fragment>() + 2 * mem::size_of::<u16>()
+        );
+        assert_eq!(report_after_delete.version_vector_len, 1);
 
-        buffer.edit(vec![3..3], "def", &mut local_clock, &mut lamport_clock);
-        buffer.edit(vec![0..0], "ghi", &mut local_clock, &mut lamport_clock);
-        assert_eq!(buffer.to_string(), "ghiabcdef");
-        assert_eq!(buffer.offset_for_anchor(&before_start_anchor).unwrap(), 0);
-        assert_eq!(buffer.offset_for_anchor(&after_start_anchor).unwrap(), 3);
-        assert_eq!(buffer.offset_for_anchor(&before_end_anchor).unwrap(), 6);
-        assert_eq!(buffer.offset_for_anchor(&after_end_anchor).unwrap(), 9);
+        let mut remote_buffer = Buffer::new("abcdef");
+        let mut remote_local_clock = time::Local::new(Uuid::from_u128(2));
+        let mut remote_lamport_clock = time::Lamport::new(Uuid::from_u128(2));
+        let ops = remote_buffer.edit(
+            vec![0..0],
+            "123",
+            &mut remote_local_clock,
+            &mut remote_lamport_clock,
+        );
+        let ops = remote_buffer
+            .edit(
+                vec![1..2],
+                "x",
+                &mut remote_local_clock,
+                &mut remote_lamport_clock,
+            )
+            .into_iter()
+            .chain(ops)
+            .collect::<Vec<_>>();
+        buffer
+            .apply_ops(
+                ops.into_iter().take(1),
+                &mut local_clock,
+                &mut lamport_clock,
+            )
+            .unwrap();
+        assert_eq!(buffer.deferred_ops_len(), 1);
+        assert!(buffer.memory_usage().operation_log_bytes > 0);
     }
 
     #[test]
@@ -3036,6 +7723,9 @@ mod tests {
                 }
             }
 
+            for buffer in &buffers {
+                buffer.check_invariants().unwrap();
+            }
             for buffer in &buffers[1..] {
                 assert_eq!(buffer.to_string(), buffers[0].to_string());
                 assert_eq!(
@@ -1,11 +1,12 @@
 use crate::btree::{self, SeekBias};
-use crate::buffer::{self, Buffer, Point, Selection, SelectionSetId, Text};
-use crate::operation_queue::{self, OperationQueue};
+use crate::buffer::{self, Buffer, MemoryReport, OpStats, Point, Selection, SelectionSetId, Text};
+use crate::operation_queue::{self, Lane, OperationQueue};
 use crate::serialization;
 use crate::time;
 use crate::Error;
 use crate::Oid;
 use crate::ReplicaId;
+use crate::ReplicaIdExt;
 use flatbuffers::{FlatBufferBuilder, UnionWIPOffset, WIPOffset};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_derive::{Deserialize, Serialize};
@@ -13,11 +14,14 @@ use smallvec::SmallVec;
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
+use std::io;
+use std::mem;
 use std::ops::{Add, AddAssign, Range};
 use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
 pub const ROOT_FILE_ID: FileId = FileId::Base(0);
+pub const MAX_PRESENCE_LABEL_LEN: usize = 64;
 
 pub type Id = time::Lamport;
 
@@ -31,10 +35,17 @@ pub struct Epoch {
     parent_refs: btree::Tree<ParentRefValue>,
     child_refs: btree::Tree<ChildRefValue>,
     replica_locations: HashMap<ReplicaId, ReplicaLocation>,
+    replica_presences: HashMap<ReplicaId, ReplicaPresence>,
+    presences_last_update: time::Lamport,
+    order_keys: HashMap<FileId, OrderKey>,
+    name_conflicts: HashMap<FileId, (FileId, Arc<OsString>)>,
     version: time::Global,
     local_clock: time::Local,
     text_files: HashMap<FileId, TextFile>,
     deferred_ops: OperationQueue<Operation>,
+    history: OperationQueue<Operation>,
+    history_index: HistoryIndex,
+    history_pruned: bool,
 }
 
 pub struct Cursor<'a> {
@@ -61,6 +72,33 @@ pub struct CursorEntry {
     pub visible: bool,
 }
 
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StructuralChange {
+    Added {
+        file_id: FileId,
+        path: PathBuf,
+        file_type: FileType,
+    },
+    Removed {
+        file_id: FileId,
+        path: PathBuf,
+    },
+    Renamed {
+        file_id: FileId,
+        old_path: PathBuf,
+        new_path: PathBuf,
+    },
+}
+
+#[derive(Eq, PartialEq)]
+struct StructuralEntry {
+    file_id: FileId,
+    parent_id: FileId,
+    name: Arc<OsString>,
+    path: PathBuf,
+    file_type: FileType,
+}
+
 #[derive(Clone, Debug, Eq, Deserialize, PartialEq, Serialize)]
 pub struct DirEntry {
     pub depth: usize,
@@ -98,6 +136,17 @@ pub enum Operation {
         file_id: Option<FileId>,
         lamport_timestamp: time::Lamport,
     },
+    UpdatePresence {
+        replica_id: ReplicaId,
+        label: String,
+        color: u32,
+        lamport_timestamp: time::Lamport,
+    },
+    UpdateOrder {
+        file_id: FileId,
+        order_key: Vec<u8>,
+        lamport_timestamp: time::Lamport,
+    },
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -179,12 +228,40 @@ struct ReplicaLocation {
     lamport_timestamp: time::Lamport,
 }
 
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Presence {
+    pub label: String,
+    pub color: u32,
+}
+
+#[derive(Clone)]
+struct ReplicaPresence {
+    presence: Presence,
+    lamport_timestamp: time::Lamport,
+}
+
+#[derive(Clone)]
+struct OrderKey {
+    key: Vec<u8>,
+    lamport_timestamp: time::Lamport,
+}
+
 #[derive(Clone)]
 enum TextFile {
     Deferred(Vec<buffer::Operation>),
     Buffered(Buffer),
 }
 
+// Maps to the lamport timestamps of the history's operations, so that queries for a replica's
+// operations in a lane, the operations containing an edit or the structural changes to a file can
+// look them up in the history instead of scanning it. Timestamps are kept in ascending order.
+#[derive(Clone, Default)]
+struct HistoryIndex {
+    by_replica: HashMap<(ReplicaId, Lane), Vec<time::Lamport>>,
+    by_edit: HashMap<time::Local, time::Lamport>,
+    by_file: HashMap<FileId, Vec<time::Lamport>>,
+}
+
 impl Epoch {
     pub fn new(replica_id: ReplicaId, id: Id, head: Option<Oid>) -> Self {
         Self {
@@ -196,10 +273,267 @@ impl Epoch {
             parent_refs: btree::Tree::new(),
             child_refs: btree::Tree::new(),
             replica_locations: HashMap::new(),
+            replica_presences: HashMap::new(),
+            presences_last_update: time::Lamport::default(),
+            order_keys: HashMap::new(),
+            name_conflicts: HashMap::new(),
             version: time::Global::new(),
             local_clock: time::Local::new(replica_id),
             text_files: HashMap::new(),
             deferred_ops: OperationQueue::new(),
+            history: OperationQueue::new(),
+            history_index: HistoryIndex::default(),
+            history_pruned: false,
+        }
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn contains_replica(&self, replica_id: ReplicaId) -> bool {
+        self.id.replica_id == replica_id
+            || self.version.get(replica_id) > 0
+            || self.replica_locations.contains_key(&replica_id)
+            || self.replica_presences.contains_key(&replica_id)
+    }
+
+    pub fn rename_replica(&mut self, old: ReplicaId, new: ReplicaId) {
+        self.id.rename_replica(old, new);
+        for file_id in &mut self.base_entries_stack {
+            file_id.rename_replica(old, new);
+        }
+
+        let mut metadata_edits = self
+            .metadata
+            .items()
+            .into_iter()
+            .map(|mut metadata| {
+                metadata.file_id.rename_replica(old, new);
+                btree::Edit::Insert(metadata)
+            })
+            .collect::<Vec<_>>();
+        self.metadata = btree::Tree::new();
+        self.metadata.edit(&mut metadata_edits);
+
+        let mut parent_ref_edits = self
+            .parent_refs
+            .items()
+            .into_iter()
+            .map(|mut parent_ref| {
+                parent_ref.child_id.rename_replica(old, new);
+                parent_ref.timestamp.rename_replica(old, new);
+                if let Some((parent_id, _)) = parent_ref.parent.as_mut() {
+                    parent_id.rename_replica(old, new);
+                }
+                btree::Edit::Insert(parent_ref)
+            })
+            .collect::<Vec<_>>();
+        self.parent_refs = btree::Tree::new();
+        self.parent_refs.edit(&mut parent_ref_edits);
+
+        let mut child_ref_edits = self
+            .child_refs
+            .items()
+            .into_iter()
+            .map(|mut child_ref| {
+                child_ref.parent_id.rename_replica(old, new);
+                child_ref.child_id.rename_replica(old, new);
+                child_ref.timestamp.rename_replica(old, new);
+                btree::Edit::Insert(child_ref)
+            })
+            .collect::<Vec<_>>();
+        self.child_refs = btree::Tree::new();
+        self.child_refs.edit(&mut child_ref_edits);
+
+        let replica_locations = mem::replace(&mut self.replica_locations, HashMap::new());
+        for (mut replica_id, mut location) in replica_locations {
+            if replica_id == old {
+                replica_id = new;
+            }
+            if let Some(file_id) = location.file_id.as_mut() {
+                file_id.rename_replica(old, new);
+            }
+            location.lamport_timestamp.rename_replica(old, new);
+            self.replica_locations.insert(replica_id, location);
+        }
+
+        let replica_presences = mem::replace(&mut self.replica_presences, HashMap::new());
+        for (mut replica_id, mut presence) in replica_presences {
+            if replica_id == old {
+                replica_id = new;
+            }
+            presence.lamport_timestamp.rename_replica(old, new);
+            self.replica_presences.insert(replica_id, presence);
+        }
+        self.presences_last_update.rename_replica(old, new);
+
+        let order_keys = mem::replace(&mut self.order_keys, HashMap::new());
+        for (mut file_id, mut order_key) in order_keys {
+            file_id.rename_replica(old, new);
+            order_key.lamport_timestamp.rename_replica(old, new);
+            self.order_keys.insert(file_id, order_key);
+        }
+
+        let name_conflicts = mem::replace(&mut self.name_conflicts, HashMap::new());
+        for (mut file_id, (mut parent_id, name)) in name_conflicts {
+            file_id.rename_replica(old, new);
+            parent_id.rename_replica(old, new);
+            self.name_conflicts.insert(file_id, (parent_id, name));
+        }
+
+        let text_files = mem::replace(&mut self.text_files, HashMap::new());
+        for (mut file_id, mut text_file) in text_files {
+            file_id.rename_replica(old, new);
+            match &mut text_file {
+                TextFile::Deferred(operations) => {
+                    for operation in operations {
+                        operation.rename_replica(old, new);
+                    }
+                }
+                TextFile::Buffered(buffer) => buffer.rename_replica(old, new),
+            }
+            self.text_files.insert(file_id, text_file);
+        }
+
+        let deferred_ops = self
+            .deferred_ops
+            .drain()
+            .map(|mut op| {
+                op.rename_replica(old, new);
+                op
+            })
+            .collect();
+        self.deferred_ops.insert(deferred_ops);
+
+        let history = self
+            .history
+            .drain()
+            .map(|mut op| {
+                op.rename_replica(old, new);
+                op
+            })
+            .collect::<Vec<_>>();
+        self.history_index = HistoryIndex::default();
+        for op in &history {
+            self.history_index.insert(op);
+        }
+        self.history.insert(history);
+
+        self.version.rename_replica(old, new);
+        self.local_clock.rename_replica(old, new);
+    }
+
+    pub fn collect_garbage(&mut self, barrier: &time::Global) {
+        for text_file in self.text_files.values_mut() {
+            if let TextFile::Buffered(buffer) = text_file {
+                buffer.collect_garbage(barrier);
+            }
+        }
+    }
+
+    // Drops the operations that `barrier` observed from the history, along with the active
+    // location, presence and order updates that a later update superseded. Peers catching up and
+    // the queries for a file's or an edit's operations no longer see them.
+    pub fn prune_history(&mut self, barrier: &time::Global) {
+        let history = self.history.drain().collect::<Vec<_>>();
+        let len = history.len();
+        let history = history
+            .into_iter()
+            .filter(|op| match op {
+                Operation::UpdateActiveLocation {
+                    lamport_timestamp, ..
+                } => self
+                    .replica_locations
+                    .get(&lamport_timestamp.replica_id)
+                    .map_or(false, |location| {
+                        location.lamport_timestamp == *lamport_timestamp
+                    }),
+                Operation::UpdatePresence {
+                    replica_id,
+                    lamport_timestamp,
+                    ..
+                } => self
+                    .replica_presences
+                    .get(replica_id)
+                    .map_or(false, |presence| {
+                        presence.lamport_timestamp == *lamport_timestamp
+                    }),
+                Operation::UpdateOrder {
+                    file_id,
+                    lamport_timestamp,
+                    ..
+                } => self.order_keys.get(file_id).map_or(false, |order_key| {
+                    order_key.lamport_timestamp == *lamport_timestamp
+                }),
+                _ => op
+                    .local_timestamp()
+                    .map_or(true, |local_timestamp| !barrier.observed(local_timestamp)),
+            })
+            .collect::<Vec<_>>();
+        self.history_pruned |= history.len() < len;
+        self.history_index = HistoryIndex::default();
+        for op in &history {
+            self.history_index.insert(op);
+        }
+        self.history.insert(history);
+    }
+
+    // Rebuilds an open text file from its base text by replaying the file's operations in the
+    // history in lamport order. Replicas whose contents diverged despite applying the same
+    // operations end up with identical buffers once each of them resyncs the file. This needs
+    // every operation of the file, so it fails once the history has been pruned.
+    pub fn resync_text_file(
+        &mut self,
+        file_id: FileId,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<(), Error> {
+        if self.history_pruned {
+            return Err(Error::InvalidOperation);
+        }
+
+        let operations = self
+            .history
+            .iter()
+            .filter_map(|op| match op {
+                Operation::BufferOperation {
+                    file_id: op_file_id,
+                    operations,
+                    ..
+                } if op_file_id == file_id => Some(operations),
+                _ => None,
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            let buffer = buffer
+                .rebuild(operations, &mut self.local_clock, lamport_clock)
+                .map_err(|_| Error::InvalidOperation)?;
+            self.text_files.insert(file_id, TextFile::Buffered(buffer));
+            Ok(())
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
+    pub fn take_anchor_fallbacks(&self) -> Vec<(FileId, usize)> {
+        self.text_files
+            .iter()
+            .filter_map(|(file_id, text_file)| match text_file {
+                TextFile::Buffered(buffer) => match buffer.take_anchor_fallbacks() {
+                    0 => None,
+                    count => Some((*file_id, count)),
+                },
+                TextFile::Deferred(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn coalesce_fragments(&mut self) {
+        for text_file in self.text_files.values_mut() {
+            if let TextFile::Buffered(buffer) = text_file {
+                buffer.coalesce_fragments();
+            }
         }
     }
 
@@ -211,6 +545,70 @@ impl Epoch {
         }
     }
 
+    pub fn buffer_snapshot_at(
+        &self,
+        file_id: FileId,
+        version: &time::Global,
+    ) -> Result<Buffer, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            Ok(buffer.snapshot_at(version))
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
+    pub fn point_for_anchor(
+        &self,
+        file_id: FileId,
+        anchor: &buffer::Anchor,
+    ) -> Result<Point, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            buffer.point_for_anchor(anchor)
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
+    pub fn buffer_char_count(&self, file_id: FileId, range: Range<Point>) -> Result<usize, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            buffer.char_count(range)
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
+    pub fn buffer_len_bytes(&self, file_id: FileId) -> Result<usize, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            Ok(buffer.len_bytes())
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
+    pub fn buffer_ends_with_newline(&self, file_id: FileId) -> Result<bool, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            Ok(buffer.ends_with_newline())
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
+    pub fn export_buffer(
+        &self,
+        file_id: FileId,
+        ensure_trailing_newline: bool,
+    ) -> Result<String, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            if ensure_trailing_newline {
+                Ok(buffer.export_with_trailing_newline())
+            } else {
+                Ok(buffer.export())
+            }
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
     pub fn buffer_selections_last_update(
         &self,
         file_id: FileId,
@@ -226,6 +624,277 @@ impl Epoch {
         self.version.clone()
     }
 
+    // Files created after `version` that still exist, with their current paths, in the order they
+    // were created.
+    pub fn files_created_since(&self, version: &time::Global) -> Vec<(FileId, PathBuf)> {
+        self.history_since(version)
+            .into_iter()
+            .filter_map(|op| match op {
+                Operation::InsertMetadata {
+                    file_id,
+                    local_timestamp,
+                    ..
+                } if !version.observed(local_timestamp) => {
+                    self.path(file_id).map(|path| (file_id, path))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Files that existed at `version` and have been removed since, with the paths they had before
+    // being removed. Like `structural_diff`, this only reports entries that were removed
+    // themselves, since removing a directory implicitly removes its descendants.
+    pub fn files_removed_since(&self, version: &time::Global) -> Vec<(FileId, PathBuf)> {
+        let mut removed = Vec::new();
+        let mut reported = HashSet::new();
+        for op in self.history_since(version) {
+            if let Operation::UpdateParent {
+                child_id,
+                new_parent: None,
+                local_timestamp,
+                lamport_timestamp,
+            } = op
+            {
+                let existed = match child_id {
+                    FileId::Base(_) => true,
+                    FileId::New(creation) => version.observed(creation),
+                };
+                if existed
+                    && !version.observed(local_timestamp)
+                    && self.path(child_id).is_none()
+                    && reported.insert(child_id)
+                {
+                    let path = self
+                        .parent_before(child_id, lamport_timestamp)
+                        .and_then(|(parent_id, name)| {
+                            self.path(parent_id)
+                                .map(|parent_path| parent_path.join(name.as_ref()))
+                        })
+                        .or_else(|| self.base_path(child_id));
+                    if let Some(path) = path {
+                        removed.push((child_id, path));
+                    }
+                }
+            }
+        }
+        removed
+    }
+
+    // The last parent assigned to `file_id` by an operation preceding `lamport_timestamp`.
+    fn parent_before(
+        &self,
+        file_id: FileId,
+        lamport_timestamp: time::Lamport,
+    ) -> Option<(FileId, Arc<OsString>)> {
+        let timestamps = self.history_index.by_file.get(&file_id)?;
+        timestamps
+            .iter()
+            .rev()
+            .skip_while(|timestamp| **timestamp >= lamport_timestamp)
+            .filter_map(|timestamp| match self.history.get(*timestamp)? {
+                Operation::InsertMetadata { parent, .. } => parent,
+                Operation::UpdateParent { new_parent, .. } => new_parent,
+                _ => None,
+            })
+            .next()
+    }
+
+    // Entries are matched by file id, so a moved file is reported as renamed wherever it ends
+    // up. Only entries whose own parent or name changed are reported, since moving a directory
+    // implicitly moves its descendants. Removals come first, children before their parents,
+    // followed by renames and additions in tree order, so the changes can be applied in order.
+    pub fn structural_diff(&self, other: &Epoch) -> Vec<StructuralChange> {
+        let old_entries = self.structural_entries();
+        let new_entries = other.structural_entries();
+        let old_entries_by_id = old_entries
+            .iter()
+            .map(|entry| (entry.file_id, entry))
+            .collect::<HashMap<_, _>>();
+        let new_file_ids = new_entries
+            .iter()
+            .map(|entry| entry.file_id)
+            .collect::<HashSet<_>>();
+
+        let mut changes = Vec::new();
+        for entry in old_entries.iter().rev() {
+            if !new_file_ids.contains(&entry.file_id) {
+                changes.push(StructuralChange::Removed {
+                    file_id: entry.file_id,
+                    path: entry.path.clone(),
+                });
+            }
+        }
+        for entry in &new_entries {
+            match old_entries_by_id.get(&entry.file_id) {
+                Some(old_entry) => {
+                    if old_entry.parent_id != entry.parent_id || old_entry.name != entry.name {
+                        changes.push(StructuralChange::Renamed {
+                            file_id: entry.file_id,
+                            old_path: old_entry.path.clone(),
+                            new_path: entry.path.clone(),
+                        });
+                    }
+                }
+                None => changes.push(StructuralChange::Added {
+                    file_id: entry.file_id,
+                    path: entry.path.clone(),
+                    file_type: entry.file_type,
+                }),
+            }
+        }
+        changes
+    }
+
+    // Text files must be open on both epochs, or on neither, for their contents to compare equal,
+    // because the base text of an unopened file isn't available. Unopened files are equal when the
+    // same operations are deferred for them, regardless of the order in which they arrived.
+    pub fn state_eq(&self, other: &Epoch) -> bool {
+        let entries = self.structural_entries();
+        if self.head != other.head || entries != other.structural_entries() {
+            return false;
+        }
+
+        entries
+            .iter()
+            .filter(|entry| entry.file_type == FileType::Text)
+            .all(|entry| self.content_eq(other, entry.file_id))
+    }
+
+    fn content_eq(&self, other: &Epoch, file_id: FileId) -> bool {
+        let sorted = |ops: &[buffer::Operation]| {
+            let mut ops = ops.to_vec();
+            ops.sort_by_key(|op| operation_queue::Operation::timestamp(op));
+            ops
+        };
+        match (
+            self.text_files.get(&file_id),
+            other.text_files.get(&file_id),
+        ) {
+            (Some(TextFile::Buffered(buffer)), Some(TextFile::Buffered(other_buffer))) => {
+                buffer.hash_range(0..buffer.len()).ok()
+                    == other_buffer.hash_range(0..other_buffer.len()).ok()
+            }
+            (Some(TextFile::Deferred(ops)), Some(TextFile::Deferred(other_ops))) => {
+                sorted(ops) == sorted(other_ops)
+            }
+            (Some(TextFile::Deferred(ops)), None) | (None, Some(TextFile::Deferred(ops))) => {
+                ops.is_empty()
+            }
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    fn structural_entries(&self) -> Vec<StructuralEntry> {
+        let mut entries = Vec::new();
+        if let Some(mut cursor) = self.cursor() {
+            let mut parent_ids = vec![ROOT_FILE_ID];
+            loop {
+                let entry = cursor.entry().unwrap();
+                let visible = entry.visible;
+                parent_ids.truncate(entry.depth);
+                if visible {
+                    entries.push(StructuralEntry {
+                        file_id: entry.file_id,
+                        parent_id: *parent_ids.last().unwrap(),
+                        name: entry.name,
+                        path: cursor.path().unwrap().to_path_buf(),
+                        file_type: entry.file_type,
+                    });
+                    parent_ids.push(entry.file_id);
+                }
+                if !cursor.next(visible) {
+                    break;
+                }
+            }
+        }
+        entries
+    }
+
+    // Visible entries along with removed ones, so that a status view can show deletions. Removed
+    // directories are reported without their descendants.
+    pub fn statuses(&self) -> Vec<(PathBuf, FileType, FileStatus)> {
+        let mut statuses = Vec::new();
+        if let Some(mut cursor) = self.cursor() {
+            loop {
+                let entry = cursor.entry().unwrap();
+                if entry.visible || entry.status == FileStatus::Removed {
+                    statuses.push((
+                        cursor.path().unwrap().to_path_buf(),
+                        entry.file_type,
+                        entry.status,
+                    ));
+                }
+                if !cursor.next(entry.visible) {
+                    break;
+                }
+            }
+        }
+        aggregate_directory_statuses(&mut statuses);
+        statuses
+    }
+
+    // Statuses relative to a commit other than the head, whose entries are listed depth-first in
+    // `base_entries`, in tree order. Entries are matched by path, so a file moved since that
+    // commit is reported as removed from its old path and new at the other. Text files that exist
+    // at the same path in both have no status, since telling whether they were modified means
+    // comparing them with the commit's version. Directory statuses are not aggregated.
+    pub fn statuses_against(
+        &self,
+        base_entries: Vec<DirEntry>,
+    ) -> Result<Vec<(PathBuf, FileType, Option<FileStatus>)>, Error> {
+        let mut base_paths = HashMap::new();
+        let mut base_path = PathBuf::new();
+        let mut depth = 0;
+        for entry in base_entries {
+            if entry.depth == 0 || entry.depth > depth + 1 {
+                return Err(Error::InvalidDirEntry);
+            }
+            for _ in entry.depth - 1..depth {
+                base_path.pop();
+            }
+            base_path.push(&entry.name);
+            depth = entry.depth;
+            base_paths.insert(base_path.clone(), entry.file_type);
+        }
+
+        let mut statuses = Vec::new();
+        if let Some(mut cursor) = self.cursor() {
+            loop {
+                let entry = cursor.entry().unwrap();
+                if entry.visible {
+                    let path = cursor.path().unwrap().to_path_buf();
+                    let status = if base_paths.get(&path) == Some(&entry.file_type) {
+                        base_paths.remove(&path);
+                        if entry.file_type == FileType::Text {
+                            None
+                        } else {
+                            Some(FileStatus::Unchanged)
+                        }
+                    } else {
+                        Some(FileStatus::New)
+                    };
+                    statuses.push((path, entry.file_type, status));
+                }
+                if !cursor.next(entry.visible) {
+                    break;
+                }
+            }
+        }
+
+        for (path, file_type) in &base_paths {
+            if !path
+                .parent()
+                .map_or(false, |parent| base_paths.contains_key(parent))
+            {
+                statuses.push((path.clone(), *file_type, Some(FileStatus::Removed)));
+            }
+        }
+        statuses.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(statuses)
+    }
+
     pub fn cursor(&self) -> Option<Cursor> {
         let metadata_cursor = self.metadata.cursor();
         let parent_ref_cursor = self.parent_refs.cursor();
@@ -390,6 +1059,21 @@ impl Epoch {
             self.local_clock.observe(local_timestamp);
         }
         lamport_clock.observe(op.lamport_timestamp());
+        self.record_history(op.clone());
+
+        let placed_file_id = match &op {
+            Operation::InsertMetadata {
+                file_id,
+                parent: Some(_),
+                ..
+            } => Some(*file_id),
+            Operation::UpdateParent {
+                child_id,
+                new_parent: Some(_),
+                ..
+            } => Some(*child_id),
+            _ => None,
+        };
 
         match op {
             Operation::InsertMetadata {
@@ -544,11 +1228,76 @@ impl Epoch {
                         lamport_timestamp,
                     });
             }
+            Operation::UpdatePresence {
+                replica_id,
+                label,
+                color,
+                lamport_timestamp,
+            } => {
+                self.update_presence(replica_id, label, color, lamport_timestamp);
+            }
+            Operation::UpdateOrder {
+                file_id,
+                order_key,
+                lamport_timestamp,
+            } => {
+                if self.order_keys.get(&file_id).map_or(true, |order_key| {
+                    lamport_timestamp > order_key.lamport_timestamp
+                }) {
+                    self.order_keys.insert(
+                        file_id,
+                        OrderKey {
+                            key: order_key,
+                            lamport_timestamp,
+                        },
+                    );
+                }
+            }
+        }
+
+        if let Some(file_id) = placed_file_id {
+            self.record_name_conflicts(file_id);
         }
 
         Ok(())
     }
 
+    // Operations are applied in causal order and local operations never reuse a taken name, so a
+    // name shared by several visible children can only come from concurrent operations that didn't
+    // observe each other. Each of those children is remembered along with the name they competed
+    // for, before `fix_conflicts` gives all but one of them a unique name.
+    fn record_name_conflicts(&mut self, file_id: FileId) {
+        let mut parent_ref_cursor = self.parent_refs.cursor();
+        parent_ref_cursor.seek(&file_id, SeekBias::Left);
+        if let Some((parent_id, name)) = parent_ref_cursor.item().and_then(|r| r.parent) {
+            let mut cursor = self.child_refs.cursor();
+            cursor.seek(
+                &ChildRefKey {
+                    parent_id,
+                    name: name.clone(),
+                },
+                SeekBias::Left,
+            );
+            let mut child_ids = Vec::new();
+            while let Some(child_ref) = cursor.item() {
+                if child_ref.parent_id != parent_id || child_ref.name != name {
+                    break;
+                }
+                if child_ref.visible {
+                    child_ids.push(child_ref.child_id);
+                }
+                cursor.next();
+            }
+
+            if child_ids.len() > 1 {
+                for child_id in child_ids {
+                    self.name_conflicts
+                        .insert(child_id, (parent_id, name.clone()));
+                }
+            }
+        }
+    }
+
     fn can_apply_op(&self, op: &Operation) -> bool {
         match op {
             Operation::InsertMetadata { .. } => true,
@@ -557,6 +1306,8 @@ impl Epoch {
             Operation::UpdateActiveLocation { file_id, .. } => {
                 file_id.map_or(true, |file_id| self.metadata(file_id).is_ok())
             }
+            Operation::UpdatePresence { .. } => true,
+            Operation::UpdateOrder { file_id, .. } => self.metadata(*file_id).is_ok(),
         }
     }
 
@@ -671,6 +1422,128 @@ impl Epoch {
         }
     }
 
+    // Moves the file in front of `before` among its ordered siblings, or behind all of them. Only
+    // siblings that have been reordered at some point have a position; all others are listed after
+    // them in alphabetical order, so `before` has to be an ordered sibling.
+    pub fn reorder(
+        &mut self,
+        file_id: FileId,
+        before: Option<FileId>,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Operation, Error> {
+        self.check_file_id(file_id, None)?;
+        let mut parent_ref_cursor = self.parent_refs.cursor();
+        parent_ref_cursor.seek(&file_id, SeekBias::Left);
+        let (parent_id, _) = parent_ref_cursor
+            .item()
+            .and_then(|parent_ref| parent_ref.parent)
+            .ok_or(Error::InvalidFileId("file has been removed".into()))?;
+
+        let ordered_siblings = self
+            .ordered_children(parent_id)?
+            .into_iter()
+            .filter_map(|(child_id, _)| {
+                if child_id == file_id {
+                    None
+                } else {
+                    self.order_keys
+                        .get(&child_id)
+                        .map(|order_key| (child_id, order_key.key.as_slice()))
+                }
+            })
+            .collect::<Vec<_>>();
+        let (lo, hi) = if let Some(before) = before {
+            let index = ordered_siblings
+                .iter()
+                .position(|(child_id, _)| *child_id == before)
+                .ok_or(Error::InvalidFileId("not an ordered sibling".into()))?;
+            let lo = index.checked_sub(1).map(|index| ordered_siblings[index].1);
+            (lo, Some(ordered_siblings[index].1))
+        } else {
+            (ordered_siblings.last().map(|(_, key)| *key), None)
+        };
+
+        let operation = Operation::UpdateOrder {
+            file_id,
+            order_key: order_key_between(lo.unwrap_or(&[]), hi, lamport_clock.replica_id),
+            lamport_timestamp: lamport_clock.tick(),
+        };
+        self.apply_op(operation.clone(), lamport_clock).unwrap();
+        Ok(operation)
+    }
+
+    // The visible children of a directory, with ordered children first, followed by the rest in
+    // alphabetical order.
+    pub fn ordered_children(&self, dir_id: FileId) -> Result<Vec<(FileId, Arc<OsString>)>, Error> {
+        self.check_file_id(dir_id, Some(FileType::Directory))?;
+
+        let mut children = Vec::new();
+        let mut cursor = self.child_refs.cursor();
+        cursor.seek(&dir_id, SeekBias::Left);
+        while let Some(child_ref) = cursor.item() {
+            if child_ref.parent_id != dir_id {
+                break;
+            }
+            if child_ref.visible {
+                children.push((child_ref.child_id, child_ref.name));
+            }
+            cursor.next();
+        }
+
+        children.sort_by(
+            |(a, _), (b, _)| match (self.order_keys.get(a), self.order_keys.get(b)) {
+                (Some(a), Some(b)) => a.key.cmp(&b.key),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+        );
+        Ok(children)
+    }
+
+    // Paths that more than one file was created or moved to concurrently. All but one of these
+    // files were renamed by appending "~" to their name when the conflict was detected, and are
+    // reported here until they're removed or given a name of their own.
+    pub fn duplicate_paths(&self) -> Vec<(PathBuf, Vec<FileId>)> {
+        let mut duplicates: HashMap<PathBuf, Vec<FileId>> = HashMap::new();
+        for (file_id, (parent_id, original_name)) in &self.name_conflicts {
+            let mut cursor = self.parent_refs.cursor();
+            cursor.seek(file_id, SeekBias::Left);
+            if let Some((cur_parent_id, name)) = cursor.item().and_then(|r| r.parent) {
+                let name = name.to_string_lossy();
+                let original_name_str = original_name.to_string_lossy();
+                if cur_parent_id == *parent_id
+                    && name.starts_with(original_name_str.as_ref())
+                    && name[original_name_str.len()..].chars().all(|c| c == '~')
+                {
+                    if let Some(path) = self.path(*file_id) {
+                        duplicates
+                            .entry(path.with_file_name(original_name.as_os_str()))
+                            .or_insert_with(Vec::new)
+                            .push(*file_id);
+                    }
+                }
+            }
+        }
+
+        let mut duplicates = duplicates
+            .into_iter()
+            .filter(|(_, file_ids)| file_ids.len() > 1)
+            .map(|(path, mut file_ids)| {
+                file_ids.sort();
+                (path, file_ids)
+            })
+            .collect::<Vec<_>>();
+        duplicates.sort();
+        duplicates
+    }
+
+    pub fn order_key(&self, file_id: FileId) -> Option<&[u8]> {
+        self.order_keys
+            .get(&file_id)
+            .map(|order_key| order_key.key.as_slice())
+    }
+
     pub fn remove(
         &mut self,
         file_id: FileId,
@@ -688,6 +1561,58 @@ impl Epoch {
         Ok(operation)
     }
 
+    // Removes every directory that doesn't contain any visible entries, including directories that
+    // only contain other empty directories. The root is never removed.
+    pub fn prune_empty_dirs(&mut self, lamport_clock: &mut time::Lamport) -> Vec<Operation> {
+        // Each entry on the stack is a directory we are visiting, along with its depth and whether
+        // we found anything inside of it that isn't going to be pruned.
+        fn close_dir(stack: &mut Vec<(FileId, usize, bool)>, dirs_to_remove: &mut Vec<FileId>) {
+            let (dir_id, _, has_entries) = stack.pop().unwrap();
+            if !has_entries {
+                dirs_to_remove.push(dir_id);
+            } else if let Some(parent) = stack.last_mut() {
+                parent.2 = true;
+            }
+        }
+
+        let mut dirs_to_remove = Vec::new();
+        if let Some(mut cursor) = self.cursor() {
+            let mut stack = Vec::new();
+            loop {
+                let entry = cursor.entry().unwrap();
+                let advanced = if entry.visible {
+                    while stack
+                        .last()
+                        .map_or(false, |(_, depth, _)| *depth >= entry.depth)
+                    {
+                        close_dir(&mut stack, &mut dirs_to_remove);
+                    }
+                    if entry.file_type == FileType::Directory {
+                        stack.push((entry.file_id, entry.depth, false));
+                    } else if let Some(parent) = stack.last_mut() {
+                        parent.2 = true;
+                    }
+                    cursor.next(true)
+                } else {
+                    cursor.next(false)
+                };
+
+                if !advanced {
+                    break;
+                }
+            }
+
+            while !stack.is_empty() {
+                close_dir(&mut stack, &mut dirs_to_remove);
+            }
+        }
+
+        dirs_to_remove
+            .into_iter()
+            .map(|dir_id| self.remove(dir_id, lamport_clock).unwrap())
+            .collect()
+    }
+
     pub fn set_active_location(
         &mut self,
         file_id: Option<FileId>,
@@ -705,10 +1630,12 @@ impl Epoch {
                 file_id,
             },
         );
-        Ok(Operation::UpdateActiveLocation {
+        let operation = Operation::UpdateActiveLocation {
             file_id,
             lamport_timestamp,
-        })
+        };
+        self.record_history(operation.clone());
+        Ok(operation)
     }
 
     pub fn replica_location(&self, replica_id: ReplicaId) -> Option<FileId> {
@@ -725,6 +1652,73 @@ impl Epoch {
             })
     }
 
+    pub fn set_presence<L>(
+        &mut self,
+        replica_id: ReplicaId,
+        label: L,
+        color: u32,
+        lamport_clock: &mut time::Lamport,
+    ) -> Operation
+    where
+        L: Into<String>,
+    {
+        let mut label = label.into();
+        truncate_label(&mut label);
+        let lamport_timestamp = lamport_clock.tick();
+        self.update_presence(replica_id, label.clone(), color, lamport_timestamp);
+        let operation = Operation::UpdatePresence {
+            replica_id,
+            label,
+            color,
+            lamport_timestamp,
+        };
+        self.record_history(operation.clone());
+        operation
+    }
+
+    pub fn presence(&self, replica_id: ReplicaId) -> Option<&Presence> {
+        self.replica_presences
+            .get(&replica_id)
+            .map(|presence| &presence.presence)
+    }
+
+    pub fn presences<'a>(&'a self) -> impl Iterator<Item = (ReplicaId, &'a Presence)> + 'a {
+        self.replica_presences
+            .iter()
+            .map(|(replica_id, presence)| (*replica_id, &presence.presence))
+    }
+
+    pub fn presences_last_update(&self) -> time::Lamport {
+        self.presences_last_update
+    }
+
+    fn update_presence(
+        &mut self,
+        replica_id: ReplicaId,
+        mut label: String,
+        color: u32,
+        lamport_timestamp: time::Lamport,
+    ) {
+        if self
+            .replica_presences
+            .get(&replica_id)
+            .map_or(true, |presence| {
+                lamport_timestamp > presence.lamport_timestamp
+            })
+        {
+            // Remote peers may not enforce the same bound, so we truncate incoming labels too.
+            truncate_label(&mut label);
+            self.replica_presences.insert(
+                replica_id,
+                ReplicaPresence {
+                    presence: Presence { label, color },
+                    lamport_timestamp,
+                },
+            );
+            self.presences_last_update = lamport_timestamp;
+        }
+    }
+
     pub fn edit<I, T>(
         &mut self,
         file_id: FileId,
@@ -745,6 +1739,61 @@ impl Epoch {
         )
     }
 
+    pub fn set_text(
+        &mut self,
+        file_id: FileId,
+        new_text: &str,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Operation, Error> {
+        self.mutate_buffer(
+            file_id,
+            lamport_clock,
+            |buffer, local_clock, lamport_clock| {
+                Ok(buffer.set_text(new_text, local_clock, lamport_clock))
+            },
+        )
+    }
+
+    pub fn splice(
+        &mut self,
+        file_id: FileId,
+        old_range: Range<Point>,
+        new_text: &str,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<(Operation, Range<buffer::Anchor>), Error> {
+        let mut new_range = None;
+        let operation = self.mutate_buffer(
+            file_id,
+            lamport_clock,
+            |buffer, local_clock, lamport_clock| {
+                let (operations, range) =
+                    buffer.splice(old_range, new_text, local_clock, lamport_clock)?;
+                new_range = Some(range);
+                Ok(operations)
+            },
+        )?;
+        Ok((operation, new_range.unwrap()))
+    }
+
+    pub fn edit_at_anchor<T>(
+        &mut self,
+        file_id: FileId,
+        old_range: Range<buffer::Anchor>,
+        new_text: T,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Operation, Error>
+    where
+        T: Into<Text>,
+    {
+        self.mutate_buffer(
+            file_id,
+            lamport_clock,
+            |buffer, local_clock, lamport_clock| {
+                buffer.edit_at_anchor(old_range, new_text, local_clock, lamport_clock)
+            },
+        )
+    }
+
     pub fn edit_2d<I, T>(
         &mut self,
         file_id: FileId,
@@ -765,6 +1814,39 @@ impl Epoch {
         )
     }
 
+    pub fn edit_indented(
+        &mut self,
+        file_id: FileId,
+        range: Range<Point>,
+        text: &str,
+        tab_size: u32,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Operation, Error> {
+        self.mutate_buffer(
+            file_id,
+            lamport_clock,
+            |buffer, local_clock, lamport_clock| {
+                buffer.edit_indented(range, text, tab_size, local_clock, lamport_clock)
+            },
+        )
+    }
+
+    pub fn edit_from_reader<R: io::BufRead>(
+        &mut self,
+        file_id: FileId,
+        range: Range<Point>,
+        reader: R,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Operation, Error> {
+        self.mutate_buffer(
+            file_id,
+            lamport_clock,
+            |buffer, local_clock, lamport_clock| {
+                buffer.edit_from_reader(range, reader, local_clock, lamport_clock)
+            },
+        )
+    }
+
     pub fn add_selection_set<I>(
         &mut self,
         file_id: FileId,
@@ -801,6 +1883,12 @@ impl Epoch {
             file_id,
             lamport_clock,
             |buffer, _local_clock, lamport_clock| {
+                // Replacing a set with the ranges it already has doesn't need to be broadcast.
+                let ranges = ranges.into_iter().collect::<Vec<_>>();
+                if buffer.selection_ranges(set_id)?.eq(ranges.iter().cloned()) {
+                    return Ok(Vec::new());
+                }
+
                 let operation = buffer.replace_selection_set(set_id, ranges, lamport_clock)?;
                 Ok(vec![operation])
             },
@@ -823,6 +1911,39 @@ impl Epoch {
         )
     }
 
+    pub fn set_selection_set_hidden(
+        &mut self,
+        file_id: FileId,
+        set_id: SelectionSetId,
+        hidden: bool,
+        lamport_clock: &mut time::Lamport,
+    ) -> Result<Operation, Error> {
+        self.mutate_buffer(
+            file_id,
+            lamport_clock,
+            |buffer, _local_clock, lamport_clock| {
+                let operation = if hidden {
+                    buffer.hide_selection_set(set_id, lamport_clock)?
+                } else {
+                    buffer.show_selection_set(set_id, lamport_clock)?
+                };
+                Ok(vec![operation])
+            },
+        )
+    }
+
+    pub fn is_selection_set_hidden(
+        &self,
+        file_id: FileId,
+        set_id: SelectionSetId,
+    ) -> Result<bool, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            buffer.is_selection_set_hidden(set_id)
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
     pub fn all_selections(
         &self,
         file_id: FileId,
@@ -860,6 +1981,104 @@ impl Epoch {
         }
     }
 
+    // Looks up the buffer operation that inserted the text the anchor is attached to in this
+    // epoch's history.
+    pub fn operation_for_anchor(
+        &self,
+        file_id: FileId,
+        anchor: &buffer::Anchor,
+    ) -> Result<Option<Operation>, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            let insertion_id = match buffer.operation_for_anchor(anchor) {
+                Some(buffer::Operation::Edit {
+                    local_timestamp, ..
+                }) => local_timestamp,
+                _ => return Ok(None),
+            };
+            Ok(self
+                .history_for_edits(file_id, Some(insertion_id))
+                .into_iter()
+                .next())
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
+    pub fn operations_in_range(
+        &self,
+        file_id: FileId,
+        range: Range<buffer::Anchor>,
+    ) -> Result<Vec<Operation>, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            let edit_ids = buffer.edit_ids_in_range(range)?;
+            Ok(self.history_for_edits(file_id, edit_ids))
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
+    // The operations that contain any of the given edits, in the order they appear in the history.
+    pub fn operations_for_edits(
+        &self,
+        file_id: FileId,
+        edit_ids: &[time::Local],
+    ) -> Vec<Operation> {
+        self.history_for_edits(file_id, edit_ids.iter().cloned())
+    }
+
+    fn history_for_edits<I>(&self, file_id: FileId, edit_ids: I) -> Vec<Operation>
+    where
+        I: IntoIterator<Item = time::Local>,
+    {
+        let mut timestamps = edit_ids
+            .into_iter()
+            .filter_map(|edit_id| self.history_index.by_edit.get(&edit_id).cloned())
+            .collect::<Vec<_>>();
+        timestamps.sort();
+        timestamps.dedup();
+        timestamps
+            .into_iter()
+            .filter_map(|timestamp| self.history.get(timestamp))
+            .filter(|op| match op {
+                Operation::BufferOperation {
+                    file_id: op_file_id,
+                    ..
+                } => *op_file_id == file_id,
+                _ => false,
+            })
+            .collect()
+    }
+
+    // The operations that `version` hasn't observed, in the order they appear in the history.
+    // Each replica's operations in a lane are walked back from its latest one until reaching one
+    // that the version observed. Active location, presence and order updates carry no local
+    // timestamp, so they are never known to be observed and can't end the walk: they are returned
+    // whenever they follow the replica's last observed operation, and a lane holding nothing else
+    // is scanned in full. The cost is only proportional to the number of unobserved operations
+    // when the lanes being walked contain locally timestamped operations.
+    fn history_since(&self, version: &time::Global) -> Vec<Operation> {
+        let mut operations = Vec::new();
+        for timestamps in self.history_index.by_replica.values() {
+            for timestamp in timestamps.iter().rev() {
+                let op = self.history.get(*timestamp).unwrap();
+                if op
+                    .local_timestamp()
+                    .map_or(false, |local_timestamp| version.observed(local_timestamp))
+                {
+                    break;
+                }
+                operations.push(op);
+            }
+        }
+        operations.sort_by_key(|op| op.lamport_timestamp());
+        operations
+    }
+
+    fn record_history(&mut self, operation: Operation) {
+        self.history_index.insert(&operation);
+        self.history.insert(vec![operation]);
+    }
+
     fn mutate_buffer<F>(
         &mut self,
         file_id: FileId,
@@ -877,12 +2096,14 @@ impl Epoch {
             let operations = mutate(buffer, &mut self.local_clock, lamport_clock)?;
             let local_timestamp = self.local_clock.tick();
             self.version.observe(local_timestamp);
-            Ok(Operation::BufferOperation {
+            let operation = Operation::BufferOperation {
                 file_id,
                 operations,
                 local_timestamp,
                 lamport_timestamp: lamport_clock.tick(),
-            })
+            };
+            self.record_history(operation.clone());
+            Ok(operation)
         } else {
             Err(Error::InvalidFileId("file has not been opened".into()))
         }
@@ -1000,6 +2221,46 @@ impl Epoch {
         }
     }
 
+    pub fn inverse_of_edit(
+        &self,
+        file_id: FileId,
+        edit_id: time::Local,
+    ) -> Result<Vec<(Range<usize>, Vec<u16>)>, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            Ok(buffer.inverse_of_edit(edit_id))
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
+    pub fn hunks(&self, file_id: FileId) -> Result<impl Iterator<Item = Range<Point>>, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            Ok(buffer.hunks())
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
+    pub fn hunks_from(
+        &self,
+        file_id: FileId,
+        from: Point,
+    ) -> Result<impl Iterator<Item = Range<Point>>, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            Ok(buffer.hunks_from(from))
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
+    pub fn hunks_against(&self, file_id: FileId, base: &str) -> Result<Vec<Range<Point>>, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            Ok(buffer.hunks_against(base))
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
     pub fn buffer_deferred_ops_len(&self, file_id: FileId) -> Result<usize, Error> {
         if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
             Ok(buffer.deferred_ops_len())
@@ -1008,6 +2269,92 @@ impl Epoch {
         }
     }
 
+    pub fn deferred_ops_len(&self) -> usize {
+        let buffer_deferred_ops_len: usize = self
+            .text_files
+            .values()
+            .map(|text_file| match text_file {
+                TextFile::Buffered(buffer) => buffer.deferred_ops_len(),
+                TextFile::Deferred(_) => 0,
+            })
+            .sum();
+        self.deferred_ops.len() + buffer_deferred_ops_len
+    }
+
+    pub fn history(&self) -> Vec<Operation> {
+        self.history.iter().collect()
+    }
+
+    // The history is ordered by lamport timestamp, which is consistent with causality.
+    pub fn operations_from(&self, replica_id: ReplicaId) -> Vec<Operation> {
+        let mut operations = self
+            .lane_operations_from(replica_id, Lane::Structure, None)
+            .chain(self.lane_operations_from(replica_id, Lane::Content, None))
+            .collect::<Vec<_>>();
+        operations.sort_by_key(|op| op.lamport_timestamp());
+        operations
+    }
+
+    // The replica's operations in `lane` that come after `after`, in causal order. Only those
+    // operations are visited, so resuming from the last operation seen costs nothing for the ones
+    // that precede it.
+    pub fn lane_operations_from<'a>(
+        &'a self,
+        replica_id: ReplicaId,
+        lane: Lane,
+        after: Option<time::Lamport>,
+    ) -> impl 'a + Iterator<Item = Operation> {
+        let timestamps = self
+            .history_index
+            .by_replica
+            .get(&(replica_id, lane))
+            .map_or(&[][..], |timestamps| timestamps.as_slice());
+        let start = after.map_or(0, |after| match timestamps.binary_search(&after) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        });
+        timestamps[start..]
+            .iter()
+            .filter_map(move |timestamp| self.history.get(*timestamp))
+    }
+
+    pub fn memory_usage(&self) -> MemoryReport {
+        let mut report = MemoryReport::default();
+        for text_file in self.text_files.values() {
+            if let TextFile::Buffered(buffer) = text_file {
+                report += &buffer.memory_usage();
+            }
+        }
+        report.operation_log_bytes += self
+            .history
+            .iter()
+            .chain(self.deferred_ops.iter())
+            .map(|op| op.estimated_size())
+            .sum::<usize>();
+        report.version_vector_len = self.version.replica_ids().count();
+        report
+    }
+
+    pub fn buffer_memory_usage(&self, file_id: FileId) -> Result<MemoryReport, Error> {
+        if let Some(TextFile::Buffered(buffer)) = self.text_files.get(&file_id) {
+            Ok(buffer.memory_usage())
+        } else {
+            Err(Error::InvalidFileId("file has not been opened".into()))
+        }
+    }
+
+    pub fn operation_stats(&self) -> HashMap<ReplicaId, OpStats> {
+        let mut stats = HashMap::<ReplicaId, OpStats>::new();
+        for text_file in self.text_files.values() {
+            if let TextFile::Buffered(buffer) = text_file {
+                for (replica_id, buffer_stats) in buffer.operation_stats() {
+                    *stats.entry(replica_id).or_default() += &buffer_stats;
+                }
+            }
+        }
+        stats
+    }
+
     pub fn file_type(&self, file_id: FileId) -> Result<FileType, Error> {
         Ok(self.metadata(file_id)?.file_type)
     }
@@ -1153,9 +2500,13 @@ impl Epoch {
             }
         }
 
-        // Convert the reverted moves into new move operations.
+        // Convert the reverted moves into new move operations, reserving their timestamps at once.
         let mut moved_file_ids = Vec::new();
-        for (child_id, timestamp) in &reverted_moves {
+        let local_values = self.local_clock.tick_by(reverted_moves.len() as u64);
+        let lamport_values = lamport_clock.tick_by(reverted_moves.len() as u64);
+        for ((child_id, timestamp), (local_value, lamport_value)) in
+            reverted_moves.iter().zip(local_values.zip(lamport_values))
+        {
             cursor.seek(
                 &ParentRefValueKey {
                     child_id: *child_id,
@@ -1166,8 +2517,14 @@ impl Epoch {
             fixup_ops.push(Operation::UpdateParent {
                 child_id: *child_id,
                 new_parent: cursor.item().unwrap().parent,
-                local_timestamp: self.local_clock.tick(),
-                lamport_timestamp: lamport_clock.tick(),
+                local_timestamp: time::Local {
+                    replica_id: self.local_clock.replica_id,
+                    value: local_value,
+                },
+                lamport_timestamp: time::Lamport {
+                    value: lamport_value,
+                    replica_id: lamport_clock.replica_id,
+                },
             });
             moved_file_ids.push(*child_id);
         }
@@ -1367,15 +2724,100 @@ impl<'a> Cursor<'a> {
         false
     }
 
-    fn is_modified_file(&self, file_id: FileId) -> bool {
-        self.epoch
-            .text_files
-            .get(&file_id)
-            .map_or(false, |f| f.is_modified())
+    fn is_modified_file(&self, file_id: FileId) -> bool {
+        self.epoch
+            .text_files
+            .get(&file_id)
+            .map_or(false, |f| f.is_modified())
+    }
+}
+
+// Directories whose own status is unchanged report `Modified` when anything beneath them changed.
+// The statuses must be in tree order, so that each entry follows the directories containing it.
+fn aggregate_directory_statuses(statuses: &mut [(PathBuf, FileType, FileStatus)]) {
+    // The directories containing the current entry, and whether anything beneath them changed.
+    let mut dirs: Vec<(usize, bool)> = Vec::new();
+    for ix in 0..statuses.len() {
+        while dirs.last().map_or(false, |(dir_ix, _)| {
+            !statuses[ix].0.starts_with(&statuses[*dir_ix].0)
+        }) {
+            dirs.pop();
+        }
+        if statuses[ix].2 != FileStatus::Unchanged {
+            for (dir_ix, changed) in dirs.iter_mut().rev() {
+                if *changed {
+                    break;
+                }
+                *changed = true;
+                if statuses[*dir_ix].2 == FileStatus::Unchanged {
+                    statuses[*dir_ix].2 = FileStatus::Modified;
+                }
+            }
+        }
+        if statuses[ix].1 == FileType::Directory {
+            dirs.push((ix, false));
+        }
+    }
+}
+
+impl Operation {
+    fn estimated_size(&self) -> usize {
+        match self {
+            Operation::BufferOperation { operations, .. } => {
+                mem::size_of::<Self>()
+                    + operations
+                        .iter()
+                        .map(|op| op.estimated_size())
+                        .sum::<usize>()
+            }
+            _ => mem::size_of::<Self>(),
+        }
+    }
+
+    // Fails if the context includes the operation itself, or doesn't include the creation of the
+    // files it refers to.
+    pub fn restrict_to_context(&mut self, context: &time::Global) -> Result<(), Error> {
+        if self
+            .local_timestamp()
+            .map_or(false, |timestamp| context.observed(timestamp))
+        {
+            return Err(Error::InvalidOperation);
+        }
+
+        let mut file_ids: SmallVec<[FileId; 2]> = SmallVec::new();
+        match self {
+            Operation::InsertMetadata { parent, .. } => {
+                file_ids.extend(parent.as_ref().map(|(parent_id, _)| *parent_id));
+            }
+            Operation::UpdateParent {
+                child_id,
+                new_parent,
+                ..
+            } => {
+                file_ids.push(*child_id);
+                file_ids.extend(new_parent.as_ref().map(|(parent_id, _)| *parent_id));
+            }
+            Operation::BufferOperation { file_id, .. } | Operation::UpdateOrder { file_id, .. } => {
+                file_ids.push(*file_id);
+            }
+            Operation::UpdateActiveLocation { file_id, .. } => file_ids.extend(*file_id),
+            Operation::UpdatePresence { .. } => {}
+        }
+        if file_ids.iter().any(|file_id| match file_id {
+            FileId::New(local_timestamp) => !context.observed(*local_timestamp),
+            FileId::Base(_) => false,
+        }) {
+            return Err(Error::InvalidOperation);
+        }
+
+        if let Operation::BufferOperation { operations, .. } = self {
+            for operation in operations {
+                operation.restrict_to_context(context)?;
+            }
+        }
+        Ok(())
     }
-}
 
-impl Operation {
     fn local_timestamp(&self) -> Option<time::Local> {
         match self {
             Operation::InsertMetadata {
@@ -1388,6 +2830,109 @@ impl Operation {
                 local_timestamp, ..
             } => Some(*local_timestamp),
             Operation::UpdateActiveLocation { .. } => None,
+            Operation::UpdatePresence { .. } => None,
+            Operation::UpdateOrder { .. } => None,
+        }
+    }
+
+    pub fn rename_replica(&mut self, old: ReplicaId, new: ReplicaId) {
+        match self {
+            Operation::InsertMetadata {
+                file_id,
+                parent,
+                local_timestamp,
+                lamport_timestamp,
+                ..
+            } => {
+                file_id.rename_replica(old, new);
+                if let Some((parent_id, _)) = parent.as_mut() {
+                    parent_id.rename_replica(old, new);
+                }
+                local_timestamp.rename_replica(old, new);
+                lamport_timestamp.rename_replica(old, new);
+            }
+            Operation::UpdateParent {
+                child_id,
+                new_parent,
+                local_timestamp,
+                lamport_timestamp,
+            } => {
+                child_id.rename_replica(old, new);
+                if let Some((parent_id, _)) = new_parent.as_mut() {
+                    parent_id.rename_replica(old, new);
+                }
+                local_timestamp.rename_replica(old, new);
+                lamport_timestamp.rename_replica(old, new);
+            }
+            Operation::BufferOperation {
+                file_id,
+                operations,
+                local_timestamp,
+                lamport_timestamp,
+            } => {
+                file_id.rename_replica(old, new);
+                for operation in operations {
+                    operation.rename_replica(old, new);
+                }
+                local_timestamp.rename_replica(old, new);
+                lamport_timestamp.rename_replica(old, new);
+            }
+            Operation::UpdateActiveLocation {
+                file_id,
+                lamport_timestamp,
+            } => {
+                if let Some(file_id) = file_id.as_mut() {
+                    file_id.rename_replica(old, new);
+                }
+                lamport_timestamp.rename_replica(old, new);
+            }
+            Operation::UpdatePresence {
+                replica_id,
+                lamport_timestamp,
+                ..
+            } => {
+                if *replica_id == old {
+                    *replica_id = new;
+                }
+                lamport_timestamp.rename_replica(old, new);
+            }
+            Operation::UpdateOrder {
+                file_id,
+                lamport_timestamp,
+                ..
+            } => {
+                file_id.rename_replica(old, new);
+                lamport_timestamp.rename_replica(old, new);
+            }
+        }
+    }
+
+    pub fn lane(&self) -> Lane {
+        match self {
+            Operation::InsertMetadata { .. }
+            | Operation::UpdateParent { .. }
+            | Operation::UpdateOrder { .. } => Lane::Structure,
+            _ => Lane::Content,
+        }
+    }
+
+    pub fn file_id(&self) -> Option<FileId> {
+        match self {
+            Operation::InsertMetadata { file_id, .. } => Some(*file_id),
+            Operation::UpdateParent { child_id, .. } => Some(*child_id),
+            Operation::BufferOperation { file_id, .. } => Some(*file_id),
+            Operation::UpdateOrder { file_id, .. } => Some(*file_id),
+            Operation::UpdateActiveLocation { .. } | Operation::UpdatePresence { .. } => None,
+        }
+    }
+
+    // Edits that don't insert or delete anything and selection updates that don't change any ranges
+    // produce buffer operations without any effect.
+    pub fn is_noop(&self) -> bool {
+        if let Operation::BufferOperation { operations, .. } = self {
+            operations.is_empty()
+        } else {
+            false
         }
     }
 
@@ -1405,6 +2950,12 @@ impl Operation {
             Operation::UpdateActiveLocation {
                 lamport_timestamp, ..
             } => *lamport_timestamp,
+            Operation::UpdatePresence {
+                lamport_timestamp, ..
+            } => *lamport_timestamp,
+            Operation::UpdateOrder {
+                lamport_timestamp, ..
+            } => *lamport_timestamp,
         }
     }
 
@@ -1415,7 +2966,8 @@ impl Operation {
         use crate::serialization::epoch::{
             BufferOperation, BufferOperationArgs, FileId as FileIdType, InsertMetadata,
             InsertMetadataArgs, Operation as OperationType, UpdateActiveLocation,
-            UpdateActiveLocationArgs, UpdateParent, UpdateParentArgs,
+            UpdateActiveLocationArgs, UpdateOrder, UpdateOrderArgs, UpdateParent, UpdateParentArgs,
+            UpdatePresence, UpdatePresenceArgs,
         };
 
         fn parent_to_flatbuf<'a, 'fbb>(
@@ -1550,6 +3102,48 @@ impl Operation {
                     .as_union_value(),
                 )
             }
+            Operation::UpdatePresence {
+                replica_id,
+                label,
+                color,
+                lamport_timestamp,
+            } => {
+                let label = Some(builder.create_string(label));
+                (
+                    OperationType::UpdatePresence,
+                    UpdatePresence::create(
+                        builder,
+                        &UpdatePresenceArgs {
+                            replica_id: Some(&replica_id.to_flatbuf()),
+                            label,
+                            color: *color,
+                            lamport_timestamp: Some(&lamport_timestamp.to_flatbuf()),
+                        },
+                    )
+                    .as_union_value(),
+                )
+            }
+            Operation::UpdateOrder {
+                file_id,
+                order_key,
+                lamport_timestamp,
+            } => {
+                let (file_id_type, file_id) = file_id.to_flatbuf(builder);
+                let order_key = Some(builder.create_vector(order_key));
+                (
+                    OperationType::UpdateOrder,
+                    UpdateOrder::create(
+                        builder,
+                        &UpdateOrderArgs {
+                            file_id_type,
+                            file_id: Some(file_id),
+                            order_key,
+                            lamport_timestamp: Some(&lamport_timestamp.to_flatbuf()),
+                        },
+                    )
+                    .as_union_value(),
+                )
+            }
         }
     }
 
@@ -1561,12 +3155,14 @@ impl Operation {
             parent_id_type: serialization::epoch::FileId,
             parent_id_message: Option<flatbuffers::Table<'a>>,
             name: Option<&'a str>,
-        ) -> Option<(FileId, Arc<OsString>)> {
-            parent_id_message.map(|parent_id_message| {
-                let file_id = FileId::from_flatbuf(parent_id_type, parent_id_message);
-                let name = Arc::new(OsString::from(name.unwrap()));
-                (file_id, name)
-            })
+        ) -> Result<Option<(FileId, Arc<OsString>)>, Error> {
+            if let Some(parent_id_message) = parent_id_message {
+                let file_id = FileId::from_flatbuf(parent_id_type, parent_id_message)?;
+                let name = name.ok_or(Error::DeserializeError("name_in_parent"))?;
+                Ok(Some((file_id, Arc::new(OsString::from(name)))))
+            } else {
+                Ok(None)
+            }
         }
 
         match operation_type {
@@ -1575,17 +3171,25 @@ impl Operation {
                 Ok(Some(Operation::InsertMetadata {
                     file_id: FileId::from_flatbuf(
                         message.file_id_type(),
-                        message.file_id().ok_or(Error::DeserializeError)?,
-                    ),
+                        message
+                            .file_id()
+                            .ok_or(Error::DeserializeError("file_id"))?,
+                    )?,
                     file_type: FileType::from_flatbuf(&message.file_type()),
                     parent: parent_from_flatbuf(
                         message.parent_id_type(),
                         message.parent_id(),
                         message.name_in_parent(),
+                    )?,
+                    local_timestamp: time::Local::from_flatbuf(
+                        message
+                            .local_timestamp()
+                            .ok_or(Error::DeserializeError("local_timestamp"))?,
                     ),
-                    local_timestamp: time::Local::from_flatbuf(&message.local_timestamp().unwrap()),
                     lamport_timestamp: time::Lamport::from_flatbuf(
-                        message.lamport_timestamp().ok_or(Error::DeserializeError)?,
+                        message
+                            .lamport_timestamp()
+                            .ok_or(Error::DeserializeError("lamport_timestamp"))?,
                     ),
                 }))
             }
@@ -1594,24 +3198,32 @@ impl Operation {
                 Ok(Some(Operation::UpdateParent {
                     child_id: FileId::from_flatbuf(
                         message.child_id_type(),
-                        message.child_id().ok_or(Error::DeserializeError)?,
-                    ),
+                        message
+                            .child_id()
+                            .ok_or(Error::DeserializeError("child_id"))?,
+                    )?,
                     new_parent: parent_from_flatbuf(
                         message.new_parent_id_type(),
                         message.new_parent_id(),
                         message.new_name_in_parent(),
-                    ),
+                    )?,
                     local_timestamp: time::Local::from_flatbuf(
-                        message.local_timestamp().ok_or(Error::DeserializeError)?,
+                        message
+                            .local_timestamp()
+                            .ok_or(Error::DeserializeError("local_timestamp"))?,
                     ),
                     lamport_timestamp: time::Lamport::from_flatbuf(
-                        message.lamport_timestamp().ok_or(Error::DeserializeError)?,
+                        message
+                            .lamport_timestamp()
+                            .ok_or(Error::DeserializeError("lamport_timestamp"))?,
                     ),
                 }))
             }
             serialization::epoch::Operation::BufferOperation => {
                 let message = serialization::epoch::BufferOperation::init_from_table(message);
-                let op_messages = message.operations().ok_or(Error::DeserializeError)?;
+                let op_messages = message
+                    .operations()
+                    .ok_or(Error::DeserializeError("operations"))?;
                 let mut operations = Vec::with_capacity(op_messages.len());
                 for i in 0..op_messages.len() {
                     if let Some(op) = buffer::Operation::from_flatbuf(&op_messages.get(i))? {
@@ -1622,21 +3234,27 @@ impl Operation {
                 Ok(Some(Operation::BufferOperation {
                     file_id: FileId::from_flatbuf(
                         message.file_id_type(),
-                        message.file_id().ok_or(Error::DeserializeError)?,
-                    ),
+                        message
+                            .file_id()
+                            .ok_or(Error::DeserializeError("file_id"))?,
+                    )?,
                     operations,
                     local_timestamp: time::Local::from_flatbuf(
-                        message.local_timestamp().ok_or(Error::DeserializeError)?,
+                        message
+                            .local_timestamp()
+                            .ok_or(Error::DeserializeError("local_timestamp"))?,
                     ),
                     lamport_timestamp: time::Lamport::from_flatbuf(
-                        message.lamport_timestamp().ok_or(Error::DeserializeError)?,
+                        message
+                            .lamport_timestamp()
+                            .ok_or(Error::DeserializeError("lamport_timestamp"))?,
                     ),
                 }))
             }
             serialization::epoch::Operation::UpdateActiveLocation => {
                 let message = serialization::epoch::UpdateActiveLocation::init_from_table(message);
                 let file_id = if let Some(file_id) = message.file_id() {
-                    Some(FileId::from_flatbuf(message.file_id_type(), file_id))
+                    Some(FileId::from_flatbuf(message.file_id_type(), file_id)?)
                 } else {
                     None
                 };
@@ -1644,7 +3262,49 @@ impl Operation {
                 Ok(Some(Operation::UpdateActiveLocation {
                     file_id,
                     lamport_timestamp: time::Lamport::from_flatbuf(
-                        message.lamport_timestamp().ok_or(Error::DeserializeError)?,
+                        message
+                            .lamport_timestamp()
+                            .ok_or(Error::DeserializeError("lamport_timestamp"))?,
+                    ),
+                }))
+            }
+            serialization::epoch::Operation::UpdatePresence => {
+                let message = serialization::epoch::UpdatePresence::init_from_table(message);
+                Ok(Some(Operation::UpdatePresence {
+                    replica_id: ReplicaId::from_flatbuf(
+                        message
+                            .replica_id()
+                            .ok_or(Error::DeserializeError("replica_id"))?,
+                    ),
+                    label: message
+                        .label()
+                        .ok_or(Error::DeserializeError("label"))?
+                        .to_string(),
+                    color: message.color(),
+                    lamport_timestamp: time::Lamport::from_flatbuf(
+                        message
+                            .lamport_timestamp()
+                            .ok_or(Error::DeserializeError("lamport_timestamp"))?,
+                    ),
+                }))
+            }
+            serialization::epoch::Operation::UpdateOrder => {
+                let message = serialization::epoch::UpdateOrder::init_from_table(message);
+                Ok(Some(Operation::UpdateOrder {
+                    file_id: FileId::from_flatbuf(
+                        message.file_id_type(),
+                        message
+                            .file_id()
+                            .ok_or(Error::DeserializeError("file_id"))?,
+                    )?,
+                    order_key: message
+                        .order_key()
+                        .ok_or(Error::DeserializeError("order_key"))?
+                        .to_vec(),
+                    lamport_timestamp: time::Lamport::from_flatbuf(
+                        message
+                            .lamport_timestamp()
+                            .ok_or(Error::DeserializeError("lamport_timestamp"))?,
                     ),
                 }))
             }
@@ -1668,6 +3328,12 @@ impl FileId {
         }
     }
 
+    pub fn rename_replica(&mut self, old: ReplicaId, new: ReplicaId) {
+        if let FileId::New(local_timestamp) = self {
+            local_timestamp.rename_replica(old, new);
+        }
+    }
+
     fn to_flatbuf<'fbb>(
         &self,
         builder: &mut FlatBufferBuilder<'fbb>,
@@ -1697,17 +3363,19 @@ impl FileId {
     fn from_flatbuf<'a>(
         file_id_type: serialization::epoch::FileId,
         message: flatbuffers::Table<'a>,
-    ) -> Self {
+    ) -> Result<Self, Error> {
         match file_id_type {
             serialization::epoch::FileId::BaseFileId => {
                 let message = serialization::epoch::BaseFileId::init_from_table(message);
-                FileId::Base(message.index())
+                Ok(FileId::Base(message.index()))
             }
             serialization::epoch::FileId::NewFileId => {
                 let message = serialization::epoch::NewFileId::init_from_table(message);
-                FileId::New(time::Local::from_flatbuf(&message.id().unwrap()))
+                Ok(FileId::New(time::Local::from_flatbuf(
+                    message.id().ok_or(Error::DeserializeError("id"))?,
+                )))
             }
-            serialization::epoch::FileId::NONE => unreachable!(),
+            serialization::epoch::FileId::NONE => Err(Error::DeserializeError("file_id_type")),
         }
     }
 }
@@ -1970,6 +3638,54 @@ impl btree::Dimension<ChildRefValueSummary> for usize {
     }
 }
 
+impl HistoryIndex {
+    fn insert(&mut self, operation: &Operation) {
+        let lamport_timestamp = operation.lamport_timestamp();
+        let timestamps = self
+            .by_replica
+            .entry((lamport_timestamp.replica_id, operation.lane()))
+            .or_insert(Vec::new());
+        if !insert_sorted(timestamps, lamport_timestamp) {
+            return;
+        }
+
+        match operation {
+            Operation::InsertMetadata { file_id, .. }
+            | Operation::UpdateParent {
+                child_id: file_id,
+                ..
+            } => {
+                insert_sorted(
+                    self.by_file.entry(*file_id).or_insert(Vec::new()),
+                    lamport_timestamp,
+                );
+            }
+            Operation::BufferOperation { operations, .. } => {
+                for op in operations {
+                    if let buffer::Operation::Edit {
+                        local_timestamp, ..
+                    } = op
+                    {
+                        self.by_edit.insert(*local_timestamp, lamport_timestamp);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Returns false if `timestamp` was already present.
+fn insert_sorted(timestamps: &mut Vec<time::Lamport>, timestamp: time::Lamport) -> bool {
+    match timestamps.binary_search(&timestamp) {
+        Ok(_) => false,
+        Err(index) => {
+            timestamps.insert(index, timestamp);
+            true
+        }
+    }
+}
+
 impl TextFile {
     fn is_modified(&self) -> bool {
         match self {
@@ -2001,6 +3717,39 @@ where
     Ok(OsString::from(String::deserialize(deserializer)?))
 }
 
+// Returns a key that sorts between `lo` and `hi`, treating keys as base-256 fractions. The key ends
+// with the replica id, so keys generated concurrently for the same gap never collide, and with a
+// non-zero byte, so no two distinct keys ever denote the same fraction.
+fn order_key_between(lo: &[u8], hi: Option<&[u8]>, replica_id: ReplicaId) -> Vec<u8> {
+    let mut key = Vec::new();
+    let mut hi = hi;
+    for i in 0.. {
+        let lo_digit = u16::from(lo.get(i).cloned().unwrap_or(0));
+        let hi_digit = hi.map_or(256, |hi| u16::from(hi.get(i).cloned().unwrap_or(0)));
+        if hi_digit - lo_digit > 1 {
+            key.push(((lo_digit + hi_digit) / 2) as u8);
+            break;
+        }
+        key.push(lo_digit as u8);
+        if hi_digit > lo_digit {
+            hi = None;
+        }
+    }
+    key.extend_from_slice(replica_id.as_bytes());
+    key.push(1);
+    key
+}
+
+fn truncate_label(label: &mut String) {
+    if label.len() > MAX_PRESENCE_LABEL_LEN {
+        let mut len = MAX_PRESENCE_LABEL_LEN;
+        while !label.is_char_boundary(len) {
+            len -= 1;
+        }
+        label.truncate(len);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -2276,6 +4025,175 @@ mod tests {
 
         assert!(!cursor.next(true));
         assert!(cursor.entry().is_err());
+
+        assert_eq!(
+            epoch.statuses(),
+            vec![
+                (
+                    PathBuf::from("a"),
+                    FileType::Directory,
+                    FileStatus::Modified
+                ),
+                (
+                    PathBuf::from("a/b"),
+                    FileType::Directory,
+                    FileStatus::Removed
+                ),
+                (
+                    PathBuf::from("a/d"),
+                    FileType::Directory,
+                    FileStatus::Unchanged
+                ),
+                (PathBuf::from("a/x"), FileType::Text, FileStatus::New),
+                (
+                    PathBuf::from("a/z"),
+                    FileType::Directory,
+                    FileStatus::Renamed
+                ),
+                (
+                    PathBuf::from("f"),
+                    FileType::Directory,
+                    FileStatus::Unchanged
+                ),
+                (
+                    PathBuf::from("g"),
+                    FileType::Text,
+                    FileStatus::RenamedAndModified
+                ),
+            ]
+        );
+        assert!(Epoch::with_replica_id(replica_id).statuses().is_empty());
+    }
+
+    #[test]
+    fn test_structural_diff() {
+        let replica_id = Uuid::nil();
+        let mut epoch_1 = Epoch::with_replica_id(replica_id);
+        let mut lamport_clock = time::Lamport::new(replica_id);
+        epoch_1
+            .append_base_entries(
+                vec![
+                    DirEntry {
+                        depth: 1,
+                        name: OsString::from("a"),
+                        file_type: FileType::Directory,
+                    },
+                    DirEntry {
+                        depth: 2,
+                        name: OsString::from("b"),
+                        file_type: FileType::Text,
+                    },
+                    DirEntry {
+                        depth: 1,
+                        name: OsString::from("c"),
+                        file_type: FileType::Text,
+                    },
+                    DirEntry {
+                        depth: 1,
+                        name: OsString::from("d"),
+                        file_type: FileType::Directory,
+                    },
+                ],
+                &mut lamport_clock,
+            )
+            .unwrap();
+        assert!(epoch_1.structural_diff(&epoch_1).is_empty());
+
+        let a = epoch_1.file_id("a").unwrap();
+        let b = epoch_1.file_id("a/b").unwrap();
+        let c = epoch_1.file_id("c").unwrap();
+        let d = epoch_1.file_id("d").unwrap();
+        let mut epoch_2 = epoch_1.clone();
+        epoch_2
+            .rename(a, ROOT_FILE_ID, "x", &mut lamport_clock)
+            .unwrap();
+        epoch_2.remove(c, &mut lamport_clock).unwrap();
+        epoch_2
+            .create_file(d, "e", FileType::Text, &mut lamport_clock)
+            .unwrap();
+        epoch_2.rename(b, d, "b2", &mut lamport_clock).unwrap();
+        let e = epoch_2.file_id("d/e").unwrap();
+
+        assert_eq!(
+            epoch_1.structural_diff(&epoch_2),
+            vec![
+                StructuralChange::Removed {
+                    file_id: c,
+                    path: PathBuf::from("c"),
+                },
+                StructuralChange::Renamed {
+                    file_id: b,
+                    old_path: PathBuf::from("a/b"),
+                    new_path: PathBuf::from("d/b2"),
+                },
+                StructuralChange::Added {
+                    file_id: e,
+                    path: PathBuf::from("d/e"),
+                    file_type: FileType::Text,
+                },
+                StructuralChange::Renamed {
+                    file_id: a,
+                    old_path: PathBuf::from("a"),
+                    new_path: PathBuf::from("x"),
+                },
+            ]
+        );
+        assert_eq!(
+            epoch_2.structural_diff(&epoch_1).first(),
+            Some(&StructuralChange::Removed {
+                file_id: e,
+                path: PathBuf::from("d/e"),
+            })
+        );
+    }
+
+    #[test]
+    fn test_concurrent_moves_cycle() {
+        let base_entries = vec![
+            DirEntry {
+                depth: 1,
+                name: OsString::from("a"),
+                file_type: FileType::Directory,
+            },
+            DirEntry {
+                depth: 1,
+                name: OsString::from("b"),
+                file_type: FileType::Directory,
+            },
+        ];
+        let replica_1 = Uuid::from_u128(1);
+        let replica_2 = Uuid::from_u128(2);
+        let mut epoch_1 = Epoch::with_replica_id(replica_1);
+        let mut lamport_clock_1 = time::Lamport::new(replica_1);
+        epoch_1
+            .append_base_entries(base_entries.clone(), &mut lamport_clock_1)
+            .unwrap();
+        let mut epoch_2 = Epoch::with_replica_id(replica_2);
+        let mut lamport_clock_2 = time::Lamport::new(replica_2);
+        epoch_2
+            .append_base_entries(base_entries, &mut lamport_clock_2)
+            .unwrap();
+
+        // Moving each directory into the other concurrently would make a cycle, so the move with
+        // the later timestamp is reverted on both replicas.
+        let a = epoch_1.file_id("a").unwrap();
+        let b = epoch_1.file_id("b").unwrap();
+        let op_1 = epoch_1.rename(a, b, "a", &mut lamport_clock_1).unwrap();
+        let op_2 = epoch_2.rename(b, a, "b", &mut lamport_clock_2).unwrap();
+        let fixup_ops_1 = epoch_1.apply_ops(vec![op_2], &mut lamport_clock_1).unwrap();
+        let fixup_ops_2 = epoch_2.apply_ops(vec![op_1], &mut lamport_clock_2).unwrap();
+        assert_eq!(fixup_ops_1.len(), 1);
+        assert_eq!(fixup_ops_2.len(), 1);
+        for (epoch, fixup_op, replica_id) in &[
+            (&epoch_1, &fixup_ops_1[0], replica_1),
+            (&epoch_2, &fixup_ops_2[0], replica_2),
+        ] {
+            let local_timestamp = fixup_op.local_timestamp().unwrap();
+            assert_eq!(local_timestamp.replica_id, *replica_id);
+            assert!(epoch.version().observed(local_timestamp));
+            assert_eq!(epoch.path(a), Some(PathBuf::from("b/a")));
+            assert_eq!(epoch.path(b), Some(PathBuf::from("b")));
+        }
     }
 
     #[test]
@@ -2386,6 +4304,87 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_resync_text_file() -> Result<(), Error> {
+        let replica_1_id = Uuid::from_u128(1);
+        let mut epoch_1 = Epoch::with_replica_id(replica_1_id);
+        let mut clock_1 = time::Lamport::new(replica_1_id);
+        let (file_id, new_file_op) = epoch_1.new_text_file(&mut clock_1);
+        epoch_1.open_text_file(file_id, "", &mut clock_1)?;
+        let edit_op = epoch_1.edit(file_id, Some(0..0), "abcdef", &mut clock_1)?;
+
+        let replica_2_id = Uuid::from_u128(2);
+        let mut epoch_2 = Epoch::with_replica_id(replica_2_id);
+        let mut clock_2 = time::Lamport::new(replica_2_id);
+        epoch_2.apply_ops(vec![new_file_op, edit_op], &mut clock_2)?;
+        epoch_2.open_text_file(file_id, "", &mut clock_2)?;
+
+        // Simulate a bug by editing the second replica's buffer without recording an operation.
+        let anchor = match epoch_2.text_files.get_mut(&file_id) {
+            Some(TextFile::Buffered(buffer)) => {
+                let replica_3_id = Uuid::from_u128(3);
+                buffer.edit(
+                    Some(2..3),
+                    "X",
+                    &mut time::Local::new(replica_3_id),
+                    &mut time::Lamport::new(replica_3_id),
+                );
+                buffer.anchor_before_offset(5)?
+            }
+            _ => unreachable!(),
+        };
+        let edit_op = epoch_1.edit(file_id, Some(6..6), "123", &mut clock_1)?;
+        epoch_2.apply_ops(Some(edit_op), &mut clock_2)?;
+        assert_eq!(epoch_1.text(file_id)?.into_string(), "abcdef123");
+        assert_eq!(epoch_2.text(file_id)?.into_string(), "abXdef123");
+
+        epoch_1.resync_text_file(file_id, &mut clock_1)?;
+        epoch_2.resync_text_file(file_id, &mut clock_2)?;
+        assert_eq!(epoch_1.text(file_id)?.into_string(), "abcdef123");
+        assert_eq!(epoch_2.text(file_id)?.into_string(), "abcdef123");
+        assert_eq!(
+            epoch_2.point_for_anchor(file_id, &anchor)?,
+            Point::new(0, 5)
+        );
+
+        let edit_op = epoch_2.edit(file_id, Some(0..1), "", &mut clock_2)?;
+        epoch_1.apply_ops(Some(edit_op), &mut clock_1)?;
+        assert_eq!(epoch_1.text(file_id)?.into_string(), "bcdef123");
+        assert_eq!(epoch_2.text(file_id)?.into_string(), "bcdef123");
+
+        let version = epoch_2.version();
+        epoch_2.prune_history(&version);
+        assert_eq!(
+            epoch_2.resync_text_file(file_id, &mut clock_2),
+            Err(Error::InvalidOperation)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_order_key_between() {
+        let mut rng = StdRng::from_seed(&[42]);
+        let replica_ids = [Uuid::from_u128(1), Uuid::from_u128(2)];
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        for _ in 0..200 {
+            let index = rng.gen_range(0, keys.len() + 1);
+            let lo = index.checked_sub(1).map(|index| keys[index].as_slice());
+            let hi = keys.get(index).map(|key| key.as_slice());
+            let replica_id = *rng.choose(&replica_ids).unwrap();
+            let key = order_key_between(lo.unwrap_or(&[]), hi, replica_id);
+            assert!(lo.map_or(true, |lo| lo < key.as_slice()));
+            assert!(hi.map_or(true, |hi| key.as_slice() < hi));
+            assert_eq!(key.last(), Some(&1));
+            keys.insert(index, key);
+        }
+
+        // Keys generated concurrently for the same gap are distinct.
+        assert_ne!(
+            order_key_between(&keys[0], Some(&keys[1]), replica_ids[0]),
+            order_key_between(&keys[0], Some(&keys[1]), replica_ids[1])
+        );
+    }
+
     #[test]
     fn test_replication_random() {
         use crate::tests::Network;
@@ -7,11 +7,19 @@ mod serialization;
 pub mod time;
 mod work_tree;
 
-pub use crate::buffer::{Buffer, Change, Point};
-pub use crate::epoch::{Cursor, DirEntry, Epoch, FileStatus, FileType, ROOT_FILE_ID};
+pub use crate::buffer::{
+    Anchor, Buffer, Change, CodePointOffset, CodeWordClassifier, MemoryReport, OpStats,
+    PersistentAnchor, Point, Row, SearchOptions, SeekDimension, Utf8Offset, WordClassifier,
+};
+pub use crate::epoch::{
+    Cursor, DirEntry, Epoch, FileId, FileStatus, FileType, Id as EpochId, Presence,
+    StructuralChange, MAX_PRESENCE_LABEL_LEN, ROOT_FILE_ID,
+};
+pub use crate::operation_queue::{Lane, OverflowPolicy};
 pub use crate::work_tree::{
-    BufferId, BufferSelectionRanges, ChangeObserver, GitProvider, LocalSelectionSetId, Operation,
-    OperationEnvelope, WorkTree,
+    BufferId, BufferSelectionRanges, ChangeObserver, Diagnostics, GitProvider, GroupingPolicy,
+    LocalSelectionSetId, Operation, OperationEnvelope, StatusCursor, TransactionId, Warning,
+    WorkTree, MAX_ROUTE_LEN,
 };
 use std::borrow::Cow;
 use std::fmt;
@@ -21,10 +29,12 @@ use uuid::Uuid;
 pub type ReplicaId = Uuid;
 pub type Oid = [u8; 20];
 
+pub const SERIALIZATION_VERSION: u32 = 1;
+
 #[derive(Debug)]
 pub enum Error {
     IoError(io::Error),
-    DeserializeError,
+    DeserializeError(&'static str),
     InvalidPath(Cow<'static, str>),
     InvalidOperations,
     InvalidFileId(Cow<'static, str>),
@@ -34,8 +44,16 @@ pub enum Error {
     InvalidSelectionSet(buffer::SelectionSetId),
     InvalidLocalSelectionSet(LocalSelectionSetId),
     InvalidAnchor(Cow<'static, str>),
+    InvalidReplicaId(ReplicaId),
+    StaleEpoch(EpochId),
+    UnsupportedVersion(u32),
     OffsetOutOfRange,
     CursorExhausted,
+    QueueFull,
+    RateLimited(ReplicaId),
+    ReadOnlyBuffer(BufferId),
+    AlreadySent,
+    RouteTooLong(usize),
 }
 
 trait ReplicaIdExt {
@@ -98,7 +116,9 @@ impl PartialEq for Error {
             (Error::IoError(err_1), Error::IoError(err_2)) => {
                 err_1.kind() == err_2.kind() && err_1.to_string() == err_2.to_string()
             }
-            (Error::DeserializeError, Error::DeserializeError) => true,
+            (Error::DeserializeError(field_1), Error::DeserializeError(field_2)) => {
+                field_1 == field_2
+            }
             (Error::InvalidPath(err_1), Error::InvalidPath(err_2)) => err_1 == err_2,
             (Error::InvalidOperations, Error::InvalidOperations) => true,
             (Error::InvalidFileId(err_1), Error::InvalidFileId(err_2)) => err_1 == err_2,
@@ -110,8 +130,18 @@ impl PartialEq for Error {
                 id_1 == id_2
             }
             (Error::InvalidAnchor(err_1), Error::InvalidAnchor(err_2)) => err_1 == err_2,
+            (Error::InvalidReplicaId(id_1), Error::InvalidReplicaId(id_2)) => id_1 == id_2,
+            (Error::StaleEpoch(id_1), Error::StaleEpoch(id_2)) => id_1 == id_2,
+            (Error::UnsupportedVersion(version_1), Error::UnsupportedVersion(version_2)) => {
+                version_1 == version_2
+            }
             (Error::OffsetOutOfRange, Error::OffsetOutOfRange) => true,
             (Error::CursorExhausted, Error::CursorExhausted) => true,
+            (Error::QueueFull, Error::QueueFull) => true,
+            (Error::RateLimited(id_1), Error::RateLimited(id_2)) => id_1 == id_2,
+            (Error::ReadOnlyBuffer(id_1), Error::ReadOnlyBuffer(id_2)) => id_1 == id_2,
+            (Error::AlreadySent, Error::AlreadySent) => true,
+            (Error::RouteTooLong(len_1), Error::RouteTooLong(len_2)) => len_1 == len_2,
             _ => false,
         }
     }
@@ -1,4 +1,4 @@
-use crate::btree::{Cursor, Dimension, Edit, Item, KeyedItem, Tree};
+use crate::btree::{Cursor, Dimension, Edit, Item, KeyedItem, SeekBias, Tree};
 use crate::time;
 use std::fmt::Debug;
 use std::ops::{Add, AddAssign};
@@ -7,6 +7,21 @@ pub trait Operation: Clone + Debug + Eq {
     fn timestamp(&self) -> time::Lamport;
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    Block,
+    DropOldest,
+    Error,
+}
+
+// Operations are assigned to lanes by kind, so that transports can prioritize changes to the
+// file tree over a flood of edits. Causal order is only preserved within each lane.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Lane {
+    Structure,
+    Content,
+}
+
 #[derive(Clone, Debug)]
 pub struct OperationQueue<T: Operation>(Tree<T>);
 
@@ -43,6 +58,23 @@ impl<T: Operation> OperationQueue<T> {
         self.0.edit(&mut edits);
     }
 
+    pub fn iter(&self) -> Cursor<T> {
+        self.0.cursor()
+    }
+
+    pub fn get(&self, timestamp: time::Lamport) -> Option<T> {
+        let mut cursor = self.0.cursor();
+        cursor.seek(&OperationKey(timestamp), SeekBias::Left);
+        cursor.item().filter(|op| op.timestamp() == timestamp)
+    }
+
+    // Iterates over the operations whose timestamp is greater than `timestamp`.
+    pub fn iter_after(&self, timestamp: time::Lamport) -> Cursor<T> {
+        let mut cursor = self.0.cursor();
+        cursor.seek(&OperationKey(timestamp), SeekBias::Right);
+        cursor
+    }
+
     pub fn drain(&mut self) -> Cursor<T> {
         let cursor = self.0.cursor();
         self.0 = Tree::new();
@@ -139,6 +171,25 @@ mod tests {
         assert_eq!(queue.len(), 1);
     }
 
+    #[test]
+    fn test_get_and_iter_after() {
+        let mut clock = time::Lamport::new(ReplicaId::from_u128(1));
+        let timestamps = (0..5).map(|_| clock.tick()).collect::<Vec<_>>();
+
+        let mut queue = OperationQueue::new();
+        queue.insert(timestamps.iter().cloned().map(TestOperation).collect());
+        assert_eq!(queue.get(timestamps[2]), Some(TestOperation(timestamps[2])));
+        assert_eq!(queue.get(clock.tick()), None);
+        assert_eq!(
+            queue.iter_after(timestamps[2]).collect::<Vec<_>>(),
+            vec![TestOperation(timestamps[3]), TestOperation(timestamps[4])]
+        );
+        assert_eq!(
+            queue.iter_after(time::Lamport::default()).count(),
+            timestamps.len()
+        );
+    }
+
     #[derive(Clone, Debug, Eq, PartialEq)]
     struct TestOperation(time::Lamport);
 
@@ -804,12 +804,14 @@ impl<'a> UpdateSelections<'a> {
       if let Some(x) = args.lamport_timestamp { builder.add_lamport_timestamp(x); }
       if let Some(x) = args.selections { builder.add_selections(x); }
       if let Some(x) = args.set_id { builder.add_set_id(x); }
+      builder.add_hidden(args.hidden);
       builder.finish()
     }
 
     pub const VT_SET_ID: flatbuffers::VOffsetT = 4;
     pub const VT_SELECTIONS: flatbuffers::VOffsetT = 6;
     pub const VT_LAMPORT_TIMESTAMP: flatbuffers::VOffsetT = 8;
+    pub const VT_HIDDEN: flatbuffers::VOffsetT = 10;
 
   #[inline]
   pub fn set_id(&self) -> Option<&'a super::Timestamp> {
@@ -823,12 +825,17 @@ impl<'a> UpdateSelections<'a> {
   pub fn lamport_timestamp(&self) -> Option<&'a super::Timestamp> {
     self._tab.get::<super::Timestamp>(UpdateSelections::VT_LAMPORT_TIMESTAMP, None)
   }
+  #[inline]
+  pub fn hidden(&self) -> bool {
+    self._tab.get::<bool>(UpdateSelections::VT_HIDDEN, Some(false)).unwrap()
+  }
 }
 
 pub struct UpdateSelectionsArgs<'a> {
     pub set_id: Option<&'a  super::Timestamp>,
     pub selections: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , flatbuffers::ForwardsUOffset<Selection<'a >>>>>,
     pub lamport_timestamp: Option<&'a  super::Timestamp>,
+    pub hidden: bool,
 }
 impl<'a> Default for UpdateSelectionsArgs<'a> {
     #[inline]
@@ -837,6 +844,7 @@ impl<'a> Default for UpdateSelectionsArgs<'a> {
             set_id: None,
             selections: None,
             lamport_timestamp: None,
+            hidden: false,
         }
     }
 }
@@ -858,6 +866,10 @@ impl<'a: 'b, 'b> UpdateSelectionsBuilder<'a, 'b> {
     self.fbb_.push_slot_always::<&super::Timestamp>(UpdateSelections::VT_LAMPORT_TIMESTAMP, lamport_timestamp);
   }
   #[inline]
+  pub fn add_hidden(&mut self, hidden: bool) {
+    self.fbb_.push_slot::<bool>(UpdateSelections::VT_HIDDEN, hidden, false);
+  }
+  #[inline]
   pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> UpdateSelectionsBuilder<'a, 'b> {
     let start = _fbb.start_table();
     UpdateSelectionsBuilder {
@@ -1125,11 +1137,13 @@ pub enum Operation {
   UpdateParent = 2,
   BufferOperation = 3,
   UpdateActiveLocation = 4,
+  UpdatePresence = 5,
+  UpdateOrder = 6,
 
 }
 
 const ENUM_MIN_OPERATION: u8 = 0;
-const ENUM_MAX_OPERATION: u8 = 4;
+const ENUM_MAX_OPERATION: u8 = 6;
 
 impl<'a> flatbuffers::Follow<'a> for Operation {
   type Inner = Self;
@@ -1163,21 +1177,25 @@ impl flatbuffers::Push for Operation {
 }
 
 #[allow(non_camel_case_types)]
-const ENUM_VALUES_OPERATION:[Operation; 5] = [
+const ENUM_VALUES_OPERATION:[Operation; 7] = [
   Operation::NONE,
   Operation::InsertMetadata,
   Operation::UpdateParent,
   Operation::BufferOperation,
-  Operation::UpdateActiveLocation
+  Operation::UpdateActiveLocation,
+  Operation::UpdatePresence,
+  Operation::UpdateOrder
 ];
 
 #[allow(non_camel_case_types)]
-const ENUM_NAMES_OPERATION:[&'static str; 5] = [
+const ENUM_NAMES_OPERATION:[&'static str; 7] = [
     "NONE",
     "InsertMetadata",
     "UpdateParent",
     "BufferOperation",
-    "UpdateActiveLocation"
+    "UpdateActiveLocation",
+    "UpdatePresence",
+    "UpdateOrder"
 ];
 
 pub fn enum_name_operation(e: Operation) -> &'static str {
@@ -1990,6 +2008,250 @@ impl<'a: 'b, 'b> UpdateActiveLocationBuilder<'a, 'b> {
   }
 }
 
+pub enum UpdatePresenceOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct UpdatePresence<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for UpdatePresence<'a> {
+    type Inner = UpdatePresence<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> UpdatePresence<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        UpdatePresence {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args UpdatePresenceArgs<'args>) -> flatbuffers::WIPOffset<UpdatePresence<'bldr>> {
+      let mut builder = UpdatePresenceBuilder::new(_fbb);
+      if let Some(x) = args.lamport_timestamp { builder.add_lamport_timestamp(x); }
+      if let Some(x) = args.replica_id { builder.add_replica_id(x); }
+      builder.add_color(args.color);
+      if let Some(x) = args.label { builder.add_label(x); }
+      builder.finish()
+    }
+
+    pub const VT_REPLICA_ID: flatbuffers::VOffsetT = 4;
+    pub const VT_LABEL: flatbuffers::VOffsetT = 6;
+    pub const VT_COLOR: flatbuffers::VOffsetT = 8;
+    pub const VT_LAMPORT_TIMESTAMP: flatbuffers::VOffsetT = 10;
+
+  #[inline]
+  pub fn replica_id(&self) -> Option<&'a super::ReplicaId> {
+    self._tab.get::<super::ReplicaId>(UpdatePresence::VT_REPLICA_ID, None)
+  }
+  #[inline]
+  pub fn label(&self) -> Option<&'a str> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<&str>>(UpdatePresence::VT_LABEL, None)
+  }
+  #[inline]
+  pub fn color(&self) -> u32 {
+    self._tab.get::<u32>(UpdatePresence::VT_COLOR, Some(0)).unwrap()
+  }
+  #[inline]
+  pub fn lamport_timestamp(&self) -> Option<&'a super::Timestamp> {
+    self._tab.get::<super::Timestamp>(UpdatePresence::VT_LAMPORT_TIMESTAMP, None)
+  }
+}
+
+pub struct UpdatePresenceArgs<'a> {
+    pub replica_id: Option<&'a  super::ReplicaId>,
+    pub label: Option<flatbuffers::WIPOffset<&'a  str>>,
+    pub color: u32,
+    pub lamport_timestamp: Option<&'a  super::Timestamp>,
+}
+impl<'a> Default for UpdatePresenceArgs<'a> {
+    #[inline]
+    fn default() -> Self {
+        UpdatePresenceArgs {
+            replica_id: None,
+            label: None,
+            color: 0,
+            lamport_timestamp: None,
+        }
+    }
+}
+pub struct UpdatePresenceBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> UpdatePresenceBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_replica_id(&mut self, replica_id: &'b  super::ReplicaId) {
+    self.fbb_.push_slot_always::<&super::ReplicaId>(UpdatePresence::VT_REPLICA_ID, replica_id);
+  }
+  #[inline]
+  pub fn add_label(&mut self, label: flatbuffers::WIPOffset<&'b  str>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(UpdatePresence::VT_LABEL, label);
+  }
+  #[inline]
+  pub fn add_color(&mut self, color: u32) {
+    self.fbb_.push_slot::<u32>(UpdatePresence::VT_COLOR, color, 0);
+  }
+  #[inline]
+  pub fn add_lamport_timestamp(&mut self, lamport_timestamp: &'b  super::Timestamp) {
+    self.fbb_.push_slot_always::<&super::Timestamp>(UpdatePresence::VT_LAMPORT_TIMESTAMP, lamport_timestamp);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> UpdatePresenceBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    UpdatePresenceBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<UpdatePresence<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum UpdateOrderOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct UpdateOrder<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for UpdateOrder<'a> {
+    type Inner = UpdateOrder<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> UpdateOrder<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        UpdateOrder {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args UpdateOrderArgs<'args>) -> flatbuffers::WIPOffset<UpdateOrder<'bldr>> {
+      let mut builder = UpdateOrderBuilder::new(_fbb);
+      if let Some(x) = args.lamport_timestamp { builder.add_lamport_timestamp(x); }
+      if let Some(x) = args.order_key { builder.add_order_key(x); }
+      if let Some(x) = args.file_id { builder.add_file_id(x); }
+      builder.add_file_id_type(args.file_id_type);
+      builder.finish()
+    }
+
+    pub const VT_FILE_ID_TYPE: flatbuffers::VOffsetT = 4;
+    pub const VT_FILE_ID: flatbuffers::VOffsetT = 6;
+    pub const VT_ORDER_KEY: flatbuffers::VOffsetT = 8;
+    pub const VT_LAMPORT_TIMESTAMP: flatbuffers::VOffsetT = 10;
+
+  #[inline]
+  pub fn file_id_type(&self) -> FileId {
+    self._tab.get::<FileId>(UpdateOrder::VT_FILE_ID_TYPE, Some(FileId::NONE)).unwrap()
+  }
+  #[inline]
+  pub fn file_id(&self) -> Option<flatbuffers::Table<'a>> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Table<'a>>>(UpdateOrder::VT_FILE_ID, None)
+  }
+  #[inline]
+  pub fn order_key(&self) -> Option<&'a [u8]> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, u8>>>(UpdateOrder::VT_ORDER_KEY, None).map(|v| v.safe_slice())
+  }
+  #[inline]
+  pub fn lamport_timestamp(&self) -> Option<&'a super::Timestamp> {
+    self._tab.get::<super::Timestamp>(UpdateOrder::VT_LAMPORT_TIMESTAMP, None)
+  }
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn file_id_as_base_file_id(&'a self) -> Option<BaseFileId> {
+    if self.file_id_type() == FileId::BaseFileId {
+      self.file_id().map(|u| BaseFileId::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn file_id_as_new_file_id(&'a self) -> Option<NewFileId> {
+    if self.file_id_type() == FileId::NewFileId {
+      self.file_id().map(|u| NewFileId::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+}
+
+pub struct UpdateOrderArgs<'a> {
+    pub file_id_type: FileId,
+    pub file_id: Option<flatbuffers::WIPOffset<flatbuffers::UnionWIPOffset>>,
+    pub order_key: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a ,  u8>>>,
+    pub lamport_timestamp: Option<&'a  super::Timestamp>,
+}
+impl<'a> Default for UpdateOrderArgs<'a> {
+    #[inline]
+    fn default() -> Self {
+        UpdateOrderArgs {
+            file_id_type: FileId::NONE,
+            file_id: None,
+            order_key: None,
+            lamport_timestamp: None,
+        }
+    }
+}
+pub struct UpdateOrderBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> UpdateOrderBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_file_id_type(&mut self, file_id_type: FileId) {
+    self.fbb_.push_slot::<FileId>(UpdateOrder::VT_FILE_ID_TYPE, file_id_type, FileId::NONE);
+  }
+  #[inline]
+  pub fn add_file_id(&mut self, file_id: flatbuffers::WIPOffset<flatbuffers::UnionWIPOffset>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(UpdateOrder::VT_FILE_ID, file_id);
+  }
+  #[inline]
+  pub fn add_order_key(&mut self, order_key: flatbuffers::WIPOffset<flatbuffers::Vector<'b , u8>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(UpdateOrder::VT_ORDER_KEY, order_key);
+  }
+  #[inline]
+  pub fn add_lamport_timestamp(&mut self, lamport_timestamp: &'b  super::Timestamp) {
+    self.fbb_.push_slot_always::<&super::Timestamp>(UpdateOrder::VT_LAMPORT_TIMESTAMP, lamport_timestamp);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> UpdateOrderBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    UpdateOrderBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<UpdateOrder<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
 }  // pub mod epoch
 
 pub mod worktree {
@@ -2245,6 +2507,26 @@ impl<'a> EpochOperation<'a> {
     }
   }
 
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn operation_as_update_presence(&'a self) -> Option<super::epoch::UpdatePresence> {
+    if self.operation_type() == super::epoch::Operation::UpdatePresence {
+      self.operation().map(|u| super::epoch::UpdatePresence::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn operation_as_update_order(&'a self) -> Option<super::epoch::UpdateOrder> {
+    if self.operation_type() == super::epoch::Operation::UpdateOrder {
+      self.operation().map(|u| super::epoch::UpdateOrder::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
 }
 
 pub struct EpochOperationArgs<'a> {
@@ -7,8 +7,9 @@ use serde::{Deserializer, Serializer};
 use serde_derive::{Deserialize, Serialize};
 use std::cmp::{self, Ordering};
 use std::collections::HashMap;
+use std::iter::FromIterator;
 use std::mem;
-use std::ops::{Add, AddAssign};
+use std::ops::{Add, AddAssign, Range};
 use std::sync::Arc;
 
 #[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Ord, PartialOrd)]
@@ -48,12 +49,25 @@ impl Local {
         timestamp
     }
 
+    // Reserves the values that `n` consecutive calls to `tick` would have returned.
+    pub fn tick_by(&mut self, n: u64) -> Range<u64> {
+        let start = self.value;
+        self.value += n;
+        start..self.value
+    }
+
     pub fn observe(&mut self, timestamp: Self) {
         if timestamp.replica_id == self.replica_id {
             self.value = cmp::max(self.value, timestamp.value + 1);
         }
     }
 
+    pub fn rename_replica(&mut self, old: ReplicaId, new: ReplicaId) {
+        if self.replica_id == old {
+            self.replica_id = new;
+        }
+    }
+
     pub fn to_flatbuf(&self) -> serialization::Timestamp {
         serialization::Timestamp::new(self.value, &self.replica_id.to_flatbuf())
     }
@@ -110,6 +124,10 @@ impl Global {
         *self.0.get(&replica_id).unwrap_or(&0)
     }
 
+    pub fn replica_ids<'a>(&'a self) -> impl 'a + Iterator<Item = ReplicaId> {
+        self.0.keys().cloned()
+    }
+
     pub fn observe(&mut self, timestamp: Local) {
         let map = Arc::make_mut(&mut self.0);
         let value = map.entry(timestamp.replica_id).or_insert(0);
@@ -125,10 +143,35 @@ impl Global {
         }
     }
 
+    pub fn meet(&self, other: &Self) -> Self {
+        let mut meet = HashMap::new();
+        for (replica_id, value) in self.0.as_ref() {
+            let value = cmp::min(*value, other.get(*replica_id));
+            if value > 0 {
+                meet.insert(*replica_id, value);
+            }
+        }
+        Global(Arc::new(meet))
+    }
+
+    pub fn join(&self, other: &Self) -> Self {
+        let mut join = self.clone();
+        join.observe_all(other);
+        join
+    }
+
     pub fn observed(&self, timestamp: Local) -> bool {
         self.get(timestamp.replica_id) >= timestamp.value
     }
 
+    pub fn rename_replica(&mut self, old: ReplicaId, new: ReplicaId) {
+        if self.0.contains_key(&old) {
+            let map = Arc::make_mut(&mut self.0);
+            let value = map.remove(&old).unwrap();
+            map.insert(new, value);
+        }
+    }
+
     pub fn changed_since(&self, other: &Self) -> bool {
         self.0
             .iter()
@@ -139,8 +182,10 @@ impl Global {
         &self,
         builder: &mut FlatBufferBuilder<'fbb>,
     ) -> WIPOffset<serialization::GlobalTimestamp<'fbb>> {
-        builder.start_vector::<serialization::Timestamp>(self.0.len());
-        for (replica_id, value) in self.0.as_ref() {
+        let mut entries = self.0.iter().collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|(replica_id, _)| **replica_id);
+        builder.start_vector::<serialization::Timestamp>(entries.len());
+        for (replica_id, value) in entries {
             builder.push(&serialization::Timestamp::new(
                 *value,
                 &replica_id.to_flatbuf(),
@@ -157,7 +202,10 @@ impl Global {
         message: serialization::GlobalTimestamp<'fbb>,
     ) -> Result<Self, Error> {
         let mut local_timestamps = HashMap::new();
-        for local_timestamp in message.timestamps().ok_or(Error::DeserializeError)? {
+        for local_timestamp in message
+            .timestamps()
+            .ok_or(Error::DeserializeError("timestamps"))?
+        {
             let replica_id = ReplicaId::from_flatbuf(local_timestamp.replica_id());
             let value = local_timestamp.value();
             local_timestamps.insert(replica_id, value);
@@ -166,6 +214,17 @@ impl Global {
     }
 }
 
+impl FromIterator<Local> for Global {
+    fn from_iter<I: IntoIterator<Item = Local>>(locals: I) -> Self {
+        let mut map = HashMap::new();
+        for local in locals {
+            let value = map.entry(local.replica_id).or_insert(0);
+            *value = cmp::max(*value, local.value);
+        }
+        Global(Arc::new(map))
+    }
+}
+
 impl PartialOrd for Global {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         let mut global_ordering = Ordering::Equal;
@@ -199,10 +258,22 @@ impl Lamport {
         timestamp
     }
 
+    pub fn tick_by(&mut self, n: u64) -> Range<u64> {
+        let start = self.value;
+        self.value += n;
+        start..self.value
+    }
+
     pub fn observe(&mut self, timestamp: Self) {
         self.value = cmp::max(self.value, timestamp.value) + 1;
     }
 
+    pub fn rename_replica(&mut self, old: ReplicaId, new: ReplicaId) {
+        if self.replica_id == old {
+            self.replica_id = new;
+        }
+    }
+
     pub fn to_flatbuf(&self) -> serialization::Timestamp {
         serialization::Timestamp::new(self.value, &self.replica_id.to_flatbuf())
     }
@@ -221,3 +292,117 @@ impl Lamport {
         bytes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_meet_and_join() {
+        let replica_1 = Uuid::from_u128(1);
+        let replica_2 = Uuid::from_u128(2);
+        let replica_3 = Uuid::from_u128(3);
+
+        let a = global(&[(replica_1, 3), (replica_2, 5)]);
+        let b = global(&[(replica_1, 4), (replica_2, 2), (replica_3, 1)]);
+        assert_eq!(a.meet(&b), global(&[(replica_1, 3), (replica_2, 2)]));
+        assert_eq!(b.meet(&a), a.meet(&b));
+        assert_eq!(
+            a.join(&b),
+            global(&[(replica_1, 4), (replica_2, 5), (replica_3, 1)])
+        );
+        assert_eq!(b.join(&a), a.join(&b));
+        assert!(a.meet(&b) <= a && a.meet(&b) <= b);
+        assert!(a.join(&b) >= a && a.join(&b) >= b);
+
+        let c = global(&[(replica_3, 7)]);
+        assert_eq!(a.meet(&c), Global::new());
+        assert_eq!(
+            a.join(&c),
+            global(&[(replica_1, 3), (replica_2, 5), (replica_3, 7)])
+        );
+        assert_eq!(a.meet(&Global::new()), Global::new());
+        assert_eq!(a.join(&Global::new()), a);
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let replica_1 = Uuid::from_u128(1);
+        let replica_2 = Uuid::from_u128(2);
+        let locals = vec![
+            Local {
+                replica_id: replica_1,
+                value: 3,
+            },
+            Local {
+                replica_id: replica_2,
+                value: 1,
+            },
+            Local {
+                replica_id: replica_1,
+                value: 7,
+            },
+            Local {
+                replica_id: replica_1,
+                value: 5,
+            },
+        ];
+        assert_eq!(
+            Global::from_iter(locals.clone()),
+            global(&[(replica_1, 7), (replica_2, 1)])
+        );
+        assert_eq!(
+            locals.into_iter().rev().collect::<Global>().get(replica_1),
+            7
+        );
+        assert_eq!(Global::from_iter(None), Global::new());
+    }
+
+    #[test]
+    fn test_tick_by() {
+        let mut local_1 = Local::new(Uuid::from_u128(1));
+        let mut local_2 = local_1;
+        local_1.tick();
+        local_2.tick();
+        let values = (0..5).map(|_| local_1.tick().value).collect::<Vec<_>>();
+        assert_eq!(local_2.tick_by(5).collect::<Vec<_>>(), values);
+        assert_eq!(local_2, local_1);
+        assert_eq!(local_2.tick_by(0), local_1.value..local_1.value);
+
+        let mut lamport_1 = Lamport::new(Uuid::from_u128(1));
+        let mut lamport_2 = lamport_1;
+        let values = (0..3).map(|_| lamport_1.tick().value).collect::<Vec<_>>();
+        assert_eq!(lamport_2.tick_by(3).collect::<Vec<_>>(), values);
+        assert_eq!(lamport_2, lamport_1);
+    }
+
+    #[test]
+    fn test_deterministic_serialization() {
+        let timestamps = (1..=20)
+            .map(|i| (Uuid::from_u128(i), i as u64))
+            .collect::<Vec<_>>();
+        let reversed_timestamps = timestamps.iter().cloned().rev().collect::<Vec<_>>();
+        assert_eq!(
+            serialize(&global(&timestamps)),
+            serialize(&global(&reversed_timestamps))
+        );
+    }
+
+    fn serialize(global: &Global) -> Vec<u8> {
+        let mut builder = FlatBufferBuilder::new();
+        let root = global.to_flatbuf(&mut builder);
+        builder.finish(root, None);
+        builder.finished_data().to_vec()
+    }
+
+    fn global(timestamps: &[(ReplicaId, u64)]) -> Global {
+        timestamps
+            .iter()
+            .map(|(replica_id, value)| Local {
+                replica_id: *replica_id,
+                value: *value,
+            })
+            .collect()
+    }
+}
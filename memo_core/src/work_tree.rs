@@ -1,28 +1,59 @@
-use crate::buffer::{self, Change, Point, Text};
-use crate::epoch::{self, Cursor, DirEntry, Epoch, FileId, FileType};
+use crate::buffer::{self, Change, MemoryReport, OpStats, Point, Text};
+use crate::epoch::{self, Cursor, DirEntry, Epoch, FileId, FileStatus, FileType, Presence};
+use crate::operation_queue::{Lane, OverflowPolicy};
 use crate::serialization;
-use crate::{time, Error, Oid, ReplicaId};
+use crate::{time, Error, Oid, ReplicaId, SERIALIZATION_VERSION};
 use flatbuffers::{FlatBufferBuilder, WIPOffset};
 use futures::{future, stream, Async, Future, Poll, Stream};
 use serde_derive::{Deserialize, Serialize};
 use std::cell::{Ref, RefCell, RefMut};
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
 use std::mem;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const STREAMING_APPLY_CHUNK_SIZE: usize = 64;
+const DEFAULT_DEDUP_WINDOW: usize = 4096;
+pub const MAX_ROUTE_LEN: usize = 255;
 
 pub trait GitProvider {
     fn base_entries(&self, oid: Oid) -> Box<Stream<Item = DirEntry, Error = io::Error>>;
     fn base_text(&self, oid: Oid, path: &Path) -> Box<Future<Item = String, Error = io::Error>>;
+
+    fn is_ignored(&self, _path: &Path) -> Result<bool, io::Error> {
+        Ok(false)
+    }
 }
 
 pub trait ChangeObserver {
     fn changed(&self, buffer_id: BufferId, changes: Vec<Change>, selections: BufferSelectionRanges);
 }
 
+pub trait Diagnostics {
+    fn warn(&self, warning: Warning);
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Warning {
+    DroppedStaleOperation {
+        epoch_id: epoch::Id,
+        cur_epoch_id: epoch::Id,
+    },
+    DroppedDeferredOperations {
+        count: usize,
+    },
+    // Anchors or remote edits that referred to collected tombstones and were resolved to where the
+    // tombstones used to be.
+    AnchorResolutionFallback {
+        file_id: FileId,
+        count: usize,
+    },
+}
+
 pub struct WorkTree {
     epoch: Option<Rc<RefCell<Epoch>>>,
     buffers: Rc<RefCell<HashMap<BufferId, FileId>>>,
@@ -34,6 +65,28 @@ pub struct WorkTree {
     lamport_clock: Rc<RefCell<time::Lamport>>,
     git: Rc<GitProvider>,
     observer: Option<Rc<ChangeObserver>>,
+    diagnostics: Option<Rc<Diagnostics>>,
+    hard_remove_deletions: bool,
+    hard_removed_barrier: Option<(epoch::Id, time::Global)>,
+    reject_stale_ops: bool,
+    skip_noop_ops: bool,
+    ensure_trailing_newline: bool,
+    deferred_ops_limit: Option<(usize, OverflowPolicy)>,
+    blocked_ops: Vec<Operation>,
+    saved_versions: HashMap<BufferId, (epoch::Id, time::Global)>,
+    size_thresholds: Rc<RefCell<HashMap<BufferId, SizeThreshold>>>,
+    acks: HashMap<ReplicaId, Version>,
+    last_flushed: Option<(epoch::Id, HashMap<Lane, time::Lamport>)>,
+    replica_activity: ReplicaActivity,
+    compaction: Compaction,
+    rate_limits: HashMap<ReplicaId, RateLimit>,
+    rolled_back: HashSet<time::Lamport>,
+    undo_grouping: Option<GroupingPolicy>,
+    undo_groups: RefCell<HashMap<BufferId, Vec<UndoGroup>>>,
+    snapshots: HashMap<BufferId, buffer::Buffer>,
+    base_hunks: Rc<RefCell<HashMap<(BufferId, Oid), BaseHunks>>>,
+    applied_ops: AppliedOps,
+    ignored_paths: RefCell<HashMap<PathBuf, bool>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -45,6 +98,9 @@ pub struct Version {
 pub struct OperationEnvelope {
     pub epoch_head: Option<Oid>,
     pub operation: Operation,
+    // An application-defined routing key that is carried along with the operation but never
+    // interpreted by the work tree. It can be at most `MAX_ROUTE_LEN` bytes long.
+    pub route: Vec<u8>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -65,10 +121,90 @@ pub struct BufferId(u32);
 #[derive(Copy, Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct LocalSelectionSetId(u32);
 
+// Identifies an undo group by the id of its first edit.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TransactionId(time::Local);
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct BufferSelectionRanges {
     pub local: HashMap<LocalSelectionSetId, Vec<Range<Point>>>,
     pub remote: HashMap<ReplicaId, Vec<Vec<Range<Point>>>>,
+    pub presences: HashMap<ReplicaId, Presence>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GroupingPolicy {
+    pub max_pause: Duration,
+    pub break_on_newline: bool,
+}
+
+#[cfg(feature = "serde_json")]
+#[derive(Serialize)]
+struct OperationLogEntry {
+    epoch: u64,
+    replica_id: ReplicaId,
+    lamport: u64,
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    range: Option<[OperationLogPosition; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+}
+
+#[cfg(feature = "serde_json")]
+#[derive(Serialize)]
+struct OperationLogPosition {
+    replica_id: ReplicaId,
+    insertion: u64,
+    offset: usize,
+}
+
+#[derive(Default)]
+struct Compaction {
+    idle_after: Option<Duration>,
+    observed: Option<(Instant, epoch::Id, time::Global)>,
+    last_run: Option<(Instant, epoch::Id, time::Global)>,
+}
+
+struct BaseHunks {
+    version: (epoch::Id, time::Global),
+    hunks: Rc<Vec<Range<Point>>>,
+}
+
+struct UndoGroup {
+    edit_ids: Vec<time::Local>,
+    end: usize,
+    last_edit: Instant,
+    mergeable: bool,
+}
+
+struct RateLimit {
+    ops_per_sec: u32,
+    allowance: f64,
+    last_checked: Option<Instant>,
+}
+
+struct SizeThreshold {
+    bytes: usize,
+    last_bytes: usize,
+    callback: Box<Fn(BufferId, usize)>,
+}
+
+struct AppliedOps {
+    window: usize,
+    ids: HashSet<(epoch::Id, time::Lamport)>,
+    order: VecDeque<(epoch::Id, time::Lamport)>,
+}
+
+#[derive(Default)]
+struct ReplicaActivity {
+    known: HashSet<ReplicaId>,
+    last_seen: HashMap<ReplicaId, time::Lamport>,
+    idle: HashSet<ReplicaId>,
+    on_joined: Option<Box<Fn(ReplicaId)>>,
+    on_idle: Option<(u64, Box<Fn(ReplicaId, time::Lamport)>)>,
 }
 
 enum MaybeDone<F: Future> {
@@ -81,6 +217,17 @@ struct BaseTextRequest {
     path: PathBuf,
 }
 
+// Statuses are listed in tree order. Text files without a status yet are compared with their text
+// at `base` once the cursor reaches them, or reaches a directory containing them.
+pub struct StatusCursor {
+    statuses: Vec<(PathBuf, FileType, Option<FileStatus>)>,
+    next_ix: usize,
+    comparison: Option<(usize, Box<Future<Item = bool, Error = Error>>)>,
+    epoch: Rc<RefCell<Epoch>>,
+    base: Oid,
+    git: Rc<GitProvider>,
+}
+
 struct SwitchEpoch {
     to_assign: Rc<RefCell<Epoch>>,
     cur_epoch: Rc<RefCell<Epoch>>,
@@ -93,6 +240,7 @@ struct SwitchEpoch {
     lamport_clock: Rc<RefCell<time::Lamport>>,
     git: Rc<GitProvider>,
     observer: Option<Rc<ChangeObserver>>,
+    diagnostics: Option<Rc<Diagnostics>>,
 }
 
 impl WorkTree {
@@ -123,6 +271,28 @@ impl WorkTree {
             lamport_clock: Rc::new(RefCell::new(time::Lamport::new(replica_id))),
             git,
             observer,
+            diagnostics: None,
+            hard_remove_deletions: false,
+            hard_removed_barrier: None,
+            reject_stale_ops: false,
+            skip_noop_ops: false,
+            ensure_trailing_newline: false,
+            deferred_ops_limit: None,
+            blocked_ops: Vec::new(),
+            saved_versions: HashMap::new(),
+            size_thresholds: Rc::new(RefCell::new(HashMap::new())),
+            acks: HashMap::new(),
+            last_flushed: None,
+            replica_activity: ReplicaActivity::default(),
+            compaction: Compaction::default(),
+            rate_limits: HashMap::new(),
+            rolled_back: HashSet::new(),
+            undo_grouping: None,
+            undo_groups: RefCell::new(HashMap::new()),
+            snapshots: HashMap::new(),
+            base_hunks: Rc::new(RefCell::new(HashMap::new())),
+            applied_ops: AppliedOps::new(DEFAULT_DEDUP_WINDOW),
+            ignored_paths: RefCell::new(HashMap::new()),
         };
 
         let ops = if ops.peek().is_none() {
@@ -134,12 +304,276 @@ impl WorkTree {
         Ok((tree, ops))
     }
 
+    // Gives the local replica a new id, for example when a replica restored from disk turns out to
+    // share its id with another one. Peers only ever learn about one of the two ids, so this is
+    // refused once any operations have been flushed.
+    pub fn rename_replica(&mut self, old: ReplicaId, new: ReplicaId) -> Result<(), Error> {
+        if old != self.replica_id() || self.last_flushed.is_some() {
+            return Err(Error::InvalidReplicaId(old));
+        }
+        let new_is_present = self.replica_id() == new
+            || self
+                .epoch
+                .as_ref()
+                .map_or(false, |epoch| epoch.borrow().contains_replica(new));
+        if new_is_present {
+            return Err(Error::InvalidReplicaId(new));
+        }
+
+        self.lamport_clock.borrow_mut().rename_replica(old, new);
+        if let Some(epoch) = self.epoch.as_ref() {
+            epoch.borrow_mut().rename_replica(old, new);
+        }
+        for file_id in self.buffers.borrow_mut().values_mut() {
+            file_id.rename_replica(old, new);
+        }
+        for set_ids in self.local_selection_sets.borrow_mut().values_mut() {
+            for set_id in set_ids.values_mut() {
+                set_id.rename_replica(old, new);
+            }
+        }
+
+        let deferred_ops = mem::replace(&mut *self.deferred_ops.borrow_mut(), HashMap::new());
+        for (mut epoch_id, mut operations) in deferred_ops {
+            epoch_id.rename_replica(old, new);
+            for operation in &mut operations {
+                operation.rename_replica(old, new);
+            }
+            self.deferred_ops.borrow_mut().insert(epoch_id, operations);
+        }
+        for (epoch_id, version) in self.saved_versions.values_mut() {
+            epoch_id.rename_replica(old, new);
+            version.rename_replica(old, new);
+        }
+        if let Some(ack) = self.acks.remove(&old) {
+            self.acks.insert(new, ack);
+        }
+        for ack in self.acks.values_mut() {
+            ack.epoch_id.rename_replica(old, new);
+            ack.epoch_version.rename_replica(old, new);
+        }
+
+        for operation in &mut self.blocked_ops {
+            match operation {
+                Operation::StartEpoch { epoch_id, .. } => epoch_id.rename_replica(old, new),
+                Operation::EpochOperation {
+                    epoch_id,
+                    operation,
+                } => {
+                    epoch_id.rename_replica(old, new);
+                    operation.rename_replica(old, new);
+                }
+            }
+        }
+        if let Some((epoch_id, barrier)) = self.hard_removed_barrier.as_mut() {
+            epoch_id.rename_replica(old, new);
+            barrier.rename_replica(old, new);
+        }
+        for (_, epoch_id, version) in self
+            .compaction
+            .observed
+            .iter_mut()
+            .chain(self.compaction.last_run.iter_mut())
+        {
+            epoch_id.rename_replica(old, new);
+            version.rename_replica(old, new);
+        }
+        for base_hunks in self.base_hunks.borrow_mut().values_mut() {
+            base_hunks.version.0.rename_replica(old, new);
+            base_hunks.version.1.rename_replica(old, new);
+        }
+        for snapshot in self.snapshots.values_mut() {
+            snapshot.rename_replica(old, new);
+        }
+        for groups in self.undo_groups.borrow_mut().values_mut() {
+            for group in groups {
+                for edit_id in &mut group.edit_ids {
+                    edit_id.rename_replica(old, new);
+                }
+            }
+        }
+        let rolled_back = mem::replace(&mut self.rolled_back, HashSet::new());
+        for mut timestamp in rolled_back {
+            timestamp.rename_replica(old, new);
+            self.rolled_back.insert(timestamp);
+        }
+        for (epoch_id, timestamp) in &mut self.applied_ops.order {
+            epoch_id.rename_replica(old, new);
+            timestamp.rename_replica(old, new);
+        }
+        self.applied_ops.ids = self.applied_ops.order.iter().cloned().collect();
+        if let Some(rate_limit) = self.rate_limits.remove(&old) {
+            self.rate_limits.insert(new, rate_limit);
+        }
+
+        let activity = &mut self.replica_activity;
+        if activity.known.remove(&old) {
+            activity.known.insert(new);
+        }
+        if activity.idle.remove(&old) {
+            activity.idle.insert(new);
+        }
+        let last_seen = mem::replace(&mut activity.last_seen, HashMap::new());
+        for (mut replica_id, mut timestamp) in last_seen {
+            if replica_id == old {
+                replica_id = new;
+            }
+            timestamp.rename_replica(old, new);
+            activity.last_seen.insert(replica_id, timestamp);
+        }
+
+        Ok(())
+    }
+
+    // Reports operations that are discarded without an error being returned to the caller, such as
+    // operations from an old epoch, as well as anchors that could only be resolved approximately.
+    // Until diagnostics are set, these are dropped silently.
+    pub fn set_diagnostics(&mut self, diagnostics: Rc<Diagnostics>) {
+        self.diagnostics = Some(diagnostics);
+    }
+
+    fn warn(&self, warning: Warning) {
+        if let Some(diagnostics) = self.diagnostics.as_ref() {
+            diagnostics.warn(warning);
+        }
+    }
+
+    fn report_anchor_fallbacks(&self) {
+        if let Some(epoch) = self.epoch.as_ref() {
+            for (file_id, count) in epoch.borrow().take_anchor_fallbacks() {
+                self.warn(Warning::AnchorResolutionFallback { file_id, count });
+            }
+        }
+    }
+
+    // Collects tombstones automatically whenever a recorded ack advances the GC barrier, instead
+    // of waiting for an explicit `collect_garbage` or an idle compaction. The tree is walked once
+    // per barrier advance rather than once per deletion. Like `gc_barrier`, this relies on every
+    // peer reporting acks through `record_ack`, and nothing is collected until one has.
+    pub fn set_hard_remove_deletions(&mut self, hard_remove_deletions: bool) {
+        self.hard_remove_deletions = hard_remove_deletions;
+        if hard_remove_deletions && !self.acks.is_empty() {
+            self.hard_remove_acknowledged_deletions();
+        }
+    }
+
+    pub fn set_reject_stale_ops(&mut self, reject_stale_ops: bool) {
+        self.reject_stale_ops = reject_stale_ops;
+    }
+
+    // When enabled, operations without any effect are left out of `flush_operations`, which cuts
+    // down on chatter from clients that update unchanged selections on every frame.
+    pub fn set_skip_noop_ops(&mut self, skip_noop_ops: bool) {
+        self.skip_noop_ops = skip_noop_ops;
+    }
+
+    // Only affects `export`. Normalizing the ending of the live buffer instead would generate
+    // operations that every replica would have to agree on.
+    pub fn set_ensure_trailing_newline(&mut self, ensure_trailing_newline: bool) {
+        self.ensure_trailing_newline = ensure_trailing_newline;
+    }
+
+    // Bounds the number of operations that can be deferred until their epoch starts. When an
+    // operation doesn't fit, `Block` holds it back until a later call to `apply_ops` finds room for
+    // it, `DropOldest` discards the deferred operations with the lowest timestamps, and `Error`
+    // rejects the whole batch with `Error::QueueFull`.
+    pub fn set_deferred_ops_limit(&mut self, capacity: usize, policy: OverflowPolicy) {
+        self.deferred_ops_limit = Some((capacity, policy));
+    }
+
+    // Sets how many of the most recently applied remote operations are remembered, so that
+    // `apply_ops` can skip redelivered copies of them. A window of zero disables deduplication.
+    pub fn set_dedup_window(&mut self, window: usize) {
+        self.applied_ops.set_window(window);
+    }
+
+    pub fn already_applied(&self, op: &OperationEnvelope) -> bool {
+        self.applied_ops.contains(op.operation.id())
+    }
+
+    // Limits how many operations `apply_ops_from` accepts from `replica_id`. Up to a second's worth
+    // of operations can arrive in a burst. A batch that exceeds the remaining allowance is rejected
+    // as a whole with `Error::RateLimited`, before any of it is applied.
+    pub fn set_rate_limit(&mut self, replica_id: ReplicaId, ops_per_sec: u32) {
+        self.rate_limits.insert(
+            replica_id,
+            RateLimit {
+                ops_per_sec,
+                allowance: f64::from(ops_per_sec),
+                last_checked: None,
+            },
+        );
+    }
+
+    // Groups consecutive local edits to the same buffer into undo units. An edit joins the current
+    // group if it's made within `max_pause` of the previous one and touches the position where the
+    // previous one ended, as when typing or deleting backwards. Inserting a newline starts a new
+    // group when `break_on_newline` is set. Until a policy is set, every edit forms a group of its
+    // own. There is no undo operation yet; the groups describe the units it would revert.
+    pub fn set_undo_grouping(&mut self, policy: GroupingPolicy) {
+        self.undo_grouping = Some(policy);
+    }
+
+    // The ids of the local edits made to the buffer in the current epoch, grouped into undo units
+    // from oldest to newest. Only edits made through `edit` can be merged into an existing group;
+    // every other kind of edit, including a rollback, forms a group of its own.
+    pub fn undo_groups(&self, buffer_id: BufferId) -> Result<Vec<Vec<time::Local>>, Error> {
+        self.file_id_for_buffer(buffer_id)?;
+        Ok(self
+            .undo_groups
+            .borrow()
+            .get(&buffer_id)
+            .map_or(Vec::new(), |groups| {
+                groups.iter().map(|group| group.edit_ids.clone()).collect()
+            }))
+    }
+
+    // The operations of every undo group in the current epoch that has changed since `since`, so
+    // that undo units can be reconstructed elsewhere. A group is always returned in full, even if
+    // some of its operations were already observed. Groups are ordered by transaction id, which
+    // matches the order in which they were started.
+    pub fn operations_grouped(
+        &self,
+        since: &time::Global,
+    ) -> Vec<(TransactionId, Vec<OperationEnvelope>)> {
+        let cur_epoch = self.cur_epoch();
+        let buffers = self.buffers.borrow();
+        let mut transactions = Vec::new();
+        for (buffer_id, groups) in self.undo_groups.borrow().iter() {
+            let file_id = match buffers.get(buffer_id) {
+                Some(file_id) => *file_id,
+                None => continue,
+            };
+            for group in groups {
+                let operations = cur_epoch.operations_for_edits(file_id, &group.edit_ids);
+                let changed = operations.iter().any(|operation| match operation {
+                    epoch::Operation::BufferOperation {
+                        local_timestamp, ..
+                    } => !since.observed(*local_timestamp),
+                    _ => false,
+                });
+                if changed {
+                    transactions.push((
+                        TransactionId(group.edit_ids[0]),
+                        OperationEnvelope::wrap_many(cur_epoch.id, cur_epoch.head, operations),
+                    ));
+                }
+            }
+        }
+        transactions.sort_by_key(|(transaction_id, _)| *transaction_id);
+        transactions
+    }
+
+    pub fn overflow_policy(&self) -> Option<OverflowPolicy> {
+        self.deferred_ops_limit.map(|(_, policy)| policy)
+    }
+
     pub fn head(&self) -> Option<Oid> {
         self.epoch.as_ref().and_then(|e| e.borrow().head)
     }
 
     pub fn epoch_id(&self) -> epoch::Id {
-        self.cur_epoch().id
+        self.cur_epoch().id()
     }
 
     pub fn reset(
@@ -150,6 +584,7 @@ impl WorkTree {
         stream::once(Ok(OperationEnvelope {
             epoch_head: head,
             operation: Operation::StartEpoch { epoch_id, head },
+            route: Vec::new(),
         }))
         .chain(self.start_epoch(epoch_id, head))
     }
@@ -164,20 +599,46 @@ impl WorkTree {
         let mut cur_epoch_ops = Vec::new();
         let mut epoch_streams = Vec::new();
 
+        let ops = ops.into_iter().collect::<Vec<_>>();
+        if self.reject_stale_ops {
+            if let Some(epoch) = self.epoch.as_ref() {
+                let cur_epoch_id = epoch.borrow().id();
+                if let Some(op) = ops.iter().find(|op| op.epoch_id() < cur_epoch_id) {
+                    return Err(Error::StaleEpoch(op.epoch_id()));
+                }
+            }
+        }
+        let mut batch_ids = HashSet::new();
+        let ops = ops
+            .into_iter()
+            .filter(|op| !self.applied_ops.contains(op.id()) && batch_ids.insert(op.id()))
+            .collect();
+        let ops = self.limit_deferred_ops(ops)?;
+
+        let mut applied_ids = Vec::new();
         for op in ops {
+            let id = op.id();
             match op {
                 Operation::StartEpoch { epoch_id, head } => {
                     self.lamport_clock.borrow_mut().observe(epoch_id);
                     epoch_streams.push(self.start_epoch(epoch_id, head));
+                    self.applied_ops.insert(id);
                 }
                 Operation::EpochOperation {
                     epoch_id,
                     operation,
                 } => {
                     if let Some(epoch) = self.epoch.clone() {
-                        match epoch_id.cmp(&epoch.borrow().id) {
-                            Ordering::Less => {}
-                            Ordering::Equal => cur_epoch_ops.push(operation),
+                        let cur_epoch_id = epoch.borrow().id;
+                        match epoch_id.cmp(&cur_epoch_id) {
+                            Ordering::Less => self.warn(Warning::DroppedStaleOperation {
+                                epoch_id,
+                                cur_epoch_id,
+                            }),
+                            Ordering::Equal => {
+                                applied_ids.push(id);
+                                cur_epoch_ops.push(operation);
+                            }
                             Ordering::Greater => self.defer_epoch_op(epoch_id, operation),
                         }
                     } else {
@@ -187,9 +648,17 @@ impl WorkTree {
             }
         }
 
+        self.drop_oldest_deferred_ops();
+
         if let Some(epoch_ref) = self.epoch.clone() {
             let mut epoch = epoch_ref.borrow_mut();
 
+            let seen_timestamps = cur_epoch_ops
+                .iter()
+                .map(|op| op.lamport_timestamp())
+                .collect::<Vec<_>>();
+            let version_before = epoch.version();
+            let presences_last_update = epoch.presences_last_update();
             let mut prev_versions = HashMap::new();
             for file_id in self.buffers.borrow().values() {
                 let edit_version = epoch.buffer_version(*file_id).unwrap();
@@ -197,15 +666,48 @@ impl WorkTree {
                 prev_versions.insert(*file_id, (edit_version, selections_last_update));
             }
 
-            let fixup_ops = epoch.apply_ops(cur_epoch_ops, &mut self.lamport_clock.borrow_mut())?;
+            let mut fixup_ops =
+                epoch.apply_ops(cur_epoch_ops, &mut self.lamport_clock.borrow_mut())?;
+            for id in applied_ids {
+                self.applied_ops.insert(id);
+            }
+
+            // Remote edits can shift local selections into each other, so merge them before the
+            // next local edit is applied to the same text twice.
+            for (buffer_id, file_id) in self.buffers.borrow().iter() {
+                let (edit_version, _) = &prev_versions[file_id];
+                if epoch
+                    .changes_since(*file_id, edit_version)?
+                    .next()
+                    .is_none()
+                {
+                    continue;
+                }
+                if let Some(buffer_sets) = self.local_selection_sets.borrow().get(buffer_id) {
+                    for set_id in buffer_sets.values() {
+                        let mut ranges = epoch
+                            .selection_ranges(*file_id, *set_id)?
+                            .collect::<Vec<_>>();
+                        if BufferSelectionRanges::normalize_ranges(&mut ranges) {
+                            fixup_ops.push(epoch.replace_selection_set(
+                                *file_id,
+                                *set_id,
+                                ranges,
+                                &mut self.lamport_clock.borrow_mut(),
+                            )?);
+                        }
+                    }
+                }
+            }
 
             if let Some(observer) = self.observer.as_ref() {
+                let presences_changed = epoch.presences_last_update() != presences_last_update;
                 for (buffer_id, file_id) in self.buffers.borrow().iter() {
-                    let (edit_version, selections_last_update) =
-                        prev_versions.remove(file_id).unwrap();
-                    let changes: Vec<_> = epoch.changes_since(*file_id, &edit_version)?.collect();
+                    let (edit_version, selections_last_update) = &prev_versions[file_id];
+                    let changes: Vec<_> = epoch.changes_since(*file_id, edit_version)?.collect();
                     if !changes.is_empty()
-                        || epoch.selections_changed_since(*file_id, selections_last_update)?
+                        || presences_changed
+                        || epoch.selections_changed_since(*file_id, *selections_last_update)?
                     {
                         observer.changed(
                             *buffer_id,
@@ -221,9 +723,18 @@ impl WorkTree {
                 }
             }
 
-            let fixup_ops_stream = Box::new(stream::iter_ok(OperationEnvelope::wrap_many(
-                epoch.id, epoch.head, fixup_ops,
-            )));
+            let new_replica_ids = epoch
+                .version()
+                .replica_ids()
+                .filter(|replica_id| version_before.get(*replica_id) == 0)
+                .collect::<Vec<_>>();
+            let fixup_ops = OperationEnvelope::wrap_many(epoch.id, epoch.head, fixup_ops);
+            drop(epoch);
+            self.check_size_thresholds();
+            self.report_anchor_fallbacks();
+            self.update_replica_activity(new_replica_ids, seen_timestamps);
+
+            let fixup_ops_stream = Box::new(stream::iter_ok(fixup_ops));
             Ok(epoch_streams.into_iter().fold(
                 fixup_ops_stream as Box<Stream<Item = OperationEnvelope, Error = Error>>,
                 |acc, stream| Box::new(acc.chain(stream)),
@@ -233,6 +744,173 @@ impl WorkTree {
         }
     }
 
+    // Like `apply_ops`, but ignores operations that originated from this replica. This makes it
+    // safe to use transports that reflect a replica's own operations back to it.
+    pub fn apply_ops_from<I>(
+        &mut self,
+        sender: ReplicaId,
+        ops: I,
+    ) -> Result<impl Stream<Item = OperationEnvelope, Error = Error>, Error>
+    where
+        I: IntoIterator<Item = Operation>,
+    {
+        self.apply_ops_from_at(sender, ops, Instant::now())
+    }
+
+    fn apply_ops_from_at<I>(
+        &mut self,
+        sender: ReplicaId,
+        ops: I,
+        now: Instant,
+    ) -> Result<impl Stream<Item = OperationEnvelope, Error = Error>, Error>
+    where
+        I: IntoIterator<Item = Operation>,
+    {
+        let replica_id = self.replica_id();
+        let ops: Vec<_> = if sender == replica_id {
+            Vec::new()
+        } else {
+            ops.into_iter()
+                .filter(|op| op.replica_id() != replica_id)
+                .collect()
+        };
+        self.check_rate_limit(sender, ops.len(), now)?;
+        self.apply_ops(ops)
+    }
+
+    fn check_rate_limit(
+        &mut self,
+        sender: ReplicaId,
+        op_count: usize,
+        now: Instant,
+    ) -> Result<(), Error> {
+        if let Some(limit) = self.rate_limits.get_mut(&sender) {
+            let ops_per_sec = f64::from(limit.ops_per_sec);
+            if let Some(last_checked) = limit.last_checked {
+                let elapsed = now.duration_since(last_checked);
+                let elapsed_secs =
+                    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0;
+                limit.allowance = (limit.allowance + elapsed_secs * ops_per_sec).min(ops_per_sec);
+            }
+            limit.last_checked = Some(now);
+
+            if op_count as f64 > limit.allowance {
+                return Err(Error::RateLimited(sender));
+            }
+            limit.allowance -= op_count as f64;
+        }
+        Ok(())
+    }
+
+    // Applies the operations in chunks, reporting the changes each chunk makes to open buffers
+    // before moving on to the next one. The resulting state is the same as with `apply_ops`.
+    //
+    // If a chunk fails after earlier chunks have already been applied, the operations generated
+    // in response to those chunks still need to be broadcast, so the returned stream yields them
+    // before ending with the error. An error is only returned directly when no chunk was applied.
+    pub fn apply_ops_streaming<I, F>(
+        &mut self,
+        ops: I,
+        mut f: F,
+    ) -> Result<impl Stream<Item = OperationEnvelope, Error = Error>, Error>
+    where
+        I: IntoIterator<Item = Operation>,
+        F: FnMut(BufferId, Change),
+    {
+        let ops = ops.into_iter().collect::<Vec<_>>();
+        let mut streams: Vec<Box<Stream<Item = OperationEnvelope, Error = Error>>> = Vec::new();
+        for chunk in ops.chunks(STREAMING_APPLY_CHUNK_SIZE) {
+            let prev_versions = if self.epoch.is_some() {
+                let cur_epoch = self.cur_epoch();
+                self.buffers
+                    .borrow()
+                    .iter()
+                    .filter_map(|(buffer_id, file_id)| {
+                        let version = cur_epoch.buffer_version(*file_id).ok()?;
+                        Some((*buffer_id, *file_id, version))
+                    })
+                    .collect::<Vec<_>>()
+            } else {
+                Vec::new()
+            };
+
+            match self.apply_ops(chunk.to_vec()) {
+                Ok(stream) => streams.push(Box::new(stream)),
+                Err(error) => {
+                    if streams.is_empty() {
+                        return Err(error);
+                    } else {
+                        streams.push(Box::new(stream::once(Err(error))));
+                        break;
+                    }
+                }
+            }
+
+            let cur_epoch = self.cur_epoch();
+            for (buffer_id, file_id, version) in prev_versions {
+                if let Ok(changes) = cur_epoch.changes_since(file_id, &version) {
+                    for change in changes {
+                        f(buffer_id, change);
+                    }
+                }
+            }
+        }
+
+        Ok(streams.into_iter().fold(
+            Box::new(stream::empty()) as Box<Stream<Item = OperationEnvelope, Error = Error>>,
+            |acc, stream| Box::new(acc.chain(stream)),
+        ))
+    }
+
+    // Interleaves the operations from each replica round-robin before applying them, so that a
+    // large backlog from one replica doesn't delay everyone else's updates. Each replica's
+    // operations are kept in their original order. The changes to open buffers are returned along
+    // with the operations generated in response, which need to be broadcast as with `apply_ops`.
+    pub fn apply_ops_fair(
+        &mut self,
+        ops_by_replica: HashMap<ReplicaId, Vec<Operation>>,
+    ) -> Result<
+        (
+            Vec<(BufferId, Change)>,
+            impl Stream<Item = OperationEnvelope, Error = Error>,
+        ),
+        Error,
+    > {
+        let mut queues = ops_by_replica
+            .into_iter()
+            .map(|(replica_id, ops)| (replica_id, ops.into_iter()))
+            .collect::<Vec<_>>();
+        queues.sort_by_key(|(replica_id, _)| *replica_id);
+
+        let mut ops = Vec::new();
+        loop {
+            let len = ops.len();
+            for (_, queue) in &mut queues {
+                ops.extend(queue.next());
+            }
+            if ops.len() == len {
+                break;
+            }
+        }
+
+        let mut changes = Vec::new();
+        let fixup_ops =
+            self.apply_ops_streaming(ops, |buffer_id, change| changes.push((buffer_id, change)))?;
+        Ok((changes, fixup_ops))
+    }
+
+    // Applies `op` as if its author had only observed `context` when generating it, which makes it
+    // possible to reproduce specific concurrency scenarios deterministically. The context can't
+    // include any of the operation's own timestamps.
+    pub fn apply_op_with_context(
+        &mut self,
+        mut op: Operation,
+        context: &time::Global,
+    ) -> Result<impl Stream<Item = OperationEnvelope, Error = Error>, Error> {
+        op.restrict_to_context(context)?;
+        self.apply_ops(Some(op))
+    }
+
     fn start_epoch(
         &mut self,
         new_epoch_id: epoch::Id,
@@ -243,6 +921,13 @@ impl WorkTree {
             .as_ref()
             .map_or(true, |e| new_epoch_id > e.borrow().id)
         {
+            // The new head may come with different ignore rules.
+            self.ignored_paths.borrow_mut().clear();
+            // Snapshots are taken at versions of the current epoch, which mean nothing in the next.
+            // Likewise, undo groups refer to edits that the next epoch doesn't contain.
+            self.snapshots.clear();
+            self.undo_groups.borrow_mut().clear();
+            self.base_hunks.borrow_mut().clear();
             let new_epoch = Rc::new(RefCell::new(Epoch::new(
                 self.replica_id(),
                 new_epoch_id,
@@ -284,6 +969,7 @@ impl WorkTree {
                     self.lamport_clock.clone(),
                     self.git.clone(),
                     self.observer.clone(),
+                    self.diagnostics.clone(),
                 )
                 .then(|fixup_ops| Ok(stream::iter_ok(fixup_ops?)))
                 .flatten_stream();
@@ -314,6 +1000,125 @@ impl WorkTree {
         }
     }
 
+    // Local operations are applied synchronously, before they are returned for broadcasting. A
+    // read right after a write therefore always reflects it, and this replica's own component of
+    // the version already covers it, even if a transport delivers the operation back early.
+    pub fn local_version(&self, replica_id: ReplicaId) -> u64 {
+        self.cur_epoch().version().get(replica_id)
+    }
+
+    pub fn record_ack(&mut self, replica_id: ReplicaId, version: Version) {
+        self.acks.insert(replica_id, version);
+        if self.hard_remove_deletions {
+            self.hard_remove_acknowledged_deletions();
+        }
+    }
+
+    fn hard_remove_acknowledged_deletions(&mut self) {
+        let barrier = (self.cur_epoch().id, self.gc_barrier());
+        if self.hard_removed_barrier.as_ref() != Some(&barrier) {
+            self.collect_garbage(&barrier.1);
+            self.hard_removed_barrier = Some(barrier);
+        }
+    }
+
+    // Returns a version that every known replica has acknowledged observing. No operation that is
+    // concurrent with the operations below the barrier can still be generated, so their
+    // tombstones can be collected safely. Replicas without an ack in the current epoch may not
+    // have observed anything, which keeps the barrier empty until they report one.
+    pub fn gc_barrier(&self) -> time::Global {
+        let version = self.version();
+        let local_replica_id = self.replica_id();
+        let mut barrier = version.epoch_version.clone();
+        let known_replica_ids = version
+            .epoch_version
+            .replica_ids()
+            .chain(self.acks.keys().cloned())
+            .filter(|replica_id| *replica_id != local_replica_id)
+            .collect::<HashSet<_>>();
+        for replica_id in known_replica_ids {
+            match self.acks.get(&replica_id) {
+                Some(ack) if ack.epoch_id == version.epoch_id => {
+                    barrier = barrier.meet(&ack.epoch_version);
+                }
+                _ => return time::Global::new(),
+            }
+        }
+        barrier
+    }
+
+    // Also prunes the history of the operations below the barrier that every peer acknowledged,
+    // since none of them can ask for those again.
+    pub fn collect_garbage(&self, barrier: &time::Global) {
+        let history_barrier = self.history_barrier(barrier);
+        let mut cur_epoch = self.cur_epoch_mut();
+        cur_epoch.collect_garbage(barrier);
+        cur_epoch.prune_history(&history_barrier);
+    }
+
+    // Until a peer acknowledges anything in the current epoch, the tree can't tell which of its
+    // operations were received, so the whole history is kept.
+    fn history_barrier(&self, barrier: &time::Global) -> time::Global {
+        let epoch_id = self.epoch_id();
+        if self.acks.values().any(|ack| ack.epoch_id == epoch_id) {
+            barrier.meet(&self.gc_barrier())
+        } else {
+            time::Global::new()
+        }
+    }
+
+    pub fn set_idle_compaction(&mut self, idle_after: Option<Duration>) {
+        self.compaction.idle_after = idle_after;
+    }
+
+    // Meant to be called periodically. The tree counts as idle once its version hasn't changed
+    // between calls for the configured duration, at which point fragments are coalesced,
+    // tombstones below the GC barrier are collected and the history is pruned as in
+    // `collect_garbage`. Returns whether a compaction ran.
+    pub fn maybe_compact(&mut self, now: Instant) -> bool {
+        let idle_after = match self.compaction.idle_after {
+            Some(idle_after) => idle_after,
+            None => return false,
+        };
+
+        let Version {
+            epoch_id,
+            epoch_version,
+        } = self.version();
+        match self.compaction.observed.as_ref() {
+            Some((since, observed_epoch_id, observed_version))
+                if *observed_epoch_id == epoch_id && *observed_version == epoch_version =>
+            {
+                if now.duration_since(*since) < idle_after {
+                    return false;
+                }
+            }
+            _ => {
+                self.compaction.observed = Some((now, epoch_id, epoch_version));
+                return false;
+            }
+        }
+
+        let barrier = self.gc_barrier();
+        if let Some((last_run, last_epoch_id, last_barrier)) = self.compaction.last_run.as_ref() {
+            if now.duration_since(*last_run) < idle_after
+                || (*last_epoch_id == epoch_id && *last_barrier == barrier)
+            {
+                return false;
+            }
+        }
+
+        {
+            let history_barrier = self.history_barrier(&barrier);
+            let mut cur_epoch = self.cur_epoch_mut();
+            cur_epoch.coalesce_fragments();
+            cur_epoch.collect_garbage(&barrier);
+            cur_epoch.prune_history(&history_barrier);
+        }
+        self.compaction.last_run = Some((now, epoch_id, barrier));
+        true
+    }
+
     pub fn with_cursor<F>(&self, mut f: F)
     where
         F: FnMut(&mut Cursor),
@@ -323,6 +1128,38 @@ impl WorkTree {
         }
     }
 
+    // Resolves to a cursor over each entry's status relative to `base`, with directories
+    // reporting `Modified` when anything beneath them changed. Against the commit the current
+    // epoch is based on, statuses come from the epoch alone. Against any other commit, its
+    // entries are read from `git` and matched by path, and the cursor compares text files present
+    // in both with their text there as it reaches them.
+    pub fn status_cursor(
+        &self,
+        base: Oid,
+        git: Rc<GitProvider>,
+    ) -> Box<Future<Item = StatusCursor, Error = Error>> {
+        let epoch = self.epoch.as_ref().unwrap().clone();
+        if epoch.borrow().head == Some(base) {
+            let statuses = epoch
+                .borrow()
+                .statuses()
+                .into_iter()
+                .map(|(path, file_type, status)| (path, file_type, Some(status)))
+                .collect();
+            return Box::new(future::ok(StatusCursor::new(statuses, epoch, base, git)));
+        }
+
+        Box::new(
+            git.base_entries(base)
+                .collect()
+                .map_err(|err| Error::IoError(err))
+                .and_then(move |base_entries| {
+                    let statuses = epoch.borrow().statuses_against(base_entries)?;
+                    Ok(StatusCursor::new(statuses, epoch, base, git))
+                }),
+        )
+    }
+
     pub fn create_file<P>(&self, path: P, file_type: FileType) -> Result<OperationEnvelope, Error>
     where
         P: AsRef<Path>,
@@ -390,7 +1227,7 @@ impl WorkTree {
     ) -> Result<OperationEnvelope, Error> {
         let mut cur_epoch = self.cur_epoch_mut();
         let file_id = if let Some(buffer_id) = buffer_id {
-            Some(self.buffer_file_id(buffer_id)?)
+            Some(self.file_id_for_buffer(buffer_id)?)
         } else {
             None
         };
@@ -404,15 +1241,36 @@ impl WorkTree {
         ))
     }
 
-    pub fn replica_locations(&self) -> HashMap<ReplicaId, PathBuf> {
-        let epoch = self.cur_epoch();
-        let mut locations = HashMap::new();
-        for (replica_id, file_id) in epoch.replica_locations() {
-            if let Some(path) = epoch.path(file_id) {
-                locations.insert(replica_id, path);
-            }
-        }
-        locations
+    pub fn set_presence<L>(&self, replica_id: ReplicaId, label: L, color: u32) -> OperationEnvelope
+    where
+        L: Into<String>,
+    {
+        let mut cur_epoch = self.cur_epoch_mut();
+        let operation = cur_epoch.set_presence(
+            replica_id,
+            label,
+            color,
+            &mut self.lamport_clock.borrow_mut(),
+        );
+        OperationEnvelope::wrap(cur_epoch.id, cur_epoch.head, operation)
+    }
+
+    pub fn presences(&self) -> HashMap<ReplicaId, Presence> {
+        self.cur_epoch()
+            .presences()
+            .map(|(replica_id, presence)| (replica_id, presence.clone()))
+            .collect()
+    }
+
+    pub fn replica_locations(&self) -> HashMap<ReplicaId, PathBuf> {
+        let epoch = self.cur_epoch();
+        let mut locations = HashMap::new();
+        for (replica_id, file_id) in epoch.replica_locations() {
+            if let Some(path) = epoch.path(file_id) {
+                locations.insert(replica_id, path);
+            }
+        }
+        locations
     }
 
     pub fn remove<P>(&self, path: P) -> Result<OperationEnvelope, Error>
@@ -430,6 +1288,114 @@ impl WorkTree {
         ))
     }
 
+    // Moves the file at `path` in front of its sibling at `before`, or behind all ordered siblings
+    // when `before` is None.
+    pub fn reorder<P>(&self, path: P, before: Option<&Path>) -> Result<OperationEnvelope, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let mut cur_epoch = self.cur_epoch_mut();
+        let file_id = cur_epoch.file_id(path.as_ref())?;
+        let before = if let Some(before) = before {
+            Some(cur_epoch.file_id(before)?)
+        } else {
+            None
+        };
+        let operation = cur_epoch.reorder(file_id, before, &mut self.lamport_clock.borrow_mut())?;
+
+        Ok(OperationEnvelope::wrap(
+            cur_epoch.id,
+            cur_epoch.head,
+            operation,
+        ))
+    }
+
+    // Lists the children of the directory at `path` in display order, along with the order key of
+    // each child that has been explicitly reordered.
+    pub fn list_dir<P>(&self, path: P) -> Result<Vec<(DirEntry, Option<Vec<u8>>)>, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let epoch = self.cur_epoch();
+        let dir_id = epoch.file_id(path)?;
+        let depth = path.components().count() + 1;
+        epoch
+            .ordered_children(dir_id)?
+            .into_iter()
+            .map(|(file_id, name)| {
+                let entry = DirEntry {
+                    depth,
+                    name: name.as_ref().clone(),
+                    file_type: epoch.file_type(file_id)?,
+                };
+                Ok((entry, epoch.order_key(file_id).map(|key| key.to_vec())))
+            })
+            .collect()
+    }
+
+    pub fn duplicate_paths(&self) -> Vec<(PathBuf, Vec<FileId>)> {
+        self.cur_epoch().duplicate_paths()
+    }
+
+    // Resolves a duplicate path reported by `duplicate_paths` by removing every file competing for
+    // it except `keep`, which is moved to the path if it was renamed when the conflict occurred.
+    pub fn resolve_duplicate<P>(
+        &self,
+        path: P,
+        keep: FileId,
+    ) -> Result<Vec<OperationEnvelope>, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let mut cur_epoch = self.cur_epoch_mut();
+        let (_, file_ids) = cur_epoch
+            .duplicate_paths()
+            .into_iter()
+            .find(|(duplicate_path, _)| duplicate_path == path)
+            .ok_or(Error::InvalidPath("path has no duplicates".into()))?;
+        if !file_ids.contains(&keep) {
+            return Err(Error::InvalidFileId("file is not a duplicate".into()));
+        }
+
+        let mut lamport_clock = self.lamport_clock.borrow_mut();
+        let mut operations = Vec::new();
+        for file_id in file_ids {
+            if file_id != keep {
+                operations.push(cur_epoch.remove(file_id, &mut lamport_clock)?);
+            }
+        }
+        if cur_epoch
+            .path(keep)
+            .map_or(true, |keep_path| keep_path != path)
+        {
+            let parent_id = if let Some(parent_path) = path.parent() {
+                cur_epoch.file_id(parent_path)?
+            } else {
+                epoch::ROOT_FILE_ID
+            };
+            operations.push(cur_epoch.rename(
+                keep,
+                parent_id,
+                path.file_name().unwrap(),
+                &mut lamport_clock,
+            )?);
+        }
+
+        Ok(OperationEnvelope::wrap_many(
+            cur_epoch.id,
+            cur_epoch.head,
+            operations,
+        ))
+    }
+
+    pub fn prune_empty_dirs(&self) -> Vec<OperationEnvelope> {
+        let mut cur_epoch = self.cur_epoch_mut();
+        let operations = cur_epoch.prune_empty_dirs(&mut self.lamport_clock.borrow_mut());
+        OperationEnvelope::wrap_many(cur_epoch.id, cur_epoch.head, operations)
+    }
+
     pub fn exists<P>(&self, path: P) -> bool
     where
         P: AsRef<Path>,
@@ -437,6 +1403,25 @@ impl WorkTree {
         self.cur_epoch().file_id(path).is_ok()
     }
 
+    // Asks the git provider whether `path` is ignored. Answers are cached until the next epoch
+    // starts, so walking the tree only queries the provider once per path.
+    pub fn is_ignored<P>(&self, path: P) -> Result<bool, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        if let Some(ignored) = self.ignored_paths.borrow().get(path) {
+            return Ok(*ignored);
+        }
+
+        self.cur_epoch().file_id(path)?;
+        let ignored = self.git.is_ignored(path)?;
+        self.ignored_paths
+            .borrow_mut()
+            .insert(path.to_path_buf(), ignored);
+        Ok(ignored)
+    }
+
     pub fn open_text_file<P>(&self, path: P) -> Box<Future<Item = BufferId, Error = Error>>
     where
         P: Into<PathBuf>,
@@ -546,22 +1531,269 @@ impl WorkTree {
         I: IntoIterator<Item = Range<usize>>,
         T: Into<Text>,
     {
-        let file_id = self.buffer_file_id(buffer_id)?;
-        let mut cur_epoch = self.cur_epoch_mut();
-        let operation = cur_epoch
-            .edit(
+        let old_ranges = old_ranges.into_iter().collect::<Vec<_>>();
+        let new_text = new_text.into();
+        let single_range = if old_ranges.len() == 1 {
+            Some(old_ranges[0].clone())
+        } else {
+            None
+        };
+        let new_text_len = new_text.len();
+        let closes_group = new_text.contains_newline();
+
+        let envelope = {
+            let file_id = self.file_id_for_buffer(buffer_id)?;
+            let mut cur_epoch = self.cur_epoch_mut();
+            let operation = cur_epoch
+                .edit(
+                    file_id,
+                    old_ranges,
+                    new_text,
+                    &mut self.lamport_clock.borrow_mut(),
+                )
+                .unwrap();
+
+            OperationEnvelope::wrap(cur_epoch.id, cur_epoch.head, operation)
+        };
+        self.finish_local_edit(
+            buffer_id,
+            &envelope,
+            single_range.map(|range| (range, new_text_len, closes_group)),
+        );
+        Ok(envelope)
+    }
+
+    // Groups edits made to a file without going through one of its buffers into every buffer
+    // that is open for the file.
+    fn group_file_edit(&self, file_id: FileId, edit_ids: Vec<time::Local>, now: Instant) {
+        let buffer_ids = self
+            .buffers
+            .borrow()
+            .iter()
+            .filter(|(_, buffer_file_id)| **buffer_file_id == file_id)
+            .map(|(buffer_id, _)| *buffer_id)
+            .collect::<Vec<_>>();
+        for buffer_id in buffer_ids {
+            self.group_edit(buffer_id, edit_ids.clone(), None, now);
+        }
+    }
+
+    // Adds a local edit made through a buffer to the buffer's undo groups and reports any size
+    // threshold it crossed. `edit` describes a single-range edit that may extend the last group.
+    fn finish_local_edit(
+        &self,
+        buffer_id: BufferId,
+        envelope: &OperationEnvelope,
+        edit: Option<(Range<usize>, usize, bool)>,
+    ) {
+        self.group_edit(
+            buffer_id,
+            envelope.operation.local_edit_ids(),
+            edit,
+            Instant::now(),
+        );
+        self.check_size_thresholds();
+    }
+
+    fn group_edit(
+        &self,
+        buffer_id: BufferId,
+        edit_ids: Vec<time::Local>,
+        edit: Option<(Range<usize>, usize, bool)>,
+        now: Instant,
+    ) {
+        if edit_ids.is_empty() {
+            return;
+        }
+
+        let mut undo_groups = self.undo_groups.borrow_mut();
+        let groups = undo_groups.entry(buffer_id).or_insert_with(Vec::new);
+        let (range, new_text_len, has_newline) = match edit {
+            Some(edit) => edit,
+            None => {
+                groups.push(UndoGroup {
+                    edit_ids,
+                    end: 0,
+                    last_edit: now,
+                    mergeable: false,
+                });
+                return;
+            }
+        };
+        let end = range.start + new_text_len;
+
+        if let (Some(policy), Some(group)) = (self.undo_grouping, groups.last_mut()) {
+            if group.mergeable
+                && !(policy.break_on_newline && has_newline)
+                && now.duration_since(group.last_edit) <= policy.max_pause
+                && range.start <= group.end
+                && group.end <= range.end
+            {
+                group.edit_ids.extend(edit_ids);
+                group.end = end;
+                group.last_edit = now;
+                return;
+            }
+        }
+
+        groups.push(UndoGroup {
+            edit_ids,
+            end,
+            last_edit: now,
+            mergeable: true,
+        });
+    }
+
+    // A last resort for buffers whose contents diverged from other replicas even though they
+    // applied the same operations, as detected with `state_eq` or by comparing range hashes. The
+    // buffer is rebuilt from the operations in the history, and every replica holding it must do
+    // the same, including the ones whose contents look right, for all of them to agree.
+    pub fn resync_buffer(&self, buffer_id: BufferId) -> Result<(), Error> {
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        self.cur_epoch_mut()
+            .resync_text_file(file_id, &mut self.lamport_clock.borrow_mut())?;
+        self.base_hunks
+            .borrow_mut()
+            .retain(|(hunks_buffer_id, _), _| *hunks_buffer_id != buffer_id);
+        Ok(())
+    }
+
+    pub fn set_text(
+        &self,
+        buffer_id: BufferId,
+        new_text: &str,
+    ) -> Result<OperationEnvelope, Error> {
+        let envelope = {
+            let file_id = self.file_id_for_buffer(buffer_id)?;
+            let mut cur_epoch = self.cur_epoch_mut();
+            let operation =
+                cur_epoch.set_text(file_id, new_text, &mut self.lamport_clock.borrow_mut())?;
+            OperationEnvelope::wrap(cur_epoch.id, cur_epoch.head, operation)
+        };
+        self.finish_local_edit(buffer_id, &envelope, None);
+        Ok(envelope)
+    }
+
+    // Feeds the text git produced when resolving a merge into the buffer for `path`. Only the
+    // regions that differ from the current text are edited, so anchors elsewhere in the buffer
+    // are preserved. The file must have been opened.
+    pub fn apply_git_resolution<P>(
+        &self,
+        path: P,
+        resolved_text: &str,
+    ) -> Result<Vec<OperationEnvelope>, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let (file_id, envelopes) = {
+            let mut cur_epoch = self.cur_epoch_mut();
+            let file_id = cur_epoch.file_id(path)?;
+            if cur_epoch.text(file_id)?.eq(resolved_text.encode_utf16()) {
+                return Ok(Vec::new());
+            }
+            let operation =
+                cur_epoch.set_text(file_id, resolved_text, &mut self.lamport_clock.borrow_mut())?;
+            (
+                file_id,
+                vec![OperationEnvelope::wrap(
+                    cur_epoch.id,
+                    cur_epoch.head,
+                    operation,
+                )],
+            )
+        };
+        self.group_file_edit(
+            file_id,
+            envelopes[0].operation.local_edit_ids(),
+            Instant::now(),
+        );
+        self.check_size_thresholds();
+        Ok(envelopes)
+    }
+
+    pub fn edit_at_anchor<T>(
+        &self,
+        buffer_id: BufferId,
+        old_range: Range<buffer::Anchor>,
+        new_text: T,
+    ) -> Result<OperationEnvelope, Error>
+    where
+        T: Into<Text>,
+    {
+        let envelope = {
+            let file_id = self.file_id_for_buffer(buffer_id)?;
+            let mut cur_epoch = self.cur_epoch_mut();
+            let operation = cur_epoch.edit_at_anchor(
                 file_id,
-                old_ranges,
+                old_range,
                 new_text,
                 &mut self.lamport_clock.borrow_mut(),
+            )?;
+            OperationEnvelope::wrap(cur_epoch.id, cur_epoch.head, operation)
+        };
+        self.report_anchor_fallbacks();
+        self.finish_local_edit(buffer_id, &envelope, None);
+        Ok(envelope)
+    }
+
+    pub fn splice(
+        &self,
+        buffer_id: BufferId,
+        old_range: Range<Point>,
+        new_text: &str,
+    ) -> Result<(OperationEnvelope, Range<buffer::Anchor>), Error> {
+        let (envelope, new_range) = {
+            let file_id = self.file_id_for_buffer(buffer_id)?;
+            let mut cur_epoch = self.cur_epoch_mut();
+            let (operation, new_range) = cur_epoch.splice(
+                file_id,
+                old_range,
+                new_text,
+                &mut self.lamport_clock.borrow_mut(),
+            )?;
+            (
+                OperationEnvelope::wrap(cur_epoch.id, cur_epoch.head, operation),
+                new_range,
             )
-            .unwrap();
+        };
+        self.finish_local_edit(buffer_id, &envelope, None);
+        Ok((envelope, new_range))
+    }
 
-        Ok(OperationEnvelope::wrap(
-            cur_epoch.id,
-            cur_epoch.head,
-            operation,
-        ))
+    pub fn operation_for_anchor(
+        &self,
+        buffer_id: BufferId,
+        anchor: &buffer::Anchor,
+    ) -> Result<Option<OperationEnvelope>, Error> {
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        let cur_epoch = self.cur_epoch();
+        Ok(cur_epoch
+            .operation_for_anchor(file_id, anchor)?
+            .map(|operation| OperationEnvelope::wrap(cur_epoch.id, cur_epoch.head, operation)))
+    }
+
+    // Compares the file tree and the contents of every open text file, ignoring how each tree
+    // arrived at its state.
+    pub fn state_eq(&self, other: &WorkTree) -> bool {
+        match (self.epoch.as_ref(), other.epoch.as_ref()) {
+            (Some(epoch), Some(other_epoch)) => epoch.borrow().state_eq(&other_epoch.borrow()),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    pub fn operations_in_range(
+        &self,
+        buffer_id: BufferId,
+        range: Range<buffer::Anchor>,
+    ) -> Result<Vec<OperationEnvelope>, Error> {
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        let cur_epoch = self.cur_epoch();
+        Ok(cur_epoch
+            .operations_in_range(file_id, range)?
+            .into_iter()
+            .map(|operation| OperationEnvelope::wrap(cur_epoch.id, cur_epoch.head, operation))
+            .collect())
     }
 
     pub fn edit_2d<I, T>(
@@ -574,22 +1806,68 @@ impl WorkTree {
         I: IntoIterator<Item = Range<Point>>,
         T: Into<Text>,
     {
-        let file_id = self.buffer_file_id(buffer_id)?;
-        let mut cur_epoch = self.cur_epoch_mut();
-        let operation = cur_epoch
-            .edit_2d(
+        let envelope = {
+            let file_id = self.file_id_for_buffer(buffer_id)?;
+            let mut cur_epoch = self.cur_epoch_mut();
+            let operation = cur_epoch
+                .edit_2d(
+                    file_id,
+                    old_ranges,
+                    new_text,
+                    &mut self.lamport_clock.borrow_mut(),
+                )
+                .unwrap();
+
+            OperationEnvelope::wrap(cur_epoch.id, cur_epoch.head, operation)
+        };
+        self.finish_local_edit(buffer_id, &envelope, None);
+        Ok(envelope)
+    }
+
+    pub fn edit_indented(
+        &self,
+        buffer_id: BufferId,
+        range: Range<Point>,
+        text: &str,
+        tab_size: u32,
+    ) -> Result<OperationEnvelope, Error> {
+        let envelope = {
+            let file_id = self.file_id_for_buffer(buffer_id)?;
+            let mut cur_epoch = self.cur_epoch_mut();
+            let operation = cur_epoch.edit_indented(
                 file_id,
-                old_ranges,
-                new_text,
+                range,
+                text,
+                tab_size,
                 &mut self.lamport_clock.borrow_mut(),
-            )
-            .unwrap();
+            )?;
+            OperationEnvelope::wrap(cur_epoch.id, cur_epoch.head, operation)
+        };
+        self.finish_local_edit(buffer_id, &envelope, None);
+        Ok(envelope)
+    }
 
-        Ok(OperationEnvelope::wrap(
-            cur_epoch.id,
-            cur_epoch.head,
-            operation,
-        ))
+    // Inserts the contents of a potentially large file at `range` without reading it into a string
+    // first. See `Buffer::edit_from_reader`.
+    pub fn edit_from_reader<R: io::BufRead>(
+        &self,
+        buffer_id: BufferId,
+        range: Range<Point>,
+        reader: R,
+    ) -> Result<OperationEnvelope, Error> {
+        let envelope = {
+            let file_id = self.file_id_for_buffer(buffer_id)?;
+            let mut cur_epoch = self.cur_epoch_mut();
+            let operation = cur_epoch.edit_from_reader(
+                file_id,
+                range,
+                reader,
+                &mut self.lamport_clock.borrow_mut(),
+            )?;
+            OperationEnvelope::wrap(cur_epoch.id, cur_epoch.head, operation)
+        };
+        self.finish_local_edit(buffer_id, &envelope, None);
+        Ok(envelope)
     }
 
     pub fn add_selection_set<I>(
@@ -600,7 +1878,7 @@ impl WorkTree {
     where
         I: IntoIterator<Item = Range<Point>>,
     {
-        let file_id = self.buffer_file_id(buffer_id)?;
+        let file_id = self.file_id_for_buffer(buffer_id)?;
         let mut cur_epoch = self.cur_epoch_mut();
         let (remote_set_id, operation) =
             cur_epoch.add_selection_set(file_id, ranges, &mut self.lamport_clock.borrow_mut())?;
@@ -618,6 +1896,13 @@ impl WorkTree {
         ))
     }
 
+    // An empty set doesn't carry any ranges, so the operation creating it doesn't need to be
+    // broadcast. Other replicas learn about the set the first time it is replaced.
+    pub fn create_selection_set(&self, buffer_id: BufferId) -> Result<LocalSelectionSetId, Error> {
+        self.add_selection_set(buffer_id, None)
+            .map(|(local_set_id, _)| local_set_id)
+    }
+
     pub fn replace_selection_set<I>(
         &self,
         buffer_id: BufferId,
@@ -627,7 +1912,7 @@ impl WorkTree {
     where
         I: IntoIterator<Item = Range<Point>>,
     {
-        let file_id = self.buffer_file_id(buffer_id)?;
+        let file_id = self.file_id_for_buffer(buffer_id)?;
         let set_id = self.selection_set_id(buffer_id, local_set_id)?;
         let mut cur_epoch = self.cur_epoch_mut();
         let operation = cur_epoch.replace_selection_set(
@@ -648,7 +1933,7 @@ impl WorkTree {
         buffer_id: BufferId,
         local_set_id: LocalSelectionSetId,
     ) -> Result<OperationEnvelope, Error> {
-        let file_id = self.buffer_file_id(buffer_id)?;
+        let file_id = self.file_id_for_buffer(buffer_id)?;
         let set_id = self.selection_set_id(buffer_id, local_set_id)?;
         let mut cur_epoch = self.cur_epoch_mut();
         let operation = cur_epoch.remove_selection_set(
@@ -668,6 +1953,93 @@ impl WorkTree {
         ))
     }
 
+    // Hides the set from every replica's selection ranges without discarding it, so that it can be
+    // shown again with its selections intact, e.g. while the window is out of focus.
+    pub fn hide_selection_set(
+        &self,
+        buffer_id: BufferId,
+        local_set_id: LocalSelectionSetId,
+    ) -> Result<OperationEnvelope, Error> {
+        self.set_selection_set_hidden(buffer_id, local_set_id, true)
+    }
+
+    pub fn show_selection_set(
+        &self,
+        buffer_id: BufferId,
+        local_set_id: LocalSelectionSetId,
+    ) -> Result<OperationEnvelope, Error> {
+        self.set_selection_set_hidden(buffer_id, local_set_id, false)
+    }
+
+    pub fn is_selection_set_hidden(
+        &self,
+        buffer_id: BufferId,
+        local_set_id: LocalSelectionSetId,
+    ) -> Result<bool, Error> {
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        let set_id = self.selection_set_id(buffer_id, local_set_id)?;
+        self.cur_epoch().is_selection_set_hidden(file_id, set_id)
+    }
+
+    fn set_selection_set_hidden(
+        &self,
+        buffer_id: BufferId,
+        local_set_id: LocalSelectionSetId,
+        hidden: bool,
+    ) -> Result<OperationEnvelope, Error> {
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        let set_id = self.selection_set_id(buffer_id, local_set_id)?;
+        let mut cur_epoch = self.cur_epoch_mut();
+        let operation = cur_epoch.set_selection_set_hidden(
+            file_id,
+            set_id,
+            hidden,
+            &mut self.lamport_clock.borrow_mut(),
+        )?;
+        Ok(OperationEnvelope::wrap(
+            cur_epoch.id,
+            cur_epoch.head,
+            operation,
+        ))
+    }
+
+    // Opens another view of a file that is already open. Views share the file's contents, so edits
+    // made through any of them are visible in all of them, but each view has its own selection
+    // sets.
+    pub fn open_buffer(&self, file_id: FileId) -> Result<BufferId, Error> {
+        self.cur_epoch().buffer_version(file_id)?;
+        let buffer_id = *self.next_buffer_id.borrow();
+        self.next_buffer_id.borrow_mut().0 += 1;
+        self.buffers.borrow_mut().insert(buffer_id, file_id);
+        Ok(buffer_id)
+    }
+
+    // Opens a read-only view of a file as it was at `version`, which leaves the live buffer
+    // untouched. Editing the view fails with `Error::ReadOnlyBuffer`. The view stays open until
+    // it is passed to `close_snapshot` or the epoch changes.
+    pub fn open_buffer_at(
+        &mut self,
+        file_id: FileId,
+        version: &time::Global,
+    ) -> Result<BufferId, Error> {
+        let snapshot = self.cur_epoch().buffer_snapshot_at(file_id, version)?;
+        let buffer_id = *self.next_buffer_id.borrow();
+        self.next_buffer_id.borrow_mut().0 += 1;
+        self.snapshots.insert(buffer_id, snapshot);
+        Ok(buffer_id)
+    }
+
+    pub fn close_snapshot(&mut self, buffer_id: BufferId) -> Result<(), Error> {
+        self.snapshots
+            .remove(&buffer_id)
+            .map(|_| ())
+            .ok_or(Error::InvalidBufferId)
+    }
+
+    pub fn buffer_file_id(&self, buffer_id: BufferId) -> Option<FileId> {
+        self.buffers.borrow().get(&buffer_id).cloned()
+    }
+
     pub fn path(&self, buffer_id: BufferId) -> Option<PathBuf> {
         self.buffers
             .borrow()
@@ -675,21 +2047,69 @@ impl WorkTree {
             .and_then(|file_id| self.cur_epoch().path(*file_id))
     }
 
+    // Every file has a single parent, so a file id maps to at most one path. Removed files don't
+    // map to any path, even though buffers for them can remain open.
+    pub fn paths_for_file_id(&self, file_id: FileId) -> Vec<PathBuf> {
+        self.cur_epoch().path(file_id).into_iter().collect()
+    }
+
     pub fn text(&self, buffer_id: BufferId) -> Result<buffer::Iter, Error> {
-        let file_id = self.buffer_file_id(buffer_id)?;
+        if let Some(snapshot) = self.snapshots.get(&buffer_id) {
+            return Ok(snapshot.iter());
+        }
+        let file_id = self.file_id_for_buffer(buffer_id)?;
         self.cur_epoch().text(file_id)
     }
 
-    pub fn selection_ranges(&self, buffer_id: BufferId) -> Result<BufferSelectionRanges, Error> {
-        Self::selection_ranges_internal(
-            &self.local_selection_sets.borrow(),
-            &self.buffers.borrow(),
-            &self.cur_epoch(),
-            buffer_id,
-        )
+    pub fn ends_with_newline(&self, buffer_id: BufferId) -> Result<bool, Error> {
+        if let Some(snapshot) = self.snapshots.get(&buffer_id) {
+            return Ok(snapshot.ends_with_newline());
+        }
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        self.cur_epoch().buffer_ends_with_newline(file_id)
     }
 
-    fn selection_ranges_internal(
+    pub fn export(&self, buffer_id: BufferId) -> Result<String, Error> {
+        if let Some(snapshot) = self.snapshots.get(&buffer_id) {
+            if self.ensure_trailing_newline {
+                return Ok(snapshot.export_with_trailing_newline());
+            } else {
+                return Ok(snapshot.export());
+            }
+        }
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        self.cur_epoch()
+            .export_buffer(file_id, self.ensure_trailing_newline)
+    }
+
+    pub fn selection_ranges(&self, buffer_id: BufferId) -> Result<BufferSelectionRanges, Error> {
+        // A snapshot has no local selection sets, so all of its selections are reported as remote.
+        if let Some(snapshot) = self.snapshots.get(&buffer_id) {
+            let mut selections = BufferSelectionRanges {
+                local: HashMap::new(),
+                remote: HashMap::new(),
+                presences: HashMap::new(),
+            };
+            for (set_id, ranges) in snapshot.all_selection_ranges() {
+                selections
+                    .remote
+                    .entry(set_id.replica_id)
+                    .or_insert(Vec::new())
+                    .push(ranges);
+            }
+            return Ok(selections);
+        }
+        let selections = Self::selection_ranges_internal(
+            &self.local_selection_sets.borrow(),
+            &self.buffers.borrow(),
+            &self.cur_epoch(),
+            buffer_id,
+        );
+        self.report_anchor_fallbacks();
+        selections
+    }
+
+    fn selection_ranges_internal(
         local_selection_sets: &HashMap<
             BufferId,
             HashMap<LocalSelectionSetId, buffer::SelectionSetId>,
@@ -713,6 +2133,10 @@ impl WorkTree {
         let mut selections = BufferSelectionRanges {
             local: HashMap::new(),
             remote: HashMap::new(),
+            presences: epoch
+                .presences()
+                .map(|(replica_id, presence)| (replica_id, presence.clone()))
+                .collect(),
         };
         for (set_id, ranges) in epoch.all_selection_ranges(file_id)? {
             if let Some(local_set_id) = set_ids_to_local_set_ids.get(&set_id) {
@@ -729,20 +2153,659 @@ impl WorkTree {
         Ok(selections)
     }
 
+    // Counts the characters covered by the set's selections, counting text covered by more than
+    // one selection only once.
+    pub fn selected_char_count(
+        &self,
+        buffer_id: BufferId,
+        local_set_id: LocalSelectionSetId,
+    ) -> Result<usize, Error> {
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        let set_id = self.selection_set_id(buffer_id, local_set_id)?;
+        let cur_epoch = self.cur_epoch();
+        let mut ranges = cur_epoch
+            .selection_ranges(file_id, set_id)?
+            .collect::<Vec<_>>();
+        BufferSelectionRanges::normalize_ranges(&mut ranges);
+
+        let mut count = 0;
+        for range in ranges {
+            let range = range.start.min(range.end)..range.start.max(range.end);
+            count += cur_epoch.buffer_char_count(file_id, range)?;
+        }
+        Ok(count)
+    }
+
+    pub fn selection_sets(
+        &self,
+        buffer_id: BufferId,
+    ) -> Result<Vec<(ReplicaId, Option<LocalSelectionSetId>, Vec<Range<Point>>)>, Error> {
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        let mut set_ids_to_local_set_ids = HashMap::new();
+        if let Some(buffer_sets) = self.local_selection_sets.borrow().get(&buffer_id) {
+            for (local_set_id, set_id) in buffer_sets {
+                set_ids_to_local_set_ids.insert(*set_id, *local_set_id);
+            }
+        }
+
+        let cur_epoch = self.cur_epoch();
+        let mut selection_sets = cur_epoch
+            .all_selection_ranges(file_id)?
+            .filter(|(_, ranges)| !ranges.is_empty())
+            .collect::<Vec<_>>();
+        selection_sets.sort_by_key(|(set_id, _)| (set_id.replica_id, set_id.value));
+        Ok(selection_sets
+            .into_iter()
+            .map(|(set_id, ranges)| {
+                let local_set_id = set_ids_to_local_set_ids.get(&set_id).cloned();
+                (set_id.replica_id, local_set_id, ranges)
+            })
+            .collect())
+    }
+
+    pub fn point_for_anchor(
+        &self,
+        buffer_id: BufferId,
+        anchor: &buffer::Anchor,
+    ) -> Result<Point, Error> {
+        if let Some(snapshot) = self.snapshots.get(&buffer_id) {
+            return snapshot.point_for_anchor(anchor);
+        }
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        let point = self.cur_epoch().point_for_anchor(file_id, anchor);
+        self.report_anchor_fallbacks();
+        point
+    }
+
+    pub fn buffer_version(&self, buffer_id: BufferId) -> Result<time::Global, Error> {
+        if let Some(snapshot) = self.snapshots.get(&buffer_id) {
+            return Ok(snapshot.version.clone());
+        }
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        self.cur_epoch().buffer_version(file_id)
+    }
+
     pub fn changes_since(
         &self,
         buffer_id: BufferId,
         version: &time::Global,
     ) -> Result<impl Iterator<Item = buffer::Change>, Error> {
-        let file_id = self.buffer_file_id(buffer_id)?;
-        self.cur_epoch().changes_since(file_id, version)
+        let changes: Box<Iterator<Item = buffer::Change>> =
+            if let Some(snapshot) = self.snapshots.get(&buffer_id) {
+                Box::new(snapshot.changes_since(version))
+            } else {
+                let file_id = self.file_id_for_buffer(buffer_id)?;
+                Box::new(self.cur_epoch().changes_since(file_id, version)?)
+            };
+        Ok(changes)
+    }
+
+    pub fn mark_saved(&mut self, buffer_id: BufferId) -> Result<(), Error> {
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        let saved_version = {
+            let cur_epoch = self.cur_epoch();
+            (cur_epoch.id, cur_epoch.buffer_version(file_id)?)
+        };
+        self.saved_versions.insert(buffer_id, saved_version);
+        Ok(())
+    }
+
+    // Buffers track the changes made since their base text was loaded from the epoch's head, so
+    // hunks against the head are read straight from the buffer. Any other base is fetched from
+    // `git` and diffed with the buffer, and the result is reused until the buffer changes.
+    pub fn next_hunk(
+        &self,
+        buffer_id: BufferId,
+        from: Point,
+        base: Oid,
+        git: &GitProvider,
+    ) -> Box<Future<Item = Option<Range<Point>>, Error = Error>> {
+        let file_id = match self.file_id_for_buffer(buffer_id) {
+            Ok(file_id) => file_id,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        {
+            let cur_epoch = self.cur_epoch();
+            if cur_epoch.head == Some(base) {
+                return Box::new(future::result(
+                    cur_epoch
+                        .hunks_from(file_id, from)
+                        .map(|mut hunks| hunks.find(|hunk| hunk.start > from)),
+                ));
+            }
+        }
+        Box::new(
+            self.base_hunks(buffer_id, file_id, base, git)
+                .map(move |hunks| hunks.iter().find(|hunk| hunk.start > from).cloned()),
+        )
+    }
+
+    pub fn prev_hunk(
+        &self,
+        buffer_id: BufferId,
+        from: Point,
+        base: Oid,
+        git: &GitProvider,
+    ) -> Box<Future<Item = Option<Range<Point>>, Error = Error>> {
+        let file_id = match self.file_id_for_buffer(buffer_id) {
+            Ok(file_id) => file_id,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        {
+            let cur_epoch = self.cur_epoch();
+            if cur_epoch.head == Some(base) {
+                return Box::new(future::result(
+                    cur_epoch
+                        .hunks(file_id)
+                        .map(|hunks| hunks.take_while(|hunk| hunk.start < from).last()),
+                ));
+            }
+        }
+        Box::new(
+            self.base_hunks(buffer_id, file_id, base, git)
+                .map(move |hunks| {
+                    hunks
+                        .iter()
+                        .take_while(|hunk| hunk.start < from)
+                        .last()
+                        .cloned()
+                }),
+        )
+    }
+
+    fn base_hunks(
+        &self,
+        buffer_id: BufferId,
+        file_id: FileId,
+        base: Oid,
+        git: &GitProvider,
+    ) -> Box<Future<Item = Rc<Vec<Range<Point>>>, Error = Error>> {
+        let (version, path) = {
+            let cur_epoch = self.cur_epoch();
+            let version = match cur_epoch.buffer_version(file_id) {
+                Ok(version) => (cur_epoch.id, version),
+                Err(error) => return Box::new(future::err(error)),
+            };
+            (version, cur_epoch.path(file_id))
+        };
+        if let Some(base_hunks) = self.base_hunks.borrow().get(&(buffer_id, base)) {
+            if base_hunks.version == version {
+                return Box::new(future::ok(base_hunks.hunks.clone()));
+            }
+        }
+        let path = match path {
+            Some(path) => path,
+            None => {
+                return Box::new(future::err(Error::InvalidFileId(
+                    "file has been removed".into(),
+                )));
+            }
+        };
+
+        let epoch = self.epoch.clone().unwrap();
+        let base_hunks = self.base_hunks.clone();
+        Box::new(
+            git.base_text(base, &path)
+                .map_err(|err| Error::IoError(err))
+                .and_then(move |base_text| {
+                    let epoch = epoch.borrow();
+                    let hunks = Rc::new(epoch.hunks_against(file_id, &base_text)?);
+                    let version = (epoch.id, epoch.buffer_version(file_id)?);
+                    base_hunks.borrow_mut().insert(
+                        (buffer_id, base),
+                        BaseHunks {
+                            version,
+                            hunks: hunks.clone(),
+                        },
+                    );
+                    Ok(hunks)
+                }),
+        )
+    }
+
+    pub fn unsaved_changes(&self, buffer_id: BufferId) -> Result<Vec<buffer::Change>, Error> {
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        let cur_epoch = self.cur_epoch();
+        let saved_version = match self.saved_versions.get(&buffer_id) {
+            Some((epoch_id, version)) if *epoch_id == cur_epoch.id => version.clone(),
+            _ => time::Global::new(),
+        };
+        Ok(cur_epoch.changes_since(file_id, &saved_version)?.collect())
     }
 
     pub fn buffer_deferred_ops_len(&self, buffer_id: BufferId) -> Result<usize, Error> {
-        let file_id = self.buffer_file_id(buffer_id)?;
+        let file_id = self.file_id_for_buffer(buffer_id)?;
         self.cur_epoch().buffer_deferred_ops_len(file_id)
     }
 
+    // The number of local operations that `flush_operations` would return, so that a UI can warn
+    // when edits are made faster than they are sent.
+    pub fn pending_operation_count(&self) -> usize {
+        self.pending_flush(usize::max_value(), None)
+            .map_or(0, |(envelopes, _)| envelopes.len())
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.pending_flush(1, None)
+            .map_or(false, |(envelopes, _)| !envelopes.is_empty())
+    }
+
+    // The number of remote operations waiting for the operations they depend on, including the
+    // ones held back for an epoch that hasn't started yet.
+    pub fn deferred_operation_count(&self) -> usize {
+        let future_epoch_ops_len: usize = self.deferred_ops.borrow().values().map(Vec::len).sum();
+        let future_epoch_ops_len = future_epoch_ops_len + self.blocked_ops.len();
+        let cur_epoch_ops_len = self
+            .epoch
+            .as_ref()
+            .map_or(0, |epoch| epoch.borrow().deferred_ops_len());
+        future_epoch_ops_len + cur_epoch_ops_len
+    }
+
+    // The callback fires once, the first time the buffer's UTF-8 encoding grows past `bytes` after
+    // local edits or applied operations, and is then discarded. It's passed the buffer's new size
+    // in bytes.
+    pub fn on_size_threshold<F>(
+        &self,
+        buffer_id: BufferId,
+        bytes: usize,
+        callback: F,
+    ) -> Result<(), Error>
+    where
+        F: 'static + Fn(BufferId, usize),
+    {
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        let last_bytes = self.cur_epoch().buffer_len_bytes(file_id)?;
+        self.size_thresholds.borrow_mut().insert(
+            buffer_id,
+            SizeThreshold {
+                bytes,
+                last_bytes,
+                callback: Box::new(callback),
+            },
+        );
+        Ok(())
+    }
+
+    // Called the first time an operation from a replica this tree hasn't heard from before is
+    // applied.
+    pub fn on_replica_joined<F>(&mut self, callback: F)
+    where
+        F: 'static + Fn(ReplicaId),
+    {
+        self.replica_activity.on_joined = Some(Box::new(callback));
+    }
+
+    // There's no notion of wall-clock time here, so a replica counts as idle once our lamport
+    // clock has advanced more than `idle_after` past the last operation we applied from it. The
+    // callback receives that operation's timestamp and fires again only after the replica has
+    // been heard from in the meantime. Idleness is checked when applying operations.
+    pub fn on_replica_idle<F>(&mut self, idle_after: u64, callback: F)
+    where
+        F: 'static + Fn(ReplicaId, time::Lamport),
+    {
+        self.replica_activity.on_idle = Some((idle_after, Box::new(callback)));
+    }
+
+    pub fn replica_last_seen(&self, replica_id: ReplicaId) -> Option<time::Lamport> {
+        self.replica_activity.last_seen.get(&replica_id).cloned()
+    }
+
+    fn update_replica_activity(
+        &mut self,
+        new_replica_ids: Vec<ReplicaId>,
+        seen_timestamps: Vec<time::Lamport>,
+    ) {
+        let local_replica_id = self.replica_id();
+        let now = self.lamport_clock.borrow().value;
+        let activity = &mut self.replica_activity;
+
+        for timestamp in seen_timestamps {
+            if timestamp.replica_id != local_replica_id {
+                let last_seen = activity.last_seen.entry(timestamp.replica_id).or_default();
+                if timestamp > *last_seen {
+                    *last_seen = timestamp;
+                }
+                activity.idle.remove(&timestamp.replica_id);
+            }
+        }
+
+        for replica_id in new_replica_ids {
+            if replica_id != local_replica_id && activity.known.insert(replica_id) {
+                if let Some(on_joined) = activity.on_joined.as_ref() {
+                    on_joined(replica_id);
+                }
+            }
+        }
+
+        if let Some((idle_after, on_idle)) = activity.on_idle.as_ref() {
+            for (replica_id, last_seen) in &activity.last_seen {
+                if now.saturating_sub(last_seen.value) > *idle_after
+                    && activity.idle.insert(*replica_id)
+                {
+                    on_idle(*replica_id, *last_seen);
+                }
+            }
+        }
+    }
+
+    // Writes one JSON object per line for every operation in the current epoch's history. The log
+    // is meant for people to read and can't be applied to another tree.
+    #[cfg(feature = "serde_json")]
+    pub fn export_oplog_jsonl(&self, w: &mut impl io::Write) -> Result<(), Error> {
+        let cur_epoch = self.cur_epoch();
+        for envelope in
+            OperationEnvelope::wrap_many(cur_epoch.id, cur_epoch.head, cur_epoch.history())
+        {
+            for entry in envelope.operation.log_entries() {
+                serde_json::to_writer(&mut *w, &entry).map_err(io::Error::from)?;
+                w.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    // The buffers open on this tree whose contents the operation edits. A server can use this to
+    // forward an operation only to the replicas that have the file open.
+    pub fn affected_buffers(&self, envelope: &OperationEnvelope) -> Vec<BufferId> {
+        if envelope.lane() != Lane::Content {
+            return Vec::new();
+        }
+        let file_id = match envelope.file_id() {
+            Some(file_id) => file_id,
+            None => return Vec::new(),
+        };
+        let mut buffer_ids = self
+            .buffers
+            .borrow()
+            .iter()
+            .filter(|(_, buffer_file_id)| **buffer_file_id == file_id)
+            .map(|(buffer_id, _)| *buffer_id)
+            .collect::<Vec<_>>();
+        buffer_ids.sort_by_key(|buffer_id| buffer_id.0);
+        buffer_ids
+    }
+
+    // Returns where a structural operation places its file. Removals resolve to the file's current
+    // path, so this needs to be called before the operation is applied.
+    pub fn affected_path(&self, envelope: &OperationEnvelope) -> Option<PathBuf> {
+        let operation = match &envelope.operation {
+            Operation::EpochOperation { operation, .. } => operation,
+            Operation::StartEpoch { .. } => return None,
+        };
+        let cur_epoch = self.cur_epoch();
+        let parent = match operation {
+            epoch::Operation::InsertMetadata { parent, .. } => parent,
+            epoch::Operation::UpdateParent {
+                child_id,
+                new_parent,
+                ..
+            } => {
+                if new_parent.is_none() {
+                    return cur_epoch.path(*child_id);
+                }
+                new_parent
+            }
+            _ => return None,
+        };
+        let (parent_id, name) = parent.as_ref()?;
+        let mut path = if *parent_id == epoch::ROOT_FILE_ID {
+            PathBuf::new()
+        } else {
+            cur_epoch.path(*parent_id)?
+        };
+        path.push(name.as_ref());
+        Some(path)
+    }
+
+    pub fn files_created_since(&self, version: &time::Global) -> Vec<(FileId, PathBuf)> {
+        self.cur_epoch().files_created_since(version)
+    }
+
+    pub fn files_removed_since(&self, version: &time::Global) -> Vec<(FileId, PathBuf)> {
+        self.cur_epoch().files_removed_since(version)
+    }
+
+    pub fn operations_from(&self, replica_id: ReplicaId) -> Vec<OperationEnvelope> {
+        let cur_epoch = self.cur_epoch();
+        OperationEnvelope::wrap_many(
+            cur_epoch.id,
+            cur_epoch.head,
+            cur_epoch.operations_from(replica_id),
+        )
+    }
+
+    pub fn flush_operations(&mut self) -> Vec<OperationEnvelope> {
+        self.flush_operations_chunked(usize::max_value())
+    }
+
+    // Returns up to `max_ops` of this replica's operations in the current epoch that haven't been
+    // flushed yet, in causal order. Operations from previous epochs are never flushed, because
+    // peers ignore them once they have moved on to a newer epoch.
+    pub fn flush_operations_chunked(&mut self, max_ops: usize) -> Vec<OperationEnvelope> {
+        self.flush_operations_internal(max_ops, None)
+    }
+
+    // Like `flush_operations_chunked`, but operations in `lane` are flushed ahead of the others.
+    pub fn flush_lane_first(&mut self, lane: Lane, max_ops: usize) -> Vec<OperationEnvelope> {
+        self.flush_operations_internal(max_ops, Some(lane))
+    }
+
+    // Reverts a local edit that hasn't been flushed yet by applying its inverse, and returns the
+    // resulting changes along with the inverse operations. Edits that were flushed or that a peer
+    // acknowledged are refused with `Error::AlreadySent`. An edit broadcast straight from the
+    // envelope returned when it was made can't be detected, so rolling back is only safe when
+    // operations are sent with `flush_operations`. In that case the next flush carries the edit
+    // and its inverse, and the returned operations must not be sent again.
+    pub fn rollback_pending(
+        &mut self,
+        envelope: &OperationEnvelope,
+    ) -> Result<(Vec<Change>, Vec<OperationEnvelope>), Error> {
+        let operation = match &envelope.operation {
+            Operation::EpochOperation {
+                epoch_id,
+                operation,
+            } if *epoch_id == self.epoch_id() => operation,
+            _ => return Err(Error::InvalidOperation),
+        };
+        let file_id = match operation {
+            epoch::Operation::BufferOperation { file_id, .. } => *file_id,
+            _ => return Err(Error::InvalidOperation),
+        };
+        let edit_ids = envelope.operation.local_edit_ids();
+        let lamport_timestamp = operation.lamport_timestamp();
+        if lamport_timestamp.replica_id != self.replica_id()
+            || edit_ids.is_empty()
+            || self.rolled_back.contains(&lamport_timestamp)
+        {
+            return Err(Error::InvalidOperation);
+        }
+        if let Some((epoch_id, last_flushed)) = self.last_flushed.as_ref() {
+            if *epoch_id == self.epoch_id()
+                && last_flushed
+                    .get(&envelope.lane())
+                    .map_or(false, |timestamp| lamport_timestamp <= *timestamp)
+            {
+                return Err(Error::AlreadySent);
+            }
+        }
+        let epoch_id = self.epoch_id();
+        if self.acks.values().any(|ack| {
+            ack.epoch_id == epoch_id
+                && edit_ids
+                    .iter()
+                    .any(|edit_id| ack.epoch_version.observed(*edit_id))
+        }) {
+            return Err(Error::AlreadySent);
+        }
+
+        let mut cur_epoch = self.cur_epoch_mut();
+        let version = cur_epoch.buffer_version(file_id)?;
+        let mut inverse_envelopes = Vec::new();
+        let mut inverse_edit_ids = Vec::new();
+        for edit_id in edit_ids.into_iter().rev() {
+            for (range, code_units) in cur_epoch
+                .inverse_of_edit(file_id, edit_id)?
+                .into_iter()
+                .rev()
+            {
+                let operation = cur_epoch.edit(
+                    file_id,
+                    Some(range),
+                    Text::new(code_units),
+                    &mut self.lamport_clock.borrow_mut(),
+                )?;
+                let envelope = OperationEnvelope::wrap(cur_epoch.id, cur_epoch.head, operation);
+                inverse_edit_ids.extend(envelope.operation.local_edit_ids());
+                inverse_envelopes.push(envelope);
+            }
+        }
+        let changes = cur_epoch.changes_since(file_id, &version)?.collect();
+        drop(cur_epoch);
+        self.group_file_edit(file_id, inverse_edit_ids, Instant::now());
+        self.rolled_back.insert(lamport_timestamp);
+        Ok((changes, inverse_envelopes))
+    }
+
+    // The operations the next call to `flush_operations` would return. Envelopes are built from
+    // the epoch's history on demand rather than queued, so this returns copies of them, and
+    // flushing afterwards returns the same operations.
+    pub fn peek_pending(&self) -> Vec<OperationEnvelope> {
+        self.pending_flush(usize::max_value(), None)
+            .map_or(Vec::new(), |(envelopes, _)| envelopes)
+    }
+
+    fn flush_operations_internal(
+        &mut self,
+        max_ops: usize,
+        priority_lane: Option<Lane>,
+    ) -> Vec<OperationEnvelope> {
+        if let Some((envelopes, last_flushed)) = self.pending_flush(max_ops, priority_lane) {
+            self.last_flushed = Some((self.epoch_id(), last_flushed));
+            envelopes
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn pending_flush(
+        &self,
+        max_ops: usize,
+        priority_lane: Option<Lane>,
+    ) -> Option<(Vec<OperationEnvelope>, HashMap<Lane, time::Lamport>)> {
+        let mut envelopes = Vec::new();
+        let epoch_ref = self.epoch.clone()?;
+        if max_ops == 0 {
+            return None;
+        }
+
+        let replica_id = self.replica_id();
+        let epoch = epoch_ref.borrow();
+        let mut last_flushed = match self.last_flushed.clone() {
+            Some((epoch_id, last_flushed)) if epoch_id == epoch.id => last_flushed,
+            _ => {
+                if epoch.id.replica_id == replica_id {
+                    envelopes.push(OperationEnvelope {
+                        epoch_head: epoch.head,
+                        operation: Operation::StartEpoch {
+                            epoch_id: epoch.id,
+                            head: epoch.head,
+                        },
+                        route: Vec::new(),
+                    });
+                }
+                HashMap::new()
+            }
+        };
+
+        // Each lane resumes right after the last operation flushed from it, so flushing in chunks
+        // only visits the operations that are returned.
+        let skip_noop_ops = self.skip_noop_ops;
+        let lane_ops = |lane| {
+            epoch
+                .lane_operations_from(replica_id, lane, last_flushed.get(&lane).cloned())
+                .filter(move |op| !skip_noop_ops || !op.is_noop())
+                .peekable()
+        };
+        let mut structure_ops = lane_ops(Lane::Structure);
+        let mut content_ops = lane_ops(Lane::Content);
+        let mut ops = Vec::new();
+        while ops.len() < max_ops - envelopes.len() {
+            let take_structure_op = match (structure_ops.peek(), content_ops.peek()) {
+                (None, None) => break,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (Some(structure_op), Some(content_op)) => match priority_lane {
+                    Some(priority_lane) => priority_lane == Lane::Structure,
+                    None => structure_op.lamport_timestamp() < content_op.lamport_timestamp(),
+                },
+            };
+            if take_structure_op {
+                ops.push(structure_ops.next().unwrap());
+            } else {
+                ops.push(content_ops.next().unwrap());
+            }
+        }
+        for op in &ops {
+            last_flushed.insert(op.lane(), op.lamport_timestamp());
+        }
+        envelopes.extend(OperationEnvelope::wrap_many(epoch.id, epoch.head, ops));
+        Some((envelopes, last_flushed))
+    }
+
+    pub fn operation_stats(&self) -> HashMap<ReplicaId, OpStats> {
+        self.cur_epoch().operation_stats()
+    }
+
+    // An approximation derived from the fragment trees of the open buffers and the operations
+    // retained by the current epoch.
+    pub fn memory_usage(&self) -> MemoryReport {
+        self.cur_epoch().memory_usage()
+    }
+
+    // Like `memory_usage`, but restricted to a single buffer. The version vector length is that of
+    // the buffer rather than the epoch, and only the buffer's deferred operations count toward the
+    // operation log.
+    pub fn buffer_memory_usage(&self, buffer_id: BufferId) -> Result<MemoryReport, Error> {
+        if let Some(snapshot) = self.snapshots.get(&buffer_id) {
+            return Ok(snapshot.memory_usage());
+        }
+        let file_id = self.file_id_for_buffer(buffer_id)?;
+        self.cur_epoch().buffer_memory_usage(file_id)
+    }
+
+    fn check_size_thresholds(&self) {
+        let mut crossed = Vec::new();
+        {
+            let buffers = self.buffers.borrow();
+            let cur_epoch = self.cur_epoch();
+            let mut size_thresholds = self.size_thresholds.borrow_mut();
+            let buffer_ids = size_thresholds.keys().cloned().collect::<Vec<_>>();
+            for buffer_id in buffer_ids {
+                let bytes = match buffers.get(&buffer_id) {
+                    Some(file_id) => cur_epoch.buffer_len_bytes(*file_id).ok(),
+                    None => None,
+                };
+                if let Some(bytes) = bytes {
+                    let threshold = size_thresholds.get_mut(&buffer_id).unwrap();
+                    if threshold.last_bytes <= threshold.bytes && bytes > threshold.bytes {
+                        crossed.push((
+                            buffer_id,
+                            bytes,
+                            size_thresholds.remove(&buffer_id).unwrap(),
+                        ));
+                    } else {
+                        threshold.last_bytes = bytes;
+                    }
+                }
+            }
+        }
+
+        for (buffer_id, bytes, threshold) in crossed {
+            (threshold.callback)(buffer_id, bytes);
+        }
+    }
+
     fn cur_epoch(&self) -> Ref<Epoch> {
         self.epoch.as_ref().unwrap().borrow()
     }
@@ -751,6 +2814,78 @@ impl WorkTree {
         self.epoch.as_ref().unwrap().borrow_mut()
     }
 
+    fn limit_deferred_ops(&mut self, ops: Vec<Operation>) -> Result<Vec<Operation>, Error> {
+        let (capacity, policy) = if let Some(limit) = self.deferred_ops_limit {
+            limit
+        } else {
+            return Ok(ops);
+        };
+
+        let cur_epoch_id = self.epoch.as_ref().map(|epoch| epoch.borrow().id);
+        let mut available =
+            capacity.saturating_sub(self.deferred_ops.borrow().values().map(Vec::len).sum());
+        let previously_blocked_len = self.blocked_ops.len();
+        let mut all_ops = mem::replace(&mut self.blocked_ops, Vec::new());
+        all_ops.extend(ops);
+
+        // Decide the fate of every operation before moving any of them, so that the operations
+        // blocked by earlier calls survive a `QueueFull` error.
+        let mut blocked = Vec::with_capacity(all_ops.len());
+        for op in &all_ops {
+            let will_be_deferred = match op {
+                Operation::StartEpoch { .. } => false,
+                Operation::EpochOperation { epoch_id, .. } => {
+                    cur_epoch_id.map_or(true, |cur_epoch_id| *epoch_id > cur_epoch_id)
+                }
+            };
+            let mut is_blocked = false;
+            if will_be_deferred {
+                if available > 0 {
+                    available -= 1;
+                } else if policy == OverflowPolicy::Block {
+                    is_blocked = true;
+                } else if policy == OverflowPolicy::Error {
+                    all_ops.truncate(previously_blocked_len);
+                    self.blocked_ops = all_ops;
+                    return Err(Error::QueueFull);
+                }
+            }
+            blocked.push(is_blocked);
+        }
+
+        let mut accepted_ops = Vec::with_capacity(all_ops.len());
+        for (op, is_blocked) in all_ops.into_iter().zip(blocked) {
+            if is_blocked {
+                self.blocked_ops.push(op);
+            } else {
+                accepted_ops.push(op);
+            }
+        }
+        Ok(accepted_ops)
+    }
+
+    fn drop_oldest_deferred_ops(&mut self) {
+        if let Some((capacity, OverflowPolicy::DropOldest)) = self.deferred_ops_limit {
+            let mut deferred_ops = self.deferred_ops.borrow_mut();
+            let len = deferred_ops.values().map(Vec::len).sum::<usize>();
+            if len > capacity {
+                let mut timestamps = deferred_ops
+                    .values()
+                    .flat_map(|ops| ops.iter().map(|op| op.lamport_timestamp()))
+                    .collect::<Vec<_>>();
+                timestamps.sort();
+                let min_timestamp = timestamps[len - capacity];
+                for ops in deferred_ops.values_mut() {
+                    ops.retain(|op| op.lamport_timestamp() >= min_timestamp);
+                }
+                deferred_ops.retain(|_, ops| !ops.is_empty());
+                let count = len - deferred_ops.values().map(Vec::len).sum::<usize>();
+                drop(deferred_ops);
+                self.warn(Warning::DroppedDeferredOperations { count });
+            }
+        }
+    }
+
     fn defer_epoch_op(&self, epoch_id: epoch::Id, operation: epoch::Operation) {
         self.deferred_ops
             .borrow_mut()
@@ -763,7 +2898,10 @@ impl WorkTree {
         self.lamport_clock.borrow().replica_id
     }
 
-    fn buffer_file_id(&self, buffer_id: BufferId) -> Result<FileId, Error> {
+    fn file_id_for_buffer(&self, buffer_id: BufferId) -> Result<FileId, Error> {
+        if self.snapshots.contains_key(&buffer_id) {
+            return Err(Error::ReadOnlyBuffer(buffer_id));
+        }
         self.buffers
             .borrow()
             .get(&buffer_id)
@@ -793,13 +2931,109 @@ impl WorkTree {
 }
 
 impl OperationEnvelope {
-    fn wrap(epoch_id: epoch::Id, epoch_head: Option<Oid>, operation: epoch::Operation) -> Self {
-        OperationEnvelope {
-            epoch_head,
-            operation: Operation::EpochOperation {
-                epoch_id,
-                operation,
-            },
+    pub fn epoch_id(&self) -> epoch::Id {
+        self.operation.epoch_id()
+    }
+
+    pub fn lane(&self) -> Lane {
+        self.operation.lane()
+    }
+
+    pub fn is_noop(&self) -> bool {
+        self.operation.is_noop()
+    }
+
+    // The length of `operation.serialize()`, computed without copying the flatbuffer into a
+    // versioned message.
+    pub fn serialized_len(&self) -> usize {
+        let mut builder = FlatBufferBuilder::new();
+        let root = self.operation.to_flatbuf(&mut builder);
+        builder.finish(root, None);
+        4 + builder.finished_data().len()
+    }
+
+    // Serializes the route and epoch head in front of `operation.serialize()`, so that a relay can
+    // read the route with `route_of` without parsing the rest of the message.
+    pub fn serialize(&self) -> Result<Vec<u8>, Error> {
+        if self.route.len() > MAX_ROUTE_LEN {
+            return Err(Error::RouteTooLong(self.route.len()));
+        }
+
+        let mut bytes = Vec::with_capacity(22 + self.route.len() + self.serialized_len());
+        bytes.push(self.route.len() as u8);
+        bytes.extend_from_slice(&self.route);
+        if let Some(head) = self.epoch_head {
+            bytes.push(1);
+            bytes.extend_from_slice(&head);
+        } else {
+            bytes.push(0);
+        }
+        bytes.extend(self.operation.serialize());
+        Ok(bytes)
+    }
+
+    pub fn deserialize(buffer: &[u8]) -> Result<Option<Self>, Error> {
+        let route = Self::route_of(buffer)?;
+        let mut buffer = &buffer[1 + route.len()..];
+        let epoch_head = match buffer.first() {
+            Some(0) => {
+                buffer = &buffer[1..];
+                None
+            }
+            Some(1) if buffer.len() >= 21 => {
+                let mut head = [0; 20];
+                head.copy_from_slice(&buffer[1..21]);
+                buffer = &buffer[21..];
+                Some(head)
+            }
+            _ => return Err(Error::DeserializeError("epoch_head")),
+        };
+
+        Ok(
+            Operation::deserialize(buffer)?.map(|operation| OperationEnvelope {
+                epoch_head,
+                operation,
+                route: route.to_vec(),
+            }),
+        )
+    }
+
+    pub fn route_of(buffer: &[u8]) -> Result<&[u8], Error> {
+        let len = *buffer.first().ok_or(Error::DeserializeError("route"))? as usize;
+        buffer
+            .get(1..1 + len)
+            .ok_or(Error::DeserializeError("route"))
+    }
+
+    // The number of changes the envelope carries: buffer operations are batched, so they count
+    // every batched edit or selection update, while all other operations count as one.
+    pub fn change_count(&self) -> usize {
+        match &self.operation {
+            Operation::EpochOperation {
+                operation: epoch::Operation::BufferOperation { operations, .. },
+                ..
+            } => operations.len(),
+            _ => 1,
+        }
+    }
+
+    // The file whose contents or position in the tree the operation changes. Operations that
+    // don't target a single file, such as presence updates, return `None`.
+    pub fn file_id(&self) -> Option<FileId> {
+        match &self.operation {
+            Operation::StartEpoch { .. } => None,
+            Operation::EpochOperation { operation, .. } => operation.file_id(),
+        }
+    }
+
+    fn wrap(epoch_id: epoch::Id, epoch_head: Option<Oid>, operation: epoch::Operation) -> Self {
+        OperationEnvelope {
+            epoch_head,
+            operation: Operation::EpochOperation {
+                epoch_id,
+                operation,
+            },
+            route: Vec::new(),
         }
     }
 
@@ -815,11 +3049,38 @@ impl OperationEnvelope {
                     epoch_id,
                     operation,
                 },
+                route: Vec::new(),
             })
             .collect()
     }
 }
 
+#[cfg(feature = "serde_json")]
+impl OperationLogEntry {
+    fn new(epoch_id: epoch::Id, lamport_timestamp: time::Lamport, kind: &'static str) -> Self {
+        OperationLogEntry {
+            epoch: epoch_id.value,
+            replica_id: lamport_timestamp.replica_id,
+            lamport: lamport_timestamp.value,
+            kind,
+            file_id: None,
+            range: None,
+            text: None,
+        }
+    }
+}
+
+#[cfg(feature = "serde_json")]
+impl OperationLogPosition {
+    fn new(insertion_id: time::Local, offset: usize) -> Self {
+        OperationLogPosition {
+            replica_id: insertion_id.replica_id,
+            insertion: insertion_id.value,
+            offset,
+        }
+    }
+}
+
 impl Operation {
     pub fn epoch_id(&self) -> epoch::Id {
         match self {
@@ -828,6 +3089,160 @@ impl Operation {
         }
     }
 
+    // Every operation is stamped with its own tick of its author's Lamport clock, including the
+    // tick that names a new epoch.
+    fn id(&self) -> (epoch::Id, time::Lamport) {
+        match self {
+            Operation::StartEpoch { epoch_id, .. } => (*epoch_id, *epoch_id),
+            Operation::EpochOperation {
+                epoch_id,
+                operation,
+            } => (*epoch_id, operation.lamport_timestamp()),
+        }
+    }
+
+    // The ids of the edits in a buffer operation, which are what undo groups are made of.
+    fn local_edit_ids(&self) -> Vec<time::Local> {
+        match self {
+            Operation::EpochOperation {
+                operation: epoch::Operation::BufferOperation { operations, .. },
+                ..
+            } => operations
+                .iter()
+                .filter_map(|operation| match operation {
+                    buffer::Operation::Edit {
+                        local_timestamp, ..
+                    } => Some(*local_timestamp),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn lane(&self) -> Lane {
+        match self {
+            Operation::StartEpoch { .. } => Lane::Structure,
+            Operation::EpochOperation { operation, .. } => operation.lane(),
+        }
+    }
+
+    pub fn is_noop(&self) -> bool {
+        match self {
+            Operation::StartEpoch { .. } => false,
+            Operation::EpochOperation { operation, .. } => operation.is_noop(),
+        }
+    }
+
+    pub fn replica_id(&self) -> ReplicaId {
+        match self {
+            Operation::StartEpoch { epoch_id, .. } => epoch_id.replica_id,
+            Operation::EpochOperation { operation, .. } => operation.lamport_timestamp().replica_id,
+        }
+    }
+
+    pub fn restrict_to_context(&mut self, context: &time::Global) -> Result<(), Error> {
+        match self {
+            Operation::StartEpoch { .. } => Ok(()),
+            Operation::EpochOperation { operation, .. } => operation.restrict_to_context(context),
+        }
+    }
+
+    #[cfg(feature = "serde_json")]
+    fn log_entries(&self) -> Vec<OperationLogEntry> {
+        let (epoch_id, operation) = match self {
+            Operation::StartEpoch { epoch_id, .. } => {
+                return vec![OperationLogEntry::new(*epoch_id, *epoch_id, "start_epoch")];
+            }
+            Operation::EpochOperation {
+                epoch_id,
+                operation,
+            } => (*epoch_id, operation),
+        };
+
+        match operation {
+            epoch::Operation::InsertMetadata {
+                file_id,
+                parent,
+                lamport_timestamp,
+                ..
+            } => vec![OperationLogEntry {
+                file_id: Some(format!("{:?}", file_id)),
+                text: parent
+                    .as_ref()
+                    .map(|(_, name)| name.to_string_lossy().into_owned()),
+                ..OperationLogEntry::new(epoch_id, *lamport_timestamp, "insert_metadata")
+            }],
+            epoch::Operation::UpdateParent {
+                child_id,
+                new_parent,
+                lamport_timestamp,
+                ..
+            } => vec![OperationLogEntry {
+                file_id: Some(format!("{:?}", child_id)),
+                text: new_parent
+                    .as_ref()
+                    .map(|(_, name)| name.to_string_lossy().into_owned()),
+                ..OperationLogEntry::new(epoch_id, *lamport_timestamp, "update_parent")
+            }],
+            epoch::Operation::BufferOperation {
+                file_id,
+                operations,
+                ..
+            } => operations
+                .iter()
+                .map(|operation| match operation {
+                    buffer::Operation::Edit {
+                        start_id,
+                        start_offset,
+                        end_id,
+                        end_offset,
+                        new_text,
+                        lamport_timestamp,
+                        ..
+                    } => OperationLogEntry {
+                        file_id: Some(format!("{:?}", file_id)),
+                        range: Some([
+                            OperationLogPosition::new(*start_id, *start_offset),
+                            OperationLogPosition::new(*end_id, *end_offset),
+                        ]),
+                        text: new_text.as_ref().map(|text| text.to_string_lossy()),
+                        ..OperationLogEntry::new(epoch_id, *lamport_timestamp, "edit")
+                    },
+                    buffer::Operation::UpdateSelections {
+                        lamport_timestamp, ..
+                    } => OperationLogEntry {
+                        file_id: Some(format!("{:?}", file_id)),
+                        ..OperationLogEntry::new(epoch_id, *lamport_timestamp, "update_selections")
+                    },
+                })
+                .collect(),
+            epoch::Operation::UpdateActiveLocation {
+                file_id,
+                lamport_timestamp,
+            } => vec![OperationLogEntry {
+                file_id: file_id.map(|file_id| format!("{:?}", file_id)),
+                ..OperationLogEntry::new(epoch_id, *lamport_timestamp, "update_active_location")
+            }],
+            epoch::Operation::UpdatePresence {
+                label,
+                lamport_timestamp,
+                ..
+            } => vec![OperationLogEntry {
+                text: Some(label.clone()),
+                ..OperationLogEntry::new(epoch_id, *lamport_timestamp, "update_presence")
+            }],
+            epoch::Operation::UpdateOrder {
+                file_id,
+                lamport_timestamp,
+                ..
+            } => vec![OperationLogEntry {
+                file_id: Some(format!("{:?}", file_id)),
+                ..OperationLogEntry::new(epoch_id, *lamport_timestamp, "update_order")
+            }],
+        }
+    }
+
     pub fn is_selection_update(&self) -> bool {
         match self {
             Operation::EpochOperation { operation, .. } => match operation {
@@ -847,13 +3262,40 @@ impl Operation {
         let mut builder = FlatBufferBuilder::new();
         let root = self.to_flatbuf(&mut builder);
         builder.finish(root, None);
-        let (mut bytes, first_valid_byte_index) = builder.collapse();
-        bytes.drain(0..first_valid_byte_index);
-        bytes
+        let (bytes, first_valid_byte_index) = builder.collapse();
+
+        // The flatbuffer is prefixed with the format version so that peers running an
+        // incompatible version can be detected before attempting to parse it.
+        let mut versioned_bytes = Vec::with_capacity(4 + bytes.len() - first_valid_byte_index);
+        versioned_bytes.extend_from_slice(&SERIALIZATION_VERSION.to_le_bytes());
+        versioned_bytes.extend_from_slice(&bytes[first_valid_byte_index..]);
+        versioned_bytes
     }
 
     pub fn deserialize<'a>(buffer: &'a [u8]) -> Result<Option<Self>, Error> {
         use crate::serialization::worktree::Operation;
+        if buffer.len() < 4 {
+            return Err(Error::DeserializeError("version"));
+        }
+        let mut version_bytes = [0; 4];
+        version_bytes.copy_from_slice(&buffer[0..4]);
+        let version = u32::from_le_bytes(version_bytes);
+        if version != SERIALIZATION_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        // Offsets aren't verified when reading a flatbuffer, so at least make sure that the root table
+        // starts within the message rather than reading past the end of a truncated one.
+        let buffer = &buffer[4..];
+        let mut root_offset_bytes = [0; 4];
+        if buffer.len() < 4 {
+            return Err(Error::DeserializeError("root"));
+        }
+        root_offset_bytes.copy_from_slice(&buffer[0..4]);
+        if u32::from_le_bytes(root_offset_bytes) as usize + 4 > buffer.len() {
+            return Err(Error::DeserializeError("root"));
+        }
+
         let root = flatbuffers::get_root::<Operation<'a>>(buffer);
         Self::from_flatbuf(root)
     }
@@ -915,11 +3357,15 @@ impl Operation {
     ) -> Result<Option<Self>, Error> {
         use crate::serialization::worktree::{EpochOperation, OperationVariant, StartEpoch};
 
-        let variant = message.variant().ok_or(Error::DeserializeError)?;
+        let variant = message
+            .variant()
+            .ok_or(Error::DeserializeError("variant"))?;
         match message.variant_type() {
             OperationVariant::StartEpoch => {
                 let message = StartEpoch::init_from_table(variant);
-                let epoch_id = message.epoch_id().ok_or(Error::DeserializeError)?;
+                let epoch_id = message
+                    .epoch_id()
+                    .ok_or(Error::DeserializeError("epoch_id"))?;
                 Ok(Some(Operation::StartEpoch {
                     epoch_id: time::Lamport::from_flatbuf(epoch_id),
                     head: message.head().map(|head| {
@@ -931,8 +3377,12 @@ impl Operation {
             }
             OperationVariant::EpochOperation => {
                 let message = EpochOperation::init_from_table(variant);
-                let operation = message.operation().ok_or(Error::DeserializeError)?;
-                let epoch_id = message.epoch_id().ok_or(Error::DeserializeError)?;
+                let operation = message
+                    .operation()
+                    .ok_or(Error::DeserializeError("operation"))?;
+                let epoch_id = message
+                    .epoch_id()
+                    .ok_or(Error::DeserializeError("epoch_id"))?;
                 if let Some(epoch_op) =
                     epoch::Operation::from_flatbuf(message.operation_type(), operation)?
                 {
@@ -949,6 +3399,87 @@ impl Operation {
     }
 }
 
+impl BufferSelectionRanges {
+    // Sorts the ranges of every selection set and merges ranges that overlap or touch.
+    pub fn normalize(&mut self) {
+        for ranges in self.local.values_mut() {
+            Self::normalize_ranges(ranges);
+        }
+        for ranges in self.remote.values_mut().flat_map(|sets| sets.iter_mut()) {
+            Self::normalize_ranges(ranges);
+        }
+    }
+
+    fn normalize_ranges(ranges: &mut Vec<Range<Point>>) -> bool {
+        let mut sorted = ranges
+            .iter()
+            .map(|range| {
+                if range.start <= range.end {
+                    (range.start, range.end, false)
+                } else {
+                    (range.end, range.start, true)
+                }
+            })
+            .collect::<Vec<_>>();
+        sorted.sort_by_key(|(start, end, _)| (*start, *end));
+
+        let mut merged: Vec<(Point, Point, bool)> = Vec::with_capacity(sorted.len());
+        for (start, end, reversed) in sorted {
+            if let Some(prev) = merged.last_mut() {
+                if start <= prev.1 {
+                    prev.1 = prev.1.max(end);
+                    continue;
+                }
+            }
+            merged.push((start, end, reversed));
+        }
+
+        let normalized = merged
+            .into_iter()
+            .map(|(start, end, reversed)| if reversed { end..start } else { start..end })
+            .collect::<Vec<_>>();
+        if normalized == *ranges {
+            false
+        } else {
+            *ranges = normalized;
+            true
+        }
+    }
+}
+
+impl AppliedOps {
+    fn new(window: usize) -> Self {
+        AppliedOps {
+            window,
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn contains(&self, id: (epoch::Id, time::Lamport)) -> bool {
+        self.ids.contains(&id)
+    }
+
+    fn insert(&mut self, id: (epoch::Id, time::Lamport)) {
+        if self.window > 0 && self.ids.insert(id) {
+            self.order.push_back(id);
+            self.evict();
+        }
+    }
+
+    fn set_window(&mut self, window: usize) {
+        self.window = window;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.order.len() > self.window {
+            let id = self.order.pop_front().unwrap();
+            self.ids.remove(&id);
+        }
+    }
+}
+
 impl SwitchEpoch {
     fn new(
         to_assign: Rc<RefCell<Epoch>>,
@@ -961,6 +3492,7 @@ impl SwitchEpoch {
         lamport_clock: Rc<RefCell<time::Lamport>>,
         git: Rc<GitProvider>,
         observer: Option<Rc<ChangeObserver>>,
+        diagnostics: Option<Rc<Diagnostics>>,
     ) -> Self {
         let last_seen = cur_epoch.borrow().id;
         Self {
@@ -974,7 +3506,124 @@ impl SwitchEpoch {
             lamport_clock,
             git,
             observer,
+            diagnostics,
+        }
+    }
+}
+
+impl StatusCursor {
+    fn new(
+        statuses: Vec<(PathBuf, FileType, Option<FileStatus>)>,
+        epoch: Rc<RefCell<Epoch>>,
+        base: Oid,
+        git: Rc<GitProvider>,
+    ) -> Self {
+        Self {
+            statuses,
+            next_ix: 0,
+            comparison: None,
+            epoch,
+            base,
+            git,
+        }
+    }
+
+    // The index of the first entry without a status that the next entry's status depends on, if
+    // any. An unchanged directory is only known to stay that way once everything beneath it is.
+    fn blocking_ix(&mut self) -> Option<usize> {
+        let (path, file_type, status) = &self.statuses[self.next_ix];
+        if status.is_none() {
+            return Some(self.next_ix);
+        }
+        if *file_type != FileType::Directory || *status != Some(FileStatus::Unchanged) {
+            return None;
+        }
+
+        let mut modified = false;
+        let mut blocking_ix = None;
+        for (ix, (descendant_path, _, descendant_status)) in
+            self.statuses.iter().enumerate().skip(self.next_ix + 1)
+        {
+            if !descendant_path.starts_with(path) {
+                break;
+            }
+            match descendant_status {
+                Some(FileStatus::Unchanged) => {}
+                Some(_) => {
+                    modified = true;
+                    break;
+                }
+                None => {
+                    blocking_ix = Some(ix);
+                    break;
+                }
+            }
+        }
+        if modified {
+            self.statuses[self.next_ix].2 = Some(FileStatus::Modified);
+        }
+        blocking_ix
+    }
+
+    fn compare_text(&self, path: &Path) -> Box<Future<Item = bool, Error = Error>> {
+        let epoch = self.epoch.borrow();
+        let file_id = match epoch.file_id(path) {
+            Ok(file_id) => file_id,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        let text: Box<Future<Item = String, Error = Error>> = match epoch.text(file_id) {
+            Ok(text) => Box::new(future::ok(text.into_string())),
+            Err(_) => match (epoch.head, epoch.base_path(file_id)) {
+                (Some(head), Some(head_path)) => Box::new(
+                    self.git
+                        .base_text(head, &head_path)
+                        .map_err(|err| Error::IoError(err)),
+                ),
+                _ => Box::new(future::ok(String::new())),
+            },
+        };
+        Box::new(
+            self.git
+                .base_text(self.base, path)
+                .map_err(|err| Error::IoError(err))
+                .join(text)
+                .map(|(base_text, text)| text != base_text),
+        )
+    }
+}
+
+impl Stream for StatusCursor {
+    type Item = (PathBuf, FileType, FileStatus);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while self.next_ix < self.statuses.len() {
+            if let Some(ix) = self.blocking_ix() {
+                if self
+                    .comparison
+                    .as_ref()
+                    .map_or(true, |(cur_ix, _)| *cur_ix != ix)
+                {
+                    let comparison = self.compare_text(&self.statuses[ix].0);
+                    self.comparison = Some((ix, comparison));
+                }
+                let is_modified = match self.comparison.as_mut().unwrap().1.poll()? {
+                    Async::Ready(is_modified) => is_modified,
+                    Async::NotReady => return Ok(Async::NotReady),
+                };
+                self.comparison = None;
+                self.statuses[ix].2 = Some(if is_modified {
+                    FileStatus::Modified
+                } else {
+                    FileStatus::Unchanged
+                });
+            } else {
+                let (path, file_type, status) = self.statuses[self.next_ix].clone();
+                self.next_ix += 1;
+                return Ok(Async::Ready(Some((path, file_type, status.unwrap()))));
+            }
         }
+        Ok(Async::Ready(None))
     }
 }
 
@@ -1044,8 +3693,11 @@ impl Future for SwitchEpoch {
                 let mut fixup_ops = Vec::new();
 
                 let mut buffer_mappings = Vec::with_capacity(self.base_text_requests.len());
+                let mut untitled_file_ids = HashMap::new();
                 for (buffer_id, request) in self.base_text_requests.drain() {
-                    if let Some(request) = request {
+                    if let Some(new_file_id) = untitled_file_ids.get(&buffers[&buffer_id]) {
+                        buffer_mappings.push((buffer_id, *new_file_id));
+                    } else if let Some(request) = request {
                         let base_text = request.future.take_result().unwrap()?;
                         let new_file_id = to_assign.file_id(request.path).unwrap();
                         to_assign.open_text_file(new_file_id, base_text, &mut lamport_clock)?;
@@ -1075,6 +3727,7 @@ impl Future for SwitchEpoch {
                             to_assign.head,
                             operation,
                         ));
+                        untitled_file_ids.insert(buffers[&buffer_id], new_file_id);
                         buffer_mappings.push((buffer_id, new_file_id));
                     }
                 }
@@ -1086,7 +3739,36 @@ impl Future for SwitchEpoch {
                         to_assign.apply_ops(ops, &mut lamport_clock)?,
                     ));
                 }
-                deferred_ops.retain(|id, _| *id > to_assign.id);
+                // Operations deferred for epochs that were skipped can never be applied.
+                let cur_epoch_id = to_assign.id;
+                let mut dropped_epoch_ids = Vec::new();
+                deferred_ops.retain(|epoch_id, ops| {
+                    if *epoch_id > cur_epoch_id {
+                        true
+                    } else {
+                        dropped_epoch_ids.extend(ops.iter().map(|_| *epoch_id));
+                        false
+                    }
+                });
+                if let Some(diagnostics) = self.diagnostics.as_ref() {
+                    for epoch_id in dropped_epoch_ids {
+                        diagnostics.warn(Warning::DroppedStaleOperation {
+                            epoch_id,
+                            cur_epoch_id,
+                        });
+                    }
+                }
+
+                let replica_id = lamport_clock.replica_id;
+                if let Some(presence) = cur_epoch.presence(replica_id) {
+                    let op = to_assign.set_presence(
+                        replica_id,
+                        presence.label.as_str(),
+                        presence.color,
+                        &mut lamport_clock,
+                    );
+                    fixup_ops.push(OperationEnvelope::wrap(to_assign.id, to_assign.head, op));
+                }
 
                 let old_active_location = cur_epoch.replica_location(lamport_clock.replica_id);
                 let mut buffer_changes = Vec::new();
@@ -1196,8 +3878,9 @@ impl<F: Future> MaybeDone<F> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::epoch::CursorEntry;
+    use crate::epoch::{CursorEntry, FileStatus, MAX_PRESENCE_LABEL_LEN};
     use rand::{Rng, SeedableRng, StdRng};
+    use std::thread;
     use uuid::Uuid;
 
     #[test]
@@ -1278,143 +3961,2500 @@ mod tests {
                 }
             }
 
-            while !network.is_idle() {
-                for replica_index in 0..PEERS {
-                    let tree = &mut trees[replica_index];
-                    let replica_id = tree.replica_id();
-                    let received_ops = network.receive(replica_id, &mut rng);
-                    let fixup_ops = tree.apply_ops(deserialize_ops(received_ops)).unwrap();
-                    network.broadcast(
-                        replica_id,
-                        serialize_ops(open_envelopes(fixup_ops.collect().wait().unwrap())),
-                        &mut rng,
-                    );
-                }
-            }
+            while !network.is_idle() {
+                for replica_index in 0..PEERS {
+                    let tree = &mut trees[replica_index];
+                    let replica_id = tree.replica_id();
+                    let received_ops = network.receive(replica_id, &mut rng);
+                    let fixup_ops = tree.apply_ops(deserialize_ops(received_ops)).unwrap();
+                    network.broadcast(
+                        replica_id,
+                        serialize_ops(open_envelopes(fixup_ops.collect().wait().unwrap())),
+                        &mut rng,
+                    );
+                }
+            }
+
+            for replica_index in 0..PEERS - 1 {
+                let tree_1 = &trees[replica_index];
+                let tree_2 = &trees[replica_index + 1];
+                assert_eq!(tree_1.cur_epoch().id, tree_2.cur_epoch().id);
+                assert_eq!(tree_1.cur_epoch().head, tree_2.cur_epoch().head);
+                assert_eq!(tree_1.entries(), tree_2.entries());
+                assert_eq!(tree_1.replica_locations(), tree_2.replica_locations());
+            }
+
+            for replica_index in 0..PEERS {
+                let tree = &trees[replica_index];
+                let observer = &observers[replica_index];
+                for buffer_id in tree.open_buffers() {
+                    assert_eq!(
+                        observer.text(buffer_id),
+                        tree.text(buffer_id).unwrap().into_string()
+                    );
+                    assert_eq!(
+                        observer.selection_ranges(buffer_id),
+                        tree.selection_ranges(buffer_id).unwrap()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        let git = Rc::new(TestGitProvider::new());
+        let base_tree = WorkTree::empty();
+        base_tree.create_file("a", FileType::Text).unwrap();
+        let a_base = base_tree.open_text_file("a").wait().unwrap();
+        base_tree.edit(a_base, Some(0..0), "abc").unwrap();
+        let commit_0 = git.commit(&base_tree);
+
+        base_tree.edit(a_base, Some(1..2), "def").unwrap();
+        base_tree.create_file("b", FileType::Directory).unwrap();
+        let commit_1 = git.commit(&base_tree);
+
+        base_tree.edit(a_base, Some(2..3), "ghi").unwrap();
+        base_tree.create_file("b/c", FileType::Text).unwrap();
+        let commit_2 = git.commit(&base_tree);
+
+        let observer_1 = Rc::new(TestChangeObserver::new());
+        let observer_2 = Rc::new(TestChangeObserver::new());
+        let (mut tree_1, ops_1) = WorkTree::new(
+            Uuid::from_u128(1),
+            Some(commit_0),
+            vec![],
+            git.clone(),
+            Some(observer_1.clone()),
+        )
+        .unwrap();
+
+        let (mut tree_2, ops_2) = WorkTree::new(
+            Uuid::from_u128(2),
+            Some(commit_0),
+            open_envelopes(ops_1.collect().wait().unwrap()),
+            git.clone(),
+            Some(observer_2.clone()),
+        )
+        .unwrap();
+
+        assert!(ops_2.wait().next().is_none());
+
+        assert_eq!(tree_1.head(), Some(commit_0));
+        assert_eq!(tree_1.dir_entries(), git.tree(commit_0).dir_entries());
+        assert_eq!(tree_2.head(), Some(commit_0));
+        assert_eq!(tree_2.dir_entries(), git.tree(commit_0).dir_entries());
+
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        observer_1.opened_buffer(a_1, &tree_1);
+        observer_2.opened_buffer(a_2, &tree_2);
+        assert_eq!(tree_1.text_str(a_1), git.tree(commit_0).text_str(a_base));
+        assert_eq!(tree_2.text_str(a_2), git.tree(commit_0).text_str(a_base));
+
+        let ops_1 = open_envelopes(tree_1.reset(Some(commit_1)).collect().wait().unwrap());
+        let fixup_ops_2 = tree_2.apply_ops(ops_1).unwrap().collect().wait().unwrap();
+        assert!(fixup_ops_2.is_empty());
+        assert_eq!(tree_1.head(), Some(commit_1));
+        assert_eq!(tree_2.head(), Some(commit_1));
+        assert_eq!(tree_1.entries(), tree_2.entries());
+        assert_eq!(tree_1.dir_entries(), git.tree(commit_1).dir_entries());
+        assert_eq!(tree_1.text_str(a_1), git.tree(commit_1).text_str(a_1));
+        assert_eq!(observer_1.text(a_1), tree_1.text_str(a_1));
+        assert_eq!(tree_2.text_str(a_2), git.tree(commit_1).text_str(a_2));
+        assert_eq!(observer_2.text(a_2), tree_2.text_str(a_2));
+
+        let ops_2 = open_envelopes(tree_2.reset(Some(commit_2)).collect().wait().unwrap());
+        let fixup_ops_1 = tree_1
+            .apply_ops(ops_2.clone())
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert!(fixup_ops_1.is_empty());
+        assert_eq!(tree_1.head(), Some(commit_2));
+        assert_eq!(tree_2.head(), Some(commit_2));
+        assert_eq!(tree_1.entries(), tree_2.entries());
+        assert_eq!(tree_1.dir_entries(), git.tree(commit_2).dir_entries());
+        assert_eq!(tree_1.text_str(a_1), git.tree(commit_2).text_str(a_1));
+        assert_eq!(observer_1.text(a_1), tree_1.text_str(a_1));
+        assert_eq!(tree_2.text_str(a_2), git.tree(commit_2).text_str(a_2));
+        assert_eq!(observer_2.text(a_2), tree_2.text_str(a_2));
+
+        // Reload tree using only ops for the newest epoch.
+        let (mut tree_1, ops_1) = WorkTree::new(
+            Uuid::from_u128(1),
+            Some(commit_0),
+            ops_2,
+            git.clone(),
+            Some(observer_1.clone()),
+        )
+        .unwrap();
+        assert!(ops_1.wait().next().is_none());
+        assert_eq!(tree_1.head(), Some(commit_2));
+
+        let ops_1 = open_envelopes(tree_1.reset(Some(commit_0)).collect().wait().unwrap());
+        let fixup_ops_2 = tree_2.apply_ops(ops_1).unwrap().collect().wait().unwrap();
+        assert!(fixup_ops_2.is_empty());
+        assert_eq!(tree_1.head(), Some(commit_0));
+        assert_eq!(tree_2.head(), Some(commit_0));
+    }
+
+    #[test]
+    fn test_size_threshold() {
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, mut tree_2) = two_replicas(&git);
+
+        let create_op = open_envelopes(tree_1.create_file("a", FileType::Text));
+        tree_2
+            .apply_ops(create_op)
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+
+        let crossings = Rc::new(RefCell::new(Vec::new()));
+        let crossings_clone = crossings.clone();
+        tree_1
+            .on_size_threshold(a_1, 5, move |buffer_id, len| {
+                crossings_clone.borrow_mut().push((buffer_id, len))
+            })
+            .unwrap();
+        tree_1.edit(a_1, Some(0..0), "abc").unwrap();
+        assert!(crossings.borrow().is_empty());
+        // "d\u{E9}" is two code units long but takes three bytes.
+        tree_1.edit(a_1, Some(3..3), "d\u{E9}").unwrap();
+        assert_eq!(*crossings.borrow(), vec![(a_1, 6)]);
+        tree_1.edit(a_1, Some(0..5), "").unwrap();
+        tree_1.edit(a_1, Some(0..0), "abcdef").unwrap();
+        assert_eq!(*crossings.borrow(), vec![(a_1, 6)]);
+
+        let crossings_clone = crossings.clone();
+        tree_1
+            .on_size_threshold(a_1, 8, move |buffer_id, len| {
+                crossings_clone.borrow_mut().push((buffer_id, len))
+            })
+            .unwrap();
+        let edit_op = open_envelopes(tree_2.edit(a_2, Some(0..0), "123"));
+        tree_1.apply_ops(edit_op).unwrap().collect().wait().unwrap();
+        assert_eq!(*crossings.borrow(), vec![(a_1, 6), (a_1, 9)]);
+
+        assert_eq!(
+            tree_1.on_size_threshold(BufferId(100), 1, |_, _| {}),
+            Err(Error::InvalidBufferId)
+        );
+    }
+
+    #[test]
+    fn test_apply_op_with_context() {
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, mut tree_2) = two_replicas(&git);
+
+        let mut ops = open_envelopes(tree_1.create_file("a", FileType::Text));
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        ops.extend(open_envelopes(tree_1.edit(a_1, Some(0..0), "abc")));
+        tree_2.apply_ops(ops).unwrap().collect().wait().unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        let context = tree_2.buffer_version(a_2).unwrap();
+
+        let ops = open_envelopes(tree_2.edit(a_2, Some(1..1), "X"));
+        tree_1.apply_ops(ops).unwrap().collect().wait().unwrap();
+        assert_eq!(tree_1.text_str(a_1), "aXbc");
+        let mut ops = open_envelopes(tree_1.edit(a_1, Some(0..4), ""));
+        assert_eq!(tree_1.text_str(a_1), "");
+
+        // The deletion is replayed as if it was concurrent with the insertion of "X".
+        tree_2
+            .apply_op_with_context(ops[0].clone(), &context)
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree_2.text_str(a_2), "X");
+
+        // The context must include the text and the file that an operation refers to.
+        let edit_ops = open_envelopes(tree_2.edit(a_2, Some(1..1), "Y"));
+        assert_eq!(
+            tree_1
+                .apply_op_with_context(edit_ops[0].clone(), &context)
+                .err(),
+            Some(Error::InvalidOperation)
+        );
+        assert_eq!(
+            tree_1
+                .apply_op_with_context(edit_ops[0].clone(), &time::Global::new())
+                .err(),
+            Some(Error::InvalidOperation)
+        );
+
+        let context = tree_1.buffer_version(a_1).unwrap();
+        assert!(tree_2
+            .apply_op_with_context(ops.pop().unwrap(), &context)
+            .is_err());
+    }
+
+    #[test]
+    fn test_apply_ops_from() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let replica_1_id = Uuid::from_u128(1);
+        let replica_2_id = Uuid::from_u128(2);
+        let (mut tree_1, ops_1) =
+            WorkTree::new(replica_1_id, Some(commit), vec![], git.clone(), None).unwrap();
+        let (mut tree_2, ops_2) = WorkTree::new(
+            replica_2_id,
+            Some(commit),
+            open_envelopes(ops_1.collect().wait().unwrap()),
+            git.clone(),
+            None,
+        )
+        .unwrap();
+        assert!(ops_2.wait().next().is_none());
+
+        let mut ops_1 = open_envelopes(tree_1.create_file("a", FileType::Text));
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        ops_1.extend(open_envelopes(tree_1.edit(a_1, Some(0..0), "abc")));
+        tree_2
+            .apply_ops_from(replica_1_id, ops_1.clone())
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        let ops_2 = open_envelopes(tree_2.edit(a_2, Some(3..3), "def"));
+        let version_2 = tree_2.buffer_version(a_2).unwrap();
+
+        // A naive transport relays every operation to every replica, including the sender.
+        let all_ops = ops_1.iter().chain(&ops_2).cloned().collect::<Vec<_>>();
+        tree_1
+            .apply_ops_from(replica_2_id, all_ops.clone())
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        tree_2
+            .apply_ops_from(replica_1_id, all_ops.clone())
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        tree_2
+            .apply_ops_from(replica_2_id, ops_2)
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+
+        assert_eq!(tree_1.text_str(a_1), "abcdef");
+        assert_eq!(tree_2.text_str(a_2), "abcdef");
+        assert_eq!(tree_1.buffer_version(a_1).unwrap(), version_2);
+        assert_eq!(tree_2.buffer_version(a_2).unwrap(), version_2);
+        assert_eq!(tree_1.deferred_operation_count(), 0);
+        assert_eq!(tree_2.deferred_operation_count(), 0);
+    }
+
+    #[test]
+    fn test_read_your_writes() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let replica_id = Uuid::from_u128(1);
+        let (mut tree, ops) =
+            WorkTree::new(replica_id, Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+
+        let create_ops = open_envelopes(tree.create_file("a", FileType::Text));
+        let a = tree.open_text_file("a").wait().unwrap();
+        let version_before_edit = tree.version();
+        let local_version_before_edit = tree.local_version(replica_id);
+
+        let ops = open_envelopes(tree.edit(a, Some(0..0), "abc"));
+        assert_eq!(tree.text_str(a), "abc");
+        assert!(tree.local_version(replica_id) > local_version_before_edit);
+        assert!(tree.observed(version_before_edit));
+        assert!(tree.observed(tree.version()));
+        assert_eq!(tree.local_version(Uuid::from_u128(2)), 0);
+        let local_version_after_edit = tree.local_version(replica_id);
+
+        // Operations delivered back to their author have no further effect.
+        tree.apply_ops(create_ops.into_iter().chain(ops))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree.text_str(a), "abc");
+        assert_eq!(tree.local_version(replica_id), local_version_after_edit);
+    }
+
+    #[test]
+    fn test_apply_git_resolution() {
+        let git = Rc::new(TestGitProvider::new());
+        let base_tree = WorkTree::empty();
+        base_tree.create_file("a", FileType::Text).unwrap();
+        base_tree.create_file("b", FileType::Text).unwrap();
+        let a_base = base_tree.open_text_file("a").wait().unwrap();
+        base_tree
+            .edit(a_base, Some(0..0), "one\ntwo\nthree\n")
+            .unwrap();
+        let commit = git.commit(&base_tree);
+
+        let (tree_1, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+        let (tree_2, ops) =
+            WorkTree::new(Uuid::from_u128(2), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+
+        let (set_id, _) = tree_1
+            .add_selection_set(a_1, Some(Point::new(2, 1)..Point::new(2, 3)))
+            .unwrap();
+        let resolution = "one\n2\nthree\n";
+        let ops = tree_1.apply_git_resolution("a", resolution).unwrap();
+        assert_eq!(ops.len(), 1);
+        assert_eq!(tree_1.text_str(a_1), resolution);
+        assert_eq!(
+            tree_1.selection_ranges(a_1).unwrap().local[&set_id],
+            vec![Point::new(2, 1)..Point::new(2, 3)]
+        );
+        assert!(tree_1
+            .apply_git_resolution("a", resolution)
+            .unwrap()
+            .is_empty());
+
+        tree_2.apply_ops(ops).unwrap().collect().wait().unwrap();
+        assert_eq!(tree_2.text_str(a_2), resolution);
+
+        assert_eq!(
+            tree_1.apply_git_resolution("b", "x").err(),
+            Some(Error::InvalidFileId("file has not been opened".into()))
+        );
+        assert!(tree_1.apply_git_resolution("c", "x").is_err());
+    }
+
+    #[test]
+    fn test_status_cursor() {
+        let git = Rc::new(TestGitProvider::new());
+        let base_tree = WorkTree::empty();
+        base_tree.create_file("dir", FileType::Directory).unwrap();
+        base_tree.create_file("dir/a", FileType::Text).unwrap();
+        base_tree.create_file("b", FileType::Text).unwrap();
+        base_tree.create_file("c", FileType::Text).unwrap();
+        let commit = git.commit(&base_tree);
+
+        let (tree, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+        let a = tree.open_text_file("dir/a").wait().unwrap();
+        tree.edit(a, Some(0..0), "abc").unwrap();
+        tree.remove("b").unwrap();
+        tree.create_file("d", FileType::Text).unwrap();
+
+        assert_eq!(
+            tree.status_cursor(commit, git.clone())
+                .wait()
+                .unwrap()
+                .collect()
+                .wait()
+                .unwrap(),
+            vec![
+                (PathBuf::from("b"), FileType::Text, FileStatus::Removed),
+                (PathBuf::from("c"), FileType::Text, FileStatus::Unchanged),
+                (PathBuf::from("d"), FileType::Text, FileStatus::New),
+                (
+                    PathBuf::from("dir"),
+                    FileType::Directory,
+                    FileStatus::Modified
+                ),
+                (PathBuf::from("dir/a"), FileType::Text, FileStatus::Modified),
+            ]
+        );
+
+        // Against another commit, entries are matched by path and texts are compared with it.
+        let commit_2 = git.commit(&tree);
+        tree.edit(a, Some(0..0), "x").unwrap();
+        tree.remove("c").unwrap();
+        tree.create_file("dir/e", FileType::Directory).unwrap();
+        assert_eq!(
+            tree.status_cursor(commit_2, git.clone())
+                .wait()
+                .unwrap()
+                .collect()
+                .wait()
+                .unwrap(),
+            vec![
+                (PathBuf::from("c"), FileType::Text, FileStatus::Removed),
+                (PathBuf::from("d"), FileType::Text, FileStatus::Unchanged),
+                (
+                    PathBuf::from("dir"),
+                    FileType::Directory,
+                    FileStatus::Modified
+                ),
+                (PathBuf::from("dir/a"), FileType::Text, FileStatus::Modified),
+                (PathBuf::from("dir/e"), FileType::Directory, FileStatus::New),
+            ]
+        );
+        assert!(tree.status_cursor([0xff; 20], git.clone()).wait().is_err());
+
+        // Texts are compared as the cursor reaches them, so it reflects edits made after it was
+        // created.
+        let commit_3 = git.commit(&tree);
+        let mut statuses = tree
+            .status_cursor(commit_3, git.clone())
+            .wait()
+            .unwrap()
+            .wait();
+        assert_eq!(
+            statuses.next().unwrap().unwrap(),
+            (PathBuf::from("d"), FileType::Text, FileStatus::Unchanged)
+        );
+        tree.edit(a, Some(0..0), "y").unwrap();
+        assert_eq!(
+            statuses.collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![
+                (
+                    PathBuf::from("dir"),
+                    FileType::Directory,
+                    FileStatus::Modified
+                ),
+                (PathBuf::from("dir/a"), FileType::Text, FileStatus::Modified),
+                (
+                    PathBuf::from("dir/e"),
+                    FileType::Directory,
+                    FileStatus::Unchanged
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ensure_trailing_newline() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (mut tree, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+
+        tree.create_file("a", FileType::Text).unwrap();
+        let a = tree.open_text_file("a").wait().unwrap();
+        tree.edit(a, Some(0..0), "abc").unwrap();
+        assert!(!tree.ends_with_newline(a).unwrap());
+        assert_eq!(tree.export(a).unwrap(), "abc");
+
+        tree.set_ensure_trailing_newline(true);
+        let version = tree.local_version(Uuid::from_u128(1));
+        assert_eq!(tree.export(a).unwrap(), "abc\n");
+        assert_eq!(tree.text_str(a), "abc");
+        assert_eq!(tree.local_version(Uuid::from_u128(1)), version);
+
+        tree.edit(a, Some(3..3), "\n\n").unwrap();
+        assert!(tree.ends_with_newline(a).unwrap());
+        assert_eq!(tree.export(a).unwrap(), "abc\n");
+        assert_eq!(tree.export(BufferId(42)), Err(Error::InvalidBufferId));
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (tree, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+        let empty_report = tree.memory_usage();
+        assert_eq!(empty_report.fragment_bytes, 0);
+
+        tree.create_file("a", FileType::Text).unwrap();
+        tree.create_file("b", FileType::Text).unwrap();
+        let a = tree.open_text_file("a").wait().unwrap();
+        let b = tree.open_text_file("b").wait().unwrap();
+        tree.edit(a, Some(0..0), "abcdef").unwrap();
+        tree.edit(b, Some(0..0), "123").unwrap();
+        tree.edit(b, Some(0..1), "").unwrap();
+
+        let report = tree.memory_usage();
+        assert!(report.fragment_bytes > 0);
+        assert!(report.tombstone_bytes > 0);
+        assert!(report.operation_log_bytes > empty_report.operation_log_bytes);
+        assert_eq!(report.version_vector_len, 1);
+
+        let a_report = tree.buffer_memory_usage(a).unwrap();
+        let b_report = tree.buffer_memory_usage(b).unwrap();
+        assert_eq!(a_report.tombstone_bytes, 0);
+        assert_eq!(b_report.tombstone_bytes, report.tombstone_bytes);
+        assert_eq!(a_report.version_vector_len, 1);
+        assert_eq!(
+            tree.buffer_memory_usage(BufferId(42)),
+            Err(Error::InvalidBufferId)
+        );
+    }
+
+    #[test]
+    fn test_operation_for_anchor() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (tree, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+
+        tree.create_file("a", FileType::Text).unwrap();
+        let a = tree.open_text_file("a").wait().unwrap();
+        tree.edit(a, Some(0..0), "abc").unwrap();
+        let (splice_op, range) = tree
+            .splice(a, Point::new(0, 1)..Point::new(0, 2), "xyz")
+            .unwrap();
+        assert_eq!(tree.text_str(a), "axyzc");
+
+        let envelope = tree.operation_for_anchor(a, &range.end).unwrap().unwrap();
+        assert_eq!(envelope.operation, splice_op.operation);
+        assert!(tree
+            .operation_for_anchor(a, &buffer::Anchor::Start)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            tree.operation_for_anchor(BufferId(42), &range.end).err(),
+            Some(Error::InvalidBufferId)
+        );
+    }
+
+    #[test]
+    fn test_normalize_selections() {
+        let mut selections = BufferSelectionRanges {
+            local: HashMap::new(),
+            remote: HashMap::new(),
+            presences: HashMap::new(),
+        };
+        selections.local.insert(
+            LocalSelectionSetId(0),
+            vec![
+                Point::new(1, 0)..Point::new(1, 2),
+                Point::new(0, 4)..Point::new(0, 1),
+                Point::new(0, 2)..Point::new(0, 6),
+                Point::new(1, 2)..Point::new(1, 3),
+                Point::new(2, 0)..Point::new(2, 0),
+            ],
+        );
+        selections.remote.insert(
+            Uuid::from_u128(2),
+            vec![vec![
+                Point::new(0, 3)..Point::new(0, 3),
+                Point::new(0, 3)..Point::new(0, 3),
+            ]],
+        );
+        selections.normalize();
+        assert_eq!(
+            selections.local[&LocalSelectionSetId(0)],
+            vec![
+                Point::new(0, 6)..Point::new(0, 1),
+                Point::new(1, 0)..Point::new(1, 3),
+                Point::new(2, 0)..Point::new(2, 0),
+            ]
+        );
+        assert_eq!(
+            selections.remote[&Uuid::from_u128(2)],
+            vec![vec![Point::new(0, 3)..Point::new(0, 3)]]
+        );
+
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, mut tree_2) = two_replicas(&git);
+
+        let create_op = tree_1.create_file("a", FileType::Text).unwrap();
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        let edit_op = tree_1.edit(a_1, Some(0..0), "abcdef").unwrap();
+        tree_2
+            .apply_ops(open_envelopes(vec![create_op, edit_op]))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+
+        let (set_id, _) = tree_1
+            .add_selection_set(
+                a_1,
+                vec![
+                    Point::new(0, 2)..Point::new(0, 2),
+                    Point::new(0, 4)..Point::new(0, 4),
+                ],
+            )
+            .unwrap();
+
+        // Deleting the text between two cursors on another replica collapses them into one.
+        let delete_op = tree_2.edit(a_2, Some(2..4), "").unwrap();
+        let fixup_ops = tree_1
+            .apply_ops(Some(delete_op.operation))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree_1.text_str(a_1), "abef");
+        assert_eq!(
+            tree_1.selection_ranges(a_1).unwrap().local[&set_id],
+            vec![Point::new(0, 2)..Point::new(0, 2)]
+        );
+        assert_eq!(fixup_ops.len(), 1);
+
+        tree_2
+            .apply_ops(open_envelopes(fixup_ops))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            tree_2.selection_ranges(a_2).unwrap().remote[&tree_1.replica_id()],
+            vec![vec![Point::new(0, 2)..Point::new(0, 2)]]
+        );
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn test_export_oplog_jsonl() {
+        let tree = WorkTree::empty();
+        tree.create_file("a", FileType::Text).unwrap();
+        let a = tree.open_text_file("a").wait().unwrap();
+        tree.edit(a, Some(0..0), "abc").unwrap();
+        tree.edit(a, Some(1..2), "").unwrap();
+
+        let mut log = Vec::new();
+        tree.export_oplog_jsonl(&mut log).unwrap();
+        let entries = String::from_utf8(log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry["kind"].as_str().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["insert_metadata", "edit", "edit"]
+        );
+        assert_eq!(entries[0]["text"], "a");
+        assert_eq!(entries[1]["text"], "abc");
+        assert_eq!(entries[2]["range"][0]["offset"], 1);
+        assert_eq!(entries[2]["range"][1]["offset"], 2);
+        assert!(entries[2].get("text").is_none());
+        assert_eq!(
+            entries[1]["replica_id"],
+            tree.replica_id().to_hyphenated().to_string()
+        );
+    }
+
+    #[test]
+    fn test_apply_ops_streaming() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (mut tree_1, mut tree_2) = two_replicas_at(&git, commit);
+
+        let create_op = tree_1.create_file("a", FileType::Text).unwrap();
+        tree_2
+            .apply_ops(Some(create_op.operation))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+
+        let mut ops = Vec::new();
+        for i in 0..100 {
+            let len = tree_1.text_str(a_1).encode_utf16().count();
+            let position = (i * 7) % (len + 1);
+            ops.push(
+                tree_1
+                    .edit(a_1, Some(position..position), format!("{},", i))
+                    .unwrap(),
+            );
+        }
+
+        let mut mirror = buffer::Buffer::new("");
+        let mut local_clock = time::Local::new(Uuid::from_u128(3));
+        let mut lamport_clock = time::Lamport::new(Uuid::from_u128(3));
+        let mut lens = Vec::new();
+        tree_2
+            .apply_ops_streaming(open_envelopes(ops), |buffer_id, change| {
+                assert_eq!(buffer_id, a_2);
+                mirror.edit_2d(
+                    Some(change.range),
+                    change.code_units,
+                    &mut local_clock,
+                    &mut lamport_clock,
+                );
+                lens.push(mirror.len());
+            })
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+
+        // The mirror was updated before all of the operations were applied.
+        assert!(lens.len() > 1);
+        assert!(lens[0] < mirror.len());
+        assert_eq!(mirror.to_string(), tree_1.text_str(a_1));
+        assert_eq!(tree_2.text_str(a_2), tree_1.text_str(a_1));
+
+        // A failing chunk doesn't discard the outcome of the chunks applied before it.
+        let mut ops = Vec::new();
+        for i in 0..STREAMING_APPLY_CHUNK_SIZE {
+            ops.push(tree_1.edit(a_1, Some(0..0), format!("{},", i)).unwrap());
+        }
+        let text_1 = tree_1.text_str(a_1);
+        tree_1.reset(Some(commit)).collect().wait().unwrap();
+        ops.push(tree_1.create_file("b", FileType::Text).unwrap());
+        ops.push(tree_1.create_file("c", FileType::Text).unwrap());
+
+        tree_2.set_deferred_ops_limit(1, OverflowPolicy::Error);
+        let mut change_count = 0;
+        let ops_2 = tree_2
+            .apply_ops_streaming(open_envelopes(ops), |_, _| change_count += 1)
+            .unwrap();
+        assert_eq!(ops_2.collect().wait().err(), Some(Error::QueueFull));
+        assert!(change_count > 0);
+        assert_eq!(tree_2.text_str(a_2), text_1);
+        assert_eq!(tree_2.deferred_operation_count(), 0);
+    }
+
+    #[test]
+    fn test_dedup_window() {
+        let git = Rc::new(TestGitProvider::new());
+        let (tree_1, mut tree_2) = two_replicas(&git);
+
+        let create_op = tree_1.create_file("a", FileType::Text).unwrap();
+        assert!(!tree_2.already_applied(&create_op));
+        tree_2
+            .apply_ops(Some(create_op.operation.clone()))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert!(tree_2.already_applied(&create_op));
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+
+        // Redelivered operations are skipped, both within a batch and across batches.
+        let edit_op = tree_1.edit(a_1, Some(0..0), "abc").unwrap();
+        tree_2
+            .apply_ops(vec![edit_op.operation.clone(), edit_op.operation.clone()])
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert!(tree_2.already_applied(&edit_op));
+        tree_2
+            .apply_ops(Some(edit_op.operation.clone()))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree_2.text_str(a_2), "abc");
+
+        // Operations for a future epoch are only deferred, so they don't count as applied.
+        let future_op = Operation::EpochOperation {
+            epoch_id: time::Lamport {
+                value: tree_2.epoch_id().value + 10,
+                replica_id: Uuid::from_u128(1),
+            },
+            operation: match edit_op.operation.clone() {
+                Operation::EpochOperation { operation, .. } => operation,
+                _ => unreachable!(),
+            },
+        };
+        tree_2
+            .apply_ops(Some(future_op.clone()))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert!(!tree_2.already_applied(&OperationEnvelope {
+            epoch_head: None,
+            operation: future_op,
+            route: Vec::new(),
+        }));
+
+        // Only the most recent operations are remembered.
+        let edit_op_2 = tree_1.edit(a_1, Some(3..3), "d").unwrap();
+        tree_2.set_dedup_window(1);
+        assert!(!tree_2.already_applied(&create_op));
+        assert!(tree_2.already_applied(&edit_op));
+        tree_2
+            .apply_ops(Some(edit_op_2.operation.clone()))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert!(!tree_2.already_applied(&edit_op));
+        assert!(tree_2.already_applied(&edit_op_2));
+        assert_eq!(tree_2.text_str(a_2), "abcd");
+
+        tree_2.set_dedup_window(0);
+        assert!(!tree_2.already_applied(&edit_op_2));
+    }
+
+    #[test]
+    fn test_apply_ops_fair() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (mut tree_1, ops_1) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        let init_ops = open_envelopes(ops_1.collect().wait().unwrap());
+        let (mut tree_2, ops_2) = WorkTree::new(
+            Uuid::from_u128(2),
+            Some(commit),
+            init_ops.clone(),
+            git.clone(),
+            None,
+        )
+        .unwrap();
+        ops_2.collect().wait().unwrap();
+        let (mut tree_3, ops_3) = WorkTree::new(
+            Uuid::from_u128(3),
+            Some(commit),
+            init_ops,
+            git.clone(),
+            None,
+        )
+        .unwrap();
+        ops_3.collect().wait().unwrap();
+
+        let create_op = tree_1.create_file("a", FileType::Text).unwrap();
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        let edit_op = tree_1.edit(a_1, Some(0..0), "abc").unwrap();
+        for tree in &mut [&mut tree_2, &mut tree_3] {
+            tree.apply_ops(vec![create_op.operation.clone(), edit_op.operation.clone()])
+                .unwrap()
+                .collect()
+                .wait()
+                .unwrap();
+        }
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        let a_3 = tree_3.open_text_file("a").wait().unwrap();
+
+        // Replica 1 has a large backlog, while replica 2 only made a single edit.
+        let mut backlog = Vec::new();
+        for i in 0..200 {
+            let len = tree_1.text_str(a_1).encode_utf16().count();
+            backlog.push(tree_1.edit(a_1, Some(len..len), format!("{},", i)).unwrap());
+        }
+        let backlog = open_envelopes(backlog);
+        let edit_2 = open_envelopes(Some(tree_2.edit(a_2, Some(0..0), "!").unwrap()));
+        let mut ops_by_replica = HashMap::new();
+        ops_by_replica.insert(tree_1.replica_id(), backlog.clone());
+        ops_by_replica.insert(tree_2.replica_id(), edit_2.clone());
+
+        let (changes, fixup_ops) = tree_3.apply_ops_fair(ops_by_replica).unwrap();
+        fixup_ops.collect().wait().unwrap();
+        assert!(changes.len() > 2);
+        assert!(changes.iter().all(|(buffer_id, _)| *buffer_id == a_3));
+        // Replica 2's edit was applied along with the first chunk of replica 1's backlog.
+        assert!(changes[..2]
+            .iter()
+            .any(|(_, change)| change.range.start == Point::new(0, 0)));
+
+        tree_1.apply_ops(edit_2).unwrap().collect().wait().unwrap();
+        tree_2.apply_ops(backlog).unwrap().collect().wait().unwrap();
+        assert_eq!(tree_3.text_str(a_3), tree_1.text_str(a_1));
+        assert_eq!(tree_3.text_str(a_3), tree_2.text_str(a_2));
+        assert!(tree_3.text_str(a_3).starts_with("!abc0,1,"));
+    }
+
+    #[test]
+    fn test_is_ignored() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (tree, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+        for (path, file_type) in &[
+            ("x.log", FileType::Text),
+            ("target", FileType::Directory),
+            ("target/t", FileType::Text),
+            ("a", FileType::Directory),
+            ("a/keep.log", FileType::Text),
+            ("a/other.log", FileType::Text),
+            ("a/b", FileType::Text),
+            ("c", FileType::Directory),
+            ("c/b", FileType::Text),
+        ] {
+            tree.create_file(path, *file_type).unwrap();
+        }
+
+        git.set_gitignore("", vec!["*.log", "target"]);
+        git.set_gitignore("a", vec!["!keep.log", "b"]);
+        assert_eq!(tree.is_ignored("x.log"), Ok(true));
+        assert_eq!(tree.is_ignored("target"), Ok(true));
+        assert_eq!(tree.is_ignored("target/t"), Ok(true));
+        assert_eq!(tree.is_ignored("a"), Ok(false));
+        assert_eq!(tree.is_ignored("a/keep.log"), Ok(false));
+        assert_eq!(tree.is_ignored("a/other.log"), Ok(true));
+        assert_eq!(tree.is_ignored("a/b"), Ok(true));
+        assert_eq!(tree.is_ignored("c"), Ok(false));
+        assert_eq!(tree.is_ignored("c/b"), Ok(false));
+        assert!(tree.is_ignored("d").is_err());
+        assert_eq!(*git.ignore_queries.borrow(), 9);
+
+        // Repeated queries are answered from the cache.
+        assert_eq!(tree.is_ignored("a/other.log"), Ok(true));
+        assert_eq!(tree.is_ignored("c/b"), Ok(false));
+        assert_eq!(*git.ignore_queries.borrow(), 9);
+    }
+
+    #[test]
+    fn test_duplicate_paths() {
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, mut tree_2) = two_replicas(&git);
+
+        // Both replicas create a file at the same path concurrently.
+        let a_1 = tree_1
+            .create_file("a", FileType::Text)
+            .unwrap()
+            .file_id()
+            .unwrap();
+        let a_2 = tree_2
+            .create_file("a", FileType::Text)
+            .unwrap()
+            .file_id()
+            .unwrap();
+        assert!(tree_2.duplicate_paths().is_empty());
+
+        // Receiving the other replica's file renames it, which exposes the duplicate.
+        tree_2
+            .apply_ops(open_envelopes(tree_1.operations_from(tree_1.replica_id())))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree_2.paths_for_file_id(a_1), vec![PathBuf::from("a~")]);
+        let mut file_ids = vec![a_1, a_2];
+        file_ids.sort();
+        assert_eq!(
+            tree_2.duplicate_paths(),
+            vec![(PathBuf::from("a"), file_ids.clone())]
+        );
+
+        // The other replica only receives the rename, but reports the same duplicate.
+        tree_1
+            .apply_ops(open_envelopes(tree_2.operations_from(tree_2.replica_id())))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree_1.paths_for_file_id(a_1), vec![PathBuf::from("a~")]);
+        assert_eq!(
+            tree_1.duplicate_paths(),
+            vec![(PathBuf::from("a"), file_ids)]
+        );
+
+        assert_eq!(
+            tree_2.resolve_duplicate("b", a_1).err(),
+            Some(Error::InvalidPath("path has no duplicates".into()))
+        );
+        assert_eq!(
+            tree_2.resolve_duplicate("a", epoch::ROOT_FILE_ID).err(),
+            Some(Error::InvalidFileId("file is not a duplicate".into()))
+        );
+        let ops = tree_2.resolve_duplicate("a", a_1).unwrap();
+        tree_1
+            .apply_ops(open_envelopes(ops))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        for tree in &[&tree_1, &tree_2] {
+            assert!(tree.duplicate_paths().is_empty());
+            assert_eq!(tree.paths_for_file_id(a_1), vec![PathBuf::from("a")]);
+            assert!(tree.paths_for_file_id(a_2).is_empty());
+        }
+
+        // Deliberately moving a file aside and creating another at its path isn't a duplicate.
+        let ops = vec![
+            tree_1.rename("a", "a~").unwrap(),
+            tree_1.create_file("a", FileType::Text).unwrap(),
+        ];
+        tree_2
+            .apply_ops(open_envelopes(ops))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        for tree in &[&tree_1, &tree_2] {
+            assert!(tree.duplicate_paths().is_empty());
+            assert_eq!(tree.paths_for_file_id(a_1), vec![PathBuf::from("a~")]);
+        }
+    }
+
+    #[test]
+    fn test_reorder() {
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, mut tree_2) = two_replicas(&git);
+
+        let mut ops = vec![tree_1.create_file("d", FileType::Directory).unwrap()];
+        for name in &["a", "b", "c", "e"] {
+            ops.push(
+                tree_1
+                    .create_file(format!("d/{}", name), FileType::Text)
+                    .unwrap(),
+            );
+        }
+        tree_2
+            .apply_ops(deserialize_ops(serialize_ops(open_envelopes(ops))))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(list_dir_names(&tree_1, "d"), ["a", "b", "c", "e"]);
+        assert!(tree_1
+            .list_dir("d")
+            .unwrap()
+            .iter()
+            .all(|(_, key)| key.is_none()));
+
+        let ops = vec![
+            tree_1.reorder("d/c", None).unwrap(),
+            tree_1.reorder("d/a", None).unwrap(),
+        ];
+        assert_eq!(list_dir_names(&tree_1, "d"), ["c", "a", "b", "e"]);
+        tree_2
+            .apply_ops(deserialize_ops(serialize_ops(open_envelopes(ops))))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(list_dir_names(&tree_2, "d"), ["c", "a", "b", "e"]);
+
+        // Concurrent moves into the same gap converge.
+        let op_1 = tree_1.reorder("d/a", Some(Path::new("d/c"))).unwrap();
+        let op_2 = tree_2.reorder("d/b", Some(Path::new("d/c"))).unwrap();
+        tree_1
+            .apply_ops(deserialize_ops(serialize_ops(open_envelopes(Some(op_2)))))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        tree_2
+            .apply_ops(deserialize_ops(serialize_ops(open_envelopes(Some(op_1)))))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(list_dir_names(&tree_1, "d"), ["a", "b", "c", "e"]);
+        assert_eq!(list_dir_names(&tree_2, "d"), ["a", "b", "c", "e"]);
+        assert_eq!(tree_1.list_dir("d").unwrap(), tree_2.list_dir("d").unwrap());
+
+        assert_eq!(
+            tree_1.reorder("d/a", Some(Path::new("d/e"))).err(),
+            Some(Error::InvalidFileId("not an ordered sibling".into()))
+        );
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, tree_2) = two_replicas(&git);
+
+        let create_op = tree_2.create_file("a", FileType::Text).unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        let mut ops = Vec::new();
+        for _ in 0..20 {
+            ops.push(tree_2.edit(a_2, Some(0..0), "x").unwrap().operation);
+        }
+
+        let sender = tree_2.replica_id();
+        tree_1.set_rate_limit(sender, 10);
+        let start = Instant::now();
+        tree_1
+            .apply_ops_from_at(sender, Some(create_op.operation), start)
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+
+        // Flooding the tree only lets through the allowance that is left.
+        let mut rejected = Vec::new();
+        for op in ops {
+            match tree_1.apply_ops_from_at(sender, Some(op.clone()), start) {
+                Ok(stream) => {
+                    stream.collect().wait().unwrap();
+                }
+                Err(error) => {
+                    assert_eq!(error, Error::RateLimited(sender));
+                    rejected.push(op);
+                }
+            }
+        }
+        assert_eq!(rejected.len(), 11);
+        assert_eq!(tree_1.text_str(a_1), "x".repeat(9));
+
+        // A batch larger than the remaining allowance is rejected without being applied.
+        assert_eq!(
+            tree_1
+                .apply_ops_from_at(sender, rejected.clone(), start + Duration::from_millis(500))
+                .err(),
+            Some(Error::RateLimited(sender))
+        );
+        assert_eq!(tree_1.text_str(a_1), "x".repeat(9));
+
+        // The allowance replenishes over time, up to one second's worth of operations.
+        let last_op = rejected.pop().unwrap();
+        tree_1
+            .apply_ops_from_at(sender, rejected, start + Duration::from_secs(2))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree_1.text_str(a_1), "x".repeat(19));
+        assert!(tree_1
+            .apply_ops_from_at(
+                sender,
+                Some(last_op.clone()),
+                start + Duration::from_secs(2)
+            )
+            .is_err());
+        tree_1
+            .apply_ops_from_at(sender, Some(last_op), start + Duration::from_secs(3))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree_1.text_str(a_1), "x".repeat(20));
+    }
+
+    #[test]
+    fn test_maybe_compact() {
+        let mut tree = WorkTree::empty();
+        tree.create_file("a", FileType::Text).unwrap();
+        let a = tree.open_text_file("a").wait().unwrap();
+        tree.edit(a, Some(0..0), "abc").unwrap();
+        tree.edit(a, Some(1..2), "").unwrap();
+
+        let start = Instant::now();
+        assert!(!tree.maybe_compact(start));
+        tree.set_idle_compaction(Some(Duration::from_secs(10)));
+        assert!(!tree.maybe_compact(start));
+        assert!(!tree.maybe_compact(start + Duration::from_secs(5)));
+        assert!(tree.maybe_compact(start + Duration::from_secs(10)));
+        assert_eq!(tree.text_str(a), "ac");
+
+        // Nothing has changed since the last compaction.
+        assert!(!tree.maybe_compact(start + Duration::from_secs(30)));
+
+        tree.edit(a, Some(0..1), "").unwrap();
+        assert!(!tree.maybe_compact(start + Duration::from_secs(31)));
+        assert!(!tree.maybe_compact(start + Duration::from_secs(35)));
+        assert!(tree.maybe_compact(start + Duration::from_secs(41)));
+        assert_eq!(tree.text_str(a), "c");
+    }
+
+    #[test]
+    fn test_state_eq() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (tree_1, ops_1) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        let ops_1 = open_envelopes(ops_1.collect().wait().unwrap());
+        let (mut tree_2, ops_2) = WorkTree::new(
+            Uuid::from_u128(2),
+            Some(commit),
+            ops_1.clone(),
+            git.clone(),
+            None,
+        )
+        .unwrap();
+        ops_2.collect().wait().unwrap();
+        assert!(tree_1.state_eq(&tree_2));
+
+        let create_ops = vec![
+            tree_1.create_file("dir", FileType::Directory).unwrap(),
+            tree_1.create_file("dir/a", FileType::Text).unwrap(),
+        ];
+        let a_1 = tree_1.open_text_file("dir/a").wait().unwrap();
+        tree_1.edit(a_1, Some(0..0), "abc").unwrap();
+        tree_1.edit(a_1, Some(1..2), "").unwrap();
+        assert!(!tree_1.state_eq(&tree_2));
+
+        // The same state is reached with a different history.
+        tree_2
+            .apply_ops(open_envelopes(create_ops))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        let a_2 = tree_2.open_text_file("dir/a").wait().unwrap();
+        tree_2.edit(a_2, Some(0..0), "ac").unwrap();
+        assert!(tree_1.state_eq(&tree_2));
+        assert!(tree_2.state_eq(&tree_1));
+
+        tree_2.edit(a_2, Some(2..2), "d").unwrap();
+        assert!(!tree_1.state_eq(&tree_2));
+        tree_2.edit(a_2, Some(2..3), "").unwrap();
+        assert!(tree_1.state_eq(&tree_2));
+
+        tree_2.rename("dir/a", "dir/b").unwrap();
+        assert!(!tree_1.state_eq(&tree_2));
+        tree_2.rename("dir/b", "dir/a").unwrap();
+        assert!(tree_1.state_eq(&tree_2));
+
+        let (tree_3, ops_3) = WorkTree::new(
+            Uuid::from_u128(3),
+            Some(commit),
+            ops_1.clone(),
+            git.clone(),
+            None,
+        )
+        .unwrap();
+        ops_3.collect().wait().unwrap();
+        assert!(!tree_1.state_eq(&tree_3));
+
+        // Files that aren't open compare by the operations deferred for them.
+        let mut ops = ops_1;
+        ops.extend(open_envelopes(tree_1.operations_from(tree_1.replica_id())));
+        let mut ops_without_last_edit = ops.clone();
+        ops_without_last_edit.pop();
+        let new_tree = |replica_id, ops| {
+            let (tree, tree_ops) = WorkTree::new(
+                Uuid::from_u128(replica_id),
+                Some(commit),
+                ops,
+                git.clone(),
+                None,
+            )
+            .unwrap();
+            tree_ops.collect().wait().unwrap();
+            tree
+        };
+        let tree_4 = new_tree(4, ops.clone());
+        let tree_5 = new_tree(5, ops);
+        let tree_6 = new_tree(6, ops_without_last_edit);
+        assert!(tree_4.state_eq(&tree_5));
+        assert!(!tree_4.state_eq(&tree_6));
+    }
+
+    #[test]
+    fn test_operations_in_range() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (tree, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+
+        tree.create_file("a", FileType::Text).unwrap();
+        let a = tree.open_text_file("a").wait().unwrap();
+        tree.edit(a, Some(0..0), "abc").unwrap();
+        let (splice_op, range) = tree
+            .splice(a, Point::new(0, 1)..Point::new(0, 1), "xyz")
+            .unwrap();
+        let delete_op = tree.edit(a, Some(2..3), "").unwrap();
+        tree.edit(a, Some(0..0), "Q").unwrap();
+        tree.edit(a, Some(5..6), "").unwrap();
+        assert_eq!(tree.text_str(a), "Qaxzb");
+
+        // Only the insertion of the range and the deletion inside it are returned.
+        let operations = tree
+            .operations_in_range(a, range)
+            .unwrap()
+            .into_iter()
+            .map(|envelope| envelope.operation)
+            .collect::<Vec<_>>();
+        assert_eq!(operations, vec![splice_op.operation, delete_op.operation]);
+
+        let all = tree
+            .operations_in_range(a, buffer::Anchor::Start..buffer::Anchor::End)
+            .unwrap();
+        assert_eq!(all.len(), 5);
+        assert_eq!(
+            tree.operations_in_range(BufferId(42), buffer::Anchor::Start..buffer::Anchor::End)
+                .err(),
+            Some(Error::InvalidBufferId)
+        );
+    }
+
+    #[test]
+    fn test_open_buffer_at() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (mut tree, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+
+        let file_id = tree
+            .create_file("a", FileType::Text)
+            .unwrap()
+            .file_id()
+            .unwrap();
+        let a = tree.open_text_file("a").wait().unwrap();
+        tree.edit(a, Some(0..0), "abc").unwrap();
+        let (_, range) = tree
+            .splice(a, Point::new(0, 1)..Point::new(0, 2), "XY")
+            .unwrap();
+        let version = tree.buffer_version(a).unwrap();
+        tree.edit(a, Some(0..2), "").unwrap();
+        tree.edit(a, Some(1..1), "Z").unwrap();
+        assert_eq!(tree.text_str(a), "YZc");
+
+        let snapshot = tree.open_buffer_at(file_id, &version).unwrap();
+        assert_eq!(tree.text_str(snapshot), "aXYc");
+        assert_eq!(tree.buffer_version(snapshot).unwrap(), version);
+        assert_eq!(
+            tree.point_for_anchor(snapshot, &range.start).unwrap(),
+            Point::new(0, 1)
+        );
+        assert_eq!(
+            tree.point_for_anchor(a, &range.start).unwrap(),
+            Point::new(0, 0)
+        );
+
+        // The snapshot can't be edited, and the live buffer is unaffected by it.
+        assert_eq!(
+            tree.edit(snapshot, Some(0..0), "x").err(),
+            Some(Error::ReadOnlyBuffer(snapshot))
+        );
+        assert_eq!(tree.text_str(a), "YZc");
+
+        // Read-only accessors see the snapshot's state.
+        assert_eq!(tree.export(snapshot).unwrap(), "aXYc");
+        assert_eq!(tree.ends_with_newline(snapshot), Ok(false));
+        assert!(
+            tree.changes_since(snapshot, &time::Global::new())
+                .unwrap()
+                .count()
+                > 0
+        );
+        assert!(tree.selection_ranges(snapshot).unwrap().local.is_empty());
+        assert!(tree.buffer_memory_usage(snapshot).is_ok());
+
+        tree.close_snapshot(snapshot).unwrap();
+        assert!(tree.text(snapshot).is_err());
+        assert_eq!(tree.close_snapshot(snapshot), Err(Error::InvalidBufferId));
+
+        // Snapshots don't survive a change of epoch.
+        let snapshot = tree.open_buffer_at(file_id, &version).unwrap();
+        tree.reset(Some(commit)).collect().wait().unwrap();
+        assert!(tree.text(snapshot).is_err());
+        assert_eq!(tree.close_snapshot(snapshot), Err(Error::InvalidBufferId));
+    }
+
+    #[test]
+    fn test_undo_grouping() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (mut tree, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+        tree.create_file("a", FileType::Text).unwrap();
+        let a = tree.open_text_file("a").wait().unwrap();
+        tree.set_undo_grouping(GroupingPolicy {
+            max_pause: Duration::from_secs(60),
+            break_on_newline: true,
+        });
+
+        // Typing and deleting backwards form one group, and so does the line started by a newline.
+        tree.edit(a, Some(0..0), "a").unwrap();
+        tree.edit(a, Some(1..1), "b").unwrap();
+        tree.edit(a, Some(2..2), "c").unwrap();
+        tree.edit(a, Some(2..3), "").unwrap();
+        tree.edit(a, Some(2..2), "\n").unwrap();
+        tree.edit(a, Some(3..3), "d").unwrap();
+
+        // Edits elsewhere, or spanning multiple ranges, start new groups.
+        tree.edit(a, Some(0..0), "x").unwrap();
+        tree.edit(a, vec![0..0, 2..2], "y").unwrap();
+        assert_eq!(tree.text_str(a), "yxayb\nd");
+
+        // An edit made after a pause starts a new group.
+        tree.set_undo_grouping(GroupingPolicy {
+            max_pause: Duration::from_millis(1),
+            break_on_newline: true,
+        });
+        tree.edit(a, Some(7..7), "e").unwrap();
+        thread::sleep(Duration::from_millis(10));
+        tree.edit(a, Some(8..8), "f").unwrap();
+
+        let group_lens = tree
+            .undo_groups(a)
+            .unwrap()
+            .iter()
+            .map(|group| group.len())
+            .collect::<Vec<_>>();
+        assert_eq!(group_lens, vec![4, 2, 1, 1, 1, 1]);
+        assert_eq!(tree.undo_groups(BufferId(42)), Err(Error::InvalidBufferId));
+
+        // Edits made through the other local paths, including rollbacks, form groups of their own.
+        tree.edit_2d(a, Some(Point::new(0, 0)..Point::new(0, 0)), "z")
+            .unwrap();
+        let envelope = tree.set_text(a, "abc").unwrap();
+        tree.rollback_pending(&envelope).unwrap();
+        assert_eq!(tree.text_str(a), "zyxayb\ndef");
+        let groups = tree.undo_groups(a).unwrap();
+        assert_eq!(groups.len(), 9);
+        assert_eq!(groups[6].len(), 1);
+        assert!(groups.iter().all(|group| !group.is_empty()));
+
+        // A new epoch starts without any groups.
+        let commit_1 = git.commit(&tree);
+        tree.reset(Some(commit_1)).collect().wait().unwrap();
+        assert_eq!(tree.undo_groups(a), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_operations_grouped() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (mut tree_1, ops_1) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        let mut ops = open_envelopes(ops_1.collect().wait().unwrap());
+        ops.push(tree_1.create_file("a", FileType::Text).unwrap().operation);
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        tree_1.set_undo_grouping(GroupingPolicy {
+            max_pause: Duration::from_secs(60),
+            break_on_newline: true,
+        });
+
+        tree_1.edit(a_1, Some(0..0), "a").unwrap();
+        tree_1.edit(a_1, Some(1..1), "b").unwrap();
+        let version = tree_1.version().epoch_version;
+        tree_1.edit(a_1, Some(2..2), "c").unwrap();
+        tree_1.edit(a_1, Some(3..3), "\n").unwrap();
+        tree_1.edit(a_1, Some(4..4), "d").unwrap();
+
+        let all_groups = tree_1.operations_grouped(&time::Global::new());
+        assert_eq!(
+            all_groups
+                .iter()
+                .map(|(_, envelopes)| envelopes.len())
+                .collect::<Vec<_>>(),
+            vec![3, 2]
+        );
+        assert!(all_groups[0].0 < all_groups[1].0);
+
+        // The first group is returned in full because one of its edits is new.
+        let new_groups = tree_1.operations_grouped(&version);
+        assert_eq!(new_groups.len(), 2);
+        assert_eq!(new_groups[0].0, all_groups[0].0);
+        assert_eq!(new_groups[0].1.len(), 3);
+        assert!(tree_1
+            .operations_grouped(&tree_1.version().epoch_version)
+            .is_empty());
+
+        // Replaying the groups on another replica reproduces the text.
+        for (_, envelopes) in all_groups {
+            ops.extend(open_envelopes(envelopes));
+        }
+        let (tree_2, ops_2) =
+            WorkTree::new(Uuid::from_u128(2), Some(commit), ops, git.clone(), None).unwrap();
+        ops_2.collect().wait().unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        assert_eq!(tree_2.text_str(a_2), "abc\nd");
+    }
+
+    #[test]
+    fn test_hunks() {
+        let mut base_tree = WorkTree::empty();
+        base_tree.create_file("a", FileType::Text).unwrap();
+        let a_base = base_tree.open_text_file("a").wait().unwrap();
+        base_tree
+            .edit(a_base, Some(0..0), "one\ntwo\nthree\nfour\n")
+            .unwrap();
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&base_tree);
+
+        let (tree, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+        let a = tree.open_text_file("a").wait().unwrap();
+        tree.edit_2d(a, Some(Point::new(0, 3)..Point::new(0, 3)), "!")
+            .unwrap();
+        tree.edit_2d(a, Some(Point::new(2, 0)..Point::new(3, 0)), "")
+            .unwrap();
+        tree.edit_2d(a, Some(Point::new(2, 0)..Point::new(2, 1)), "F")
+            .unwrap();
+        assert_eq!(tree.text_str(a), "one!\ntwo\nFour\n");
+
+        let hunk_1 = Point::new(0, 3)..Point::new(0, 4);
+        let hunk_2 = Point::new(2, 0)..Point::new(2, 1);
+        let next_hunk = |from| tree.next_hunk(a, from, commit, git.as_ref()).wait();
+        let prev_hunk = |from| tree.prev_hunk(a, from, commit, git.as_ref()).wait();
+        assert_eq!(next_hunk(Point::zero()), Ok(Some(hunk_1.clone())));
+        assert_eq!(next_hunk(hunk_1.start), Ok(Some(hunk_2.clone())));
+        assert_eq!(next_hunk(Point::new(0, 4)), Ok(Some(hunk_2.clone())));
+        assert_eq!(next_hunk(Point::new(1, 2)), Ok(Some(hunk_2.clone())));
+        assert_eq!(next_hunk(hunk_2.start), Ok(None));
+        assert_eq!(prev_hunk(Point::new(3, 0)), Ok(Some(hunk_2.clone())));
+        assert_eq!(prev_hunk(Point::new(2, 0)), Ok(Some(hunk_1.clone())));
+        assert_eq!(prev_hunk(Point::new(0, 3)), Ok(None));
+
+        // Hunks against other bases are computed from the base text, and recomputed as the buffer
+        // changes.
+        base_tree
+            .edit_2d(a_base, Some(Point::new(0, 3)..Point::new(0, 3)), "!")
+            .unwrap();
+        let other_commit = git.commit(&base_tree);
+        let next_hunk = |from| {
+            tree.next_hunk(a, from, other_commit, git.as_ref())
+                .wait()
+                .unwrap()
+        };
+        let prev_hunk = |from| {
+            tree.prev_hunk(a, from, other_commit, git.as_ref())
+                .wait()
+                .unwrap()
+        };
+        assert_eq!(next_hunk(Point::zero()).unwrap().start, Point::new(2, 0));
+        assert_eq!(prev_hunk(Point::new(2, 0)), None);
+        tree.edit_2d(a, Some(Point::new(0, 0)..Point::new(0, 0)), "X")
+            .unwrap();
+        assert_eq!(
+            prev_hunk(Point::new(2, 0)),
+            Some(Point::new(0, 0)..Point::new(0, 1))
+        );
+    }
+
+    #[test]
+    fn test_envelope_size() {
+        let tree = WorkTree::empty();
+        let envelope = tree.create_file("a", FileType::Text).unwrap();
+        assert_eq!(
+            envelope.serialized_len(),
+            envelope.operation.serialize().len()
+        );
+        assert_eq!(envelope.change_count(), 1);
+
+        let a = tree.open_text_file("a").wait().unwrap();
+        let envelope = tree.edit(a, Some(0..0), "abc").unwrap();
+        assert_eq!(envelope.change_count(), 1);
+        assert_eq!(
+            envelope.serialized_len(),
+            envelope.operation.serialize().len()
+        );
+        let larger_envelope = tree.edit(a, Some(0..0), "x".repeat(1000)).unwrap();
+        assert!(larger_envelope.serialized_len() > envelope.serialized_len() + 1000);
+    }
+
+    #[test]
+    fn test_envelope_route() {
+        let git = Rc::new(TestGitProvider::new());
+        let (tree_1, mut tree_2) = two_replicas(&git);
+
+        let mut envelope = tree_1.create_file("a", FileType::Text).unwrap();
+        envelope.route = b"session-1".to_vec();
+        let bytes = envelope.serialize().unwrap();
+        assert_eq!(OperationEnvelope::route_of(&bytes), Ok(&b"session-1"[..]));
+        let received = OperationEnvelope::deserialize(&bytes).unwrap().unwrap();
+        assert_eq!(received.route, b"session-1".to_vec());
+        assert_eq!(received.epoch_head, envelope.epoch_head);
+        assert_eq!(received.operation, envelope.operation);
+
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        let mut edit = tree_1.edit(a_1, Some(0..0), "abc").unwrap();
+        edit.epoch_head = Some([7; 20]);
+        edit.route = vec![0; MAX_ROUTE_LEN];
+        let received_edit = OperationEnvelope::deserialize(&edit.serialize().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(received_edit.epoch_head, Some([7; 20]));
+        assert_eq!(received_edit.route.len(), MAX_ROUTE_LEN);
+
+        // The route has no effect on how the operations are applied.
+        tree_2
+            .apply_ops(vec![received.operation, received_edit.operation])
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        assert_eq!(tree_2.text_str(a_2), tree_1.text_str(a_1));
+
+        edit.route.push(0);
+        assert_eq!(
+            edit.serialize().err(),
+            Some(Error::RouteTooLong(MAX_ROUTE_LEN + 1))
+        );
+        assert_eq!(
+            OperationEnvelope::route_of(&[3, 1]),
+            Err(Error::DeserializeError("route"))
+        );
+        assert_eq!(
+            OperationEnvelope::deserialize(&[0, 2]).err(),
+            Some(Error::DeserializeError("epoch_head"))
+        );
+    }
+
+    #[test]
+    fn test_serialization_version() {
+        let tree = WorkTree::empty();
+        let ops = open_envelopes(tree.create_file("a", FileType::Text));
+        let mut bytes = ops[0].serialize();
+        assert_eq!(Operation::deserialize(&bytes), Ok(Some(ops[0].clone())));
+
+        bytes[0..4].copy_from_slice(&(SERIALIZATION_VERSION - 1).to_le_bytes());
+        assert_eq!(
+            Operation::deserialize(&bytes),
+            Err(Error::UnsupportedVersion(SERIALIZATION_VERSION - 1))
+        );
+        assert_eq!(
+            Operation::deserialize(&bytes[0..2]),
+            Err(Error::DeserializeError("version"))
+        );
+    }
+
+    #[test]
+    fn test_deserialize_malformed_operation() {
+        use crate::serialization::{epoch as epoch_fb, worktree as worktree_fb};
+
+        let tree = WorkTree::empty();
+        let ops = open_envelopes(tree.create_file("a", FileType::Text));
+        let bytes = ops[0].serialize();
+        for len in 4..8 {
+            assert_eq!(
+                Operation::deserialize(&bytes[0..len]),
+                Err(Error::DeserializeError("root"))
+            );
+        }
+        let mut bytes = bytes.clone();
+        bytes[4..8].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+        assert_eq!(
+            Operation::deserialize(&bytes),
+            Err(Error::DeserializeError("root"))
+        );
+
+        // An InsertMetadata operation that lacks its local timestamp.
+        let mut builder = FlatBufferBuilder::new();
+        let file_id =
+            epoch_fb::BaseFileId::create(&mut builder, &epoch_fb::BaseFileIdArgs { index: 0 })
+                .as_union_value();
+        let lamport_timestamp = time::Lamport::new(Uuid::from_u128(1)).to_flatbuf();
+        let operation = epoch_fb::InsertMetadata::create(
+            &mut builder,
+            &epoch_fb::InsertMetadataArgs {
+                file_id_type: epoch_fb::FileId::BaseFileId,
+                file_id: Some(file_id),
+                lamport_timestamp: Some(&lamport_timestamp),
+                ..Default::default()
+            },
+        )
+        .as_union_value();
+        let variant = worktree_fb::EpochOperation::create(
+            &mut builder,
+            &worktree_fb::EpochOperationArgs {
+                epoch_id: Some(&lamport_timestamp),
+                operation_type: epoch_fb::Operation::InsertMetadata,
+                operation: Some(operation),
+            },
+        )
+        .as_union_value();
+        let root = worktree_fb::Operation::create(
+            &mut builder,
+            &worktree_fb::OperationArgs {
+                variant_type: worktree_fb::OperationVariant::EpochOperation,
+                variant: Some(variant),
+            },
+        );
+        builder.finish(root, None);
+        let mut bytes = SERIALIZATION_VERSION.to_le_bytes().to_vec();
+        bytes.extend_from_slice(builder.finished_data());
+        assert_eq!(
+            Operation::deserialize(&bytes),
+            Err(Error::DeserializeError("local_timestamp"))
+        );
+    }
+
+    #[test]
+    fn test_paths_for_file_id() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (tree, _) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        tree.create_file("dir", FileType::Directory).unwrap();
+        tree.create_file("dir/a", FileType::Text).unwrap();
+        let buffer_id = tree.open_text_file("dir/a").wait().unwrap();
+        let file_id = tree.buffer_file_id(buffer_id).unwrap();
+        assert_eq!(
+            tree.paths_for_file_id(file_id),
+            vec![PathBuf::from("dir/a")]
+        );
+
+        tree.rename("dir", "other").unwrap();
+        assert_eq!(
+            tree.paths_for_file_id(file_id),
+            vec![PathBuf::from("other/a")]
+        );
+
+        tree.remove("other/a").unwrap();
+        assert!(tree.paths_for_file_id(file_id).is_empty());
+        assert_eq!(tree.text_str(buffer_id), "");
+    }
+
+    #[test]
+    fn test_buffer_views() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (tree, _) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        tree.create_file("a", FileType::Text).unwrap();
+        let view_1 = tree.open_text_file("a").wait().unwrap();
+        let file_id = tree.buffer_file_id(view_1).unwrap();
+        let view_2 = tree.open_buffer(file_id).unwrap();
+        assert_ne!(view_1, view_2);
+        assert_eq!(tree.buffer_file_id(view_2), Some(file_id));
+        assert_eq!(tree.buffer_file_id(BufferId(100)), None);
+
+        tree.edit(view_1, Some(0..0), "abc").unwrap();
+        assert_eq!(tree.text_str(view_2), "abc");
+        tree.edit(view_2, Some(3..3), "def").unwrap();
+        assert_eq!(tree.text_str(view_1), "abcdef");
+
+        let (set_id, _) = tree
+            .add_selection_set(view_1, vec![Point::new(0, 1)..Point::new(0, 2)])
+            .unwrap();
+        assert_eq!(
+            tree.selection_ranges(view_1).unwrap().local,
+            vec![(set_id, vec![Point::new(0, 1)..Point::new(0, 2)])]
+                .into_iter()
+                .collect()
+        );
+        assert!(tree.selection_ranges(view_2).unwrap().local.is_empty());
+    }
+
+    #[test]
+    fn test_gc_barrier() {
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, mut tree_2) = two_replicas(&git);
+
+        let mut ops = open_envelopes(tree_1.create_file("a", FileType::Text));
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        ops.extend(open_envelopes(tree_1.edit(a_1, Some(0..0), "abc")));
+        tree_2.apply_ops(ops).unwrap().collect().wait().unwrap();
+        assert_eq!(tree_1.gc_barrier(), tree_1.version().epoch_version);
+
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        let ops = open_envelopes(tree_2.edit(a_2, Some(0..1), ""));
+        tree_1.apply_ops(ops).unwrap().collect().wait().unwrap();
+        assert_eq!(tree_1.gc_barrier(), time::Global::new());
+
+        tree_1.record_ack(Uuid::from_u128(2), tree_2.version());
+        assert_eq!(tree_1.gc_barrier(), tree_2.version().epoch_version);
+        let history_len = tree_1.cur_epoch().history().len();
+        tree_1.collect_garbage(&tree_1.gc_barrier());
+        assert_eq!(tree_1.text_str(a_1), "bc");
+
+        // Operations that both replicas observed are pruned from the history.
+        assert!(tree_1.cur_epoch().history().len() < history_len);
+        assert!(tree_1
+            .cur_epoch()
+            .history()
+            .iter()
+            .all(|op| op.lamport_timestamp().replica_id != Uuid::from_u128(2)));
+
+        let ops = open_envelopes(tree_1.edit(a_1, Some(1..2), "d"));
+        tree_1.record_ack(Uuid::from_u128(2), tree_2.version());
+        assert_eq!(tree_1.gc_barrier(), tree_2.version().epoch_version);
+        tree_2.apply_ops(ops).unwrap().collect().wait().unwrap();
+        assert_eq!(tree_2.text_str(a_2), "bd");
+    }
+
+    #[test]
+    fn test_hard_remove_deletions() {
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, mut tree_2) = two_replicas(&git);
+        tree_1.set_hard_remove_deletions(true);
+
+        let mut ops = open_envelopes(tree_1.create_file("a", FileType::Text));
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        ops.extend(open_envelopes(tree_1.edit(a_1, Some(0..0), "abcdef")));
+        tree_2.apply_ops(ops).unwrap().collect().wait().unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+
+        // Tree 1 hasn't heard from tree 2 yet, so its deletion stays tombstoned until tree 2
+        // acknowledges observing it.
+        let ops_2 = open_envelopes(tree_2.edit(a_2, Some(3..3), "X"));
+        let ops_1 = open_envelopes(tree_1.edit(a_1, Some(1..5), ""));
+        tree_1.record_ack(Uuid::from_u128(2), tree_2.version());
+        assert!(tree_1.memory_usage().tombstone_bytes > 0);
+
+        tree_1.apply_ops(ops_2).unwrap().collect().wait().unwrap();
+        tree_2.apply_ops(ops_1).unwrap().collect().wait().unwrap();
+        assert_eq!(tree_1.text_str(a_1), "aXf");
+        assert_eq!(tree_2.text_str(a_2), "aXf");
+
+        tree_1.record_ack(Uuid::from_u128(2), tree_2.version());
+        assert_eq!(tree_1.memory_usage().tombstone_bytes, 0);
+        assert_eq!(tree_1.text_str(a_1), "aXf");
+
+        let ops = open_envelopes(tree_2.edit(a_2, Some(1..2), "Y"));
+        tree_1.apply_ops(ops).unwrap().collect().wait().unwrap();
+        assert_eq!(tree_1.text_str(a_1), "aYf");
+        assert_eq!(tree_2.text_str(a_2), "aYf");
+    }
+
+    #[test]
+    fn test_noop_operations() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (mut tree, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        assert!(ops.collect().wait().unwrap().iter().all(|op| !op.is_noop()));
+
+        tree.create_file("a", FileType::Text).unwrap();
+        let a = tree.open_text_file("a").wait().unwrap();
+        assert!(!tree.edit(a, Some(0..0), "abc").unwrap().is_noop());
+        assert!(tree.edit(a, Some(1..1), "").unwrap().is_noop());
+
+        let ranges = vec![Point::new(0, 1)..Point::new(0, 2)];
+        let (set_id, op) = tree.add_selection_set(a, ranges.clone()).unwrap();
+        assert!(!op.is_noop());
+        assert!(tree
+            .replace_selection_set(a, set_id, ranges.clone())
+            .unwrap()
+            .is_noop());
+        assert!(!tree
+            .replace_selection_set(a, set_id, vec![Point::new(0, 2)..Point::new(0, 3)])
+            .unwrap()
+            .is_noop());
+        assert_eq!(
+            tree.selection_sets(a).unwrap()[0].2,
+            vec![Point::new(0, 2)..Point::new(0, 3)]
+        );
+
+        tree.flush_operations();
+        tree.set_skip_noop_ops(true);
+        tree.replace_selection_set(a, set_id, vec![Point::new(0, 2)..Point::new(0, 3)])
+            .unwrap();
+        tree.edit(a, Some(0..0), "").unwrap();
+        assert!(tree.flush_operations().is_empty());
+        tree.edit(a, Some(0..0), "x").unwrap();
+        tree.replace_selection_set(a, set_id, vec![Point::new(0, 0)..Point::new(0, 1)])
+            .unwrap();
+        assert_eq!(tree.flush_operations().len(), 2);
+    }
+
+    #[test]
+    fn test_flush_operations_chunked() {
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, mut tree_2) = two_replicas(&git);
+
+        tree_1.create_file("a", FileType::Text).unwrap();
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        for i in 0..10 {
+            tree_1.edit(a_1, Some(i..i), "x").unwrap();
+        }
+
+        let mut chunk_lens = Vec::new();
+        loop {
+            let chunk = tree_1.flush_operations_chunked(4);
+            if chunk.is_empty() {
+                break;
+            }
+            chunk_lens.push(chunk.len());
+            tree_2
+                .apply_ops(open_envelopes(chunk))
+                .unwrap()
+                .collect()
+                .wait()
+                .unwrap();
+            assert_eq!(tree_2.deferred_operation_count(), 0);
+        }
+        // The first chunk starts with the epoch, followed by the file creation and every edit.
+        assert_eq!(chunk_lens, vec![4, 4, 4]);
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        assert_eq!(tree_2.text_str(a_2), "xxxxxxxxxx");
+
+        tree_1.edit(a_1, Some(0..1), "").unwrap();
+        let ops = tree_1.flush_operations();
+        assert_eq!(ops.len(), 1);
+        tree_2
+            .apply_ops(open_envelopes(ops))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree_2.text_str(a_2), tree_1.text_str(a_1));
+        assert!(tree_1.flush_operations_chunked(0).is_empty());
+        assert!(tree_1.flush_operations().is_empty());
+        assert!(tree_2.flush_operations().is_empty());
+    }
+
+    #[test]
+    fn test_affected_buffers_and_paths() {
+        let git = Rc::new(TestGitProvider::new());
+        let (tree_1, mut tree_2) = two_replicas(&git);
+
+        let dir_op = tree_1.create_file("dir", FileType::Directory).unwrap();
+        let a_op = tree_1.create_file("dir/a", FileType::Text).unwrap();
+        let a_1 = tree_1.open_text_file("dir/a").wait().unwrap();
+        let edit_op = tree_1.edit(a_1, Some(0..0), "abc").unwrap();
+        assert_eq!(edit_op.file_id(), tree_1.buffer_file_id(a_1));
+        assert_eq!(a_op.file_id(), edit_op.file_id());
+        assert_eq!(tree_1.affected_buffers(&edit_op), vec![a_1]);
+        assert!(tree_1.affected_buffers(&a_op).is_empty());
+        assert_eq!(tree_1.affected_path(&dir_op), Some(PathBuf::from("dir")));
+        assert_eq!(tree_1.affected_path(&a_op), Some(PathBuf::from("dir/a")));
+        assert_eq!(tree_1.affected_path(&edit_op), None);
+
+        tree_2
+            .apply_ops(vec![
+                dir_op.operation,
+                a_op.operation,
+                edit_op.operation.clone(),
+            ])
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert!(tree_2.affected_buffers(&edit_op).is_empty());
+        let a_2 = tree_2.open_text_file("dir/a").wait().unwrap();
+        assert_eq!(tree_2.affected_buffers(&edit_op), vec![a_2]);
+
+        let rename_op = tree_1.rename("dir/a", "dir/b").unwrap();
+        assert_eq!(
+            tree_2.affected_path(&rename_op),
+            Some(PathBuf::from("dir/b"))
+        );
+        tree_2
+            .apply_ops(Some(rename_op.operation))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        let remove_op = tree_1.remove("dir/b").unwrap();
+        assert_eq!(
+            tree_2.affected_path(&remove_op),
+            Some(PathBuf::from("dir/b"))
+        );
+    }
+
+    #[test]
+    fn test_replica_activity() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (mut tree_1, ops_1) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        let ops_1 = open_envelopes(ops_1.collect().wait().unwrap());
+        let (tree_2, ops_2) = WorkTree::new(
+            Uuid::from_u128(2),
+            Some(commit),
+            ops_1.clone(),
+            git.clone(),
+            None,
+        )
+        .unwrap();
+        ops_2.collect().wait().unwrap();
+        let (tree_3, ops_3) =
+            WorkTree::new(Uuid::from_u128(3), Some(commit), ops_1, git.clone(), None).unwrap();
+        ops_3.collect().wait().unwrap();
+
+        let joined = Rc::new(RefCell::new(Vec::new()));
+        let idle = Rc::new(RefCell::new(Vec::new()));
+        let joined_clone = joined.clone();
+        let idle_clone = idle.clone();
+        tree_1.on_replica_joined(move |replica_id| joined_clone.borrow_mut().push(replica_id));
+        tree_1.on_replica_idle(5, move |replica_id, since| {
+            idle_clone.borrow_mut().push((replica_id, since))
+        });
+
+        let ops = open_envelopes(tree_3.create_file("c", FileType::Text));
+        tree_1.apply_ops(ops).unwrap().collect().wait().unwrap();
+        let ops = open_envelopes(tree_2.create_file("a", FileType::Text));
+        tree_1.apply_ops(ops).unwrap().collect().wait().unwrap();
+        let ops = open_envelopes(tree_2.create_file("b", FileType::Text));
+        tree_1.apply_ops(ops).unwrap().collect().wait().unwrap();
+        assert_eq!(
+            *joined.borrow(),
+            vec![Uuid::from_u128(3), Uuid::from_u128(2)]
+        );
+        assert!(idle.borrow().is_empty());
+        let tree_3_last_seen = tree_1.replica_last_seen(Uuid::from_u128(3)).unwrap();
+        assert_eq!(tree_3_last_seen.replica_id, Uuid::from_u128(3));
+
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        let mut ops = Vec::new();
+        for i in 0..10 {
+            ops.extend(open_envelopes(tree_2.edit(a_2, Some(i..i), "x")));
+        }
+        tree_1.apply_ops(ops).unwrap().collect().wait().unwrap();
+        assert_eq!(*idle.borrow(), vec![(Uuid::from_u128(3), tree_3_last_seen)]);
+        assert_eq!(joined.borrow().len(), 2);
+
+        // Replicas are reported as idle again only after they have been active.
+        let ops = open_envelopes(tree_2.edit(a_2, Some(0..0), "y"));
+        tree_1.apply_ops(ops).unwrap().collect().wait().unwrap();
+        assert_eq!(idle.borrow().len(), 1);
+        assert_eq!(tree_1.replica_last_seen(Uuid::from_u128(4)), None);
+    }
+
+    #[test]
+    fn test_rollback_pending() {
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, mut tree_2) = two_replicas(&git);
+
+        tree_1.create_file("a", FileType::Text).unwrap();
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        let sent_op = tree_1.edit(a_1, Some(0..0), "hello world").unwrap();
+        let ops = tree_1.flush_operations();
+        tree_2
+            .apply_ops(open_envelopes(ops))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+
+        let op = tree_1.edit(a_1, Some(6..11), "there").unwrap();
+        tree_1.edit(a_1, Some(0..0), ">> ").unwrap();
+        assert_eq!(tree_1.text_str(a_1), ">> hello there");
+        let (changes, inverse_ops) = tree_1.rollback_pending(&op).unwrap();
+        assert!(!changes.is_empty());
+        assert!(!inverse_ops.is_empty());
+        assert_eq!(tree_1.text_str(a_1), ">> hello world");
+        assert_eq!(
+            tree_1.rollback_pending(&op).err(),
+            Some(Error::InvalidOperation)
+        );
+        assert_eq!(
+            tree_1.rollback_pending(&sent_op).err(),
+            Some(Error::AlreadySent)
+        );
+
+        // Peers receive both the edit and its inverse.
+        tree_2
+            .apply_ops(open_envelopes(tree_1.flush_operations()))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        assert_eq!(tree_2.text_str(a_2), ">> hello world");
+
+        // An edit that a peer acknowledged was sent, even if it was never flushed.
+        let acked_op = tree_1.edit(a_1, Some(0..3), "").unwrap();
+        tree_2
+            .apply_ops(Some(acked_op.operation.clone()))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        tree_1.record_ack(tree_2.replica_id(), tree_2.version());
+        assert_eq!(
+            tree_1.rollback_pending(&acked_op).err(),
+            Some(Error::AlreadySent)
+        );
+    }
+
+    #[test]
+    fn test_files_created_and_removed_since() {
+        let git = Rc::new(TestGitProvider::new());
+        let base_tree = WorkTree::empty();
+        base_tree.create_file("a", FileType::Text).unwrap();
+        base_tree.create_file("b", FileType::Directory).unwrap();
+        base_tree.create_file("b/c", FileType::Text).unwrap();
+        let commit = git.commit(&base_tree);
+        let (tree, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+
+        let d = tree
+            .create_file("d", FileType::Text)
+            .unwrap()
+            .file_id()
+            .unwrap();
+        tree.create_file("e", FileType::Text).unwrap();
+        let version = tree.version().epoch_version;
+        let f = tree
+            .create_file("f", FileType::Text)
+            .unwrap()
+            .file_id()
+            .unwrap();
+        tree.create_file("g", FileType::Text).unwrap();
+        let h = tree
+            .create_file("h", FileType::Directory)
+            .unwrap()
+            .file_id()
+            .unwrap();
+        tree.rename("h", "b/h").unwrap();
+        tree.remove("g").unwrap();
+        tree.remove("d").unwrap();
+        tree.remove("b").unwrap();
+        tree.remove("e").unwrap();
+        tree.create_file("e", FileType::Text).unwrap();
+
+        let created = tree.files_created_since(&version);
+        assert_eq!(created.len(), 2);
+        assert_eq!(created[0], (f, PathBuf::from("f")));
+        assert_eq!(created[1].1, PathBuf::from("e"));
+
+        // Removing a directory is reported once, along with the path it had before.
+        let removed = tree.files_removed_since(&version);
+        let removed_paths = removed
+            .iter()
+            .map(|(_, path)| path.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            removed_paths,
+            vec![PathBuf::from("d"), PathBuf::from("b"), PathBuf::from("e")]
+        );
+        assert_eq!(removed[0].0, d);
+        assert!(!removed.iter().any(|(file_id, _)| *file_id == h));
+        assert!(tree
+            .files_created_since(&tree.version().epoch_version)
+            .is_empty());
+        assert!(tree
+            .files_removed_since(&tree.version().epoch_version)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_peek_pending() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit = git.commit(&WorkTree::empty());
+        let (mut tree, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+        tree.create_file("a", FileType::Text).unwrap();
+        let a = tree.open_text_file("a").wait().unwrap();
+        tree.edit(a, Some(0..0), "x").unwrap();
+        tree.edit(a, Some(1..1), "y").unwrap();
+
+        // Peeking doesn't consume the operations, which the next flush returns in the same order.
+        let peeked = open_envelopes(tree.peek_pending());
+        assert_eq!(peeked.len(), 4);
+        assert_eq!(open_envelopes(tree.peek_pending()), peeked);
+        assert_eq!(
+            open_envelopes(tree.flush_operations_chunked(3)),
+            &peeked[..3]
+        );
+        assert_eq!(open_envelopes(tree.peek_pending()), &peeked[3..]);
+        assert_eq!(open_envelopes(tree.flush_operations()), &peeked[3..]);
+        assert!(tree.peek_pending().is_empty());
+    }
+
+    #[test]
+    fn test_flush_lane_first() {
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, mut tree_2) = two_replicas(&git);
+
+        tree_1.create_file("a", FileType::Text).unwrap();
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        let ops = tree_1.flush_operations();
+        tree_2
+            .apply_ops(open_envelopes(ops))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+
+        for i in 0..3 {
+            tree_1.edit(a_1, Some(i..i), "x").unwrap();
+        }
+        tree_1.create_file("b", FileType::Directory).unwrap();
+        tree_1.edit(a_1, Some(3..3), "y").unwrap();
+
+        let structure_ops = tree_1.flush_lane_first(Lane::Structure, 1);
+        assert_eq!(structure_ops.len(), 1);
+        assert_eq!(structure_ops[0].lane(), Lane::Structure);
+        tree_2
+            .apply_ops(open_envelopes(structure_ops))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            tree_2.visible_paths(FileType::Directory),
+            vec![PathBuf::from("b")]
+        );
+
+        let content_ops = tree_1.flush_lane_first(Lane::Structure, usize::max_value());
+        assert_eq!(content_ops.len(), 4);
+        assert!(content_ops.iter().all(|op| op.lane() == Lane::Content));
+        tree_2
+            .apply_ops(open_envelopes(content_ops))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        assert_eq!(tree_2.text_str(a_2), "xxxy");
+        assert!(tree_1.flush_operations().is_empty());
+    }
+
+    #[test]
+    fn test_operations_from() {
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, mut tree_2) = two_replicas(&git);
+
+        let mut tree_1_ops = open_envelopes(tree_1.create_file("a", FileType::Text));
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        tree_1_ops.extend(open_envelopes(tree_1.edit(a_1, Some(0..0), "abc")));
+        tree_2
+            .apply_ops(tree_1_ops.clone())
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        let mut tree_2_ops = open_envelopes(tree_2.edit(a_2, Some(0..1), ""));
+        tree_2_ops.extend(open_envelopes(tree_2.edit(a_2, Some(2..2), "de")));
+        tree_2_ops.extend(open_envelopes(tree_2.create_file("b", FileType::Text)));
+        tree_1
+            .apply_ops(tree_2_ops.clone())
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree_1.text_str(a_1), "bcde");
+
+        assert_eq!(
+            open_envelopes(tree_1.operations_from(tree_1.replica_id())),
+            tree_1_ops
+        );
+        assert_eq!(
+            open_envelopes(tree_1.operations_from(tree_2.replica_id())),
+            tree_2_ops
+        );
+        assert_eq!(
+            open_envelopes(tree_2.operations_from(tree_1.replica_id())),
+            tree_1_ops
+        );
+        assert!(tree_1.operations_from(Uuid::from_u128(3)).is_empty());
+    }
+
+    #[test]
+    fn test_selected_char_count() {
+        let tree = WorkTree::empty();
+        tree.create_file("a", FileType::Text).unwrap();
+        let a = tree.open_text_file("a").wait().unwrap();
+        tree.edit(a, Some(0..0), "ab\u{1F600}c\ndef").unwrap();
+
+        let (set_id, _) = tree
+            .add_selection_set(a, vec![Point::new(0, 0)..Point::new(0, 0)])
+            .unwrap();
+        assert_eq!(tree.selected_char_count(a, set_id), Ok(0));
+
+        // Overlapping ranges are only counted once, and reversed ranges count like any other.
+        tree.replace_selection_set(
+            a,
+            set_id,
+            vec![
+                Point::new(0, 1)..Point::new(0, 4),
+                Point::new(0, 2)..Point::new(1, 1),
+                Point::new(1, 3)..Point::new(1, 2),
+            ],
+        )
+        .unwrap();
+        assert_eq!(tree.selected_char_count(a, set_id), Ok(6));
+
+        assert_eq!(
+            tree.selected_char_count(a, LocalSelectionSetId(100)),
+            Err(Error::InvalidLocalSelectionSet(LocalSelectionSetId(100)))
+        );
+        assert_eq!(
+            tree.selected_char_count(BufferId(100), set_id),
+            Err(Error::InvalidBufferId)
+        );
+    }
+
+    #[test]
+    fn test_selection_sets() {
+        let git = Rc::new(TestGitProvider::new());
+        let base_tree = WorkTree::empty();
+        base_tree.create_file("a", FileType::Text).unwrap();
+        let a_base = base_tree.open_text_file("a").wait().unwrap();
+        base_tree.edit(a_base, Some(0..0), "abc\ndef").unwrap();
+        let commit = git.commit(&base_tree);
+
+        let (mut tree_1, tree_2) = two_replicas_at(&git, commit);
 
-            for replica_index in 0..PEERS - 1 {
-                let tree_1 = &trees[replica_index];
-                let tree_2 = &trees[replica_index + 1];
-                assert_eq!(tree_1.cur_epoch().id, tree_2.cur_epoch().id);
-                assert_eq!(tree_1.cur_epoch().head, tree_2.cur_epoch().head);
-                assert_eq!(tree_1.entries(), tree_2.entries());
-                assert_eq!(tree_1.replica_locations(), tree_2.replica_locations());
-            }
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        assert!(tree_1.selection_sets(a_1).unwrap().is_empty());
+        let (a_1_set_1, _) = tree_1
+            .add_selection_set(a_1, vec![Point::new(1, 1)..Point::new(1, 2)])
+            .unwrap();
+        tree_1.add_selection_set(a_1, vec![]).unwrap();
+        let (a_1_set_2, _) = tree_1
+            .add_selection_set(a_1, vec![Point::new(0, 0)..Point::new(0, 0)])
+            .unwrap();
 
-            for replica_index in 0..PEERS {
-                let tree = &trees[replica_index];
-                let observer = &observers[replica_index];
-                for buffer_id in tree.open_buffers() {
-                    assert_eq!(
-                        observer.text(buffer_id),
-                        tree.text(buffer_id).unwrap().into_string()
-                    );
-                    assert_eq!(
-                        observer.selection_ranges(buffer_id),
-                        tree.selection_ranges(buffer_id).unwrap()
-                    );
-                }
-            }
-        }
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        let (_, a_2_set_op) = tree_2
+            .add_selection_set(a_2, vec![Point::new(0, 3)..Point::new(0, 1)])
+            .unwrap();
+        tree_1
+            .apply_ops(Some(a_2_set_op.operation))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+
+        assert_eq!(
+            tree_1.selection_sets(a_1).unwrap(),
+            vec![
+                (
+                    tree_1.replica_id(),
+                    Some(a_1_set_1),
+                    vec![Point::new(1, 1)..Point::new(1, 2)]
+                ),
+                (
+                    tree_1.replica_id(),
+                    Some(a_1_set_2),
+                    vec![Point::new(0, 0)..Point::new(0, 0)]
+                ),
+                (
+                    tree_2.replica_id(),
+                    None,
+                    vec![Point::new(0, 3)..Point::new(0, 1)]
+                ),
+            ]
+        );
+        assert_eq!(
+            tree_1.selection_sets(BufferId(100)),
+            Err(Error::InvalidBufferId)
+        );
     }
 
     #[test]
-    fn test_reset() {
+    fn test_selection_set_lifecycle() {
         let git = Rc::new(TestGitProvider::new());
         let base_tree = WorkTree::empty();
         base_tree.create_file("a", FileType::Text).unwrap();
         let a_base = base_tree.open_text_file("a").wait().unwrap();
-        base_tree.edit(a_base, Some(0..0), "abc").unwrap();
-        let commit_0 = git.commit(&base_tree);
+        base_tree.edit(a_base, Some(0..0), "abc\ndef").unwrap();
+        let commit = git.commit(&base_tree);
 
-        base_tree.edit(a_base, Some(1..2), "def").unwrap();
-        base_tree.create_file("b", FileType::Directory).unwrap();
-        let commit_1 = git.commit(&base_tree);
+        let (tree_1, mut tree_2) = two_replicas_at(&git, commit);
 
-        base_tree.edit(a_base, Some(2..3), "ghi").unwrap();
-        base_tree.create_file("b/c", FileType::Text).unwrap();
-        let commit_2 = git.commit(&base_tree);
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        let primary = tree_1.create_selection_set(a_1).unwrap();
+        let highlights = tree_1.create_selection_set(a_1).unwrap();
+        assert_ne!(primary, highlights);
+        assert!(tree_1.selection_sets(a_1).unwrap().is_empty());
+
+        let mut ops = Vec::new();
+        ops.push(
+            tree_1
+                .replace_selection_set(a_1, primary, vec![Point::new(0, 1)..Point::new(0, 1)])
+                .unwrap()
+                .operation,
+        );
+        ops.push(
+            tree_1
+                .replace_selection_set(a_1, highlights, vec![Point::new(1, 0)..Point::new(1, 3)])
+                .unwrap()
+                .operation,
+        );
+        tree_2.apply_ops(ops).unwrap().collect().wait().unwrap();
+        assert_eq!(
+            tree_2.selection_sets(a_2).unwrap(),
+            vec![
+                (
+                    tree_1.replica_id(),
+                    None,
+                    vec![Point::new(0, 1)..Point::new(0, 1)]
+                ),
+                (
+                    tree_1.replica_id(),
+                    None,
+                    vec![Point::new(1, 0)..Point::new(1, 3)]
+                ),
+            ]
+        );
 
-        let observer_1 = Rc::new(TestChangeObserver::new());
-        let observer_2 = Rc::new(TestChangeObserver::new());
-        let (mut tree_1, ops_1) = WorkTree::new(
-            Uuid::from_u128(1),
-            Some(commit_0),
-            vec![],
-            git.clone(),
-            Some(observer_1.clone()),
-        )
-        .unwrap();
+        let op = tree_1.remove_selection_set(a_1, highlights).unwrap();
+        tree_2
+            .apply_ops(Some(op.operation))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            tree_2.selection_sets(a_2).unwrap(),
+            vec![(
+                tree_1.replica_id(),
+                None,
+                vec![Point::new(0, 1)..Point::new(0, 1)]
+            )]
+        );
+        assert_eq!(
+            tree_1.replace_selection_set(a_1, highlights, vec![]).err(),
+            Some(Error::InvalidLocalSelectionSet(highlights))
+        );
+        assert_eq!(
+            tree_1.remove_selection_set(a_1, highlights).err(),
+            Some(Error::InvalidLocalSelectionSet(highlights))
+        );
+        assert_eq!(
+            tree_1.create_selection_set(BufferId(100)),
+            Err(Error::InvalidBufferId)
+        );
+    }
+
+    #[test]
+    fn test_hide_selection_set() {
+        let git = Rc::new(TestGitProvider::new());
+        let base_tree = WorkTree::empty();
+        base_tree.create_file("a", FileType::Text).unwrap();
+        let a_base = base_tree.open_text_file("a").wait().unwrap();
+        base_tree.edit(a_base, Some(0..0), "abc\ndef").unwrap();
+        let commit = git.commit(&base_tree);
 
+        let (tree_1, ops_1) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        let observer_2 = Rc::new(TestChangeObserver::new());
         let (mut tree_2, ops_2) = WorkTree::new(
             Uuid::from_u128(2),
-            Some(commit_0),
+            Some(commit),
             open_envelopes(ops_1.collect().wait().unwrap()),
             git.clone(),
             Some(observer_2.clone()),
         )
         .unwrap();
-
         assert!(ops_2.wait().next().is_none());
 
-        assert_eq!(tree_1.head(), Some(commit_0));
-        assert_eq!(tree_1.dir_entries(), git.tree(commit_0).dir_entries());
-        assert_eq!(tree_2.head(), Some(commit_0));
-        assert_eq!(tree_2.dir_entries(), git.tree(commit_0).dir_entries());
-
         let a_1 = tree_1.open_text_file("a").wait().unwrap();
         let a_2 = tree_2.open_text_file("a").wait().unwrap();
-        observer_1.opened_buffer(a_1, &tree_1);
         observer_2.opened_buffer(a_2, &tree_2);
-        assert_eq!(tree_1.text_str(a_1), git.tree(commit_0).text_str(a_base));
-        assert_eq!(tree_2.text_str(a_2), git.tree(commit_0).text_str(a_base));
-
-        let ops_1 = open_envelopes(tree_1.reset(Some(commit_1)).collect().wait().unwrap());
-        let fixup_ops_2 = tree_2.apply_ops(ops_1).unwrap().collect().wait().unwrap();
-        assert!(fixup_ops_2.is_empty());
-        assert_eq!(tree_1.head(), Some(commit_1));
-        assert_eq!(tree_2.head(), Some(commit_1));
-        assert_eq!(tree_1.entries(), tree_2.entries());
-        assert_eq!(tree_1.dir_entries(), git.tree(commit_1).dir_entries());
-        assert_eq!(tree_1.text_str(a_1), git.tree(commit_1).text_str(a_1));
-        assert_eq!(observer_1.text(a_1), tree_1.text_str(a_1));
-        assert_eq!(tree_2.text_str(a_2), git.tree(commit_1).text_str(a_2));
-        assert_eq!(observer_2.text(a_2), tree_2.text_str(a_2));
-
-        let ops_2 = open_envelopes(tree_2.reset(Some(commit_2)).collect().wait().unwrap());
-        let fixup_ops_1 = tree_1
-            .apply_ops(ops_2.clone())
+        let set = tree_1.create_selection_set(a_1).unwrap();
+        let mut ops = vec![
+            tree_1
+                .replace_selection_set(a_1, set, vec![Point::new(1, 1)..Point::new(1, 2)])
+                .unwrap(),
+            tree_1.hide_selection_set(a_1, set).unwrap(),
+        ];
+        assert_eq!(tree_1.is_selection_set_hidden(a_1, set), Ok(true));
+        assert!(tree_1.selection_sets(a_1).unwrap().is_empty());
+
+        // Edits made while the set is hidden still move its selections.
+        ops.push(tree_1.edit(a_1, Some(4..4), "xy").unwrap());
+        tree_2
+            .apply_ops(deserialize_ops(serialize_ops(open_envelopes(ops))))
             .unwrap()
             .collect()
             .wait()
             .unwrap();
-        assert!(fixup_ops_1.is_empty());
-        assert_eq!(tree_1.head(), Some(commit_2));
-        assert_eq!(tree_2.head(), Some(commit_2));
-        assert_eq!(tree_1.entries(), tree_2.entries());
-        assert_eq!(tree_1.dir_entries(), git.tree(commit_2).dir_entries());
-        assert_eq!(tree_1.text_str(a_1), git.tree(commit_2).text_str(a_1));
-        assert_eq!(observer_1.text(a_1), tree_1.text_str(a_1));
-        assert_eq!(tree_2.text_str(a_2), git.tree(commit_2).text_str(a_2));
-        assert_eq!(observer_2.text(a_2), tree_2.text_str(a_2));
+        assert!(tree_2.selection_sets(a_2).unwrap().is_empty());
+        assert!(observer_2.selection_ranges(a_2).remote.is_empty());
 
-        // Reload tree using only ops for the newest epoch.
-        let (mut tree_1, ops_1) = WorkTree::new(
-            Uuid::from_u128(1),
-            Some(commit_0),
-            ops_2,
-            git.clone(),
-            Some(observer_1.clone()),
-        )
-        .unwrap();
-        assert!(ops_1.wait().next().is_none());
-        assert_eq!(tree_1.head(), Some(commit_2));
+        let op = tree_1.show_selection_set(a_1, set).unwrap();
+        assert_eq!(tree_1.is_selection_set_hidden(a_1, set), Ok(false));
+        tree_2
+            .apply_ops(deserialize_ops(serialize_ops(open_envelopes(Some(op)))))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        let expected = vec![Point::new(1, 3)..Point::new(1, 4)];
+        assert_eq!(
+            tree_2.selection_sets(a_2).unwrap(),
+            vec![(tree_1.replica_id(), None, expected.clone())]
+        );
+        assert_eq!(
+            observer_2.selection_ranges(a_2).remote[&tree_1.replica_id()],
+            vec![expected]
+        );
 
-        let ops_1 = open_envelopes(tree_1.reset(Some(commit_0)).collect().wait().unwrap());
-        let fixup_ops_2 = tree_2.apply_ops(ops_1).unwrap().collect().wait().unwrap();
-        assert!(fixup_ops_2.is_empty());
-        assert_eq!(tree_1.head(), Some(commit_0));
-        assert_eq!(tree_2.head(), Some(commit_0));
+        tree_1.remove_selection_set(a_1, set).unwrap();
+        assert_eq!(
+            tree_1.hide_selection_set(a_1, set).err(),
+            Some(Error::InvalidLocalSelectionSet(set))
+        );
     }
 
     #[test]
@@ -1430,23 +6470,7 @@ mod tests {
         base_tree.edit(a_base, Some(8..8), "ghi\n").unwrap();
         let commit_1 = git.commit(&base_tree);
 
-        let (mut tree_1, ops_1) = WorkTree::new(
-            Uuid::from_u128(1),
-            Some(commit_0),
-            vec![],
-            git.clone(),
-            None,
-        )
-        .unwrap();
-        let (mut tree_2, ops_2) = WorkTree::new(
-            Uuid::from_u128(2),
-            Some(commit_0),
-            open_envelopes(ops_1.collect().wait().unwrap()),
-            git.clone(),
-            None,
-        )
-        .unwrap();
-        assert!(ops_2.wait().next().is_none());
+        let (mut tree_1, mut tree_2) = two_replicas_at(&git, commit_0);
 
         let a_1 = tree_1.open_text_file("a").wait().unwrap();
         let (a_1_set, a_1_set_op) = tree_1
@@ -1569,53 +6593,170 @@ mod tests {
             Some(commit_0),
             open_envelopes(ops_1.collect().wait().unwrap()),
             git.clone(),
-            None,
+            None,
+        )
+        .unwrap();
+        assert!(ops_2.wait().next().is_none());
+
+        let a_1 = tree_1.open_text_file("a").wait().unwrap();
+        let tree_1_location_op = tree_1.set_active_location(Some(a_1)).unwrap().operation;
+        tree_2
+            .apply_ops(Some(tree_1_location_op))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+
+        let b_2 = tree_2.open_text_file("b").wait().unwrap();
+        let tree_2_location_op = tree_2.set_active_location(Some(b_2)).unwrap().operation;
+        tree_1
+            .apply_ops(Some(tree_2_location_op))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+
+        assert_eq!(tree_1.replica_location(replica_1_id).unwrap(), "a");
+        assert_eq!(tree_1.replica_location(replica_2_id).unwrap(), "b");
+        assert_eq!(tree_2.replica_location(replica_1_id).unwrap(), "a");
+        assert_eq!(tree_2.replica_location(replica_2_id).unwrap(), "b");
+
+        let fixup_ops_1 = tree_1.reset(Some(commit_1)).collect().wait().unwrap();
+        assert_eq!(tree_1.replica_location(replica_1_id).unwrap(), "a");
+        let fixup_ops_2 = tree_2
+            .apply_ops(open_envelopes(fixup_ops_1))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        tree_1
+            .apply_ops(open_envelopes(fixup_ops_2))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+
+        assert_eq!(tree_1.replica_location(replica_1_id).unwrap(), "a");
+        assert_eq!(tree_1.replica_location(replica_2_id).unwrap(), "b");
+        assert_eq!(tree_2.replica_location(replica_1_id).unwrap(), "a");
+        assert_eq!(tree_2.replica_location(replica_2_id).unwrap(), "b");
+    }
+
+    #[test]
+    fn test_presence() {
+        let git = Rc::new(TestGitProvider::new());
+        let base_tree = WorkTree::empty();
+        base_tree.create_file("a", FileType::Text).unwrap();
+        let commit_0 = git.commit(&base_tree);
+        let commit_1 = git.commit(&base_tree);
+
+        let replica_1_id = Uuid::from_u128(1);
+        let (mut tree_1, ops_1) =
+            WorkTree::new(replica_1_id, Some(commit_0), vec![], git.clone(), None).unwrap();
+
+        let replica_2_id = Uuid::from_u128(2);
+        let observer_2 = Rc::new(TestChangeObserver::new());
+        let (mut tree_2, ops_2) = WorkTree::new(
+            replica_2_id,
+            Some(commit_0),
+            open_envelopes(ops_1.collect().wait().unwrap()),
+            git.clone(),
+            Some(observer_2.clone()),
         )
         .unwrap();
         assert!(ops_2.wait().next().is_none());
 
-        let a_1 = tree_1.open_text_file("a").wait().unwrap();
-        let tree_1_location_op = tree_1.set_active_location(Some(a_1)).unwrap().operation;
+        let a_2 = tree_2.open_text_file("a").wait().unwrap();
+        observer_2.opened_buffer(a_2, &tree_2);
+
+        let op_1 = tree_1
+            .set_presence(replica_1_id, "alice", 0xff0000)
+            .operation;
+        let op_2 = tree_1.set_presence(replica_1_id, "bob", 0x00ff00).operation;
         tree_2
-            .apply_ops(Some(tree_1_location_op))
+            .apply_ops(deserialize_ops(serialize_ops(vec![op_2, op_1])))
             .unwrap()
             .collect()
             .wait()
             .unwrap();
+        let presence = Presence {
+            label: "bob".into(),
+            color: 0x00ff00,
+        };
+        assert_eq!(tree_1.presences()[&replica_1_id], presence);
+        assert_eq!(tree_2.presences()[&replica_1_id], presence);
+        assert_eq!(
+            observer_2.selection_ranges(a_2).presences[&replica_1_id],
+            presence
+        );
 
-        let b_2 = tree_2.open_text_file("b").wait().unwrap();
-        let tree_2_location_op = tree_2.set_active_location(Some(b_2)).unwrap().operation;
+        let long_label = "é".repeat(MAX_PRESENCE_LABEL_LEN);
+        let op = tree_2.set_presence(replica_2_id, long_label, 0).operation;
         tree_1
-            .apply_ops(Some(tree_2_location_op))
+            .apply_ops(deserialize_ops(serialize_ops(Some(op))))
             .unwrap()
             .collect()
             .wait()
             .unwrap();
+        let label = &tree_1.presences()[&replica_2_id].label;
+        assert_eq!(label, &"é".repeat(MAX_PRESENCE_LABEL_LEN / 2));
+        assert_eq!(label, &tree_2.presences()[&replica_2_id].label);
 
-        assert_eq!(tree_1.replica_location(replica_1_id).unwrap(), "a");
-        assert_eq!(tree_1.replica_location(replica_2_id).unwrap(), "b");
-        assert_eq!(tree_2.replica_location(replica_1_id).unwrap(), "a");
-        assert_eq!(tree_2.replica_location(replica_2_id).unwrap(), "b");
-
-        let fixup_ops_1 = tree_1.reset(Some(commit_1)).collect().wait().unwrap();
-        assert_eq!(tree_1.replica_location(replica_1_id).unwrap(), "a");
-        let fixup_ops_2 = tree_2
-            .apply_ops(open_envelopes(fixup_ops_1))
-            .unwrap()
-            .collect()
-            .wait()
-            .unwrap();
-        tree_1
-            .apply_ops(open_envelopes(fixup_ops_2))
+        // The local presence is rebroadcast when switching to a new epoch.
+        let fixup_ops = tree_1.reset(Some(commit_1)).collect().wait().unwrap();
+        tree_2
+            .apply_ops(open_envelopes(fixup_ops))
             .unwrap()
             .collect()
             .wait()
             .unwrap();
+        assert_eq!(tree_1.presences()[&replica_1_id], presence);
+        assert_eq!(tree_2.presences()[&replica_1_id], presence);
+    }
 
-        assert_eq!(tree_1.replica_location(replica_1_id).unwrap(), "a");
-        assert_eq!(tree_1.replica_location(replica_2_id).unwrap(), "b");
-        assert_eq!(tree_2.replica_location(replica_1_id).unwrap(), "a");
-        assert_eq!(tree_2.replica_location(replica_2_id).unwrap(), "b");
+    #[test]
+    fn test_prune_empty_dirs() {
+        let git = Rc::new(TestGitProvider::new());
+        let base_tree = WorkTree::empty();
+        base_tree.create_file("a", FileType::Directory).unwrap();
+        base_tree.create_file("a/b", FileType::Directory).unwrap();
+        base_tree.create_file("a/b/c", FileType::Directory).unwrap();
+        base_tree.create_file("a/b/c/f", FileType::Text).unwrap();
+        base_tree.create_file("a/d", FileType::Directory).unwrap();
+        base_tree.create_file("a/d/g", FileType::Text).unwrap();
+        base_tree.create_file("e", FileType::Directory).unwrap();
+        let commit = git.commit(&base_tree);
+
+        let (tree_1, mut tree_2) = two_replicas_at(&git, commit);
+
+        let mut ops = Vec::new();
+        ops.extend(open_envelopes(
+            tree_1.create_file("a/d/h", FileType::Directory),
+        ));
+        ops.extend(open_envelopes(
+            tree_1.create_file("a/d/h/i", FileType::Text),
+        ));
+        ops.extend(open_envelopes(tree_1.remove("a/b/c/f")));
+        ops.extend(open_envelopes(tree_1.remove("a/d/h/i")));
+        ops.extend(open_envelopes(tree_1.prune_empty_dirs()));
+        assert_eq!(
+            tree_1.visible_paths(FileType::Directory),
+            vec![PathBuf::from("a"), PathBuf::from("a/d")]
+        );
+        assert_eq!(
+            tree_1.visible_paths(FileType::Text),
+            vec![PathBuf::from("a/d/g")]
+        );
+        assert!(tree_1.prune_empty_dirs().is_empty());
+
+        tree_2.apply_ops(ops).unwrap().collect().wait().unwrap();
+        assert_eq!(tree_2.dir_entries(), tree_1.dir_entries());
+
+        // Removing the last file leaves only the root, which is never pruned.
+        tree_1.remove("a/d/g").unwrap();
+        tree_1.prune_empty_dirs();
+        assert!(tree_1.dir_entries().is_empty());
+        assert!(tree_1.prune_empty_dirs().is_empty());
     }
 
     #[test]
@@ -1656,23 +6797,7 @@ mod tests {
         base_tree.create_file("b/c", FileType::Text).unwrap();
         let commit_2 = git.commit(&base_tree);
 
-        let (mut tree_1, ops_1) = WorkTree::new(
-            Uuid::from_u128(1),
-            Some(commit_0),
-            vec![],
-            git.clone(),
-            None,
-        )
-        .unwrap();
-        let (mut tree_2, ops_2) = WorkTree::new(
-            Uuid::from_u128(2),
-            Some(commit_0),
-            open_envelopes(ops_1.collect().wait().unwrap()),
-            git.clone(),
-            None,
-        )
-        .unwrap();
-        assert!(ops_2.wait().next().is_none());
+        let (mut tree_1, mut tree_2) = two_replicas_at(&git, commit_0);
 
         let ops_1 = open_envelopes(tree_1.create_file("x.txt", FileType::Text));
         let ops_2 = open_envelopes(tree_2.create_file("y.txt", FileType::Text));
@@ -1698,6 +6823,410 @@ mod tests {
         assert!(tree_2.observed(tree_1.version()));
     }
 
+    #[test]
+    fn test_reject_stale_ops() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit_0 = git.commit(&WorkTree::empty());
+        let base_tree = WorkTree::empty();
+        base_tree.create_file("a", FileType::Text).unwrap();
+        let commit_1 = git.commit(&base_tree);
+
+        let (mut tree_1, mut tree_2) = two_replicas_at(&git, commit_0);
+        let old_epoch_id = tree_2.epoch_id();
+
+        let op = tree_1.create_file("b", FileType::Text).unwrap();
+        assert_eq!(op.epoch_id(), old_epoch_id);
+        let reset_ops = open_envelopes(tree_2.reset(Some(commit_1)).collect().wait().unwrap());
+        assert!(tree_2.epoch_id() > old_epoch_id);
+
+        tree_2.set_reject_stale_ops(true);
+        assert_eq!(
+            tree_2.apply_ops(Some(op.operation.clone())).err(),
+            Some(Error::StaleEpoch(old_epoch_id))
+        );
+        tree_2.set_reject_stale_ops(false);
+        assert!(tree_2.apply_ops(Some(op.operation)).is_ok());
+        assert!(!tree_2.exists("b"));
+
+        tree_1
+            .apply_ops(reset_ops)
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree_1.epoch_id(), tree_2.epoch_id());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        struct RecordingDiagnostics(RefCell<Vec<Warning>>);
+
+        impl Diagnostics for RecordingDiagnostics {
+            fn warn(&self, warning: Warning) {
+                self.0.borrow_mut().push(warning);
+            }
+        }
+
+        let git = Rc::new(TestGitProvider::new());
+        let commit_0 = git.commit(&WorkTree::empty());
+        let (mut tree_1, mut tree_2) = two_replicas_at(&git, commit_0);
+        let diagnostics_1 = Rc::new(RecordingDiagnostics(RefCell::new(Vec::new())));
+        tree_1.set_diagnostics(diagnostics_1.clone());
+        tree_1.set_deferred_ops_limit(1, OverflowPolicy::DropOldest);
+        let diagnostics_2 = Rc::new(RecordingDiagnostics(RefCell::new(Vec::new())));
+        tree_2.set_diagnostics(diagnostics_2.clone());
+
+        let old_epoch_id = tree_2.epoch_id();
+        let stale_op = tree_1.create_file("a", FileType::Text).unwrap();
+        tree_2.reset(Some(commit_0)).collect().wait().unwrap();
+        tree_2
+            .apply_ops(Some(stale_op.operation))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            *diagnostics_2.0.borrow(),
+            vec![Warning::DroppedStaleOperation {
+                epoch_id: old_epoch_id,
+                cur_epoch_id: tree_2.epoch_id(),
+            }]
+        );
+
+        // Operations from the new epoch are deferred until it starts, and overflow the limit.
+        let future_ops = open_envelopes(vec![
+            tree_2.create_file("b", FileType::Text).unwrap(),
+            tree_2.create_file("c", FileType::Text).unwrap(),
+        ]);
+        tree_1
+            .apply_ops(future_ops)
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            *diagnostics_1.0.borrow(),
+            vec![Warning::DroppedDeferredOperations { count: 1 }]
+        );
+
+        // Operations deferred for an epoch that gets skipped are dropped once a later one starts.
+        diagnostics_1.0.borrow_mut().clear();
+        let skipped_epoch_id = tree_2.epoch_id();
+        let reset_ops = open_envelopes(tree_2.reset(Some(commit_0)).collect().wait().unwrap());
+        tree_1
+            .apply_ops(reset_ops)
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree_1.epoch_id(), tree_2.epoch_id());
+        assert_eq!(tree_1.deferred_operation_count(), 0);
+        assert_eq!(
+            *diagnostics_1.0.borrow(),
+            vec![Warning::DroppedStaleOperation {
+                epoch_id: skipped_epoch_id,
+                cur_epoch_id: tree_2.epoch_id(),
+            }]
+        );
+
+        // Anchors into collected tombstones resolve to where the tombstones used to be.
+        diagnostics_1.0.borrow_mut().clear();
+        let file_id = tree_1
+            .create_file("d", FileType::Text)
+            .unwrap()
+            .file_id()
+            .unwrap();
+        let d = tree_1.open_text_file("d").wait().unwrap();
+        tree_1.edit(d, Some(0..0), "ab").unwrap();
+        let (_, range) = tree_1
+            .splice(d, Point::new(0, 1)..Point::new(0, 1), "XY")
+            .unwrap();
+        tree_1.edit(d, Some(1..3), "").unwrap();
+        let barrier = tree_1.cur_epoch().version();
+        tree_1.collect_garbage(&barrier);
+        assert_eq!(
+            tree_1.point_for_anchor(d, &range.start),
+            Ok(Point::new(0, 1))
+        );
+        assert_eq!(
+            *diagnostics_1.0.borrow(),
+            vec![Warning::AnchorResolutionFallback { file_id, count: 1 }]
+        );
+    }
+
+    #[test]
+    fn test_deferred_ops_limit() {
+        let git = Rc::new(TestGitProvider::new());
+        let commit_0 = git.commit(&WorkTree::empty());
+        let (mut tree_1, ops_1) = WorkTree::new(
+            Uuid::from_u128(1),
+            Some(commit_0),
+            vec![],
+            git.clone(),
+            None,
+        )
+        .unwrap();
+        let ops_1 = open_envelopes(ops_1.collect().wait().unwrap());
+        let reset_ops = open_envelopes(tree_1.reset(Some(commit_0)).collect().wait().unwrap());
+        let future_ops = open_envelopes(vec![
+            tree_1.create_file("a", FileType::Text).unwrap(),
+            tree_1.create_file("b", FileType::Text).unwrap(),
+            tree_1.create_file("c", FileType::Text).unwrap(),
+        ]);
+
+        let new_tree = |policy| {
+            let (mut tree, ops) = WorkTree::new(
+                Uuid::from_u128(2),
+                Some(commit_0),
+                ops_1.clone(),
+                git.clone(),
+                None,
+            )
+            .unwrap();
+            ops.collect().wait().unwrap();
+            tree.set_deferred_ops_limit(2, policy);
+            assert_eq!(tree.overflow_policy(), Some(policy));
+            tree
+        };
+
+        let mut tree = new_tree(OverflowPolicy::Error);
+        assert_eq!(
+            tree.apply_ops(future_ops.clone()).err(),
+            Some(Error::QueueFull)
+        );
+        assert_eq!(tree.deferred_operation_count(), 0);
+        tree.apply_ops(future_ops[0..2].to_vec())
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree.deferred_operation_count(), 2);
+        assert_eq!(
+            tree.apply_ops(future_ops[2..].to_vec()).err(),
+            Some(Error::QueueFull)
+        );
+
+        let mut tree = new_tree(OverflowPolicy::DropOldest);
+        tree.apply_ops(future_ops.clone())
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree.deferred_operation_count(), 2);
+        tree.apply_ops(reset_ops.clone())
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert!(!tree.exists("a"));
+        assert!(tree.exists("b"));
+        assert!(tree.exists("c"));
+
+        let mut tree = new_tree(OverflowPolicy::Block);
+        tree.apply_ops(future_ops.clone())
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree.deferred_operation_count(), 3);
+        tree.apply_ops(reset_ops.clone())
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert!(tree.exists("a"));
+        assert!(tree.exists("b"));
+        assert!(!tree.exists("c"));
+        tree.apply_ops(None).unwrap().collect().wait().unwrap();
+        assert!(tree.exists("c"));
+        assert_eq!(tree.deferred_operation_count(), 0);
+
+        // Operations blocked by an earlier call survive a batch being rejected.
+        let mut tree = new_tree(OverflowPolicy::Block);
+        tree.apply_ops(future_ops.clone())
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        tree.set_deferred_ops_limit(2, OverflowPolicy::Error);
+        assert_eq!(
+            tree.apply_ops(future_ops[2..].to_vec()).err(),
+            Some(Error::QueueFull)
+        );
+        tree.set_deferred_ops_limit(2, OverflowPolicy::Block);
+        tree.apply_ops(reset_ops.clone())
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        tree.apply_ops(None).unwrap().collect().wait().unwrap();
+        assert!(tree.exists("a"));
+        assert!(tree.exists("b"));
+        assert!(tree.exists("c"));
+    }
+
+    #[test]
+    fn test_rename_replica() {
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, mut tree_2) = two_replicas(&git);
+
+        let mut ops_1 = open_envelopes(tree_1.create_file("a", FileType::Directory));
+        ops_1.extend(open_envelopes(tree_1.create_file("a/b", FileType::Text)));
+        let buffer_id = tree_1.open_text_file("a/b").wait().unwrap();
+        ops_1.extend(open_envelopes(tree_1.edit(buffer_id, Some(0..0), "abc")));
+        let ops_2 = open_envelopes(tree_2.create_file("c", FileType::Text));
+        tree_1.apply_ops(ops_2).unwrap().collect().wait().unwrap();
+        tree_2.apply_ops(ops_1).unwrap().collect().wait().unwrap();
+
+        assert_eq!(
+            tree_1.rename_replica(Uuid::from_u128(1), Uuid::from_u128(2)),
+            Err(Error::InvalidReplicaId(Uuid::from_u128(2)))
+        );
+        assert_eq!(
+            tree_1.rename_replica(Uuid::from_u128(2), Uuid::from_u128(3)),
+            Err(Error::InvalidReplicaId(Uuid::from_u128(2)))
+        );
+        assert_eq!(
+            tree_1.rename_replica(Uuid::from_u128(1), Uuid::from_u128(3)),
+            Ok(())
+        );
+        assert_eq!(tree_1.replica_id(), Uuid::from_u128(3));
+        assert_eq!(tree_1.version().epoch_version.get(Uuid::from_u128(1)), 0);
+        assert!(tree_1.version().epoch_version.get(Uuid::from_u128(3)) > 0);
+        assert_eq!(tree_1.dir_entries(), tree_2.dir_entries());
+        assert_eq!(tree_1.text_str(buffer_id), "abc");
+        assert!(tree_1.operations_from(Uuid::from_u128(1)).is_empty());
+        assert_eq!(
+            tree_1.operations_from(Uuid::from_u128(3)).len(),
+            tree_2.operations_from(Uuid::from_u128(1)).len()
+        );
+
+        tree_1.edit(buffer_id, Some(3..3), "def").unwrap();
+        tree_1.create_file("a/d", FileType::Text).unwrap();
+        assert_eq!(tree_1.text_str(buffer_id), "abcdef");
+        assert!(tree_1.exists("a/d"));
+
+        // Everything is flushed once under the new id, after which the id can't change anymore.
+        let flushed = tree_1.flush_operations();
+        assert!(!flushed.is_empty());
+        assert!(flushed
+            .iter()
+            .all(|envelope| envelope.operation.replica_id() == Uuid::from_u128(3)));
+        assert!(tree_1.flush_operations().is_empty());
+        assert_eq!(
+            tree_1.rename_replica(Uuid::from_u128(3), Uuid::from_u128(4)),
+            Err(Error::InvalidReplicaId(Uuid::from_u128(3)))
+        );
+        assert!(tree_1.flush_operations().is_empty());
+    }
+
+    #[test]
+    fn test_unsaved_changes() {
+        let git = Rc::new(TestGitProvider::new());
+        let base_tree = WorkTree::empty();
+        base_tree.create_file("a", FileType::Text).unwrap();
+        let a_base = base_tree.open_text_file("a").wait().unwrap();
+        base_tree.edit(a_base, Some(0..0), "abc").unwrap();
+        let commit = git.commit(&base_tree);
+
+        let (mut tree, ops) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        ops.collect().wait().unwrap();
+        let buffer_id = tree.open_text_file("a").wait().unwrap();
+        assert!(tree.unsaved_changes(buffer_id).unwrap().is_empty());
+
+        tree.edit(buffer_id, Some(1..2), "def").unwrap();
+        let changes = tree.unsaved_changes(buffer_id).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].range, Point::new(0, 1)..Point::new(0, 2));
+        assert_eq!(String::from_utf16_lossy(&changes[0].code_units), "def");
+
+        tree.mark_saved(buffer_id).unwrap();
+        assert!(tree.unsaved_changes(buffer_id).unwrap().is_empty());
+
+        tree.edit(buffer_id, Some(5..5), "ghi").unwrap();
+        let changes = tree.unsaved_changes(buffer_id).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].range, Point::new(0, 5)..Point::new(0, 5));
+        assert_eq!(String::from_utf16_lossy(&changes[0].code_units), "ghi");
+        assert_eq!(
+            tree.unsaved_changes(BufferId(42)),
+            Err(Error::InvalidBufferId)
+        );
+    }
+
+    #[test]
+    fn test_pending_operation_count() {
+        let git = Rc::new(TestGitProvider::new());
+        let (mut tree_1, mut tree_2) = two_replicas(&git);
+
+        // The epoch started by tree 1 hasn't been flushed yet.
+        assert_eq!(tree_1.pending_operation_count(), 1);
+        assert!(tree_1.has_pending());
+        tree_1.flush_operations();
+        assert_eq!(tree_1.pending_operation_count(), 0);
+        assert!(!tree_1.has_pending());
+        assert_eq!(tree_2.pending_operation_count(), 0);
+
+        let create_ops = open_envelopes(tree_1.create_file("a", FileType::Text));
+        let buffer_id = tree_1.open_text_file("a").wait().unwrap();
+        let mut edit_ops = open_envelopes(tree_1.edit(buffer_id, Some(0..0), "abc"));
+        edit_ops.extend(open_envelopes(tree_1.edit(buffer_id, Some(3..3), "def")));
+        assert_eq!(tree_1.pending_operation_count(), 3);
+        assert_eq!(tree_1.flush_operations_chunked(2).len(), 2);
+        assert_eq!(tree_1.pending_operation_count(), 1);
+        assert_eq!(tree_1.flush_operations().len(), 1);
+        assert!(!tree_1.has_pending());
+
+        // Remote operations are never pending, even while they are deferred.
+        tree_2
+            .apply_ops(edit_ops)
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree_2.pending_operation_count(), 0);
+        assert_eq!(tree_2.deferred_operation_count(), 2);
+
+        tree_2
+            .apply_ops(create_ops)
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(tree_2.deferred_operation_count(), 0);
+        assert!(!tree_2.has_pending());
+    }
+
+    fn list_dir_names(tree: &WorkTree, path: &str) -> Vec<String> {
+        tree.list_dir(path)
+            .unwrap()
+            .into_iter()
+            .map(|(entry, _)| entry.name.into_string().unwrap())
+            .collect()
+    }
+
+    fn two_replicas(git: &Rc<TestGitProvider>) -> (WorkTree, WorkTree) {
+        let commit = git.commit(&WorkTree::empty());
+        two_replicas_at(git, commit)
+    }
+
+    // Two replicas of the same work tree at `commit`, the second created from the first's operations.
+    fn two_replicas_at(git: &Rc<TestGitProvider>, commit: Oid) -> (WorkTree, WorkTree) {
+        let (tree_1, ops_1) =
+            WorkTree::new(Uuid::from_u128(1), Some(commit), vec![], git.clone(), None).unwrap();
+        let (tree_2, ops_2) = WorkTree::new(
+            Uuid::from_u128(2),
+            Some(commit),
+            open_envelopes(ops_1.collect().wait().unwrap()),
+            git.clone(),
+            None,
+        )
+        .unwrap();
+        assert!(ops_2.wait().next().is_none());
+        (tree_1, tree_2)
+    }
+
     fn open_envelopes<I: IntoIterator<Item = OperationEnvelope>>(envelopes: I) -> Vec<Operation> {
         envelopes.into_iter().map(|e| e.operation).collect()
     }
@@ -1866,6 +7395,8 @@ mod tests {
     struct TestGitProvider {
         commits: RefCell<HashMap<Oid, WorkTree>>,
         next_oid: RefCell<u64>,
+        gitignores: RefCell<HashMap<PathBuf, Vec<&'static str>>>,
+        ignore_queries: RefCell<usize>,
     }
 
     struct TestChangeObserver {
@@ -1880,9 +7411,17 @@ mod tests {
             TestGitProvider {
                 commits: RefCell::new(HashMap::new()),
                 next_oid: RefCell::new(0),
+                gitignores: RefCell::new(HashMap::new()),
+                ignore_queries: RefCell::new(0),
             }
         }
 
+        fn set_gitignore<P: Into<PathBuf>>(&self, dir_path: P, patterns: Vec<&'static str>) {
+            self.gitignores
+                .borrow_mut()
+                .insert(dir_path.into(), patterns);
+        }
+
         fn commit(&self, tree: &WorkTree) -> Oid {
             let mut tree_clone = WorkTree::empty();
             tree_clone.epoch = tree
@@ -1953,6 +7492,46 @@ mod tests {
                     .into_future(),
             )
         }
+
+        // Supports a small subset of gitignore semantics: patterns match a single path component,
+        // either exactly or by extension ("*.log"), and apply anywhere below the directory
+        // containing the .gitignore. Deeper and later patterns take precedence, a leading "!"
+        // re-includes a path, and nothing below an ignored directory can be re-included.
+        fn is_ignored(&self, path: &Path) -> Result<bool, io::Error> {
+            fn matches(pattern: &str, name: &str) -> bool {
+                if pattern.starts_with("*.") {
+                    name.ends_with(&pattern[1..])
+                } else {
+                    pattern == name
+                }
+            }
+
+            *self.ignore_queries.borrow_mut() += 1;
+            let gitignores = self.gitignores.borrow();
+            let mut prefix = PathBuf::new();
+            for component in path.components() {
+                prefix.push(component);
+                let name = component.as_os_str().to_string_lossy();
+                let mut ignored = false;
+                let mut dir_paths = prefix.ancestors().skip(1).collect::<Vec<_>>();
+                dir_paths.reverse();
+                for dir_path in dir_paths {
+                    for pattern in gitignores.get(dir_path).into_iter().flatten() {
+                        if pattern.starts_with('!') {
+                            if matches(&pattern[1..], &name) {
+                                ignored = false;
+                            }
+                        } else if matches(pattern, &name) {
+                            ignored = true;
+                        }
+                    }
+                }
+                if ignored {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
     }
 
     impl TestChangeObserver {